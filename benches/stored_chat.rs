@@ -0,0 +1,277 @@
+//! Benchmarks how a chat's messages are stored, i.e. in a vector in
+//! the order they were received, for a mostly-append workload.
+//!
+//! The first table times the operations that a `ChatServer` performs
+//! on a chat of each size, per operation. The second compares the
+//! vector with a `BTreeMap` keyed by `(timestamp, seq)`, the obvious
+//! alternative, for the same operations on a model of the chat.
+//!
+//! Run it with `cargo bench --bench stored_chat`, e.g. redirecting
+//! its output to `bench_output.txt`.
+
+use signal_http::chat::*;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Instant;
+
+/// The numbers of messages in the chats that are benchmarked.
+const SIZES: [usize; 3] = [1_000, 10_000, 100_000];
+
+/// How many messages a page lists.
+const PAGE: usize = 50;
+
+/// How many times each of the cheaper operations is repeated, so
+/// that it can be timed.
+const REPEATS: usize = 1_000;
+
+fn main() {
+    println!("chat server, microseconds per operation");
+    println!(
+        "{:>8} {:>8} {:>8} {:>8} {:>8} {:>8} {:>8}",
+        "size", "append", "skewed", "latest", "page", "erase", "expire"
+    );
+
+    for size in SIZES.iter() {
+        bench_server(*size);
+    }
+
+    println!();
+    println!("vector vs btree, microseconds per operation");
+    println!(
+        "{:>8} {:>8} {:>8} {:>8} {:>8} {:>8} {:>8}",
+        "size", "", "append", "skewed", "latest", "remove", "expire"
+    );
+
+    for size in SIZES.iter() {
+        bench_models(*size);
+    }
+}
+
+/// Times the operations of a `ChatServer` on a chat with the supplied
+/// number of messages.
+fn bench_server(size: usize) {
+    // appending also records the message in each recipient's feed and
+    // pending messages, which are bounded, so it's only appending to
+    // the chat that grows with its size
+
+    let append = time(size, || server(size, |i| i as u64)).0;
+
+    // clients' clocks are skewed, so messages aren't received in the
+    // order of their timestamps
+
+    let (skewed, mut server) = time(size, || server(size, |i| (i % 7 * 1000 + i) as u64));
+
+    let latest = time(REPEATS, || {
+        for _ in 0..REPEATS {
+            list(&mut server, None, Some(size as u64));
+        }
+    })
+    .0;
+
+    let page = time(REPEATS, || {
+        for i in 0..REPEATS {
+            list(&mut server, Some((i * size / REPEATS).to_string()), None);
+        }
+    })
+    .0;
+
+    // user 3 wrote every 100th message, which are replaced with
+    // tombstones throughout the chat
+
+    let erase = time(1, || {
+        server.issue(ChatRequest::EraseUser { user_id: 3 });
+    })
+    .0;
+
+    server.issue(ChatRequest::UpdateChat {
+        id: 1,
+        user_id: 1,
+        title: None,
+        message_ttl: Some(1),
+    });
+
+    let expire = time(1, || {
+        server.expire((size / 10) as u64);
+    })
+    .0;
+
+    println!(
+        "{:>8} {:>8.2} {:>8.2} {:>8.2} {:>8.2} {:>8.2} {:>8.2}",
+        size, append, skewed, latest, page, erase, expire
+    );
+}
+
+/// Creates a server with a chat that has the supplied number of
+/// messages, timestamped by the supplied function of their index.
+fn server<F: Fn(usize) -> u64>(size: usize, timestamp: F) -> ChatServer {
+    let mut server = ChatServer::new();
+
+    for (id, list) in [(1, vec![2, 3]), (2, vec![1, 3]), (3, vec![1, 2])].iter() {
+        server.issue(ChatRequest::StoreContactList {
+            id: *id,
+            list: list.clone(),
+        });
+    }
+
+    server.issue(ChatRequest::CreateChat {
+        id: Some(1),
+        participant_ids: vec![1, 2, 3],
+        title: None,
+        created_at: None,
+        creator: None,
+    });
+
+    for i in 0..size {
+        server.issue(ChatRequest::AddMessage {
+            id: i.to_string(),
+            chat_id: 1,
+            source_user_id: if i % 100 == 0 { 3 } else { 1 + i as Id % 2 },
+            destination_user_id: None,
+            timestamp: timestamp(i),
+            message: "hello".to_string(),
+            attachment_ids: Vec::new(),
+            envelope: None,
+            reply_to: None,
+            kind: MessageKind::Text,
+            previews: Vec::new(),
+        });
+    }
+
+    server
+}
+
+/// Lists a page of the chat, after the supplied cursor, or before the
+/// supplied sequence number.
+fn list(server: &mut ChatServer, cursor: Option<String>, before: Option<u64>) {
+    match server.issue(ChatRequest::ListChat {
+        id: 1,
+        cursor,
+        limit: Some(PAGE),
+        before,
+    }) {
+        ChatResponse::ChatListed { ref messages, .. } if !messages.is_empty() => {}
+        other => panic!("unexpected response: {:?}", other),
+    }
+}
+
+/// A message in a model of a chat, which is shared with the pages
+/// that list it, as a chat's messages are.
+struct Message {
+    seq: u64,
+    timestamp: u64,
+}
+
+/// Times the operations on models of a chat with the supplied number
+/// of messages, stored in a vector, and in a `BTreeMap`.
+fn bench_models(size: usize) {
+    let timestamp = |i: usize| (i % 7 * 1000 + i) as u64;
+    let message = |i: usize| {
+        Arc::new(Message {
+            seq: i as u64 + 1,
+            timestamp: timestamp(i),
+        })
+    };
+
+    // messages are appended to the vector in the order they're
+    // received, i.e. by sequence number, but are inserted into the map
+    // by timestamp, which is only in order if clocks aren't skewed
+
+    let (vector_append, mut vector) = time(size, || {
+        let mut messages = Vec::new();
+
+        for i in 0..size {
+            messages.push(message(i));
+        }
+
+        messages
+    });
+
+    let btree_append = time(size, || {
+        let mut messages = BTreeMap::new();
+
+        for i in 0..size {
+            messages.insert((i as u64, i as u64 + 1), message(i));
+        }
+
+        messages
+    })
+    .0;
+
+    let (btree_skewed, mut btree) = time(size, || {
+        let mut messages = BTreeMap::new();
+
+        for i in 0..size {
+            messages.insert((timestamp(i), i as u64 + 1), message(i));
+        }
+
+        messages
+    });
+
+    // the latest page is sliced from the end of the vector, but must
+    // be iterated to in the map
+
+    let vector_latest = time(REPEATS, || {
+        for _ in 0..REPEATS {
+            assert_eq!(vector[vector.len() - PAGE..].to_vec().len(), PAGE);
+        }
+    })
+    .0;
+
+    let btree_latest = time(REPEATS, || {
+        for _ in 0..REPEATS {
+            let page = btree.values().rev().take(PAGE).cloned();
+
+            assert_eq!(page.collect::<Vec<_>>().len(), PAGE);
+        }
+    })
+    .0;
+
+    // every 100th message is removed, e.g. if the map's messages were
+    // removed rather than replaced with tombstones
+
+    let vector_remove = time(1, || vector.retain(|m| m.seq % 100 != 1)).0;
+
+    let btree_remove = time(1, || {
+        let removed = btree
+            .keys()
+            .filter(|(_, seq)| seq % 100 == 1)
+            .cloned()
+            .collect::<Vec<_>>();
+
+        for key in removed {
+            btree.remove(&key);
+        }
+    })
+    .0;
+
+    // the oldest tenth of the messages expire
+
+    let cutoff = timestamp(size / 10);
+
+    let vector_expire = time(1, || vector.retain(|m| m.timestamp >= cutoff)).0;
+    let btree_expire = time(1, || btree = btree.split_off(&(cutoff, 0))).0;
+
+    println!(
+        "{:>8} {:>8} {:>8.2} {:>8} {:>8.2} {:>8.2} {:>8.2}",
+        size, "vector", vector_append, "", vector_latest, vector_remove, vector_expire
+    );
+    println!(
+        "{:>8} {:>8} {:>8.2} {:>8.2} {:>8.2} {:>8.2} {:>8.2}",
+        "", "btree", btree_append, btree_skewed, btree_latest, btree_remove, btree_expire
+    );
+}
+
+/// Runs the supplied function, returning how long it took per
+/// operation, in microseconds, given how many it performed, and what
+/// it returned.
+fn time<T, F: FnOnce() -> T>(operations: usize, f: F) -> (f64, T) {
+    let started = Instant::now();
+    let result = f();
+    let elapsed = started.elapsed();
+
+    (
+        elapsed.as_secs() as f64 * 1e6 / operations as f64
+            + f64::from(elapsed.subsec_nanos()) / 1e3 / operations as f64,
+        result,
+    )
+}
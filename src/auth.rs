@@ -0,0 +1,467 @@
+//! Provides token-based authentication, so that requests can be
+//! attributed to the users who made them.
+//!
+//! A user authenticates with a credential, which the server checks
+//! with the authenticator it is configured with, e.g. one backed by
+//! a `Credentials` file. If it's valid, they're issued an opaque
+//! token, which they then present with their subsequent requests
+//! until it expires, and which the server resolves back to their id.
+//!
+//! Tokens are kept in memory, so they don't survive restarts, and
+//! only their hashes are kept, so that they can't be recovered from
+//! the server. Authenticating isn't logged, so neither credentials
+//! nor tokens are written to a store.
+//!
+//! Credentials are configured as salted Argon2 hashes, in the PHC
+//! string format, so that they're expensive to recover even if the
+//! file leaks. Tokens are random and long enough that a plain
+//! SHA-256 hash suffices.
+//!
+//! A server can also be configured with static tokens, e.g. for bots
+//! and other services, which are resolved like issued tokens but
+//! never expire. Like issued tokens, only their SHA-256 hashes are
+//! configured, so they must be generated rather than chosen.
+//!
+//! Small deployments can instead protect some routes, e.g. those
+//! under `/admin`, with HTTP Basic authentication, whose usernames and
+//! password hashes are configured like credentials.
+
+use crate::chat::Id;
+use argon2::password_hash::{PasswordHash, PasswordVerifier};
+use argon2::Argon2;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::io::Error as IoError;
+use std::io::ErrorKind as IoErrorKind;
+use std::io::Result as IoResult;
+use std::str::{self, FromStr};
+use std::time::{Duration, Instant};
+
+/// The default for how long a token is valid for once it is issued.
+pub const DEFAULT_TOKEN_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Length of a token, in bytes, before it is hex-encoded.
+const TOKEN_LENGTH: usize = 32;
+
+/// Checks that a credential is valid for the user with the supplied
+/// id.
+pub(crate) type Authenticator = Box<dyn Fn(Id, &str) -> bool + Send + Sync>;
+
+/// The hashes of users' credentials, which can be used to check
+/// the credentials that they authenticate with.
+#[derive(Debug, Default)]
+pub struct Credentials {
+    hashes: HashMap<Id, String>,
+}
+
+impl Credentials {
+    /// Parses a credentials document, with one user per line
+    /// consisting of their id and the Argon2 hash of their
+    /// credential in the PHC string format, e.g.
+    ///
+    /// ```text
+    /// 51201 $argon2id$v=19$m=19456,t=2,p=1$c2lnbmFsaHR0cHNhbHQ$bHqMp7/Fu+ILOXySCgeJqZW0IdfMnaJEXcqKLzMtCXo
+    /// ```
+    pub fn parse(data: &str) -> IoResult<Self> {
+        Ok(Self {
+            hashes: parse_hashes(data, "credentials", password_hash)?
+                .into_iter()
+                .collect(),
+        })
+    }
+
+    /// Determines if the supplied credential is valid for the user
+    /// with the supplied id.
+    pub fn verify(&self, user_id: Id, credential: &str) -> bool {
+        verify_password(&self.hashes, &user_id, credential)
+    }
+}
+
+/// The hashes of the passwords of the users who may make requests
+/// with HTTP Basic authentication.
+#[derive(Debug, Default)]
+pub struct BasicCredentials {
+    hashes: HashMap<String, String>,
+}
+
+impl BasicCredentials {
+    /// Parses a Basic credentials document, with one user per line
+    /// consisting of their username and the Argon2 hash of their
+    /// password in the PHC string format, e.g.
+    ///
+    /// ```text
+    /// admin $argon2id$v=19$m=19456,t=2,p=1$c2lnbmFsaHR0cHNhbHQ$bHqMp7/Fu+ILOXySCgeJqZW0IdfMnaJEXcqKLzMtCXo
+    /// ```
+    pub fn parse(data: &str) -> IoResult<Self> {
+        Ok(Self {
+            hashes: parse_hashes(data, "basic credentials", password_hash)?
+                .into_iter()
+                .collect(),
+        })
+    }
+
+    /// Determines if the supplied `Authorization` header carries the
+    /// username and password of one of the users, e.g.
+    /// `Basic YWRtaW46dGVzdA==`.
+    pub fn verify(&self, authorization: &str) -> bool {
+        let mut parts = authorization.trim().splitn(2, ' ');
+
+        let encoded = match (parts.next(), parts.next()) {
+            (Some(scheme), Some(encoded)) if scheme.eq_ignore_ascii_case("Basic") => encoded,
+            _ => return false,
+        };
+
+        let decoded = match base64::decode(encoded.trim()) {
+            Ok(decoded) => decoded,
+            Err(_) => return false,
+        };
+
+        let mut parts = match str::from_utf8(&decoded) {
+            Ok(decoded) => decoded.splitn(2, ':'),
+            Err(_) => return false,
+        };
+
+        match (parts.next(), parts.next()) {
+            (Some(username), Some(password)) => {
+                verify_password(&self.hashes, &username.to_string(), password)
+            }
+
+            _ => false,
+        }
+    }
+}
+
+/// The hashes of static tokens, which resolve to the users they're
+/// configured for, and don't expire.
+#[derive(Debug, Default)]
+pub struct StaticTokens {
+    user_ids: HashMap<String, Id>,
+}
+
+impl StaticTokens {
+    /// Parses a static tokens document, with one token per line
+    /// consisting of the id of the user it resolves to and its
+    /// hex-encoded SHA-256 hash, e.g.
+    ///
+    /// ```text
+    /// 40123 9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08
+    /// ```
+    ///
+    /// A user may have several tokens, e.g. so that one can be
+    /// rotated without downtime.
+    pub fn parse(data: &str) -> IoResult<Self> {
+        Ok(Self {
+            user_ids: parse_hashes(data, "tokens", token_hash)?
+                .into_iter()
+                .map(|(user_id, hash)| (hash, user_id))
+                .collect(),
+        })
+    }
+
+    /// Resolves the supplied token to the id of the user it is
+    /// configured for, or `None` if it isn't configured.
+    pub fn resolve(&self, token: &str) -> Option<Id> {
+        self.user_ids.get(&digest(token)).cloned()
+    }
+}
+
+/// Internal API.
+///
+/// The tokens that have been issued, keyed by their hashes.
+pub(crate) struct Tokens {
+    ttl: Duration,
+    sessions: HashMap<String, Session>,
+}
+
+/// Internal API.
+///
+/// The user that a token was issued to, and when it expires.
+struct Session {
+    user_id: Id,
+    expires_at: Instant,
+}
+
+impl Default for Tokens {
+    fn default() -> Self {
+        Self {
+            ttl: DEFAULT_TOKEN_TTL,
+            sessions: HashMap::new(),
+        }
+    }
+}
+
+impl Tokens {
+    /// Internal API.
+    ///
+    /// Configures how long tokens that are issued from now on are
+    /// valid for.
+    pub(crate) fn set_ttl(&mut self, ttl: Duration) {
+        self.ttl = ttl;
+    }
+
+    /// Internal API.
+    ///
+    /// Obtains how long tokens are valid for once they're issued.
+    pub(crate) fn ttl(&self) -> Duration {
+        self.ttl
+    }
+
+    /// Internal API.
+    ///
+    /// Issues a new token to the user with the supplied id, first
+    /// discarding those that have expired.
+    pub(crate) fn issue(&mut self, user_id: Id) -> String {
+        let now = Instant::now();
+
+        self.sessions.retain(|_, session| session.expires_at > now);
+
+        let mut bytes = [0u8; TOKEN_LENGTH];
+
+        random_bytes(&mut bytes);
+
+        let token = hex::encode(bytes);
+
+        self.sessions.insert(
+            digest(&token),
+            Session {
+                user_id,
+                expires_at: now + self.ttl,
+            },
+        );
+
+        token
+    }
+
+    /// Internal API.
+    ///
+    /// Resolves the supplied token to the id of the user it was
+    /// issued to, or `None` if it is unknown or has expired.
+    pub(crate) fn resolve(&self, token: &str) -> Option<Id> {
+        self.sessions
+            .get(&digest(token))
+            .filter(|session| session.expires_at > Instant::now())
+            .map(|session| session.user_id)
+    }
+}
+
+/// Internal API.
+///
+/// Parses a document with one user, e.g. their id, and hash per
+/// line, where the supplied function validates and normalizes each
+/// hash, and the supplied name of the document describes it in
+/// errors.
+fn parse_hashes<U: FromStr>(
+    data: &str,
+    name: &str,
+    parse_hash: fn(&str) -> Option<String>,
+) -> IoResult<Vec<(U, String)>> {
+    let mut hashes = Vec::new();
+
+    for line in data.lines().map(str::trim).filter(|l| !l.is_empty()) {
+        let mut parts = line.split_whitespace();
+
+        let user_id = parts.next().and_then(|id| id.parse().ok());
+        let hash = parts.next().and_then(parse_hash);
+
+        match (user_id, hash, parts.next()) {
+            (Some(user_id), Some(hash), None) => {
+                hashes.push((user_id, hash));
+            }
+
+            _ => {
+                return Err(IoError::new(
+                    IoErrorKind::InvalidData,
+                    format!("invalid {} line: {}", name, line),
+                ));
+            }
+        }
+    }
+
+    Ok(hashes)
+}
+
+/// Internal API.
+///
+/// Validates a hex-encoded SHA-256 hash of a token, normalizing it
+/// to lowercase.
+fn token_hash(hash: &str) -> Option<String> {
+    if hash.len() == 64 && hex::decode(hash).is_ok() {
+        Some(hash.to_ascii_lowercase())
+    } else {
+        None
+    }
+}
+
+/// Internal API.
+///
+/// Validates an Argon2 hash of a password in the PHC string format.
+fn password_hash(hash: &str) -> Option<String> {
+    match PasswordHash::new(hash) {
+        Ok(parsed) if parsed.algorithm.as_str().starts_with("argon2") && parsed.hash.is_some() => {
+            Some(hash.to_string())
+        }
+
+        _ => None,
+    }
+}
+
+/// Internal API.
+///
+/// Determines if the supplied password matches the hash of the user
+/// with the supplied key. A user who doesn't have one is checked
+/// against another user's hash anyway, so that how long it takes
+/// doesn't reveal who has one. Argon2 compares the hashes in
+/// constant time.
+fn verify_password<K: Eq + Hash>(hashes: &HashMap<K, String>, key: &K, password: &str) -> bool {
+    let (hash, known) = match hashes.get(key) {
+        Some(hash) => (hash, true),
+
+        None => match hashes.values().next() {
+            Some(hash) => (hash, false),
+            None => return false,
+        },
+    };
+
+    let verified = PasswordHash::new(hash).is_ok_and(|hash| {
+        Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok()
+    });
+
+    known && verified
+}
+
+/// Internal API.
+///
+/// Hashes the supplied secret, producing a hex-encoded SHA-256.
+fn digest(secret: &str) -> String {
+    hex::encode(Sha256::digest(secret.as_bytes()))
+}
+
+/// Internal API.
+///
+/// Fills the supplied buffer with random bytes from the operating
+/// system. Without a source of randomness, nothing that needs it,
+/// e.g. a token, can be generated securely, so this panics.
+pub(crate) fn random_bytes(bytes: &mut [u8]) {
+    if let Err(e) = getrandom::getrandom(bytes) {
+        panic!("cannot obtain random bytes: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::auth::*;
+    use argon2::password_hash::{PasswordHasher, SaltString};
+    use argon2::{Algorithm, Params, Version};
+
+    /// Hashes the supplied password with the supplied salt, with
+    /// the cheapest parameters so that the tests are quick.
+    fn hash(password: &str, salt: &str) -> String {
+        Argon2::new(
+            Algorithm::Argon2id,
+            Version::V0x13,
+            Params::new(8, 1, 1, None).unwrap(),
+        )
+        .hash_password(
+            password.as_bytes(),
+            &SaltString::encode_b64(salt.as_bytes()).unwrap(),
+        )
+        .unwrap()
+        .to_string()
+    }
+
+    #[test]
+    fn test_credentials() {
+        let credentials = Credentials::parse(&format!(
+            "\n1 {}\n2 {}\n",
+            hash("secret", "salt-one"),
+            hash("other", "salt-two")
+        ))
+        .unwrap();
+
+        assert!(credentials.verify(1, "secret"));
+        assert!(credentials.verify(2, "other"));
+        assert!(!credentials.verify(1, "other"));
+        assert!(!credentials.verify(3, "secret"));
+        assert!(!credentials.verify(3, "other"));
+
+        // each hash is salted, so the same credential hashes differently
+
+        assert_ne!(hash("secret", "salt-one"), hash("secret", "salt-two"));
+
+        assert!(Credentials::parse("1").is_err());
+        assert!(Credentials::parse("1 abc").is_err());
+        assert!(Credentials::parse(&format!("1 {}", digest("secret"))).is_err());
+        assert!(Credentials::parse(&format!("a {}", hash("secret", "salt-one"))).is_err());
+        assert!(Credentials::parse(&format!("1 {} 2", hash("secret", "salt-one"))).is_err());
+        assert!(!Credentials::default().verify(1, "secret"));
+    }
+
+    #[test]
+    fn test_basic_credentials() {
+        let credentials =
+            BasicCredentials::parse(&format!("admin {}", hash("te:st", "salt-one"))).unwrap();
+
+        assert!(credentials.verify(&format!("Basic {}", base64::encode("admin:te:st"))));
+        assert!(credentials.verify(&format!("basic  {}", base64::encode("admin:te:st"))));
+
+        assert!(!credentials.verify(&format!("Basic {}", base64::encode("admin:test"))));
+        assert!(!credentials.verify(&format!("Basic {}", base64::encode("other:te:st"))));
+        assert!(!credentials.verify(&format!("Basic {}", base64::encode("admin"))));
+        assert!(!credentials.verify(&format!("Bearer {}", base64::encode("admin:te:st"))));
+        assert!(!credentials.verify("Basic not-base64"));
+        assert!(!credentials.verify("Basic"));
+    }
+
+    #[test]
+    fn test_static_tokens() {
+        let tokens = StaticTokens::parse(&format!(
+            "1 {}\n1 {}\n2 {}\n",
+            digest("a"),
+            digest("b"),
+            digest("c").to_ascii_uppercase()
+        ))
+        .unwrap();
+
+        assert_eq!(tokens.resolve("a"), Some(1));
+        assert_eq!(tokens.resolve("b"), Some(1));
+        assert_eq!(tokens.resolve("c"), Some(2));
+        assert_eq!(tokens.resolve("d"), None);
+        assert_eq!(tokens.resolve(&digest("a")), None);
+
+        assert!(StaticTokens::parse("1 abc").is_err());
+    }
+
+    #[test]
+    fn test_tokens() {
+        let mut tokens = Tokens::default();
+
+        let a = tokens.issue(1);
+        let b = tokens.issue(1);
+
+        assert_ne!(a, b);
+        assert_eq!(a.len(), TOKEN_LENGTH * 2);
+        assert_eq!(tokens.resolve(&a), Some(1));
+        assert_eq!(tokens.resolve(&b), Some(1));
+        assert_eq!(tokens.resolve("unknown"), None);
+
+        // only hashes of the tokens are kept
+
+        assert!(!tokens.sessions.contains_key(&a));
+
+        tokens.set_ttl(Duration::from_secs(0));
+
+        let c = tokens.issue(2);
+
+        assert_eq!(tokens.resolve(&c), None);
+        assert_eq!(tokens.resolve(&a), Some(1));
+
+        // expired tokens are discarded when the next is issued
+
+        tokens.issue(3);
+
+        assert_eq!(tokens.sessions.len(), 3);
+    }
+}
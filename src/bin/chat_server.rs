@@ -1,127 +1,1450 @@
 use mio::net::TcpListener;
 use mio::*;
+use net2::TcpBuilder;
+use signal_http::auth::*;
+use signal_http::blobs::*;
 use signal_http::chat::*;
 use signal_http::chat_http::*;
+use signal_http::contacts::*;
+use signal_http::encryption::Keyring;
+use signal_http::federation::*;
+use signal_http::gossip::*;
 use signal_http::http::*;
-use std::collections::HashSet;
+use signal_http::http_client;
+use signal_http::rate_limit::RateLimiter;
+use signal_http::recording::*;
+use signal_http::replication::*;
+use signal_http::seed::*;
+use signal_http::signing::{Signer, SigningClients};
+#[cfg(feature = "sled")]
+use signal_http::sled_store::*;
+use signal_http::storage::*;
+use signal_http::webhooks::*;
+use std::any::Any;
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::fs::{self, File, OpenOptions};
 use std::io::Error as IoError;
 use std::io::ErrorKind as IoErrorKind;
 use std::io::Result as IoResult;
-use std::net::SocketAddr;
+use std::io::{BufReader, LineWriter};
+use std::net::{IpAddr, SocketAddr};
+use std::process;
 use std::str;
-use std::usize;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 const BIND_HOST: &str = "127.0.0.1";
 const BIND_PORT: u16 = 8080;
 const CONTACT_LIST: &str = include_str!("../../data/contacts.json");
 
+/// Number of times fetching `--contacts-url` is attempted
+/// before giving up.
+const CONTACTS_FETCH_ATTEMPTS: u32 = 5;
+
+/// Delay before the first retry of fetching `--contacts-url`,
+/// doubling after each subsequent failure.
+const CONTACTS_FETCH_BACKOFF: Duration = Duration::from_millis(500);
+
+const CONTACTS_FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Default for `--backlog`, matching what MIO itself uses.
+const DEFAULT_BACKLOG: i32 = 1024;
+
+/// Default for `--basic-auth-prefix`, which is the routes that
+/// operators, followers, and gossiping nodes use.
+const DEFAULT_BASIC_AUTH_PREFIXES: [&str; 2] = ["/admin", "/replication"];
+
+/// Default for `--snapshot-interval`.
+const DEFAULT_SNAPSHOT_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How often messages that have outlived their chat's TTL are
+/// purged.
+const EXPIRE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Default for `--drain-timeout`.
+const DEFAULT_DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Exit code used when connections had to be reset because they
+/// didn't complete within `--drain-timeout`.
+const DRAIN_FORCED_EXIT_CODE: i32 = 2;
+
+/// How often workers that are waiting for events check whether
+/// the server has been asked to shut down.
+const SHUTDOWN_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How often workers feed their streams even if no events were
+/// published, which renews the presence of their users. This must be
+/// well within `PRESENCE_TTL`.
+const STREAM_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How often a follower polls its leader for the entries it has
+/// logged, unless there are more to fetch.
+const FOLLOW_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Delay before a worker that has died is respawned, so that
+/// a worker that repeatedly fails doesn't spin the supervisor.
+const WORKER_RESPAWN_DELAY: Duration = Duration::from_millis(100);
+
+/// Options that are supplied to the binary via
+/// command line arguments.
+#[derive(Clone)]
+struct Options {
+    access_log: bool,
+    allow_plaintext: bool,
+    backlog: i32,
+    basic_auth: Option<String>,
+    basic_auth_prefixes: Vec<String>,
+    blobs: Option<String>,
+    contacts_url: Option<String>,
+    cors_origins: Vec<String>,
+    credentials: Option<String>,
+    drain_timeout: Duration,
+    encryption_keys: Option<String>,
+    federation: Option<String>,
+    follow: Option<String>,
+    follow_basic_auth: Option<String>,
+    follow_signing_client: Option<String>,
+    gossip_basic_auth: Option<String>,
+    gossip_peers: Vec<String>,
+    gossip_signing_client: Option<String>,
+    max_accepts: usize,
+    max_blob_size: Option<usize>,
+    max_body_size: Option<usize>,
+    max_chat_bytes: Option<usize>,
+    max_chat_messages: Option<usize>,
+    max_envelope_size: Option<usize>,
+    max_revisions: Option<usize>,
+    operator_ids: Vec<Id>,
+    rate_limit: Option<u32>,
+    rate_limit_burst: Option<u32>,
+    record: Option<String>,
+    replay: Option<String>,
+    replication_backlog: Option<usize>,
+    seed: Option<String>,
+    server_timestamps: Option<ServerTimestamps>,
+    signing_clients: Option<String>,
+    sled: Option<String>,
+    snapshot: Option<String>,
+    snapshot_interval: Duration,
+    token_ttl: Option<Duration>,
+    tokens: Option<String>,
+    trace_connections: Option<Option<IpAddr>>,
+    wal: Option<String>,
+    webhooks: Vec<String>,
+    webhook_allowed_hosts: Vec<String>,
+    workers: usize,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            access_log: false,
+            allow_plaintext: false,
+            backlog: DEFAULT_BACKLOG,
+            basic_auth: None,
+            basic_auth_prefixes: Vec::new(),
+            blobs: None,
+            contacts_url: None,
+            cors_origins: Vec::new(),
+            credentials: None,
+            drain_timeout: DEFAULT_DRAIN_TIMEOUT,
+            encryption_keys: None,
+            federation: None,
+            follow: None,
+            follow_basic_auth: None,
+            follow_signing_client: None,
+            gossip_basic_auth: None,
+            gossip_peers: Vec::new(),
+            gossip_signing_client: None,
+            max_accepts: usize::MAX,
+            max_blob_size: None,
+            max_body_size: None,
+            max_chat_bytes: None,
+            max_chat_messages: None,
+            max_envelope_size: None,
+            max_revisions: None,
+            operator_ids: Vec::new(),
+            rate_limit: None,
+            rate_limit_burst: None,
+            record: None,
+            replay: None,
+            replication_backlog: None,
+            seed: None,
+            server_timestamps: None,
+            signing_clients: None,
+            sled: None,
+            snapshot: None,
+            snapshot_interval: DEFAULT_SNAPSHOT_INTERVAL,
+            token_ttl: None,
+            tokens: None,
+            trace_connections: None,
+            wal: None,
+            webhooks: Vec::new(),
+            webhook_allowed_hosts: Vec::new(),
+            workers: 1,
+        }
+    }
+}
+
+impl Options {
+    /// Parses the supplied arguments (excluding the program name),
+    /// failing if any are unknown or are missing their values.
+    fn parse<I: Iterator<Item = String>>(args: I) -> IoResult<Self> {
+        let mut args = args.peekable();
+        let mut options = Self::default();
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--access-log" => {
+                    options.access_log = true;
+                }
+
+                "--allow-plaintext" => {
+                    options.allow_plaintext = true;
+                }
+
+                "--backlog" => {
+                    options.backlog = Self::number(&arg, args.next())?;
+                }
+
+                "--basic-auth" => {
+                    options.basic_auth = Some(Self::value(&arg, args.next())?);
+                }
+
+                "--basic-auth-prefix" => {
+                    options
+                        .basic_auth_prefixes
+                        .push(Self::value(&arg, args.next())?);
+                }
+
+                "--blobs" => {
+                    options.blobs = Some(Self::value(&arg, args.next())?);
+                }
+
+                "--contacts-url" => {
+                    options.contacts_url = Some(Self::value(&arg, args.next())?);
+                }
+
+                "--cors-origin" => {
+                    options.cors_origins.push(Self::value(&arg, args.next())?);
+                }
+
+                "--credentials" => {
+                    options.credentials = Some(Self::value(&arg, args.next())?);
+                }
+
+                "--drain-timeout" => {
+                    options.drain_timeout = Duration::from_secs(Self::number(&arg, args.next())?);
+                }
+
+                "--encryption-keys" => {
+                    options.encryption_keys = Some(Self::value(&arg, args.next())?);
+                }
+
+                "--federation" => {
+                    options.federation = Some(Self::value(&arg, args.next())?);
+                }
+
+                "--follow" => {
+                    options.follow = Some(Self::value(&arg, args.next())?);
+                }
+
+                "--follow-basic-auth" => {
+                    options.follow_basic_auth = Some(Self::value(&arg, args.next())?);
+                }
+
+                "--follow-signing-client" => {
+                    options.follow_signing_client = Some(Self::value(&arg, args.next())?);
+                }
+
+                "--gossip-basic-auth" => {
+                    options.gossip_basic_auth = Some(Self::value(&arg, args.next())?);
+                }
+
+                "--gossip-peer" => {
+                    options.gossip_peers.push(Self::value(&arg, args.next())?);
+                }
+
+                "--gossip-signing-client" => {
+                    options.gossip_signing_client = Some(Self::value(&arg, args.next())?);
+                }
+
+                "--max-accepts" => {
+                    options.max_accepts = Self::number(&arg, args.next())?;
+
+                    if options.max_accepts == 0 {
+                        return Err(IoError::new(
+                            IoErrorKind::InvalidInput,
+                            "--max-accepts must be at least 1",
+                        ));
+                    }
+                }
+
+                "--max-blob-size" => {
+                    options.max_blob_size = Some(Self::number(&arg, args.next())?);
+                }
+
+                "--max-body-size" => {
+                    options.max_body_size = Some(Self::number(&arg, args.next())?);
+                }
+
+                "--max-chat-bytes" => {
+                    options.max_chat_bytes = Some(Self::number(&arg, args.next())?);
+                }
+
+                "--max-chat-messages" => {
+                    options.max_chat_messages = Some(Self::number(&arg, args.next())?);
+
+                    if options.max_chat_messages == Some(0) {
+                        return Err(IoError::new(
+                            IoErrorKind::InvalidInput,
+                            "--max-chat-messages must be at least 1",
+                        ));
+                    }
+                }
+
+                "--max-envelope-size" => {
+                    options.max_envelope_size = Some(Self::number(&arg, args.next())?);
+                }
+
+                "--max-revisions" => {
+                    options.max_revisions = Some(Self::number(&arg, args.next())?);
+                }
+
+                "--operator" => {
+                    options.operator_ids.push(Self::number(&arg, args.next())?);
+                }
+
+                "--rate-limit" => {
+                    options.rate_limit = Some(Self::number(&arg, args.next())?);
+                }
+
+                "--rate-limit-burst" => {
+                    options.rate_limit_burst = Some(Self::number(&arg, args.next())?);
+                }
+
+                "--record" => {
+                    options.record = Some(Self::value(&arg, args.next())?);
+                }
+
+                "--replay" => {
+                    options.replay = Some(Self::value(&arg, args.next())?);
+                }
+
+                "--replication-backlog" => {
+                    options.replication_backlog = Some(Self::number(&arg, args.next())?);
+                }
+
+                "--seed" => {
+                    options.seed = Some(Self::value(&arg, args.next())?);
+                }
+
+                "--server-timestamps" => {
+                    options.server_timestamps = match Self::value(&arg, args.next())?.as_str() {
+                        "override" => Some(ServerTimestamps::Override),
+                        "supplement" => Some(ServerTimestamps::Supplement),
+
+                        _ => {
+                            return Err(IoError::new(
+                                IoErrorKind::InvalidInput,
+                                "--server-timestamps must be override or supplement",
+                            ));
+                        }
+                    };
+                }
+
+                "--signing-clients" => {
+                    options.signing_clients = Some(Self::value(&arg, args.next())?);
+                }
+
+                #[cfg(feature = "sled")]
+                "--sled" => {
+                    options.sled = Some(Self::value(&arg, args.next())?);
+                }
+
+                "--snapshot" => {
+                    options.snapshot = Some(Self::value(&arg, args.next())?);
+                }
+
+                "--snapshot-interval" => {
+                    options.snapshot_interval =
+                        Duration::from_secs(Self::number(&arg, args.next())?);
+                }
+
+                "--token-ttl" => {
+                    options.token_ttl = Some(Duration::from_secs(Self::number(&arg, args.next())?));
+                }
+
+                "--tokens" => {
+                    options.tokens = Some(Self::value(&arg, args.next())?);
+                }
+
+                "--trace-connections" => {
+                    // the peer IP to filter by is optional, so only consume
+                    // the next argument if it's an IP
+
+                    let peer_ip = args.peek().and_then(|next| next.parse().ok());
+
+                    if peer_ip.is_some() {
+                        args.next();
+                    }
+
+                    options.trace_connections = Some(peer_ip);
+                }
+
+                "--wal" => {
+                    options.wal = Some(Self::value(&arg, args.next())?);
+                }
+
+                "--webhook" => {
+                    options.webhooks.push(Self::value(&arg, args.next())?);
+                }
+
+                "--webhook-allow-host" => {
+                    options
+                        .webhook_allowed_hosts
+                        .push(Self::value(&arg, args.next())?);
+                }
+
+                "--workers" => {
+                    options.workers = Self::number(&arg, args.next())?;
+
+                    if options.workers == 0 {
+                        return Err(IoError::new(
+                            IoErrorKind::InvalidInput,
+                            "--workers must be at least 1",
+                        ));
+                    }
+                }
+
+                _ => {
+                    return Err(IoError::new(
+                        IoErrorKind::InvalidInput,
+                        format!("unknown argument: {}", arg),
+                    ));
+                }
+            }
+        }
+
+        if options.record.is_some() && options.replay.is_some() {
+            return Err(IoError::new(
+                IoErrorKind::InvalidInput,
+                "--record and --replay cannot be used together",
+            ));
+        }
+
+        if options.sled.is_some() && (options.wal.is_some() || options.snapshot.is_some()) {
+            return Err(IoError::new(
+                IoErrorKind::InvalidInput,
+                "--sled cannot be used with --wal or --snapshot",
+            ));
+        }
+
+        if options.max_blob_size.is_some() && options.blobs.is_none() {
+            return Err(IoError::new(
+                IoErrorKind::InvalidInput,
+                "--max-blob-size requires --blobs",
+            ));
+        }
+
+        if options.encryption_keys.is_some() && options.wal.is_none() && options.snapshot.is_none()
+        {
+            return Err(IoError::new(
+                IoErrorKind::InvalidInput,
+                "--encryption-keys requires --wal or --snapshot",
+            ));
+        }
+
+        if options.allow_plaintext && options.encryption_keys.is_none() {
+            return Err(IoError::new(
+                IoErrorKind::InvalidInput,
+                "--allow-plaintext requires --encryption-keys",
+            ));
+        }
+
+        if options.token_ttl.is_some() && options.credentials.is_none() {
+            return Err(IoError::new(
+                IoErrorKind::InvalidInput,
+                "--token-ttl requires --credentials",
+            ));
+        }
+
+        if options.rate_limit_burst.is_some() && options.rate_limit.is_none() {
+            return Err(IoError::new(
+                IoErrorKind::InvalidInput,
+                "--rate-limit-burst requires --rate-limit",
+            ));
+        }
+
+        if !options.basic_auth_prefixes.is_empty() && options.basic_auth.is_none() {
+            return Err(IoError::new(
+                IoErrorKind::InvalidInput,
+                "--basic-auth-prefix requires --basic-auth",
+            ));
+        }
+
+        if (options.follow_basic_auth.is_some() || options.follow_signing_client.is_some())
+            && options.follow.is_none()
+        {
+            return Err(IoError::new(
+                IoErrorKind::InvalidInput,
+                "--follow-basic-auth and --follow-signing-client require --follow",
+            ));
+        }
+
+        if (options.gossip_basic_auth.is_some() || options.gossip_signing_client.is_some())
+            && options.gossip_peers.is_empty()
+        {
+            return Err(IoError::new(
+                IoErrorKind::InvalidInput,
+                "--gossip-basic-auth and --gossip-signing-client require --gossip-peer",
+            ));
+        }
+
+        Ok(options)
+    }
+
+    fn value(arg: &str, value: Option<String>) -> IoResult<String> {
+        value.ok_or_else(|| {
+            IoError::new(
+                IoErrorKind::InvalidInput,
+                format!("{} requires a value", arg),
+            )
+        })
+    }
+
+    fn number<N: str::FromStr>(arg: &str, value: Option<String>) -> IoResult<N> {
+        Self::value(arg, value)?.parse().map_err(|_| {
+            IoError::new(
+                IoErrorKind::InvalidInput,
+                format!("{} requires a number", arg),
+            )
+        })
+    }
+}
+
+/// State that is shared between all workers. Requests only require
+/// shared access to the `ChatHttpServer`, which locks its own state,
+/// so workers serve them concurrently.
+struct Shared {
+    chat_http_server: ChatHttpServer,
+    recorder: Option<Mutex<Recorder<LineWriter<File>>>>,
+}
+
+impl Shared {
+    /// Locks the recorder, if requests are being recorded. If a worker
+    /// panicked whilst holding the lock, it's still used -- a request
+    /// that is missing from a recording is better than none at all.
+    fn recorder(&self) -> Option<MutexGuard<'_, Recorder<LineWriter<File>>>> {
+        self.recorder
+            .as_ref()
+            .map(|recorder| recorder.lock().unwrap_or_else(PoisonError::into_inner))
+    }
+}
+
+/// Wakes each live worker to feed its streams, by worker id. The
+/// chat server is subscribed to once, and wakes every worker that is
+/// registered whenever it publishes events, so a respawned worker
+/// registers in place of the one that died rather than subscribing
+/// again.
+#[derive(Default)]
+struct StreamWakers {
+    readiness: Mutex<HashMap<usize, SetReadiness>>,
+}
+
+impl StreamWakers {
+    /// Registers the supplied worker's readiness, replacing that of
+    /// any previous worker with the same id.
+    fn register(&self, id: usize, readiness: SetReadiness) {
+        self.lock().insert(id, readiness);
+    }
+
+    /// Deregisters the supplied worker, e.g. because it exited.
+    fn deregister(&self, id: usize) {
+        self.lock().remove(&id);
+    }
+
+    /// Wakes every registered worker.
+    fn wake(&self) {
+        for readiness in self.lock().values() {
+            let _ = readiness.set_readiness(Ready::readable());
+        }
+    }
+
+    /// Locks the readiness. A panic whilst it was locked can't leave
+    /// it inconsistent, so it's still used.
+    fn lock(&self) -> MutexGuard<'_, HashMap<usize, SetReadiness>> {
+        self.readiness
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Notifies the supervisor when a worker's thread exits, including
+/// when it is unwinding from a panic, having first deregistered its
+/// stream waker so that it's no longer woken.
+struct WorkerGuard {
+    id: usize,
+    exited: Sender<usize>,
+    wakers: Arc<StreamWakers>,
+}
+
+impl Drop for WorkerGuard {
+    fn drop(&mut self) {
+        self.wakers.deregister(self.id);
+
+        let _ = self.exited.send(self.id);
+    }
+}
+
 /// Entrypoint for the chat server's binary.
 ///
 /// This creates a `ChatServer` and parses the supplied
-/// `contacts.json` file, seeding the server with valid
-/// contact lists.
+/// `contacts.json` file (or the document fetched from
+/// `--contacts-url`), seeding the server with valid contact
+/// lists. If `--seed <file>` is supplied, its chats and messages
+/// are then applied.
 ///
-/// It then sets up an MIO event loop to process read/write
-/// readiness events, using them to drive an HTTP server.
+/// If `--replay <file>` is supplied, the recorded requests
+/// are re-issued against the server, at the pace they were
+/// recorded at, and the program exits.
+///
+/// Otherwise, it binds to a TCP socket and spawns `--workers`
+/// threads, each with an MIO event loop to process read/write
+/// readiness events, using them to drive an HTTP server. If
+/// `--record <file>` is supplied, every inbound request is
+/// appended to the file.
+///
+/// Upon SIGTERM (or SIGINT), the workers stop accepting and drain
+/// their connections. Any that remain after `--drain-timeout` are
+/// reset, in which case the program exits with a non-zero code.
 fn main() -> IoResult<()> {
+    let options = Options::parse(env::args().skip(1))?;
+
+    let chat_http_server = create_chat_http_server(&options)?;
+
+    match options.replay {
+        Some(ref path) => replay(path, chat_http_server),
+
+        None => {
+            if !serve(&options, chat_http_server)? {
+                process::exit(DRAIN_FORCED_EXIT_CODE);
+            }
+
+            Ok(())
+        }
+    }
+}
+
+/// Creates a `ChatHttpServer` for the `ChatServer` that
+/// `create_chat_server` creates. If `--blobs` is supplied, blobs
+/// are stored in that directory, and are at most `--max-blob-size`
+/// bytes. The users supplied with `--operator` may make operator
+/// requests, and browsers may make cross-origin requests from the
+/// origins supplied with `--cors-origin`. If `--signing-clients` is
+/// supplied, the clients it configures may sign their requests. If
+/// `--basic-auth` is supplied, the routes under each
+/// `--basic-auth-prefix`, or `/admin` and `/replication` if there are
+/// none, require the Basic credentials whose hashes it contains. If
+/// `--rate-limit` is supplied, each address may make that many
+/// requests per second, in bursts of `--rate-limit-burst`, or the
+/// rate if it isn't supplied.
+fn create_chat_http_server(options: &Options) -> IoResult<ChatHttpServer> {
+    let mut chat_http_server = ChatHttpServer::new(create_chat_server(options)?);
+
+    chat_http_server.set_operator_ids(options.operator_ids.iter().cloned());
+    chat_http_server.set_cors_origins(options.cors_origins.iter().cloned());
+
+    if let Some(ref path) = options.basic_auth {
+        let prefixes = match options.basic_auth_prefixes.as_slice() {
+            [] => DEFAULT_BASIC_AUTH_PREFIXES
+                .iter()
+                .map(|prefix| prefix.to_string())
+                .collect(),
+            prefixes => prefixes.to_vec(),
+        };
+
+        chat_http_server.set_basic_auth(
+            BasicCredentials::parse(&fs::read_to_string(path)?)?,
+            prefixes,
+        );
+    }
+
+    if let Some(rate_limit) = options.rate_limit {
+        chat_http_server.set_rate_limiter(RateLimiter::new(
+            rate_limit,
+            options.rate_limit_burst.unwrap_or(rate_limit),
+        ));
+    }
+
+    if let Some(ref path) = options.signing_clients {
+        chat_http_server.set_signing_clients(SigningClients::parse(&fs::read_to_string(path)?)?);
+    }
+
+    if options.access_log {
+        chat_http_server.observe(log_access);
+    }
+
+    if let Some(ref dir) = options.blobs {
+        let mut blobs = BlobStore::open(dir)?;
+
+        if let Some(max_blob_size) = options.max_blob_size {
+            blobs.set_max_size(max_blob_size);
+        }
+
+        chat_http_server.set_blob_store(blobs);
+    }
+
+    Ok(chat_http_server)
+}
+
+/// Logs the supplied exchange to stdout as a line of JSON, for
+/// `--access-log`, so that logs can be queried by e.g. route or
+/// status.
+fn log_access(exchange: &Exchange) {
+    println!(
+        "{}",
+        serde_json::json!({
+            "peer": exchange.peer.map(|peer| peer.to_string()),
+            "method": exchange.method.as_str(),
+            "path": exchange.path,
+            "route": exchange.route,
+            "request": exchange.request,
+            "status": exchange.status,
+            "latencyMs": exchange.latency.as_micros() as f64 / 1000.0,
+            "requestId": exchange.request_id,
+        })
+    );
+}
+
+/// Creates a `ChatServer`, seeded with the contact lists
+/// from `contacts.json`, or `--contacts-url` if supplied, and
+/// then the chats and messages from `--seed`.
+///
+/// If `--server-timestamps` is supplied, messages added after
+/// seeding are timestamped with the milliseconds since the
+/// UNIX epoch at which they were received.
+///
+/// If `--snapshot` is supplied and it exists, the state is
+/// restored from it. Then, if `--wal` is supplied, the requests
+/// in the write-ahead log are replayed, and every subsequent
+/// mutating request is appended to it. When built with the `sled`
+/// feature, `--sled` persists the state to a sled database instead.
+///
+/// If `--encryption-keys` is supplied, the write-ahead log and
+/// snapshot are encrypted with the keyring it refers to, and any
+/// unencrypted data is rejected unless `--allow-plaintext` is
+/// supplied to migrate it.
+///
+/// If `--credentials` is supplied, users authenticate with the
+/// credentials whose hashes it contains, and are issued tokens that
+/// are valid for `--token-ttl` seconds. If `--tokens` is supplied,
+/// the static tokens whose hashes it contains are also accepted.
+fn create_chat_server(options: &Options) -> IoResult<ChatServer> {
     let mut chat_server = ChatServer::new();
 
-    let contact_list_data = serde_json::from_str(CONTACT_LIST)?;
+    if let Some(max_chat_messages) = options.max_chat_messages {
+        chat_server.set_max_chat_messages(max_chat_messages);
+    }
+
+    if let Some(max_chat_bytes) = options.max_chat_bytes {
+        chat_server.set_max_chat_bytes(max_chat_bytes);
+    }
 
-    // parse the contacts.json file, and populate the chat server's
-    // contact lists.
-    //
-    // this could be extracted but I think it reads better inline
+    if let Some(max_envelope_size) = options.max_envelope_size {
+        chat_server.set_max_envelope_size(max_envelope_size);
+    }
 
-    if let Some(serde_json::Value::Object(contact_list_obj)) = contact_list_data {
-        for (id, list_value) in contact_list_obj.into_iter() {
-            if let (Ok(id), serde_json::Value::Array(list)) = (id.parse(), list_value) {
-                chat_server.issue(ChatRequest::StoreContactList {
-                    id,
-                    list: list
-                        .into_iter()
-                        .filter_map(|other_id| match other_id {
-                            serde_json::Value::Number(n) => n.as_u64(),
-                            _ => None,
-                        })
-                        .collect(),
-                });
+    if let Some(max_revisions) = options.max_revisions {
+        chat_server.set_max_revisions(max_revisions);
+    }
+
+    if let Some(ref path) = options.credentials {
+        let credentials = Credentials::parse(&fs::read_to_string(path)?)?;
+
+        chat_server
+            .set_authenticator(move |user_id, credential| credentials.verify(user_id, credential));
+    }
+
+    if let Some(token_ttl) = options.token_ttl {
+        chat_server.set_token_ttl(token_ttl);
+    }
+
+    if let Some(ref path) = options.tokens {
+        chat_server.set_static_tokens(StaticTokens::parse(&fs::read_to_string(path)?)?);
+    }
+
+    let contact_lists = match options.contacts_url {
+        Some(ref url) => parse_contact_lists(&fetch_contacts(url)?)?,
+        None => parse_contact_lists(CONTACT_LIST)?,
+    };
+
+    for (id, list) in contact_lists {
+        chat_server.issue(ChatRequest::StoreContactList { id, list });
+    }
+
+    if let Some(ref path) = options.seed {
+        Seed::parse(&fs::read_to_string(path)?)?.apply(&mut chat_server)?;
+    }
+
+    if let Some(mode) = options.server_timestamps {
+        chat_server.set_server_timestamps(mode, millis_now);
+    }
+
+    // the stored state is applied on top of the contacts and seed,
+    // which aren't logged as they're applied on every startup. a
+    // snapshot replaces them, but includes them too
+
+    if options.wal.is_some() || options.snapshot.is_some() {
+        let mut store = FileStore::open(options.wal.as_ref(), options.snapshot.as_ref())?;
+
+        if let Some(ref source) = options.encryption_keys {
+            store.set_keyring(Keyring::load(source)?);
+            store.set_allow_plaintext(options.allow_plaintext);
+        }
+
+        chat_server.attach_store(store)?;
+    }
+
+    #[cfg(feature = "sled")]
+    {
+        if let Some(ref path) = options.sled {
+            chat_server.attach_store(SledStore::open(path)?)?;
+        }
+    }
+
+    // only requests issued from here on are retained for followers,
+    // which start from a snapshot that includes the rest
+
+    if let Some(replication_backlog) = options.replication_backlog {
+        chat_server.set_replication_backlog(replication_backlog);
+    }
+
+    if options.follow.is_some() {
+        chat_server.set_read_only(true);
+    }
+
+    Ok(chat_server)
+}
+
+/// Fetches the contact list document from the supplied URL,
+/// retrying with exponential backoff if the request fails or
+/// doesn't succeed.
+fn fetch_contacts(url: &str) -> IoResult<String> {
+    let mut backoff = CONTACTS_FETCH_BACKOFF;
+    let mut attempt = 1;
+
+    loop {
+        let result = http_client::get(url, CONTACTS_FETCH_TIMEOUT).and_then(|response| {
+            if response.is_success() {
+                Ok(response.body)
+            } else {
+                Err(IoError::other(format!(
+                    "unexpected status {}",
+                    response.status
+                )))
+            }
+        });
+
+        match result {
+            Ok(body) => return Ok(body),
+
+            Err(ref e)
+                if attempt < CONTACTS_FETCH_ATTEMPTS && e.kind() != IoErrorKind::InvalidInput =>
+            {
+                eprintln!(
+                    "failed to fetch contacts from {} (attempt {}): {}",
+                    url, attempt, e
+                );
+
+                thread::sleep(backoff);
+
+                backoff *= 2;
+                attempt += 1;
             }
+
+            Err(e) => return Err(e),
         }
     }
+}
 
-    let mut chat_http_server = ChatHttpServer::new(chat_server);
+/// Re-issues every request in the recording at `path` against
+/// the supplied server, at the offsets they were recorded at,
+/// printing each response's status and a summary of the time
+/// taken, both overall and issuing the requests.
+fn replay(path: &str, chat_http_server: ChatHttpServer) -> IoResult<()> {
+    let recording = read_recording(BufReader::new(File::open(path)?))?;
+    let started = Instant::now();
+    let mut issuing = Duration::from_secs(0);
 
-    // next, we'll setup our MIO machinery and bind to a TCP
-    // socket.
+    for (i, recorded) in recording.iter().enumerate() {
+        let offset = Duration::from_millis(recorded.offset_ms);
 
-    const SERVER: Token = Token(0);
+        if let Some(wait) = offset.checked_sub(started.elapsed()) {
+            thread::sleep(wait);
+        }
+
+        let request = recorded.as_request();
+        let method = request.method();
+        let path = request.path();
+        let issued = Instant::now();
+        let response = chat_http_server.issue(request);
+
+        issuing += issued.elapsed();
+
+        println!("{} {:?} {} {}", i, method, path, response.status());
+    }
+
+    println!(
+        "replayed {} requests in {}ms, {}ms of which were spent issuing them (recorded over {}ms)",
+        recording.len(),
+        started.elapsed().as_millis(),
+        issuing.as_millis(),
+        recording.last().map_or(0, |r| r.offset_ms)
+    );
+
+    Ok(())
+}
+
+/// Binds to a TCP socket, spawns the workers that serve requests,
+/// and then supervises them, respawning any that die.
+///
+/// Once a shutdown is requested, this returns after every worker
+/// has exited, indicating whether they all drained cleanly.
+fn serve(options: &Options, mut chat_http_server: ChatHttpServer) -> IoResult<bool> {
+    let mut webhooks = Webhooks::new();
+
+    for url in options.webhooks.iter() {
+        webhooks.add_endpoint(url.clone());
+    }
+
+    for host in options.webhook_allowed_hosts.iter() {
+        webhooks.allow_host(host.clone());
+    }
+
+    chat_http_server
+        .server_mut()
+        .set_webhooks(webhooks.spawn()?);
+
+    if let Some(ref path) = options.federation {
+        let federation = Federation::parse(&fs::read_to_string(path)?)?;
+
+        chat_http_server
+            .server_mut()
+            .set_federation(federation.spawn(Webhooks::new())?);
+    }
+
+    let terminate = Arc::new(AtomicBool::new(false));
+
+    signal_hook::flag::register(signal_hook::SIGTERM, terminate.clone())?;
+    signal_hook::flag::register(signal_hook::SIGINT, terminate.clone())?;
+
+    let recorder = match options.record {
+        Some(ref path) => Some(Mutex::new(Recorder::new(LineWriter::new(
+            OpenOptions::new().create(true).append(true).open(path)?,
+        )))),
+
+        None => None,
+    };
+
+    // workers are woken to feed their streams whenever events are
+    // published. they come and go, so rather than each subscribing,
+    // the server is subscribed to once on behalf of all of them
+
+    let wakers = Arc::new(StreamWakers::default());
+
+    chat_http_server.server_mut().subscribe({
+        let wakers = wakers.clone();
+
+        move |_| wakers.wake()
+    });
+
+    let shared = Arc::new(Shared {
+        chat_http_server,
+        recorder,
+    });
+
+    let addr = SocketAddr::new(BIND_HOST.parse().map_err(IoError::other)?, BIND_PORT);
+
+    let builder = if addr.is_ipv4() {
+        TcpBuilder::new_v4()?
+    } else {
+        TcpBuilder::new_v6()?
+    };
+
+    let listener = TcpListener::from_std(
+        builder
+            .reuse_address(true)?
+            .bind(addr)?
+            .listen(options.backlog)?,
+    )?;
+    let (exited_tx, exited_rx) = mpsc::channel();
+    let mut workers = HashMap::with_capacity(options.workers);
+
+    for id in 0..options.workers {
+        workers.insert(
+            id,
+            spawn_worker(
+                id,
+                options,
+                listener.try_clone()?,
+                &shared,
+                &wakers,
+                &terminate,
+                &exited_tx,
+            )?,
+        );
+    }
+
+    if options.snapshot.is_some() || options.sled.is_some() {
+        spawn_snapshotter(options.snapshot_interval, &shared)?;
+    }
+
+    spawn_expirer(EXPIRE_INTERVAL, &shared)?;
+
+    if let Some(ref leader_url) = options.follow {
+        let mut follower = Follower::new(leader_url);
+
+        if let Some(ref path) = options.follow_basic_auth {
+            follower.set_basic_auth(&fs::read_to_string(path)?);
+        }
+
+        if let Some(ref path) = options.follow_signing_client {
+            follower.set_signer(Signer::parse(&fs::read_to_string(path)?)?);
+        }
+
+        spawn_follower(follower, FOLLOW_INTERVAL, &shared)?;
+    }
+
+    if !options.gossip_peers.is_empty() {
+        let mut gossip = Gossip::new(options.gossip_peers.iter().cloned());
+
+        if let Some(ref path) = options.gossip_basic_auth {
+            gossip.set_basic_auth(&fs::read_to_string(path)?);
+        }
 
-    let addr = SocketAddr::new(
-        BIND_HOST
-            .parse()
-            .map_err(|e| IoError::new(IoErrorKind::Other, e))?,
-        BIND_PORT,
+        if let Some(ref path) = options.gossip_signing_client {
+            gossip.set_signer(Signer::parse(&fs::read_to_string(path)?)?);
+        }
+
+        spawn_gossiper(gossip, GOSSIP_INTERVAL, &shared)?;
+    }
+
+    println!(
+        "server listening on {} with {} worker(s)",
+        addr, options.workers
     );
 
-    let server = TcpListener::bind(&addr)?;
+    // the workers notify us when they exit, which they only do if
+    // they've failed or panicked, or they've finished draining after
+    // a shutdown was requested. the supervisor (this thread) logs
+    // why and, unless shutting down, respawns them with a fresh `Poll`,
+    // sharing the same state as before
+
+    let mut clean = true;
+
+    loop {
+        let id = exited_rx.recv().map_err(IoError::other)?;
+
+        if let Some(handle) = workers.remove(&id) {
+            let drained = match handle.join() {
+                Ok(Ok(0)) => {
+                    eprintln!("worker {} drained", id);
+
+                    true
+                }
+
+                Ok(Ok(reset)) => {
+                    eprintln!(
+                        "worker {} reset {} connection(s) after the drain timeout",
+                        id, reset
+                    );
+
+                    false
+                }
+
+                Ok(Err(e)) => {
+                    eprintln!("worker {} failed: {}", id, e);
+
+                    false
+                }
+
+                Err(payload) => {
+                    eprintln!("worker {} panicked: {}", id, panic_message(&payload));
+
+                    false
+                }
+            };
+
+            if terminate.load(Ordering::SeqCst) {
+                clean = clean && drained;
+
+                if workers.is_empty() {
+                    // the store is closed once every worker has
+                    // drained, so that it's released before exiting
+
+                    if let Err(e) = shared.chat_http_server.server().write().close_store() {
+                        eprintln!("failed to close store: {}", e);
+
+                        clean = false;
+                    }
+
+                    return Ok(clean);
+                }
+
+                continue;
+            }
+
+            thread::sleep(WORKER_RESPAWN_DELAY);
+
+            workers.insert(
+                id,
+                spawn_worker(
+                    id,
+                    options,
+                    listener.try_clone()?,
+                    &shared,
+                    &wakers,
+                    &terminate,
+                    &exited_tx,
+                )?,
+            );
+
+            eprintln!("worker {} respawned", id);
+        }
+    }
+}
+
+/// Spawns a thread that writes a snapshot of the chat server to
+/// its store every `interval`, truncating its write-ahead log.
+/// Failures are logged, and the next snapshot is still attempted.
+fn spawn_snapshotter(interval: Duration, shared: &Arc<Shared>) -> IoResult<JoinHandle<()>> {
+    let shared = shared.clone();
+
+    thread::Builder::new()
+        .name("snapshotter".to_string())
+        .spawn(move || loop {
+            thread::sleep(interval);
+
+            if let Err(e) = shared.chat_http_server.server().write().checkpoint() {
+                eprintln!("failed to write snapshot: {}", e);
+            }
+        })
+}
+
+/// Spawns a thread that periodically purges the messages that have
+/// outlived their chat's TTL.
+fn spawn_expirer(interval: Duration, shared: &Arc<Shared>) -> IoResult<JoinHandle<()>> {
+    let shared = shared.clone();
+
+    thread::Builder::new()
+        .name("expirer".to_string())
+        .spawn(move || loop {
+            thread::sleep(interval);
+
+            shared.chat_http_server.expire_messages(millis_now());
+        })
+}
+
+/// Spawns a thread that replicates a leader, first restoring its
+/// snapshot, and then polling it for the entries that it logs every
+/// `interval`. Failures are logged, and the poll is retried.
+fn spawn_follower(
+    follower: Follower,
+    interval: Duration,
+    shared: &Arc<Shared>,
+) -> IoResult<JoinHandle<()>> {
+    let shared = shared.clone();
+
+    thread::Builder::new()
+        .name("follower".to_string())
+        .spawn(move || {
+            let mut synced = false;
+
+            loop {
+                // the leader is polled without locking the server, so
+                // that requests are still served whilst waiting for it.
+                // only this thread changes the state of a follower
+
+                let update = if synced {
+                    let after = shared.chat_http_server.server().read().log_index();
+
+                    follower.fetch(after)
+                } else {
+                    follower.fetch_snapshot()
+                };
+
+                let result = update
+                    .and_then(|update| update.apply(&mut shared.chat_http_server.server().write()));
+
+                match result {
+                    Ok(count) => {
+                        synced = true;
+
+                        if count == MAX_REPLICATED_ENTRIES {
+                            continue;
+                        }
+                    }
+
+                    Err(e) => eprintln!("failed to replicate {}: {}", follower.leader_url(), e),
+                }
+
+                thread::sleep(interval);
+            }
+        })
+}
+
+/// Reads the system clock, in milliseconds since the Unix epoch.
+fn millis_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// Spawns a thread that exchanges digests of presence with each of
+/// the supplied gossip's peers every `interval`. Failures are logged,
+/// and the exchange is retried in the next round.
+fn spawn_gossiper(
+    gossip: Gossip,
+    interval: Duration,
+    shared: &Arc<Shared>,
+) -> IoResult<JoinHandle<()>> {
+    let shared = shared.clone();
+
+    thread::Builder::new()
+        .name("gossiper".to_string())
+        .spawn(move || loop {
+            thread::sleep(interval);
+
+            for peer_url in gossip.peer_urls() {
+                // peers are sent the digest without holding the lock, so
+                // that requests are still served whilst waiting for them
+
+                let digest = shared.chat_http_server.presence().digest(millis_now());
+
+                match gossip.exchange(peer_url, &digest) {
+                    Ok(peer_digest) => shared
+                        .chat_http_server
+                        .presence()
+                        .merge(peer_digest, millis_now()),
+
+                    Err(e) => eprintln!("failed to gossip with {}: {}", peer_url, e),
+                }
+            }
+        })
+}
+
+/// Spawns a worker thread that accepts connections from the supplied
+/// listener and serves their requests.
+fn spawn_worker(
+    id: usize,
+    options: &Options,
+    listener: TcpListener,
+    shared: &Arc<Shared>,
+    wakers: &Arc<StreamWakers>,
+    terminate: &Arc<AtomicBool>,
+    exited: &Sender<usize>,
+) -> IoResult<JoinHandle<IoResult<usize>>> {
+    let shared = shared.clone();
+    let wakers = wakers.clone();
+    let terminate = terminate.clone();
+    let options = options.clone();
+    let guard = WorkerGuard {
+        id,
+        exited: exited.clone(),
+        wakers: wakers.clone(),
+    };
+
+    thread::Builder::new()
+        .name(format!("worker-{}", id))
+        .spawn(move || {
+            let _guard = guard;
+
+            run_worker(id, &options, listener, shared, &wakers, &terminate)
+        })
+}
+
+/// Runs a worker's event loop until an error occurs, forwarding the
+/// MIO events for its connections to its own HTTP server.
+///
+/// Once `terminate` is set, the worker stops accepting connections
+/// and exits after its existing connections have completed, or
+/// `--drain-timeout` has elapsed, returning how many connections
+/// had to be reset.
+///
+/// If `--trace-connections` is supplied, everything that happens to
+/// each connection is logged, optionally only for a specific peer.
+///
+/// Requests whose bodies are larger than `--max-body-size` bytes, if
+/// supplied, or 16MiB otherwise, are responded to with a 413.
+///
+/// If `--access-log` is supplied, each request is logged to stdout
+/// as a line of JSON, with its peer, method, path, route, the
+/// `ChatRequest` it issued, status, latency, and request id.
+///
+/// Connections whose responses are streams, e.g. of server-sent
+/// events or WebSockets, are fed whenever the chat server has
+/// published events, which it signals by waking the worker via its
+/// registration in `wakers`, and
+/// whenever their clients send them data, as well as every
+/// `STREAM_HEARTBEAT_INTERVAL` so that their users remain online.
+fn run_worker(
+    id: usize,
+    options: &Options,
+    listener: TcpListener,
+    shared: Arc<Shared>,
+    wakers: &StreamWakers,
+    terminate: &AtomicBool,
+) -> IoResult<usize> {
+    // first, we'll setup our MIO machinery for the socket
+
+    const SERVER: Token = Token(0);
+
+    // tokens for connections never reach this one, see `calc_next_token`
+
+    const STREAMS: Token = Token(usize::MAX - 1);
+
+    let server = listener;
     let poll = Poll::new()?;
 
     poll.register(&server, SERVER, Ready::readable(), PollOpt::edge())?;
 
+    let (registration, streams_readiness) = Registration::new2();
+
+    poll.register(&registration, STREAMS, Ready::readable(), PollOpt::edge())?;
+
+    wakers.register(id, streams_readiness.clone());
+
     let mut events = Events::with_capacity(1024);
     let mut used_tokens = HashSet::new();
     let mut last_token = Token(0);
-    let mut http_server =
-        HttpServer::new(move |request: HttpRequest| chat_http_server.issue(request));
+    let stream_shared = shared.clone();
+    let mut http_server = HttpServer::new(move |request: HttpRequest, peer: Option<SocketAddr>| {
+        // whilst recording, the recorder stays locked until the request
+        // has been issued, so that requests are recorded in the order
+        // that they're issued in, and are replayed the same way
 
-    println!("server listening on {}", addr);
+        let mut recorder = shared.recorder();
 
-    // we've successfully bound, so let's start the event loop,
-    // forwarding the MIO events to the HTTP server
+        if let Some(ref mut recorder) = recorder {
+            if let Err(e) = recorder.record(&request) {
+                eprintln!("failed to record request: {}", e);
+            }
+        }
+
+        shared
+            .chat_http_server
+            .issue_from(request, peer.map(|peer| peer.ip()))
+    });
+
+    if let Some(max_body_size) = options.max_body_size {
+        http_server.set_max_body_size(max_body_size);
+    }
+
+    http_server.set_stream_handler(move |cursor, input| {
+        stream_shared.chat_http_server.poll_stream(cursor, input)
+    });
+
+    if let Some(peer_ip) = options.trace_connections {
+        http_server.set_tracer(move |token, peer, event| {
+            if peer_ip.is_none() || peer.map(|p| p.ip()) == peer_ip {
+                match peer {
+                    Some(peer) => {
+                        eprintln!("worker {} token {} ({}): {}", id, token.0, peer, event)
+                    }
+                    None => eprintln!("worker {} token {}: {}", id, token.0, event),
+                }
+            }
+        });
+    }
+
+    // set when the listener may have connections waiting to be accepted.
+    // it's edge triggered, so this remains set until accepting would block
+
+    let mut accept_pending = false;
+
+    // cleared whilst the listener is deregistered, i.e. when every token
+    // is in use or the worker is draining
+
+    let mut listening = true;
+
+    // set once a shutdown has been requested, after which no more
+    // connections are accepted
+
+    let mut drain_deadline = None;
+
+    // when the streams were last fed, so that they're fed again once
+    // the heartbeat interval has elapsed
+
+    let mut last_heartbeat = Instant::now();
+
+    // next, let's start the event loop, forwarding the MIO events
+    // to the HTTP server
 
     loop {
-        poll.poll(&mut events, None)?;
+        if drain_deadline.is_none() && terminate.load(Ordering::SeqCst) {
+            if listening {
+                poll.deregister(&server)?;
+            }
+
+            accept_pending = false;
+            listening = false;
+            drain_deadline = Some(Instant::now() + options.drain_timeout);
+
+            // streams never complete, so they're closed rather than
+            // drained
+
+            for token in http_server.close_streams() {
+                used_tokens.remove(&token);
+            }
+        }
+
+        if let Some(deadline) = drain_deadline {
+            if http_server.active_connections() == 0 {
+                return Ok(0);
+            } else if Instant::now() >= deadline {
+                return Ok(http_server.reset_connections());
+            }
+        }
+
+        // the timeout ensures that shutdown requests and drain
+        // deadlines are noticed even if there are no events
+
+        let timeout = if accept_pending {
+            Duration::from_millis(0)
+        } else {
+            SHUTDOWN_CHECK_INTERVAL
+        };
+
+        match poll.poll(&mut events, Some(timeout)) {
+            Ok(_) => {}
+
+            Err(ref e) if e.kind() == IoErrorKind::Interrupted => {
+                continue;
+            }
+
+            Err(e) => {
+                return Err(e);
+            }
+        }
+
+        let mut token_freed = false;
+        let mut streams_pending = last_heartbeat.elapsed() >= STREAM_HEARTBEAT_INTERVAL;
 
         for event in events.iter() {
             match event.token() {
-                SERVER => loop {
-                    // a connection is available, so we'll accept them until the OS
-                    // indicates we'd block (edge triggered)
-
-                    match server.accept() {
-                        Ok((stream, _socket_addr)) => {
-                            last_token =
-                                calc_next_token(&used_tokens, last_token).ok_or_else(|| {
-                                    // this is an edge case -- every token is in use, meaning
-                                    // the server has ~4.2bn active connections (32bit), or
-                                    // [...a very large number] of active connections (64bit)
-                                    // so it's quite alright to panic..an orchestrator/supervisor
-                                    // can always restart it anyways
-                                    //
-                                    // an alternative would be to stash this until a connection
-                                    // has disconnected and thus a token has become available,
-                                    // at the cost of some additional complexity
-
-                                    IoError::new(IoErrorKind::Other, "tokens exhausted")
-                                })?;
-
-                            used_tokens.insert(last_token);
-
-                            poll.register(&stream, last_token, Ready::all(), PollOpt::edge())?;
-
-                            http_server.connection_accepted(Token(last_token.0), stream);
-                        }
-
-                        Err(ref e) if e.kind() == IoErrorKind::WouldBlock => {
-                            break;
-                        }
+                SERVER => {
+                    accept_pending = true;
+                }
 
-                        Err(e) => {
-                            return Err(e);
-                        }
-                    }
-                },
+                STREAMS => {
+                    streams_pending = true;
+                }
 
                 token => {
                     // a connection is read/writable, so let the `HttpServer` know,
@@ -139,10 +1462,105 @@ fn main() -> IoResult<()> {
 
                     if !http_server.is_connection_active(token) {
                         used_tokens.remove(&token);
+
+                        token_freed = true;
                     }
                 }
             }
         }
+
+        // events were published, so we'll feed them to the streams. the
+        // readiness is cleared first, so that events published whilst
+        // doing so wake the worker again
+
+        if streams_pending {
+            streams_readiness.set_readiness(Ready::empty())?;
+            last_heartbeat = Instant::now();
+
+            for token in http_server.poll_streams() {
+                used_tokens.remove(&token);
+
+                token_freed = true;
+            }
+        }
+
+        // accepting was paused because every token was in use, but one
+        // has now become available. connections may have arrived in the
+        // meantime, so we'll attempt to accept them right away
+
+        if !listening && token_freed && drain_deadline.is_none() {
+            poll.register(&server, SERVER, Ready::readable(), PollOpt::edge())?;
+
+            accept_pending = true;
+            listening = true;
+        }
+
+        // connections are available, so we'll accept them until the OS
+        // indicates we'd block, or we've accepted `--max-accepts` of them,
+        // in which case the rest are accepted after servicing connection
+        // I/O. other workers are notified too, so it's normal for this
+        // to find nothing
+
+        let mut accepted = 0;
+
+        while accept_pending && accepted < options.max_accepts {
+            let token = match calc_next_token(&used_tokens, last_token) {
+                Some(token) => token,
+
+                None => {
+                    // this is an edge case -- every token is in use, meaning
+                    // the server has ~4.2bn active connections (32bit), or
+                    // [...a very large number] of active connections (64bit)
+                    //
+                    // we'll stop listening until a connection has disconnected
+                    // and thus a token has become available, leaving any
+                    // pending connections in the backlog until then
+
+                    eprintln!("worker {} exhausted its tokens; pausing accepts", id);
+
+                    poll.deregister(&server)?;
+
+                    accept_pending = false;
+                    listening = false;
+
+                    break;
+                }
+            };
+
+            match server.accept() {
+                Ok((stream, _socket_addr)) => {
+                    last_token = token;
+
+                    used_tokens.insert(last_token);
+
+                    poll.register(&stream, last_token, Ready::all(), PollOpt::edge())?;
+
+                    http_server.connection_accepted(Token(last_token.0), stream);
+
+                    accepted += 1;
+                }
+
+                Err(ref e) if e.kind() == IoErrorKind::WouldBlock => {
+                    accept_pending = false;
+                }
+
+                Err(e) => {
+                    return Err(e);
+                }
+            }
+        }
+    }
+}
+
+/// Extracts a printable message from a panic's payload, which is
+/// typically a `&str` or `String`.
+fn panic_message(payload: &Box<dyn Any + Send>) -> &str {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message
+    } else {
+        "unknown panic payload"
     }
 }
 
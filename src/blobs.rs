@@ -0,0 +1,359 @@
+//! Provides storage for binary attachments, i.e. blobs, which
+//! are kept on disk rather than in a `ChatServer`'s state.
+//!
+//! Blobs are content-addressed: a blob's id is the hex-encoded
+//! SHA-256 checksum of its content, so uploading the same content
+//! twice yields the same blob, and a blob that has been corrupted
+//! on disk is detected when it is read.
+//!
+//! Messages refer to blobs by id. Once no message refers to a
+//! blob, e.g. because the messages it was attached to have been
+//! deleted, it can be garbage collected. Blobs that were recently
+//! uploaded are kept, as the message they're for may not have been
+//! added yet.
+//!
+//! As ids are derived from content, knowing one doesn't imply being
+//! allowed to read the blob, so the users that uploaded each blob are
+//! recorded alongside it.
+
+use crate::auth::random_bytes;
+use crate::chat::Id;
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::fs;
+use std::io::Error as IoError;
+use std::io::ErrorKind as IoErrorKind;
+use std::io::Result as IoResult;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+/// Default for the largest blob that can be stored, in bytes.
+const DEFAULT_MAX_SIZE: usize = 10 * 1024 * 1024;
+
+/// Default for how long an unreferenced blob is kept after it
+/// was uploaded.
+const DEFAULT_GRACE_PERIOD: Duration = Duration::from_secs(60 * 60);
+
+/// Length of a blob id, i.e. a hex-encoded SHA-256 checksum.
+const ID_LENGTH: usize = 64;
+
+/// Content is read and written in chunks of up to this many bytes.
+const CHUNK_SIZE: usize = 8192;
+
+/// Suffix of the file that lists the users who uploaded a blob,
+/// one id per line, which is named after the blob.
+const UPLOADERS_SUFFIX: &str = ".uploaders";
+
+/// Stores blobs as files in a directory, named by their ids.
+pub struct BlobStore {
+    dir: PathBuf,
+    max_size: usize,
+    grace_period: Duration,
+}
+
+impl BlobStore {
+    /// Opens the store in the supplied directory, creating it if
+    /// it doesn't exist.
+    pub fn open<P: AsRef<Path>>(dir: P) -> IoResult<Self> {
+        fs::create_dir_all(&dir)?;
+
+        Ok(Self {
+            dir: dir.as_ref().to_path_buf(),
+            max_size: DEFAULT_MAX_SIZE,
+            grace_period: DEFAULT_GRACE_PERIOD,
+        })
+    }
+
+    /// Configures the largest blob that can be stored, in bytes.
+    pub fn set_max_size(&mut self, max_size: usize) {
+        self.max_size = max_size;
+    }
+
+    /// Configures how long a blob is kept after it was uploaded,
+    /// even if no message refers to it.
+    pub fn set_grace_period(&mut self, grace_period: Duration) {
+        self.grace_period = grace_period;
+    }
+
+    /// Obtains the largest blob that can be stored, in bytes.
+    pub fn max_size(&self) -> usize {
+        self.max_size
+    }
+
+    /// Stores the supplied content, returning its blob id. Content
+    /// that is larger than the maximum size is rejected.
+    pub fn put(&self, data: &[u8]) -> IoResult<String> {
+        self.put_reader(data)
+    }
+
+    /// Stores the content read from the supplied reader, returning
+    /// its blob id. The content is streamed to disk as it's read, and
+    /// rejected once it's larger than the maximum size.
+    pub fn put_reader<R: Read>(&self, mut reader: R) -> IoResult<String> {
+        // the blob is written to a temporary file which then replaces
+        // the path, so a crash can't leave a partially written blob.
+        // its id isn't known until it has been read, so the temporary
+        // file has a random name
+
+        let mut suffix = [0; 8];
+        random_bytes(&mut suffix);
+
+        let tmp_path = self.dir.join(format!("{}.tmp", hex::encode(suffix)));
+
+        let result = self.write_blob(&mut reader, &tmp_path).and_then(|id| {
+            fs::rename(&tmp_path, self.dir.join(&id))?;
+
+            Ok(id)
+        });
+
+        if result.is_err() {
+            let _ = fs::remove_file(&tmp_path);
+        }
+
+        result
+    }
+
+    /// Records that the supplied user uploaded the blob with the
+    /// supplied id, so that they may read it.
+    pub fn add_uploader(&self, id: &str, user_id: Id) -> IoResult<()> {
+        if !valid_id(id) || self.is_uploader(id, user_id) {
+            return Ok(());
+        }
+
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.uploaders_path(id))?;
+
+        file.write_all(format!("{}\n", user_id).as_bytes())
+    }
+
+    /// Determines if the supplied user uploaded the blob with the
+    /// supplied id.
+    pub fn is_uploader(&self, id: &str, user_id: Id) -> bool {
+        valid_id(id)
+            && fs::read_to_string(self.uploaders_path(id))
+                .is_ok_and(|uploaders| uploaders.lines().any(|line| line.parse() == Ok(user_id)))
+    }
+
+    /// Reads the blob with the supplied id, if there is one, failing
+    /// if its content no longer matches its checksum.
+    pub fn get(&self, id: &str) -> IoResult<Option<Vec<u8>>> {
+        if !valid_id(id) {
+            return Ok(None);
+        }
+
+        match fs::read(self.dir.join(id)) {
+            Ok(data) if checksum(&data) == id => Ok(Some(data)),
+
+            Ok(_) => Err(IoError::new(
+                IoErrorKind::InvalidData,
+                format!("blob {} is corrupt", id),
+            )),
+
+            Err(ref e) if e.kind() == IoErrorKind::NotFound => Ok(None),
+
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Determines if a blob with the supplied id is stored.
+    pub fn contains(&self, id: &str) -> bool {
+        valid_id(id) && self.dir.join(id).is_file()
+    }
+
+    /// Removes every blob that isn't referenced and is older than
+    /// the grace period, returning how many were removed.
+    pub fn collect_garbage(&self, referenced: &HashSet<&str>) -> IoResult<usize> {
+        let cutoff = SystemTime::now() - self.grace_period;
+        let mut removed = 0;
+
+        for entry in fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            let name = entry.file_name();
+
+            let id = match name.to_str() {
+                Some(id) if valid_id(id) => id,
+                _ => continue,
+            };
+
+            if referenced.contains(id) || entry.metadata()?.modified()? > cutoff {
+                continue;
+            }
+
+            fs::remove_file(entry.path())?;
+
+            match fs::remove_file(self.uploaders_path(id)) {
+                Err(ref e) if e.kind() == IoErrorKind::NotFound => {}
+                result => result?,
+            }
+
+            removed += 1;
+        }
+
+        Ok(removed)
+    }
+
+    /// Internal API.
+    ///
+    /// Writes the content read from the supplied reader to the file
+    /// at the supplied path, returning its blob id, or failing if it's
+    /// larger than the maximum size.
+    fn write_blob(&self, reader: &mut dyn Read, path: &Path) -> IoResult<String> {
+        let mut buffer = [0; CHUNK_SIZE];
+        let mut file = fs::File::create(path)?;
+        let mut hasher = Sha256::new();
+        let mut size = 0;
+
+        loop {
+            let read = match reader.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(read) => read,
+                Err(ref e) if e.kind() == IoErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+
+            size += read;
+
+            if size > self.max_size {
+                return Err(IoError::new(
+                    IoErrorKind::InvalidInput,
+                    format!("blobs must be at most {} bytes", self.max_size),
+                ));
+            }
+
+            hasher.update(&buffer[..read]);
+            file.write_all(&buffer[..read])?;
+        }
+
+        file.sync_data()?;
+
+        Ok(hex::encode(hasher.finalize()))
+    }
+
+    /// Internal API.
+    ///
+    /// Obtains the path of the file that lists the uploaders of the
+    /// blob with the supplied id.
+    fn uploaders_path(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{}{}", id, UPLOADERS_SUFFIX))
+    }
+}
+
+/// Internal API.
+///
+/// Computes the blob id of the supplied content.
+fn checksum(data: &[u8]) -> String {
+    hex::encode(Sha256::digest(data))
+}
+
+/// Internal API.
+///
+/// Determines if the supplied id could have been produced by
+/// `checksum`, which also ensures it's safe to use as a file name.
+fn valid_id(id: &str) -> bool {
+    id.len() == ID_LENGTH
+        && id
+            .bytes()
+            .all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::blobs::*;
+    use std::env;
+    use std::process;
+
+    fn open_store(name: &str) -> BlobStore {
+        let dir = env::temp_dir().join(format!("signal-http-blobs-{}-{}", name, process::id()));
+
+        let _ = fs::remove_dir_all(&dir);
+
+        BlobStore::open(dir).unwrap()
+    }
+
+    #[test]
+    fn test_put_and_get() {
+        let mut store = open_store("put");
+        store.set_max_size(4);
+
+        assert_eq!(
+            store.put(b"abc").unwrap(),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+
+        // content needn't be UTF-8, and is only stored once
+
+        let id = store.put(&[0, 159, 146, 150]).unwrap();
+
+        assert_eq!(store.put(&[0, 159, 146, 150]).unwrap(), id);
+        assert_eq!(store.get(&id).unwrap(), Some(vec![0, 159, 146, 150]));
+        assert!(store.contains(&id));
+
+        assert!(store.put(&[0; 5]).is_err());
+        assert_eq!(store.get(&checksum(&[0; 5])).unwrap(), None);
+        assert_eq!(store.get("../../etc/passwd").unwrap(), None);
+
+        // content is streamed in chunks, and rejected part way through
+        // once it's too large, leaving nothing behind
+
+        let data = vec![7; CHUNK_SIZE * 2 + 1];
+
+        store.set_max_size(data.len());
+
+        let large_id = store.put_reader(&data[..]).unwrap();
+
+        assert_eq!(large_id, checksum(&data));
+        assert_eq!(store.get(&large_id).unwrap(), Some(data.clone()));
+
+        store.set_max_size(CHUNK_SIZE);
+
+        assert!(store.put_reader(&[8; CHUNK_SIZE * 2][..]).is_err());
+        assert_eq!(fs::read_dir(&store.dir).unwrap().count(), 3);
+
+        // corruption is detected
+
+        fs::write(store.dir.join(&id), [1, 2, 3]).unwrap();
+
+        assert!(store.get(&id).is_err());
+
+        fs::remove_dir_all(&store.dir).unwrap();
+    }
+
+    #[test]
+    fn test_collect_garbage() {
+        let mut store = open_store("gc");
+
+        let a = store.put(b"a").unwrap();
+        let b = store.put(b"b").unwrap();
+
+        store.add_uploader(&b, 1).unwrap();
+        store.add_uploader(&b, 2).unwrap();
+        store.add_uploader(&b, 1).unwrap();
+
+        assert!(store.is_uploader(&b, 1));
+        assert!(store.is_uploader(&b, 2));
+        assert!(!store.is_uploader(&b, 3));
+        assert!(!store.is_uploader(&a, 1));
+
+        // recently uploaded blobs are kept, even if unreferenced
+
+        assert_eq!(store.collect_garbage(&HashSet::new()).unwrap(), 0);
+
+        store.set_grace_period(Duration::from_secs(0));
+
+        let referenced = [a.as_str()].iter().cloned().collect();
+
+        assert_eq!(store.collect_garbage(&referenced).unwrap(), 1);
+        assert!(store.contains(&a));
+        assert!(!store.contains(&b));
+
+        // along with the record of who uploaded them
+
+        assert!(!store.is_uploader(&b, 1));
+        assert!(!store.uploaders_path(&b).exists());
+
+        fs::remove_dir_all(&store.dir).unwrap();
+    }
+}
@@ -2,449 +2,10202 @@
 //! which has a pure domain logic implementation,
 //! `ChatServer`.
 
+use crate::auth::{Authenticator, StaticTokens, Tokens};
+use crate::devices::Device;
+use crate::envelope::{Envelope, DEFAULT_MAX_ENVELOPE_SIZE};
+use crate::federation::{Federation, Relay};
+use crate::feed::{Feed, FeedEvent};
+use crate::mentions::{self, Mentions};
+use crate::metrics::{ChatMetrics, Counters};
+use crate::prekeys::{
+    DeviceKeys, PreKeyBundle, PreKeyUpload, LOW_PRE_KEY_THRESHOLD, MAX_ONE_TIME_PRE_KEYS,
+};
+use crate::previews::{self, LinkPreview};
+use crate::replication::ReplicationLog;
+use crate::reports::{Report, Resolution};
+use crate::search::{self, Index};
+use crate::stats::{ChatStats, Stats};
+use crate::storage::{ChatStore, LogEntry};
+use crate::transcript::{Transcript, TranscriptFormat};
+use crate::webhooks::WebhookQueue;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::io::Error as IoError;
+use std::io::ErrorKind as IoErrorKind;
+use std::io::Result as IoResult;
 use std::str;
-use std::usize;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
 
 /// Id type for chats, messages, users
 pub type Id = u64;
 
-/// Response representation of a chat
-#[derive(Debug, PartialEq, Deserialize, Serialize)]
+/// The default for the most prior revisions that are retained for
+/// each message that has been edited.
+const DEFAULT_MAX_REVISIONS: usize = 10;
+
+/// The most messages that are pending delivery to each user. Once a
+/// user has more, the oldest are no longer pending, though they can
+/// still be found in their chats.
+const MAX_PENDING_MESSAGES: usize = 1000;
+
+/// The longest id of a sticker, in bytes.
+const MAX_STICKER_ID_LENGTH: usize = 64;
+
+/// The most webhook URLs that each user can register.
+const MAX_WEBHOOKS_PER_USER: usize = 10;
+
+/// Response representation of a chat. If it has a message TTL,
+/// its messages are purged once they are that old, in the same
+/// units as their timestamps. When it is listed for a user, it
+/// includes whether they've muted or archived it.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Chat {
     pub(crate) id: Id,
-    pub(crate) participant_ids: [Id; 2],
+    pub(crate) participant_ids: Vec<Id>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) title: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) created_at: Option<u64>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) creator: Option<Id>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) message_ttl: Option<u64>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) muted: Option<Mute>,
+
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub(crate) archived: bool,
+}
+
+/// Describes a chat that a user has muted, i.e. that they don't
+/// want to be notified of new messages in. A mute without an end
+/// lasts until the chat is unmuted.
+#[derive(Copy, Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Mute {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) until: Option<u64>,
 }
 
-/// Response representation of a chat message
-#[derive(Debug, PartialEq, Deserialize, Serialize)]
+/// Response representation of a chat message. Messages in
+/// group chats are typically addressed to every participant,
+/// in which case there is no destination user.
+///
+/// The sequence number is assigned by the server when the
+/// message is added, and is what messages are ordered by. If
+/// the server is configured to supplement client timestamps,
+/// when it received the message is also included.
+///
+/// Deleted messages remain as tombstones, keeping their id,
+/// position, and author, but not their content -- including the
+/// ids of the blobs that were attached to them.
+///
+/// Reactions map each emoji to the users that reacted with it,
+/// and receipts map each recipient to the message's status for
+/// them, where absent recipients have only been sent it.
+///
+/// A message whose content the server can't read, e.g. because it
+/// is encrypted, has an envelope rather than text. A message that
+/// was forwarded refers to the message it was copied from, and a
+/// reply refers to the id of the message, in the same chat, that it
+/// replies to.
+///
+/// A message's kind determines how its text is interpreted, e.g. as
+/// the id of a sticker rather than something a user wrote. A text
+/// message can include previews of the pages it links to.
+///
+/// A message that has been edited retains its prior revisions,
+/// oldest first, up to the server's configured limit.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct ChatMessage {
     pub(crate) id: String,
+
+    #[serde(default)]
+    pub(crate) seq: u64,
+
     pub(crate) timestamp: u64,
     pub(crate) message: String,
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) attachment_ids: Vec<String>,
+
     pub(crate) source_user_id: Id,
-    pub(crate) destination_user_id: Id,
-}
 
-/// Contains request messages for the chat request-response
-/// protocol.
-pub enum ChatRequest {
-    CreateChat {
-        id: Id,
-        participant_ids: [Id; 2],
-    },
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) destination_user_id: Option<Id>,
 
-    AddMessage {
-        id: String,
-        chat_id: Id,
-        source_user_id: Id,
-        destination_user_id: Id,
-        timestamp: u64,
-        message: String,
-    },
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) edited_at: Option<u64>,
 
-    ListChats {
-        user_id: Id,
-    },
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub(crate) deleted: bool,
 
-    ListChat {
-        id: Id,
-    },
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub(crate) reactions: BTreeMap<String, Vec<Id>>,
 
-    StoreContactList {
-        id: Id,
-        list: Vec<Id>,
-    },
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub(crate) receipts: BTreeMap<Id, ReceiptStatus>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) received_at: Option<u64>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) envelope: Option<Envelope>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) forwarded_from: Option<MessageRef>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) reply_to: Option<String>,
+
+    #[serde(default, skip_serializing_if = "MessageKind::is_text")]
+    pub(crate) kind: MessageKind,
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) previews: Vec<LinkPreview>,
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) revisions: Vec<Revision>,
 }
 
-/// Contains response messages for the chat request-response
-/// protocol.
-#[derive(Debug, PartialEq)]
-pub enum ChatResponse<'a> {
-    ChatCreated,
-    ChatAlreadyExists,
-    ChatParsingError,
-    ChatValidationError,
-    ChatListed { messages: &'a [ChatMessage] },
-    ChatsListed { chats: Vec<Chat> },
-    ContactListStored,
-    MessageAdded,
-    MessageParsingError,
-    UnknownChat,
+/// A prior revision of a message's text, along with when it was
+/// written, i.e. when the message was sent or last edited before.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Revision {
+    pub(crate) message: String,
+    pub(crate) timestamp: u64,
 }
 
-/// Implements the "domain logic" for the chat server,
-/// which receives `ChatRequest`s and turns them into
-/// `ChatResponse`s, mutating its state whilst doing so.
-#[derive(Default)]
-pub struct ChatServer {
-    chats: HashMap<Id, StoredChat>,
-    chats_by_user_id: HashMap<Id, Vec<ChatRef>>,
-    contact_lists: HashMap<Id, Vec<Id>>,
+/// Identifies a message by its chat's id and its own.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MessageRef {
+    pub(crate) chat_id: Id,
+    pub(crate) message_id: String,
 }
 
-impl ChatServer {
-    /// Creates a new chat server
-    pub fn new() -> Self {
-        Self {
-            chats: HashMap::new(),
-            chats_by_user_id: HashMap::new(),
-            contact_lists: HashMap::new(),
-        }
-    }
+/// Response representation of a message that matched a search,
+/// along with the chat it's in and an excerpt of its text around
+/// where it matched.
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SearchResult {
+    pub(crate) chat_id: Id,
+    pub(crate) message: Arc<ChatMessage>,
+    pub(crate) snippet: String,
+}
 
-    /// Issue a domain-specific request against this chat
-    /// server, returning a domain-specific response.
-    pub fn issue(&mut self, command: ChatRequest) -> ChatResponse {
-        match command {
-            ChatRequest::CreateChat {
-                id,
-                participant_ids,
-            } => {
-                if self.chats.contains_key(&id)
-                    || self
-                        .chat_id(participant_ids[0], participant_ids[1])
-                        .is_some()
-                {
-                    ChatResponse::ChatAlreadyExists
-                } else if !self
-                    .contact_lists
-                    .get(&participant_ids[0])
-                    .map_or(false, |list| list.contains(&participant_ids[1]))
-                    || !self
-                        .contact_lists
-                        .get(&participant_ids[1])
-                        .map_or(false, |list| list.contains(&participant_ids[0]))
-                {
-                    ChatResponse::ChatValidationError
-                } else {
-                    self.chats.insert(
-                        id,
-                        StoredChat {
-                            participant_ids,
-                            messages: Vec::new(),
-                        },
-                    );
+/// Response representation of a message that a user starred,
+/// along with the chat it's in.
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StarredMessage {
+    pub(crate) chat_id: Id,
+    pub(crate) message: Arc<ChatMessage>,
+}
 
-                    self.chats_by_user_id
-                        .entry(participant_ids[0])
-                        .or_insert_with(Vec::new)
-                        .push(ChatRef {
-                            id,
-                            destination_user_id: participant_ids[1],
-                        });
+/// Response representation of a message that mentions a user,
+/// along with the chat it's in and the cursor of the mention.
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Mention {
+    pub(crate) cursor: String,
+    pub(crate) chat_id: Id,
+    pub(crate) message: Arc<ChatMessage>,
+}
 
-                    self.chats_by_user_id
-                        .entry(participant_ids[1])
-                        .or_insert_with(Vec::new)
-                        .push(ChatRef {
-                            id,
-                            destination_user_id: participant_ids[0],
-                        });
+/// Response representation of what was removed when a user was
+/// erased.
+#[derive(Debug, Default, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Erasure {
+    pub(crate) contact_list_removed: bool,
+    pub(crate) chats_left: usize,
+    pub(crate) messages_removed: usize,
+    pub(crate) reactions_removed: usize,
+    pub(crate) receipts_removed: usize,
+    pub(crate) devices_removed: usize,
+    pub(crate) webhooks_removed: usize,
+    pub(crate) events_removed: usize,
+    pub(crate) reports_redacted: usize,
+}
 
-                    ChatResponse::ChatCreated
-                }
-            }
+/// Response representation of the contact data that is stored for a
+/// user, i.e. their contact list, and the users they've blocked and
+/// whose invitations they haven't answered, in id order.
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UserContacts {
+    pub(crate) user_id: Id,
+    pub(crate) contacts: Vec<Id>,
+    pub(crate) blocked: Vec<Id>,
+    pub(crate) requested_by: Vec<Id>,
+}
 
-            ChatRequest::AddMessage {
-                id,
-                chat_id,
-                source_user_id,
-                destination_user_id,
-                timestamp,
-                message,
-            } => self
-                .chat_id(source_user_id, destination_user_id)
-                .filter(|other_chat_id| chat_id == *other_chat_id)
-                .and_then(|chat_id| self.chats.get_mut(&chat_id))
-                .map_or(ChatResponse::UnknownChat, |chat| {
-                    chat.insert(id, source_user_id, destination_user_id, timestamp, message);
+/// Response representation of a message that is pending delivery
+/// to a user, along with the chat it's in.
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PendingMessage {
+    pub(crate) chat_id: Id,
+    pub(crate) message: Arc<ChatMessage>,
+}
 
-                    ChatResponse::MessageAdded
-                }),
+/// Determines how a `ChatServer` uses its clock, if it has one,
+/// when messages are added.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ServerTimestamps {
+    /// Replace the client's timestamp with the time the
+    /// message was received.
+    Override,
 
-            ChatRequest::ListChats { user_id } => {
-                let chat_refs = self.chats_by_user_id.get(&user_id);
+    /// Keep the client's timestamp, recording the time the
+    /// message was received alongside it.
+    Supplement,
+}
 
-                match chat_refs {
-                    Some(rs) => {
-                        let mut chats = Vec::with_capacity(rs.len());
+/// Supplies the current time, in the same units as the
+/// timestamps that clients supply.
+type Clock = Box<dyn Fn() -> u64 + Send + Sync>;
 
-                        for r in rs {
-                            if let Some(c) = self.chats.get(&r.id) {
-                                chats.push(Chat {
-                                    id: r.id,
-                                    participant_ids: c.participant_ids,
-                                });
-                            }
-                        }
+/// The outcome of moderating a message before it is added.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Moderation {
+    /// Add the message as it was supplied.
+    Accept,
 
-                        ChatResponse::ChatsListed { chats }
-                    }
+    /// Add the message, but with its text replaced, e.g. to
+    /// mask profanity.
+    Replace(String),
 
-                    None => ChatResponse::ChatsListed { chats: Vec::new() },
-                }
-            }
+    /// Don't add the message, giving the supplied reason.
+    Reject(String),
+}
 
-            ChatRequest::ListChat { id } => match self.chats.get(&id) {
-                Some(chat) => ChatResponse::ChatListed {
-                    messages: &chat.messages,
-                },
+/// Inspects a message, and the id of the chat it is being
+/// added to, before it is added.
+type Moderator = Box<dyn FnMut(Id, &ChatMessage) -> Moderation + Send + Sync>;
 
-                None => ChatResponse::UnknownChat,
-            },
+/// Receives the events of a `ChatServer`.
+type Listener = Box<dyn FnMut(&ChatEvent) + Send>;
 
-            ChatRequest::StoreContactList { id, list } => {
-                self.contact_lists.insert(id, list);
+/// Why a chat couldn't be created: either its id is already
+/// taken, or its participants already have a chat together.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ChatConflict {
+    Id,
+    Participants,
+}
 
-                ChatResponse::ContactListStored
-            }
-        }
+/// The status of a message for one of its recipients. This
+/// only advances, i.e. a read message can't become delivered.
+#[derive(Copy, Clone, Debug, PartialEq, PartialOrd, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReceiptStatus {
+    Sent,
+    Delivered,
+    Read,
+}
+
+/// What a message is, which determines what it may contain.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MessageKind {
+    /// Text that a user wrote, which may have attachments or an
+    /// envelope.
+    #[default]
+    Text,
+
+    /// A sticker, whose text is its id, e.g. `cat-waving`, without
+    /// attachments, previews, or an envelope.
+    Sticker,
+
+    /// A notice about the chat itself, e.g. that a user left it,
+    /// which the server sends to every other participant. Users
+    /// can't send notices.
+    System,
+}
+
+impl MessageKind {
+    /// Internal API.
+    ///
+    /// Determines if this is the kind of message that users write.
+    pub(crate) fn is_text(&self) -> bool {
+        *self == MessageKind::Text
     }
 
     /// Internal API.
     ///
-    /// Given the ID of two users, determines the ID of the chat
-    /// between them if there is one.
-    fn chat_id(&mut self, source_user_id: Id, destination_user_id: Id) -> Option<Id> {
-        self.chats_by_user_id
-            .get(&source_user_id)
-            .and_then(|chats| {
-                chats
-                    .iter()
-                    .find(|r| r.destination_user_id == destination_user_id)
-            })
-            .map(|chat_ref| chat_ref.id)
+    /// Determines if the supplied message is valid for this kind.
+    fn permits(self, message: &ChatMessage) -> bool {
+        match self {
+            MessageKind::Text => true,
+
+            MessageKind::Sticker => {
+                !message.message.is_empty()
+                    && message.message.len() <= MAX_STICKER_ID_LENGTH
+                    && !message.message.contains(char::is_whitespace)
+                    && message.attachment_ids.is_empty()
+                    && message.previews.is_empty()
+                    && message.envelope.is_none()
+            }
+
+            // notices are only added by the server, so users can't
+            // forge them
+            MessageKind::System => false,
+        }
     }
 }
 
-/// Internal API.
-///
-/// The in-memory representation of a chat, which consists of
-/// a sorted vector of `ChatMessage`s and an array of the
-/// participants' ids.
-#[derive(Debug, PartialEq)]
-struct StoredChat {
-    participant_ids: [Id; 2],
-    messages: Vec<ChatMessage>,
-}
+/// Contains request messages for the chat request-response
+/// protocol.
+#[derive(Debug, Deserialize, Serialize)]
+pub enum ChatRequest {
+    /// Creates a chat with the supplied id, or if none is
+    /// supplied, one that is allocated by the server.
+    CreateChat {
+        id: Option<Id>,
+        participant_ids: Vec<Id>,
+        title: Option<String>,
+        created_at: Option<u64>,
+        creator: Option<Id>,
+    },
 
-impl StoredChat {
-    /// Internal API.
-    ///
-    /// Insert a new chat message into this instance. This uses
-    /// a simple algorithm that scans from the end of the vector.
-    fn insert(
-        &mut self,
+    /// Adds a message to a chat. Its attachments are the ids of
+    /// blobs, which are stored separately. If it has an envelope,
+    /// its text must be empty. If it is a reply, the message it
+    /// replies to must be in the same chat. Its content must be
+    /// valid for its kind, which is text unless otherwise supplied.
+    /// Its previews' images are also the ids of blobs.
+    AddMessage {
         id: String,
+        chat_id: Id,
         source_user_id: Id,
-        destination_user_id: Id,
+        destination_user_id: Option<Id>,
         timestamp: u64,
         message: String,
-    ) {
-        // simple algorithm scans from the end of the vector, finding
-        // the spot to insert at. this is optimized for when received
-        // messages are typically newer than previously received, or
-        // at least relatively recent
 
-        let chat_message = ChatMessage {
-            id,
-            timestamp,
-            message,
-            source_user_id,
-            destination_user_id,
-        };
+        #[serde(default)]
+        attachment_ids: Vec<String>,
 
-        let len = self.messages.len();
-        let messages = self.messages.as_slice();
-        let mut i = len;
+        #[serde(default)]
+        envelope: Option<Envelope>,
 
-        while i > 0 && messages[i - 1].timestamp > timestamp {
-            i -= 1;
-        }
+        #[serde(default)]
+        reply_to: Option<String>,
 
-        if i == len {
-            self.messages.push(chat_message);
-        } else {
-            self.messages.insert(i, chat_message);
-        }
-    }
-}
+        #[serde(default)]
+        kind: MessageKind,
 
-/// Internal API.
-///
-/// Representation of available chats for a particular user,
-/// including the chat id and the other participant's id.
-struct ChatRef {
-    id: Id,
-    destination_user_id: Id,
-}
+        #[serde(default)]
+        previews: Vec<LinkPreview>,
+    },
 
-#[cfg(test)]
-mod tests {
-    use crate::chat::*;
+    /// Copies a message into another chat on behalf of a user who
+    /// participates in both. The copy is assigned a new id, and is
+    /// sent by the user to every other participant of that chat.
+    ForwardMessage {
+        from_chat_id: Id,
+        message_id: String,
+        to_chat_id: Id,
+        user_id: Id,
+        timestamp: u64,
+    },
 
-    #[test]
-    fn test_chat_server() {
-        let mut server = ChatServer::new();
+    /// Replaces a message with a tombstone, which may only be
+    /// done by its author.
+    DeleteMessage {
+        chat_id: Id,
+        message_id: String,
+        requested_by: Id,
+    },
 
-        // first, given that there are no loaded contact lists,
-        // we assert that we can't create a chat
+    /// Replaces the text of a message, which may only be
+    /// done by its author.
+    EditMessage {
+        chat_id: Id,
+        message_id: String,
+        editor_user_id: Id,
+        new_text: String,
+        edited_at: u64,
+    },
 
-        assert_eq!(
-            server.issue(ChatRequest::CreateChat {
-                id: 1,
-                participant_ids: [1, 2]
-            }),
-            ChatResponse::ChatValidationError
-        );
+    /// Produces a transcript of a chat, i.e. its metadata,
+    /// participants, and messages, in the supplied format.
+    ExportChat {
+        chat_id: Id,
+        format: TranscriptFormat,
+    },
 
-        // then, we'll load a contact list and assert that we
-        // still cannot create a chat (must be symmetric)
+    /// Reconstructs a chat from a transcript that was previously
+    /// exported, e.g. by another server. If the chat already exists,
+    /// only the messages that it doesn't already have are added, so
+    /// importing the same transcript again has no effect.
+    ImportChat {
+        transcript: Transcript<'static>,
+    },
+
+    LeaveChat {
+        chat_id: Id,
+        user_id: Id,
+    },
+
+    /// Lists the chats that a user participates in, most recently
+    /// active first. Those they've archived are omitted unless
+    /// `include_archived` is set. If `since` or `before` are supplied,
+    /// only those that were last active after or before them are
+    /// listed, and at most `limit` of them.
+    ListChats {
+        user_id: Id,
+
+        #[serde(default)]
+        include_archived: bool,
+
+        #[serde(default)]
+        limit: Option<usize>,
+
+        #[serde(default)]
+        before: Option<u64>,
+
+        #[serde(default)]
+        since: Option<u64>,
+    },
+
+    /// Lists a user's contacts, which is empty if they have no
+    /// contact list.
+    ListContacts {
+        user_id: Id,
+    },
+
+    /// Obtains the events in a user's feed after the supplied
+    /// cursor. If none is supplied, they start after the cursor that
+    /// the supplied device last acknowledged, or from the oldest that
+    /// is retained if no device is supplied either.
+    PollEvents {
+        user_id: Id,
+        after_cursor: Option<String>,
+        limit: Option<usize>,
+
+        #[serde(default)]
+        device_id: Option<Id>,
+    },
+
+    /// Records that one of a user's devices has received the events
+    /// in their feed up to the supplied cursor.
+    AckEvents {
+        user_id: Id,
+        device_id: Id,
+        cursor: String,
+    },
+
+    /// Obtains the messages that were sent to a user and that they
+    /// haven't acknowledged yet, oldest first, returning at most
+    /// `limit` of them. Those that have since been deleted or purged,
+    /// or are in chats the user has left, are omitted.
+    FetchPending {
+        user_id: Id,
+        limit: Option<usize>,
+    },
+
+    /// Records that a user has received the supplied messages, so
+    /// that they're no longer pending delivery to them.
+    AckPending {
+        user_id: Id,
+        messages: Vec<MessageRef>,
+    },
+
+    /// Records that a message was delivered to one of its recipients,
+    /// and to which of their devices if one is supplied.
+    MarkDelivered {
+        chat_id: Id,
+        message_id: String,
+        user_id: Id,
+
+        #[serde(default)]
+        device_id: Option<Id>,
+    },
+
+    /// Records that a message was read by one of its recipients,
+    /// and on which of their devices if one is supplied.
+    MarkRead {
+        chat_id: Id,
+        message_id: String,
+        user_id: Id,
+
+        #[serde(default)]
+        device_id: Option<Id>,
+    },
+
+    /// Records that every message in a chat up to the one with the
+    /// supplied sequence number was delivered to one of its
+    /// participants, and to which of their devices if one is
+    /// supplied. Each of them that the participant is a recipient of
+    /// is marked delivered, as if by `MarkDelivered`, and is no
+    /// longer pending delivery to them.
+    AckMessages {
+        user_id: Id,
+        chat_id: Id,
+        up_to_seq: u64,
+
+        #[serde(default)]
+        device_id: Option<Id>,
+    },
+
+    /// Lists the prior revisions of a message that has been
+    /// edited, oldest first.
+    ListMessageHistory {
+        chat_id: Id,
+        message_id: String,
+    },
+
+    /// Lists the message with the supplied id and the replies to
+    /// it, including replies to those replies, in sequence order.
+    ListThread {
+        chat_id: Id,
+        root_message_id: String,
+    },
+
+    /// Lists a chat's messages, optionally starting after the
+    /// message identified by a cursor from a previous response,
+    /// and returning at most `limit` of them. If `before` is supplied,
+    /// only the messages before the one with that sequence number are
+    /// listed, and without a cursor, the most recent of those are.
+    ListChat {
+        id: Id,
+        cursor: Option<String>,
+        limit: Option<usize>,
+
+        #[serde(default)]
+        before: Option<u64>,
+    },
+
+    /// Searches the messages in the chats that a user participates
+    /// in, returning at most `limit` of those that match the query,
+    /// most recent first. See the `search` module for how queries
+    /// are matched.
+    SearchMessages {
+        user_id: Id,
+        query: String,
+        limit: Option<usize>,
+    },
+
+    /// Adds a reaction to a message on behalf of a participant.
+    ReactToMessage {
+        chat_id: Id,
+        message_id: String,
+        user_id: Id,
+        emoji: String,
+    },
+
+    /// Removes a reaction that a participant previously added.
+    RemoveReaction {
+        chat_id: Id,
+        message_id: String,
+        user_id: Id,
+        emoji: String,
+    },
+
+    StoreContactList {
+        id: Id,
+        list: Vec<Id>,
+    },
+
+    /// Adds a single contact to a user's contact list.
+    AddContact {
+        user_id: Id,
+        contact_id: Id,
+    },
+
+    /// Invites another user to become a contact. If they have
+    /// already invited the requesting user, this accepts that
+    /// invitation instead.
+    RequestContact {
+        user_id: Id,
+        contact_id: Id,
+    },
+
+    /// Accepts an invitation from another user, adding each of
+    /// the users to the other's contact list.
+    AcceptContact {
+        user_id: Id,
+        contact_id: Id,
+    },
+
+    /// Declines an invitation from another user.
+    DeclineContact {
+        user_id: Id,
+        contact_id: Id,
+    },
+
+    /// Removes a single contact from a user's contact list. Messages
+    /// can no longer be sent between the two users, even in chats
+    /// that already exist.
+    RemoveContact {
+        user_id: Id,
+        contact_id: Id,
+    },
+
+    /// Prevents chats from being created, and messages from being
+    /// sent, between two users, regardless of their contact lists.
+    BlockUser {
+        user_id: Id,
+        blocked_id: Id,
+    },
+
+    /// Removes a block that a user previously added.
+    UnblockUser {
+        user_id: Id,
+        blocked_id: Id,
+    },
+
+    /// Replaces a chat's metadata, i.e. its title and message TTL,
+    /// which may be done by any participant.
+    UpdateChat {
+        id: Id,
+        user_id: Id,
+        title: Option<String>,
+
+        #[serde(default)]
+        message_ttl: Option<u64>,
+    },
+
+    /// Purges the messages that have outlived their chat's TTL as
+    /// of `now`. See `ChatServer::expire`.
+    ExpireMessages {
+        now: u64,
+    },
+
+    /// Registers a URL that is POSTed each message that is sent to
+    /// the user, which must be an `http://` or `https://` URL. Each
+    /// user can register at most `MAX_WEBHOOKS_PER_USER` URLs.
+    RegisterWebhook {
+        user_id: Id,
+        url: String,
+    },
+
+    UnregisterWebhook {
+        user_id: Id,
+        url: String,
+    },
+
+    /// Registers one of a user's devices, or renames it if it is
+    /// already registered.
+    RegisterDevice {
+        user_id: Id,
+        device_id: Id,
+        name: Option<String>,
+    },
+
+    UnregisterDevice {
+        user_id: Id,
+        device_id: Id,
+    },
+
+    ListDevices {
+        user_id: Id,
+    },
+
+    /// Stops a participant from being notified of the messages added
+    /// to a chat until the supplied time, in the same units as message
+    /// timestamps, or until it is unmuted if no time is supplied.
+    MuteChat {
+        user_id: Id,
+        chat_id: Id,
+        until: Option<u64>,
+    },
+
+    UnmuteChat {
+        user_id: Id,
+        chat_id: Id,
+    },
+
+    /// Hides a chat from a participant's list of chats, without
+    /// leaving it.
+    ArchiveChat {
+        user_id: Id,
+        chat_id: Id,
+    },
+
+    UnarchiveChat {
+        user_id: Id,
+        chat_id: Id,
+    },
+
+    /// Bookmarks a message for a participant of its chat, so that
+    /// they can find it again later.
+    StarMessage {
+        user_id: Id,
+        chat_id: Id,
+        message_id: String,
+    },
+
+    UnstarMessage {
+        user_id: Id,
+        chat_id: Id,
+        message_id: String,
+    },
+
+    /// Lists the messages that a user has starred, most recently
+    /// starred first. Those that have since been deleted or purged,
+    /// or are in chats the user has left, are omitted.
+    ListStarred {
+        user_id: Id,
+    },
+
+    /// Lists the messages that mention a user after the supplied
+    /// cursor, oldest first, or those that are retained if none is
+    /// supplied. Those that have since been deleted or purged, or are
+    /// in chats the user has left, are omitted. See the `mentions`
+    /// module.
+    ListMentions {
+        user_id: Id,
+        after_cursor: Option<String>,
+    },
+
+    /// Reports a message to the operator on behalf of a participant
+    /// of its chat, giving the reason. A user only has one unresolved
+    /// report of each message, so reporting it again has no effect.
+    /// See the `reports` module.
+    ReportMessage {
+        reporter: Id,
+        chat_id: Id,
+        message_id: String,
+        reason: String,
+    },
+
+    /// Lists the reports that haven't been resolved, oldest first, or
+    /// every report if `include_resolved` is set. Only operators may
+    /// do so.
+    ListReports {
+        #[serde(default)]
+        include_resolved: bool,
+    },
+
+    /// Resolves a report. If the reported message is removed, every
+    /// other unresolved report of it is resolved too. Only operators
+    /// may do so.
+    ResolveReport {
+        report_id: Id,
+        resolution: Resolution,
+    },
+
+    /// Stores the public keys of one of a user's devices, which
+    /// must be registered. See the `prekeys` module.
+    UploadPreKeys {
+        user_id: Id,
+        device_id: Id,
+        keys: PreKeyUpload,
+    },
+
+    /// Obtains a bundle of keys for the supplied device of a user,
+    /// or for each of their devices if none is supplied, consuming
+    /// a one-time pre-key of each.
+    FetchPreKeyBundle {
+        user_id: Id,
+        device_id: Option<Id>,
+        requested_by: Id,
+    },
+
+    /// Obtains how many one-time pre-keys one of a user's devices
+    /// has left.
+    CountPreKeys {
+        user_id: Id,
+        device_id: Id,
+    },
+
+    /// Checks a user's credential with the server's authenticator,
+    /// and if it is valid, issues them a token that identifies them
+    /// until it expires. See the `auth` module.
+    Authenticate {
+        user_id: Id,
+        credential: String,
+    },
+
+    /// Computes statistics about the server's state, where users are
+    /// active if they've sent a message in the last `active_minutes`.
+    /// Only operators may do so. See the `stats` module.
+    Stats {
+        active_minutes: u64,
+    },
+
+    /// Obtains the state of the chat with the supplied id exactly as
+    /// it's stored, e.g. so that an operator can debug it. Only
+    /// operators may do so.
+    DumpChat {
+        chat_id: Id,
+    },
+
+    /// Obtains the contact data that is stored for the supplied user,
+    /// or for every user if none is supplied, e.g. so that an operator
+    /// can debug it. Only operators may do so.
+    DumpContacts {
+        user_id: Option<Id>,
+    },
+
+    /// Removes everything that the server stores about a user, e.g.
+    /// because they asked to be forgotten. They leave each of their
+    /// chats, their messages are replaced with tombstones, and they
+    /// are removed from every other user's contacts. The server's
+    /// store is then checkpointed, so that it no longer has them,
+    /// which is why it's rejected if the store can't discard what was
+    /// logged, e.g. a write-ahead log without a snapshot path.
+    EraseUser {
+        user_id: Id,
+    },
+
+    /// Issues each of the supplied mutations in order, e.g. those a
+    /// client made whilst it was offline. If `stop_on_error` is set,
+    /// those after the first that fails aren't issued.
+    Batch {
+        requests: Vec<ChatRequest>,
+
+        #[serde(default)]
+        stop_on_error: bool,
+    },
+}
+
+impl ChatRequest {
+    /// Determines if this request can change the state of a
+    /// `ChatServer`, i.e. it isn't a query.
+    pub fn is_mutation(&self) -> bool {
+        match self {
+            ChatRequest::ListChats { .. }
+            | ChatRequest::ListChat { .. }
+            | ChatRequest::ListMessageHistory { .. }
+            | ChatRequest::ListThread { .. }
+            | ChatRequest::ListContacts { .. }
+            | ChatRequest::SearchMessages { .. }
+            | ChatRequest::ExportChat { .. }
+            | ChatRequest::PollEvents { .. }
+            | ChatRequest::FetchPending { .. }
+            | ChatRequest::ListDevices { .. }
+            | ChatRequest::CountPreKeys { .. }
+            | ChatRequest::ListReports { .. }
+            | ChatRequest::ListMentions { .. }
+            | ChatRequest::ListStarred { .. }
+            | ChatRequest::Stats { .. }
+            | ChatRequest::DumpChat { .. }
+            | ChatRequest::DumpContacts { .. } => false,
+
+            // tokens aren't part of the server's state, so issuing
+            // them isn't logged
+            ChatRequest::Authenticate { .. } => false,
+
+            _ => true,
+        }
+    }
+
+    /// Obtains the id of the user that this request is made on
+    /// behalf of, e.g. so that it can be authorized, or `None` if it
+    /// isn't made on behalf of a particular user.
+    pub fn user_id(&self) -> Option<Id> {
+        match self {
+            ChatRequest::CreateChat { creator, .. } => *creator,
+            ChatRequest::AddMessage { source_user_id, .. } => Some(*source_user_id),
+            ChatRequest::DeleteMessage { requested_by, .. } => Some(*requested_by),
+            ChatRequest::EditMessage { editor_user_id, .. } => Some(*editor_user_id),
+            ChatRequest::FetchPreKeyBundle { requested_by, .. } => Some(*requested_by),
+            ChatRequest::ReportMessage { reporter, .. } => Some(*reporter),
+            ChatRequest::StoreContactList { id, .. } => Some(*id),
+
+            ChatRequest::AcceptContact { user_id, .. }
+            | ChatRequest::AckEvents { user_id, .. }
+            | ChatRequest::AckMessages { user_id, .. }
+            | ChatRequest::AckPending { user_id, .. }
+            | ChatRequest::AddContact { user_id, .. }
+            | ChatRequest::ArchiveChat { user_id, .. }
+            | ChatRequest::Authenticate { user_id, .. }
+            | ChatRequest::BlockUser { user_id, .. }
+            | ChatRequest::CountPreKeys { user_id, .. }
+            | ChatRequest::DeclineContact { user_id, .. }
+            | ChatRequest::EraseUser { user_id }
+            | ChatRequest::FetchPending { user_id, .. }
+            | ChatRequest::ForwardMessage { user_id, .. }
+            | ChatRequest::LeaveChat { user_id, .. }
+            | ChatRequest::ListChats { user_id, .. }
+            | ChatRequest::ListContacts { user_id }
+            | ChatRequest::ListDevices { user_id }
+            | ChatRequest::ListMentions { user_id, .. }
+            | ChatRequest::ListStarred { user_id }
+            | ChatRequest::MarkDelivered { user_id, .. }
+            | ChatRequest::MarkRead { user_id, .. }
+            | ChatRequest::MuteChat { user_id, .. }
+            | ChatRequest::PollEvents { user_id, .. }
+            | ChatRequest::ReactToMessage { user_id, .. }
+            | ChatRequest::RegisterDevice { user_id, .. }
+            | ChatRequest::RegisterWebhook { user_id, .. }
+            | ChatRequest::RemoveContact { user_id, .. }
+            | ChatRequest::RemoveReaction { user_id, .. }
+            | ChatRequest::RequestContact { user_id, .. }
+            | ChatRequest::SearchMessages { user_id, .. }
+            | ChatRequest::StarMessage { user_id, .. }
+            | ChatRequest::UnarchiveChat { user_id, .. }
+            | ChatRequest::UnblockUser { user_id, .. }
+            | ChatRequest::UnmuteChat { user_id, .. }
+            | ChatRequest::UnregisterDevice { user_id, .. }
+            | ChatRequest::UnregisterWebhook { user_id, .. }
+            | ChatRequest::UnstarMessage { user_id, .. }
+            | ChatRequest::UpdateChat { user_id, .. }
+            | ChatRequest::UploadPreKeys { user_id, .. } => Some(*user_id),
+
+            ChatRequest::Batch { .. }
+            | ChatRequest::DumpChat { .. }
+            | ChatRequest::DumpContacts { .. }
+            | ChatRequest::ExpireMessages { .. }
+            | ChatRequest::ExportChat { .. }
+            | ChatRequest::ImportChat { .. }
+            | ChatRequest::ListChat { .. }
+            | ChatRequest::ListMessageHistory { .. }
+            | ChatRequest::ListReports { .. }
+            | ChatRequest::ListThread { .. }
+            | ChatRequest::ResolveReport { .. }
+            | ChatRequest::Stats { .. } => None,
+        }
+    }
+
+    /// Obtains the id of the chat whose messages this request reads
+    /// or adds to, e.g. so that it can be authorized, or `None` if it
+    /// doesn't operate on a particular chat's messages.
+    pub fn chat_id(&self) -> Option<Id> {
+        match self {
+            ChatRequest::ListChat { id, .. } => Some(*id),
+
+            ChatRequest::AddMessage { chat_id, .. }
+            | ChatRequest::ExportChat { chat_id, .. }
+            | ChatRequest::ListMessageHistory { chat_id, .. }
+            | ChatRequest::ListThread { chat_id, .. } => Some(*chat_id),
+
+            _ => None,
+        }
+    }
+
+    /// Determines if this request may only be made by an operator,
+    /// e.g. because it moderates other users' content, manages their
+    /// contacts, or changes chats that the caller needn't be in.
+    pub fn requires_operator(&self) -> bool {
+        matches!(
+            self,
+            ChatRequest::ImportChat { .. }
+                | ChatRequest::ExpireMessages { .. }
+                | ChatRequest::ListReports { .. }
+                | ChatRequest::ResolveReport { .. }
+                | ChatRequest::Stats { .. }
+                | ChatRequest::DumpChat { .. }
+                | ChatRequest::DumpContacts { .. }
+                | ChatRequest::StoreContactList { .. }
+                | ChatRequest::AddContact { .. }
+                | ChatRequest::ListContacts { .. }
+        )
+    }
+
+    /// Obtains the name of this request's variant, e.g. `AddMessage`,
+    /// so that requests can be told apart in logs and metrics without
+    /// their content.
+    pub fn name(&self) -> &'static str {
+        match self {
+            ChatRequest::CreateChat { .. } => "CreateChat",
+            ChatRequest::AddMessage { .. } => "AddMessage",
+            ChatRequest::ForwardMessage { .. } => "ForwardMessage",
+            ChatRequest::DeleteMessage { .. } => "DeleteMessage",
+            ChatRequest::EditMessage { .. } => "EditMessage",
+            ChatRequest::ExportChat { .. } => "ExportChat",
+            ChatRequest::ImportChat { .. } => "ImportChat",
+            ChatRequest::LeaveChat { .. } => "LeaveChat",
+            ChatRequest::ListChats { .. } => "ListChats",
+            ChatRequest::ListContacts { .. } => "ListContacts",
+            ChatRequest::PollEvents { .. } => "PollEvents",
+            ChatRequest::AckEvents { .. } => "AckEvents",
+            ChatRequest::FetchPending { .. } => "FetchPending",
+            ChatRequest::AckPending { .. } => "AckPending",
+            ChatRequest::MarkDelivered { .. } => "MarkDelivered",
+            ChatRequest::MarkRead { .. } => "MarkRead",
+            ChatRequest::AckMessages { .. } => "AckMessages",
+            ChatRequest::ListMessageHistory { .. } => "ListMessageHistory",
+            ChatRequest::ListThread { .. } => "ListThread",
+            ChatRequest::ListChat { .. } => "ListChat",
+            ChatRequest::SearchMessages { .. } => "SearchMessages",
+            ChatRequest::ReactToMessage { .. } => "ReactToMessage",
+            ChatRequest::RemoveReaction { .. } => "RemoveReaction",
+            ChatRequest::StoreContactList { .. } => "StoreContactList",
+            ChatRequest::AddContact { .. } => "AddContact",
+            ChatRequest::RequestContact { .. } => "RequestContact",
+            ChatRequest::AcceptContact { .. } => "AcceptContact",
+            ChatRequest::DeclineContact { .. } => "DeclineContact",
+            ChatRequest::RemoveContact { .. } => "RemoveContact",
+            ChatRequest::BlockUser { .. } => "BlockUser",
+            ChatRequest::UnblockUser { .. } => "UnblockUser",
+            ChatRequest::UpdateChat { .. } => "UpdateChat",
+            ChatRequest::ExpireMessages { .. } => "ExpireMessages",
+            ChatRequest::RegisterWebhook { .. } => "RegisterWebhook",
+            ChatRequest::UnregisterWebhook { .. } => "UnregisterWebhook",
+            ChatRequest::RegisterDevice { .. } => "RegisterDevice",
+            ChatRequest::UnregisterDevice { .. } => "UnregisterDevice",
+            ChatRequest::ListDevices { .. } => "ListDevices",
+            ChatRequest::MuteChat { .. } => "MuteChat",
+            ChatRequest::UnmuteChat { .. } => "UnmuteChat",
+            ChatRequest::ArchiveChat { .. } => "ArchiveChat",
+            ChatRequest::UnarchiveChat { .. } => "UnarchiveChat",
+            ChatRequest::StarMessage { .. } => "StarMessage",
+            ChatRequest::UnstarMessage { .. } => "UnstarMessage",
+            ChatRequest::ListStarred { .. } => "ListStarred",
+            ChatRequest::ListMentions { .. } => "ListMentions",
+            ChatRequest::ReportMessage { .. } => "ReportMessage",
+            ChatRequest::ListReports { .. } => "ListReports",
+            ChatRequest::ResolveReport { .. } => "ResolveReport",
+            ChatRequest::UploadPreKeys { .. } => "UploadPreKeys",
+            ChatRequest::FetchPreKeyBundle { .. } => "FetchPreKeyBundle",
+            ChatRequest::CountPreKeys { .. } => "CountPreKeys",
+            ChatRequest::Authenticate { .. } => "Authenticate",
+            ChatRequest::Stats { .. } => "Stats",
+            ChatRequest::DumpChat { .. } => "DumpChat",
+            ChatRequest::DumpContacts { .. } => "DumpContacts",
+            ChatRequest::EraseUser { .. } => "EraseUser",
+            ChatRequest::Batch { .. } => "Batch",
+        }
+    }
+}
+
+/// Contains response messages for the chat request-response
+/// protocol.
+#[derive(Debug, PartialEq)]
+pub enum ChatResponse {
+    Authenticated {
+        token: String,
+        expires_in: u64,
+    },
+    AuthenticationFailed,
+    AckParsingError,
+    ArchiveParsingError,
+    Batch {
+        responses: Vec<ChatResponse>,
+    },
+    BatchParsingError,
+    BlocklistUpdated,
+    ChatArchived,
+    ChatCreated {
+        id: Id,
+    },
+    ChatAlreadyExists {
+        id: Id,
+        conflict: ChatConflict,
+    },
+    ChatExported {
+        format: TranscriptFormat,
+        transcript: String,
+    },
+    ChatImported {
+        id: Id,
+        messages_imported: usize,
+    },
+    ChatLeft,
+    ChatParsingError,
+    ChatUnarchived,
+    ChatUpdated,
+    ChatValidationError {
+        code: ErrorCode,
+        detail: Option<String>,
+    },
+    ChatMuted,
+    ChatUnmuted,
+    ChatListed {
+        messages: Vec<Arc<ChatMessage>>,
+        next_cursor: Option<String>,
+        truncated: bool,
+    },
+    ChatsListed {
+        chats: Vec<Chat>,
+    },
+    ContactAccepted,
+    ContactAdded,
+    ContactDeclined,
+    ContactParsingError,
+    ContactListStored,
+    ContactRemoved,
+    ContactRequested,
+    ContactRequired,
+    ContactsListed {
+        contacts: Vec<Id>,
+    },
+    CursorParsingError,
+    DeviceParsingError,
+    DeviceRegistered,
+    DeviceUnregistered,
+    DevicesListed {
+        devices: Vec<Device>,
+    },
+    DuplicateMessage,
+    EventsAcked,
+    EventsPolled {
+        events: Vec<FeedEvent>,
+        next_cursor: Option<String>,
+    },
+    UnknownAttachment,
+    EditParsingError,
+    EnvelopeTooLarge {
+        max_size: usize,
+    },
+    Forbidden,
+    ForwardParsingError,
+    LeaveParsingError,
+    MuteParsingError,
+    MessageAdded,
+    MessageDeleted {
+        message: Arc<ChatMessage>,
+    },
+    MessageEdited {
+        message: Arc<ChatMessage>,
+    },
+    MessageForbidden,
+    MessageHistoryListed {
+        revisions: Vec<Revision>,
+    },
+    MessageParsingError,
+    MessageReported {
+        id: Id,
+    },
+    MessageRejected {
+        reason: String,
+    },
+    MessagesAcked,
+    MessagesExpired {
+        count: usize,
+    },
+    MessagesFound {
+        results: Vec<SearchResult>,
+    },
+    MessageStarred,
+    MessageUnstarred,
+    MentionsListed {
+        mentions: Vec<Mention>,
+    },
+    PendingAcked,
+    PendingFetched {
+        messages: Vec<PendingMessage>,
+    },
+    PendingParsingError,
+    PreKeyBundlesFetched {
+        bundles: Vec<PreKeyBundle>,
+    },
+    PreKeyParsingError,
+    PreKeysCounted {
+        remaining: usize,
+    },
+    PreKeysStored {
+        remaining: usize,
+    },
+    PreKeysUnavailable,
+    QueryParsingError,
+    ReactionAdded,
+    ReactionRemoved,
+    ReceiptUpdated,
+    ReportParsingError,
+    ReportResolved,
+    ReportsListed {
+        reports: Vec<Report>,
+    },
+    StarParsingError,
+    StatsComputed {
+        stats: Stats,
+    },
+    StatsParsingError,
+    ChatDumped {
+        chat: serde_json::Value,
+    },
+    ContactsDumped {
+        users: Vec<UserContacts>,
+    },
+    ThreadListed {
+        messages: Vec<Arc<ChatMessage>>,
+    },
+    ReadOnly,
+    StarredListed {
+        messages: Vec<StarredMessage>,
+    },
+    StorageError,
+    Unauthorized,
+    UnknownChat,
+    UnknownContactRequest,
+    UnknownDevice,
+    UnknownMessage,
+    UnknownReport,
+    UpdateParsingError,
+    UserBlocked,
+    UserErased {
+        erasure: Erasure,
+    },
+    UserParsingError,
+    WebhookParsingError,
+    WebhookRegistered,
+    WebhookUnregistered,
+}
+
+impl ChatResponse {
+    /// Determines if this response indicates that its request
+    /// failed, e.g. because it was invalid or its chat is unknown.
+    pub fn is_error(&self) -> bool {
+        self.error().is_some()
+    }
+
+    /// Describes why this response's request failed, if it did, so
+    /// that clients can tell failures apart without parsing messages.
+    pub fn error(&self) -> Option<ChatError> {
+        let (code, detail) = match self {
+            ChatResponse::ChatValidationError { code, detail } => (*code, detail.clone()),
+
+            ChatResponse::AckParsingError
+            | ChatResponse::ArchiveParsingError
+            | ChatResponse::BatchParsingError
+            | ChatResponse::ChatParsingError
+            | ChatResponse::ContactParsingError
+            | ChatResponse::DeviceParsingError
+            | ChatResponse::EditParsingError
+            | ChatResponse::ForwardParsingError
+            | ChatResponse::LeaveParsingError
+            | ChatResponse::MessageParsingError
+            | ChatResponse::MuteParsingError
+            | ChatResponse::PendingParsingError
+            | ChatResponse::QueryParsingError
+            | ChatResponse::StatsParsingError
+            | ChatResponse::UserParsingError
+            | ChatResponse::PreKeyParsingError
+            | ChatResponse::ReportParsingError
+            | ChatResponse::StarParsingError
+            | ChatResponse::UpdateParsingError
+            | ChatResponse::WebhookParsingError => (ErrorCode::ParsingError, None),
+
+            ChatResponse::ChatAlreadyExists {
+                id,
+                conflict: ChatConflict::Id,
+            } => (
+                ErrorCode::DuplicateChatId,
+                Some(format!("chat {} already exists", id)),
+            ),
+
+            ChatResponse::ChatAlreadyExists {
+                id,
+                conflict: ChatConflict::Participants,
+            } => (
+                ErrorCode::ChatAlreadyExists,
+                Some(format!(
+                    "chat {} already exists between the participants",
+                    id
+                )),
+            ),
+            ChatResponse::AuthenticationFailed => (ErrorCode::AuthenticationFailed, None),
+            ChatResponse::ContactRequired => (ErrorCode::ContactRequired, None),
+            ChatResponse::CursorParsingError => (ErrorCode::InvalidCursor, None),
+
+            ChatResponse::EnvelopeTooLarge { max_size } => (
+                ErrorCode::EnvelopeTooLarge,
+                Some(format!("envelopes can have at most {} bytes", max_size)),
+            ),
+
+            ChatResponse::Forbidden => (ErrorCode::Forbidden, None),
+            ChatResponse::MessageForbidden => (ErrorCode::MessageForbidden, None),
+
+            ChatResponse::MessageRejected { reason } => {
+                (ErrorCode::MessageRejected, Some(reason.clone()))
+            }
+
+            ChatResponse::PreKeysUnavailable => (ErrorCode::PreKeysUnavailable, None),
+            ChatResponse::ReadOnly => (ErrorCode::ReadOnly, None),
+            ChatResponse::StorageError => (ErrorCode::StorageError, None),
+            ChatResponse::Unauthorized => (ErrorCode::Unauthorized, None),
+            ChatResponse::UnknownAttachment => (ErrorCode::UnknownAttachment, None),
+            ChatResponse::UnknownChat => (ErrorCode::UnknownChat, None),
+            ChatResponse::UnknownContactRequest => (ErrorCode::UnknownContactRequest, None),
+            ChatResponse::UnknownDevice => (ErrorCode::UnknownDevice, None),
+            ChatResponse::UnknownMessage => (ErrorCode::UnknownMessage, None),
+            ChatResponse::UnknownReport => (ErrorCode::UnknownReport, None),
+            ChatResponse::UserBlocked => (ErrorCode::UserBlocked, None),
+
+            _ => return None,
+        };
+
+        Some(ChatError { code, detail })
+    }
+}
+
+impl ChatResponse {
+    /// Internal API.
+    ///
+    /// Creates a validation error with the supplied code, and no
+    /// detail.
+    fn invalid(code: ErrorCode) -> Self {
+        ChatResponse::ChatValidationError { code, detail: None }
+    }
+}
+
+/// Identifies why a request failed. Validation errors have codes
+/// that identify which check failed, and requests that fail before
+/// they're issued, e.g. because they aren't routed, have codes that
+/// identify why.
+#[derive(Copy, Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ErrorCode {
+    AuthenticationFailed,
+    BlobTooLarge,
+    ChatAlreadyExists,
+    ContactRequired,
+    DuplicateChatId,
+    DuplicateParticipants,
+    EncryptedMessage,
+    EntriesUnavailable,
+    EnvelopeTooLarge,
+    FeatureDisabled,
+    Forbidden,
+    InvalidCreator,
+    InvalidCursor,
+    InvalidEnvelope,
+    InvalidKeys,
+    InvalidLinkPreview,
+    InvalidMessageKind,
+    InvalidReply,
+    InvalidUrl,
+    MessageForbidden,
+    MessageRejected,
+    MethodNotAllowed,
+    NotAcceptable,
+    NotInContactList,
+    ParsingError,
+    PreKeysUnavailable,
+    RateLimited,
+    ReadOnly,
+    SelfContact,
+    StorageError,
+    TooFewParticipants,
+    TooManyKeys,
+    TooManyWebhooks,
+    Unauthorized,
+    UnknownAttachment,
+    UnknownBlob,
+    UnknownChat,
+    UnknownContactRequest,
+    UnknownDevice,
+    UnknownMessage,
+    UnknownReport,
+    UnknownRoute,
+    UnsupportedMediaType,
+    UnsupportedRequest,
+    UserBlocked,
+}
+
+/// The reason that a request failed, along with detail that
+/// explains it, if there is any.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ChatError {
+    pub(crate) code: ErrorCode,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) detail: Option<String>,
+}
+
+impl From<ErrorCode> for ChatError {
+    fn from(code: ErrorCode) -> Self {
+        Self { code, detail: None }
+    }
+}
+
+/// Describes a change to a `ChatServer`'s state, which is delivered
+/// to its listeners after the request that made it has been applied,
+/// and appended to the feeds of the users it concerns.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(
+    tag = "type",
+    rename_all = "camelCase",
+    rename_all_fields = "camelCase"
+)]
+pub enum ChatEvent {
+    ChatCreated {
+        chat: Chat,
+    },
+    ChatImported {
+        chat: Chat,
+        messages_imported: usize,
+    },
+    ChatLeft {
+        chat_id: Id,
+        user_id: Id,
+    },
+    ChatUpdated {
+        chat: Chat,
+    },
+    ChatMuted {
+        user_id: Id,
+        chat_id: Id,
+        until: Option<u64>,
+    },
+    ChatUnmuted {
+        user_id: Id,
+        chat_id: Id,
+    },
+    ChatArchived {
+        user_id: Id,
+        chat_id: Id,
+    },
+    ChatUnarchived {
+        user_id: Id,
+        chat_id: Id,
+    },
+    ContactAccepted {
+        user_id: Id,
+        contact_id: Id,
+    },
+    ContactAdded {
+        user_id: Id,
+        contact_id: Id,
+    },
+    ContactDeclined {
+        user_id: Id,
+        contact_id: Id,
+    },
+    ContactListStored {
+        user_id: Id,
+    },
+    ContactRemoved {
+        user_id: Id,
+        contact_id: Id,
+    },
+    ContactRequested {
+        user_id: Id,
+        contact_id: Id,
+    },
+    DeviceRegistered {
+        user_id: Id,
+        device: Device,
+    },
+    DeviceUnregistered {
+        user_id: Id,
+        device_id: Id,
+    },
+    MessageAdded {
+        chat_id: Id,
+        message: ChatMessage,
+    },
+    MessageDeleted {
+        chat_id: Id,
+        message_id: String,
+    },
+    MessageEdited {
+        chat_id: Id,
+        message: ChatMessage,
+    },
+    MessageStarred {
+        user_id: Id,
+        chat_id: Id,
+        message_id: String,
+    },
+    MessageUnstarred {
+        user_id: Id,
+        chat_id: Id,
+        message_id: String,
+    },
+    /// A device has fewer one-time pre-keys left than it should, and
+    /// is expected to upload more.
+    PreKeysLow {
+        user_id: Id,
+        device_id: Id,
+        remaining: usize,
+    },
+    /// Messages were removed because they expired or were evicted,
+    /// rather than being deleted by their authors.
+    MessagesPurged {
+        chat_id: Id,
+        message_ids: Vec<String>,
+    },
+    ReactionAdded {
+        chat_id: Id,
+        message_id: String,
+        user_id: Id,
+        emoji: String,
+    },
+    ReactionRemoved {
+        chat_id: Id,
+        message_id: String,
+        user_id: Id,
+        emoji: String,
+    },
+    ReceiptUpdated {
+        chat_id: Id,
+        message_id: String,
+        user_id: Id,
+        status: ReceiptStatus,
+    },
+    UserBlocked {
+        user_id: Id,
+        blocked_id: Id,
+    },
+    UserUnblocked {
+        user_id: Id,
+        blocked_id: Id,
+    },
+}
+
+/// A point-in-time copy of a `ChatServer`'s state, along with
+/// the index of the last write-ahead log entry that it includes.
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Snapshot<'a> {
+    log_index: u64,
+    chats: Cow<'a, HashMap<Id, StoredChat>>,
+    chats_by_user_id: Cow<'a, HashMap<Id, Vec<ChatRef>>>,
+
+    #[serde(default)]
+    starred_by_user_id: Cow<'a, HashMap<Id, Vec<StarRef>>>,
+
+    #[serde(default)]
+    pending_by_user_id: Cow<'a, HashMap<Id, Vec<PendingRef>>>,
+
+    #[serde(default)]
+    mentions_by_user_id: Cow<'a, HashMap<Id, Mentions>>,
+
+    contact_lists: Cow<'a, HashMap<Id, Vec<Id>>>,
+    blocklists: Cow<'a, HashMap<Id, HashSet<Id>>>,
+    contact_requests: Cow<'a, HashMap<Id, HashSet<Id>>>,
+    last_chat_id: Id,
+
+    #[serde(default)]
+    feeds: Cow<'a, HashMap<Id, Feed>>,
+
+    #[serde(default)]
+    webhook_urls: Cow<'a, HashMap<Id, Vec<String>>>,
+
+    #[serde(default)]
+    devices: Cow<'a, HashMap<Id, Vec<Device>>>,
+
+    #[serde(default)]
+    pre_keys: Cow<'a, HashMap<Id, BTreeMap<Id, DeviceKeys>>>,
+
+    #[serde(default)]
+    reports: Cow<'a, [Report]>,
+}
+
+impl Snapshot<'_> {
+    /// Obtains the index of the last write-ahead log entry that the
+    /// snapshot includes.
+    pub fn log_index(&self) -> u64 {
+        self.log_index
+    }
+}
+
+/// Implements the "domain logic" for the chat server,
+/// which receives `ChatRequest`s and turns them into
+/// `ChatResponse`s, mutating its state whilst doing so.
+#[derive(Default)]
+pub struct ChatServer {
+    chats: HashMap<Id, StoredChat>,
+    chats_by_user_id: HashMap<Id, Vec<ChatRef>>,
+    starred_by_user_id: HashMap<Id, Vec<StarRef>>,
+    pending_by_user_id: HashMap<Id, Vec<PendingRef>>,
+    mentions_by_user_id: HashMap<Id, Mentions>,
+    contact_lists: HashMap<Id, Vec<Id>>,
+    blocklists: HashMap<Id, HashSet<Id>>,
+    contact_requests: HashMap<Id, HashSet<Id>>,
+    feeds: HashMap<Id, Feed>,
+    webhook_urls: HashMap<Id, Vec<String>>,
+    devices: HashMap<Id, Vec<Device>>,
+    pre_keys: HashMap<Id, BTreeMap<Id, DeviceKeys>>,
+    reports: Vec<Report>,
+    last_chat_id: Id,
+    server_timestamps: Option<(ServerTimestamps, Clock)>,
+    moderator: Option<Moderator>,
+    store: Option<Box<dyn ChatStore + Send + Sync>>,
+    log_index: u64,
+    replication: Option<ReplicationLog>,
+    read_only: bool,
+    released_blobs: bool,
+    index: Index,
+    max_chat_messages: Option<usize>,
+    max_chat_bytes: Option<usize>,
+    max_envelope_size: usize,
+    max_revisions: usize,
+    counters: Arc<Counters>,
+    listeners: Arc<Mutex<Vec<Listener>>>,
+    webhooks: Option<WebhookQueue>,
+    federation: Option<Relay>,
+    authenticator: Option<Authenticator>,
+    tokens: Mutex<Tokens>,
+    static_tokens: Option<StaticTokens>,
+}
+
+impl ChatServer {
+    /// Creates a new chat server
+    pub fn new() -> Self {
+        Self {
+            chats: HashMap::new(),
+            chats_by_user_id: HashMap::new(),
+            starred_by_user_id: HashMap::new(),
+            pending_by_user_id: HashMap::new(),
+            mentions_by_user_id: HashMap::new(),
+            contact_lists: HashMap::new(),
+            blocklists: HashMap::new(),
+            contact_requests: HashMap::new(),
+            feeds: HashMap::new(),
+            webhook_urls: HashMap::new(),
+            devices: HashMap::new(),
+            pre_keys: HashMap::new(),
+            reports: Vec::new(),
+            last_chat_id: 0,
+            server_timestamps: None,
+            moderator: None,
+            store: None,
+            log_index: 0,
+            replication: None,
+            read_only: false,
+            released_blobs: false,
+            index: Index::default(),
+            max_chat_messages: None,
+            max_chat_bytes: None,
+            max_envelope_size: DEFAULT_MAX_ENVELOPE_SIZE,
+            max_revisions: DEFAULT_MAX_REVISIONS,
+            counters: Arc::new(Counters::default()),
+            listeners: Arc::new(Mutex::new(Vec::new())),
+            webhooks: None,
+            federation: None,
+            authenticator: None,
+            tokens: Mutex::new(Tokens::default()),
+            static_tokens: None,
+        }
+    }
+
+    /// Configures the most messages that a chat retains. Once a
+    /// chat has more, its oldest messages are evicted.
+    pub fn set_max_chat_messages(&mut self, max_chat_messages: usize) {
+        self.max_chat_messages = Some(max_chat_messages);
+    }
+
+    /// Configures the most bytes of message text that a chat retains.
+    /// Once a chat has more, its oldest messages are evicted, though
+    /// its latest message is always retained.
+    pub fn set_max_chat_bytes(&mut self, max_chat_bytes: usize) {
+        self.max_chat_bytes = Some(max_chat_bytes);
+    }
+
+    /// Configures the most bytes that an envelope's content can have
+    /// once it is decoded.
+    pub fn set_max_envelope_size(&mut self, max_envelope_size: usize) {
+        self.max_envelope_size = max_envelope_size;
+    }
+
+    /// Configures the most prior revisions that are retained for each
+    /// message that has been edited. Once a message has more, its
+    /// oldest revisions are discarded.
+    pub fn set_max_revisions(&mut self, max_revisions: usize) {
+        self.max_revisions = max_revisions;
+    }
+
+    /// Configures the server to timestamp messages itself, using
+    /// the supplied clock, rather than relying solely on clients'
+    /// clocks.
+    pub fn set_server_timestamps<F>(&mut self, mode: ServerTimestamps, clock: F)
+    where
+        F: Fn() -> u64 + Send + Sync + 'static,
+    {
+        self.server_timestamps = Some((mode, Box::new(clock)));
+    }
+
+    /// Configures a hook that inspects every message before it is
+    /// added, and can reject it or replace its text. This is where
+    /// profanity filters, spam heuristics, and the like plug in.
+    pub fn set_moderator<F>(&mut self, moderator: F)
+    where
+        F: FnMut(Id, &ChatMessage) -> Moderation + Send + Sync + 'static,
+    {
+        self.moderator = Some(Box::new(moderator));
+    }
+
+    /// Subscribes the supplied listener to this server's events. It is
+    /// called synchronously, after each request that changes state has
+    /// been applied, with an event for every change the request made.
+    pub fn subscribe<F>(&mut self, listener: F)
+    where
+        F: FnMut(&ChatEvent) + Send + 'static,
+    {
+        Self::lock_listeners(&self.listeners).push(Box::new(listener));
+    }
+
+    /// Configures the server to notify webhooks of the messages that
+    /// are added, by queueing them for delivery.
+    pub fn set_webhooks(&mut self, webhooks: WebhookQueue) {
+        self.webhooks = Some(webhooks);
+    }
+
+    /// Obtains the queue that webhooks are delivered by, if the
+    /// server notifies webhooks.
+    pub fn webhooks(&self) -> Option<&WebhookQueue> {
+        self.webhooks.as_ref()
+    }
+
+    /// Configures the server to retain the supplied number of its
+    /// most recently logged entries, so that followers can replicate
+    /// them.
+    pub fn set_replication_backlog(&mut self, max_entries: usize) {
+        self.replication = Some(ReplicationLog::new(max_entries));
+    }
+
+    /// Configures whether the server rejects requests that would
+    /// change its state, e.g. because it is a follower, whose state
+    /// only changes by replicating its leader's.
+    pub fn set_read_only(&mut self, read_only: bool) {
+        self.read_only = read_only;
+    }
+
+    /// Configures the server to relay messages and receipts to the
+    /// home servers of the users they concern, when those are peers
+    /// that it is federated with.
+    pub fn set_federation(&mut self, relay: Relay) {
+        self.federation = Some(relay);
+    }
+
+    /// Obtains the server's federation configuration, if it has one,
+    /// e.g. to verify the requests that peers relay to it.
+    pub fn federation(&self) -> Option<&Federation> {
+        self.federation.as_ref().map(Relay::federation)
+    }
+
+    /// Configures the server to issue tokens to users who
+    /// authenticate with a credential that the supplied authenticator
+    /// accepts. Clients are then expected to present a token with
+    /// every request.
+    pub fn set_authenticator<F>(&mut self, authenticator: F)
+    where
+        F: Fn(Id, &str) -> bool + Send + Sync + 'static,
+    {
+        self.authenticator = Some(Box::new(authenticator));
+    }
+
+    /// Configures how long the tokens that are issued from now on are
+    /// valid for.
+    pub fn set_token_ttl(&mut self, ttl: Duration) {
+        self.lock_tokens().set_ttl(ttl);
+    }
+
+    /// Configures static tokens, which clients may present instead of
+    /// those that are issued to them, e.g. for bots and services that
+    /// don't authenticate with a credential. Clients are then expected
+    /// to present a token with every request.
+    pub fn set_static_tokens(&mut self, static_tokens: StaticTokens) {
+        self.static_tokens = Some(static_tokens);
+    }
+
+    /// Determines if the server has an authenticator or static tokens,
+    /// i.e. whether clients are expected to present a token with every
+    /// request.
+    pub fn requires_authentication(&self) -> bool {
+        self.authenticator.is_some() || self.static_tokens.is_some()
+    }
+
+    /// Resolves the supplied token to the id of the user it was
+    /// issued to, or is configured for if it's static, or `None` if it
+    /// is unknown or has expired.
+    pub fn verify_token(&self, token: &str) -> Option<Id> {
+        self.static_tokens
+            .as_ref()
+            .and_then(|static_tokens| static_tokens.resolve(token))
+            .or_else(|| self.lock_tokens().resolve(token))
+    }
+
+    /// Configures the server to persist its state to the supplied
+    /// store, first restoring the state that the store already has.
+    /// Every mutating request is then appended to the store before
+    /// it is applied.
+    pub fn attach_store<S>(&mut self, mut store: S) -> IoResult<()>
+    where
+        S: ChatStore + Send + Sync + 'static,
+    {
+        let (snapshot, entries) = store.load()?;
+
+        if let Some(snapshot) = snapshot {
+            self.restore(snapshot);
+        }
+
+        self.replay(entries);
+        self.store = Some(Box::new(store));
+
+        Ok(())
+    }
+
+    /// Closes the store that the server persists its state to, if
+    /// any, after which its state is no longer persisted.
+    pub fn close_store(&mut self) -> IoResult<()> {
+        match self.store.take() {
+            Some(store) => store.close(),
+            None => Ok(()),
+        }
+    }
+
+    /// Applies the supplied entries, previously read from a write-ahead
+    /// log, to this server without logging them again. Entries that
+    /// are already included in a restored snapshot are skipped.
+    pub fn replay<I: IntoIterator<Item = LogEntry>>(&mut self, entries: I) {
+        for entry in entries {
+            if entry.index > self.log_index {
+                self.log_index = entry.index;
+                self.apply(entry.request, entry.now, &mut Vec::new());
+            }
+        }
+    }
+
+    /// Obtains the index of the last entry that was logged, or
+    /// replicated from a leader.
+    pub fn log_index(&self) -> u64 {
+        self.log_index
+    }
+
+    /// Obtains the serialized entries that were logged after the one
+    /// with the supplied index, for a follower to replicate. Returns
+    /// `None` if some of them are no longer retained, in which case
+    /// the follower must restore a snapshot first, or if replication
+    /// isn't configured.
+    pub fn replicated_entries(&self, after: u64) -> Option<&[String]> {
+        self.replication
+            .as_ref()
+            .and_then(|replication| replication.after(after, self.log_index))
+    }
+
+    /// Determines if the supplied users have each other in their
+    /// contact lists.
+    pub fn are_contacts(&self, a: Id, b: Id) -> bool {
+        self.contacts(a, b)
+    }
+
+    /// Determines if the supplied user has registered the device with
+    /// the supplied id.
+    pub fn has_device(&self, user_id: Id, device_id: Id) -> bool {
+        self.device(user_id, device_id).is_some()
+    }
+
+    /// Determines if the supplied user participates in the chat with
+    /// the supplied id, which is `false` if it doesn't exist.
+    pub fn is_participant(&self, chat_id: Id, user_id: Id) -> bool {
+        self.chats
+            .get(&chat_id)
+            .is_some_and(|chat| chat.participant_ids.contains(&user_id))
+    }
+
+    /// Obtains the sequence number of the latest event in the supplied
+    /// user's feed, which is `0` if they haven't had any.
+    pub fn last_event_seq(&self, user_id: Id) -> u64 {
+        self.feeds.get(&user_id).map_or(0, Feed::last_seq)
+    }
+
+    /// Determines if the server retains logged entries for followers.
+    pub fn is_replicated(&self) -> bool {
+        self.replication.is_some()
+    }
+
+    /// Applies the supplied entry, which a leader logged, as if it
+    /// were issued to this server. Entries must be replicated in
+    /// order -- those that were already applied are skipped, and an
+    /// entry that would leave a gap fails.
+    pub fn replicate(&mut self, entry: LogEntry) -> IoResult<()> {
+        if entry.index <= self.log_index {
+            return Ok(());
+        }
+
+        if entry.index != self.log_index + 1 {
+            return Err(IoError::new(
+                IoErrorKind::InvalidData,
+                format!(
+                    "expected entry {} to be replicated, not {}",
+                    self.log_index + 1,
+                    entry.index
+                ),
+            ));
+        }
+
+        self.log(&entry)?;
+        self.log_index = entry.index;
+
+        let mut events = Vec::new();
+
+        self.mutate(entry.request, entry.now, &mut events);
+        self.notify_listeners(&events);
+
+        Ok(())
+    }
+
+    /// Replaces this server's state with that of the supplied snapshot,
+    /// taken by a leader, and then checkpoints it, so that entries this
+    /// server logged before aren't replayed on top of it.
+    pub fn resync(&mut self, snapshot: Snapshot) -> IoResult<()> {
+        self.restore(snapshot);
+
+        if let Some(ref mut replication) = self.replication {
+            replication.clear();
+        }
+
+        self.checkpoint()
+    }
+
+    /// Takes a snapshot of this server's state.
+    pub fn snapshot(&self) -> Snapshot<'_> {
+        Snapshot {
+            log_index: self.log_index,
+            chats: Cow::Borrowed(&self.chats),
+            chats_by_user_id: Cow::Borrowed(&self.chats_by_user_id),
+            starred_by_user_id: Cow::Borrowed(&self.starred_by_user_id),
+            pending_by_user_id: Cow::Borrowed(&self.pending_by_user_id),
+            mentions_by_user_id: Cow::Borrowed(&self.mentions_by_user_id),
+            contact_lists: Cow::Borrowed(&self.contact_lists),
+            blocklists: Cow::Borrowed(&self.blocklists),
+            contact_requests: Cow::Borrowed(&self.contact_requests),
+            last_chat_id: self.last_chat_id,
+            feeds: Cow::Borrowed(&self.feeds),
+            webhook_urls: Cow::Borrowed(&self.webhook_urls),
+            devices: Cow::Borrowed(&self.devices),
+            pre_keys: Cow::Borrowed(&self.pre_keys),
+            reports: Cow::Borrowed(&self.reports),
+        }
+    }
+
+    /// Replaces this server's state with that of the supplied
+    /// snapshot. Its configuration, e.g. its clock, is retained.
+    pub fn restore(&mut self, snapshot: Snapshot) {
+        self.log_index = snapshot.log_index;
+        self.chats = snapshot.chats.into_owned();
+        self.chats_by_user_id = snapshot.chats_by_user_id.into_owned();
+        self.starred_by_user_id = snapshot.starred_by_user_id.into_owned();
+        self.pending_by_user_id = snapshot.pending_by_user_id.into_owned();
+        self.mentions_by_user_id = snapshot.mentions_by_user_id.into_owned();
+        self.contact_lists = snapshot.contact_lists.into_owned();
+        self.blocklists = snapshot.blocklists.into_owned();
+        self.contact_requests = snapshot.contact_requests.into_owned();
+        self.last_chat_id = snapshot.last_chat_id;
+        self.feeds = snapshot.feeds.into_owned();
+        self.webhook_urls = snapshot.webhook_urls.into_owned();
+        self.devices = snapshot.devices.into_owned();
+        self.pre_keys = snapshot.pre_keys.into_owned();
+        self.reports = snapshot.reports.into_owned();
+
+        // the search index isn't included in snapshots, as it can be
+        // rebuilt from the messages
+
+        self.index = Index::default();
+
+        for (chat_id, chat) in self.chats.iter() {
+            for message in chat.messages.iter().filter(|m| !m.deleted) {
+                self.index.insert((*chat_id, message.seq), &message.message);
+            }
+        }
+    }
+
+    /// Computes a digest of this server's chats, their messages, and
+    /// users' contacts, as a hex-encoded SHA-256 hash. It doesn't
+    /// depend on the order that maps are iterated in, so two servers
+    /// with the same state have the same digest, e.g. a server and
+    /// one that replayed its log or restored its snapshot.
+    pub fn state_digest(&self) -> String {
+        let chats = self
+            .chats
+            .iter()
+            .map(|(id, chat)| (id, (chat.to_chat(*id), &chat.messages)))
+            .collect::<BTreeMap<_, _>>();
+
+        let contact_lists = self.contact_lists.iter().collect::<BTreeMap<_, _>>();
+
+        let sorted = |sets: &'_ HashMap<Id, HashSet<Id>>| {
+            sets.iter()
+                .map(|(id, set)| (*id, set.iter().cloned().collect::<BTreeSet<_>>()))
+                .collect::<BTreeMap<_, _>>()
+        };
+
+        let state = (
+            chats,
+            contact_lists,
+            sorted(&self.blocklists),
+            sorted(&self.contact_requests),
+        );
+
+        hex::encode(Sha256::digest(
+            serde_json::to_vec(&state).unwrap_or_default(),
+        ))
+    }
+
+    /// Writes a snapshot of this server's state to its store, if
+    /// it has one, allowing the store to discard the entries that
+    /// were appended before it.
+    pub fn checkpoint(&mut self) -> IoResult<()> {
+        match self.store.take() {
+            Some(mut store) => {
+                let result = store.snapshot(&self.snapshot());
+
+                self.store = Some(store);
+
+                result
+            }
+
+            None => Ok(()),
+        }
+    }
+
+    /// Obtains the ids of the blobs that are attached to any
+    /// message, i.e. those that mustn't be garbage collected.
+    pub fn referenced_blobs(&self) -> HashSet<&str> {
+        self.chats
+            .values()
+            .flat_map(|chat| chat.messages.iter())
+            .flat_map(|message| message.blob_ids())
+            .map(String::as_str)
+            .collect()
+    }
+
+    /// Determines if the supplied blob is attached to a message in any
+    /// of the chats that the supplied user participates in, i.e.
+    /// whether they may read it.
+    pub fn is_blob_shared_with(&self, blob_id: &str, user_id: Id) -> bool {
+        self.chats
+            .values()
+            .filter(|chat| chat.participant_ids.contains(&user_id))
+            .flat_map(|chat| chat.messages.iter())
+            .flat_map(|message| message.blob_ids())
+            .any(|id| id == blob_id)
+    }
+
+    /// Determines if any blobs have stopped being attached to a
+    /// message, e.g. because it was deleted, since
+    /// `take_released_blobs` was last called.
+    pub fn released_blobs(&self) -> bool {
+        self.released_blobs
+    }
+
+    /// Determines if any blobs have stopped being attached to a
+    /// message, e.g. because it was deleted, since this was last
+    /// called.
+    pub fn take_released_blobs(&mut self) -> bool {
+        let released = self.released_blobs;
+
+        self.released_blobs = false;
+
+        released
+    }
+
+    /// Purges every message that has outlived its chat's TTL as
+    /// of `now`, in the same units as message timestamps, returning
+    /// how many were purged. This is expected to be called
+    /// periodically, and is logged like any other mutation so that
+    /// purged messages aren't restored by a replay.
+    pub fn expire(&mut self, now: u64) -> usize {
+        // most sweeps find nothing to purge, and needn't be logged
+
+        let any_expired = self.chats.values().any(|chat| {
+            chat.message_ttl.is_some_and(|ttl| {
+                let cutoff = now.saturating_sub(ttl);
+
+                chat.messages.iter().any(|m| m.timestamp < cutoff)
+            })
+        });
+
+        if !any_expired {
+            return 0;
+        }
+
+        match self.issue(ChatRequest::ExpireMessages { now }) {
+            ChatResponse::MessagesExpired { count } => count,
+            _ => 0,
+        }
+    }
+
+    /// Obtains a snapshot of the counters of this server's activity,
+    /// which include every request issued since it was created.
+    pub fn metrics(&self) -> ChatMetrics {
+        self.counters.snapshot()
+    }
+
+    /// Issue a domain-specific request against this chat
+    /// server, returning a domain-specific response.
+    pub fn issue(&mut self, command: ChatRequest) -> ChatResponse {
+        match command {
+            ChatRequest::Batch {
+                requests,
+                stop_on_error,
+            } => self.issue_batch(requests, stop_on_error),
+
+            command if command.is_mutation() => self.issue_mutation(command),
+
+            command => self.query(command),
+        }
+    }
+
+    /// Answers the supplied query, which only requires shared access
+    /// to the server, so that queries can be answered concurrently,
+    /// e.g. by a `SharedChatServer`. Mutations fail validation.
+    pub fn query(&self, command: ChatRequest) -> ChatResponse {
+        let started = Instant::now();
+        let response = self.answer(command);
+
+        self.counters.record(&response, started.elapsed());
+
+        response
+    }
+
+    /// Internal API.
+    ///
+    /// Issues each of the supplied requests in turn, as if they were
+    /// issued individually, stopping after the first that fails if
+    /// requested. Only mutations can be batched, so queries fail
+    /// validation.
+    fn issue_batch(&mut self, requests: Vec<ChatRequest>, stop_on_error: bool) -> ChatResponse {
+        let mut responses = Vec::with_capacity(requests.len());
+
+        for request in requests {
+            let response = match request {
+                ChatRequest::Batch { .. } => ChatResponse::invalid(ErrorCode::UnsupportedRequest),
+                request if request.is_mutation() => self.issue_mutation(request),
+                _ => ChatResponse::invalid(ErrorCode::UnsupportedRequest),
+            };
+
+            let failed = response.is_error();
+
+            responses.push(response);
+
+            if failed && stop_on_error {
+                break;
+            }
+        }
+
+        ChatResponse::Batch { responses }
+    }
+
+    /// Internal API.
+    ///
+    /// Issues the supplied mutation, notifying webhooks and listeners
+    /// of the events it produces.
+    fn issue_mutation(&mut self, command: ChatRequest) -> ChatResponse {
+        let webhook_urls = match self.webhooks {
+            Some(_) => self.webhook_urls(&command),
+            None => Vec::new(),
+        };
+        let started = Instant::now();
+        let mut events = Vec::new();
+        let response = self.log_and_mutate(command, &mut events);
+
+        self.counters.record(&response, started.elapsed());
+
+        if let Some(ref webhooks) = self.webhooks {
+            for event in events.iter() {
+                if let ChatEvent::MessageAdded { .. } = event {
+                    webhooks.notify(event, &webhook_urls);
+                }
+            }
+        }
+
+        self.relay(&events);
+        self.notify_listeners(&events);
+
+        response
+    }
+
+    /// Internal API.
+    ///
+    /// Relays the messages that local users added, and the receipts
+    /// for messages that they received, to the home servers of the
+    /// other users they concern. Requests that peers relayed aren't
+    /// on behalf of local users, so they're never relayed back.
+    fn relay(&self, events: &[ChatEvent]) {
+        let relay = match self.federation {
+            Some(ref relay) => relay,
+            None => return,
+        };
+
+        let federation = relay.federation();
+
+        for event in events {
+            match event {
+                ChatEvent::MessageAdded { chat_id, message }
+                    if federation.home(message.source_user_id).is_none() =>
+                {
+                    let homes = self
+                        .participant_ids(*chat_id)
+                        .into_iter()
+                        .filter(|id| {
+                            *id != message.source_user_id
+                                && message.destination_user_id.is_none_or(|d| d == *id)
+                        })
+                        .filter_map(|id| federation.home(id))
+                        .collect::<BTreeSet<_>>();
+
+                    for home in homes {
+                        relay.relay_message(*chat_id, message, home);
+                    }
+                }
+
+                ChatEvent::ReceiptUpdated {
+                    chat_id,
+                    message_id,
+                    user_id,
+                    status,
+                } if federation.home(*user_id).is_none() => {
+                    let home = match self
+                        .chats
+                        .get(chat_id)
+                        .and_then(|chat| chat.messages.iter().find(|m| m.id == *message_id))
+                        .and_then(|message| federation.home(message.source_user_id))
+                    {
+                        Some(home) => home,
+                        None => continue,
+                    };
+
+                    let (chat_id, message_id, user_id) = (*chat_id, message_id.clone(), *user_id);
+
+                    let request = match status {
+                        ReceiptStatus::Delivered => ChatRequest::MarkDelivered {
+                            chat_id,
+                            message_id,
+                            user_id,
+                            device_id: None,
+                        },
+
+                        ReceiptStatus::Read => ChatRequest::MarkRead {
+                            chat_id,
+                            message_id,
+                            user_id,
+                            device_id: None,
+                        },
+
+                        _ => continue,
+                    };
+
+                    relay.relay(&request, home);
+                }
+
+                _ => {}
+            }
+        }
+    }
+
+    /// Internal API.
+    ///
+    /// Calls every listener with each of the supplied events.
+    fn notify_listeners(&self, events: &[ChatEvent]) {
+        if !events.is_empty() {
+            for listener in Self::lock_listeners(&self.listeners).iter_mut() {
+                for event in events.iter() {
+                    listener(event);
+                }
+            }
+        }
+    }
+
+    /// Internal API.
+    ///
+    /// Locks the supplied listeners. If one panicked whilst they
+    /// were locked, the others are still called.
+    fn lock_listeners(listeners: &Mutex<Vec<Listener>>) -> MutexGuard<'_, Vec<Listener>> {
+        listeners.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Internal API.
+    ///
+    /// Locks the issued tokens. A panic whilst they were locked
+    /// can't leave them inconsistent, so they're still used.
+    fn lock_tokens(&self) -> MutexGuard<'_, Tokens> {
+        self.tokens.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Internal API.
+    ///
+    /// Issues a token to the supplied user if the supplied credential
+    /// is valid for them.
+    fn authenticate(&self, user_id: Id, credential: &str) -> ChatResponse {
+        match self.authenticator {
+            Some(ref authenticator) if authenticator(user_id, credential) => {
+                let mut tokens = self.lock_tokens();
+
+                ChatResponse::Authenticated {
+                    token: tokens.issue(user_id),
+                    expires_in: tokens.ttl().as_secs(),
+                }
+            }
+
+            Some(_) => ChatResponse::AuthenticationFailed,
+
+            None => ChatResponse::invalid(ErrorCode::UnsupportedRequest),
+        }
+    }
+
+    /// Internal API.
+    ///
+    /// Appends the supplied mutation to the store, and then applies it,
+    /// unless the server is read-only.
+    fn log_and_mutate(
+        &mut self,
+        command: ChatRequest,
+        events: &mut Vec<ChatEvent>,
+    ) -> ChatResponse {
+        if self.read_only {
+            return ChatResponse::ReadOnly;
+        }
+
+        // erasing a user relies on the checkpoint that follows it
+        // discarding the entries with their data, so it's rejected
+        // before it's logged if the store can't do so
+
+        if let ChatRequest::EraseUser { .. } = command {
+            if self
+                .store
+                .as_ref()
+                .is_some_and(|store| !store.discards_entries())
+            {
+                return ChatResponse::ChatValidationError {
+                    code: ErrorCode::FeatureDisabled,
+                    detail: Some("users can't be erased from a store that keeps every entry, e.g. a write-ahead log without a snapshot path".to_string()),
+                };
+            }
+        }
+
+        let entry = LogEntry {
+            index: self.log_index + 1,
+            now: self.server_timestamps.as_ref().map(|(_, clock)| clock()),
+            request: command,
+        };
+
+        if self.log(&entry).is_err() {
+            return ChatResponse::StorageError;
+        }
+
+        let response = self.mutate(entry.request, entry.now, events);
+
+        // the store and the replication log still have the requests
+        // that the user made, so they're replaced with a snapshot
+
+        if let ChatResponse::UserErased { .. } = response {
+            if let Some(ref mut replication) = self.replication {
+                replication.clear();
+            }
+
+            if self.checkpoint().is_err() {
+                return ChatResponse::StorageError;
+            }
+        }
+
+        response
+    }
+
+    /// Internal API.
+    ///
+    /// Appends the supplied entry to the store and the replication
+    /// log, if the server has them, advancing the log index.
+    fn log(&mut self, entry: &LogEntry) -> IoResult<()> {
+        if let Some(ref mut store) = self.store {
+            store.append(entry)?;
+        }
+
+        if let Some(ref mut replication) = self.replication {
+            replication.push(entry)?;
+        }
+
+        if self.store.is_some() || self.replication.is_some() {
+            self.log_index = entry.index;
+        }
+
+        Ok(())
+    }
+
+    /// Internal API.
+    ///
+    /// Applies the supplied request, where `now` is the reading of
+    /// the server's clock, if it has one, when it was issued. Queries
+    /// are answered by `answer`, and mutations applied by `mutate`.
+    fn apply(
+        &mut self,
+        command: ChatRequest,
+        now: Option<u64>,
+        events: &mut Vec<ChatEvent>,
+    ) -> ChatResponse {
+        if command.is_mutation() {
+            self.mutate(command, now, events)
+        } else {
+            self.answer(command)
+        }
+    }
+
+    /// Internal API.
+    ///
+    /// Answers the supplied query, which only reads the server.
+    /// Mutations fail validation.
+    fn answer(&self, command: ChatRequest) -> ChatResponse {
+        match command {
+            ChatRequest::ListChats {
+                user_id,
+                include_archived,
+                limit,
+                before,
+                since,
+            } => {
+                let chat_refs = self.chats_by_user_id.get(&user_id);
+
+                match chat_refs {
+                    Some(rs) => {
+                        let mut stored_chats = Vec::with_capacity(rs.len());
+
+                        for r in rs.iter().filter(|r| include_archived || !r.archived) {
+                            match self.chats.get(&r.id) {
+                                Some(c)
+                                    if since.is_none_or(|since| c.last_activity() > since)
+                                        && before
+                                            .is_none_or(|before| c.last_activity() < before) =>
+                                {
+                                    stored_chats.push((r, c));
+                                }
+
+                                _ => {}
+                            }
+                        }
+
+                        // most recently active first. the sort is stable, so
+                        // chats that are equally active remain in the order
+                        // they were created
+
+                        stored_chats.sort_by_key(|(_, c)| Reverse(c.last_activity()));
+                        stored_chats.truncate(limit.unwrap_or(stored_chats.len()));
+
+                        let chats = stored_chats
+                            .into_iter()
+                            .map(|(r, c)| Chat {
+                                muted: r.muted,
+                                archived: r.archived,
+                                ..c.to_chat(r.id)
+                            })
+                            .collect();
+
+                        ChatResponse::ChatsListed { chats }
+                    }
+
+                    None => ChatResponse::ChatsListed { chats: Vec::new() },
+                }
+            }
+
+            ChatRequest::ExportChat { chat_id, format } => match self.chats.get(&chat_id) {
+                Some(chat) => ChatResponse::ChatExported {
+                    format,
+                    transcript: Transcript {
+                        chat: chat.to_chat(chat_id),
+                        messages: Cow::Borrowed(&chat.messages),
+                    }
+                    .encode(format),
+                },
+
+                None => ChatResponse::UnknownChat,
+            },
+
+            ChatRequest::ListContacts { user_id } => ChatResponse::ContactsListed {
+                contacts: self
+                    .contact_lists
+                    .get(&user_id)
+                    .map_or_else(Vec::new, Clone::clone),
+            },
+
+            ChatRequest::ListChat {
+                id,
+                cursor,
+                limit,
+                before,
+            } => self.list_chat(id, cursor, limit, before),
+
+            ChatRequest::ListMessageHistory {
+                chat_id,
+                message_id,
+            } => match self.chats.get(&chat_id) {
+                Some(chat) => match chat.messages.iter().find(|m| m.id == message_id) {
+                    Some(message) if !message.deleted => ChatResponse::MessageHistoryListed {
+                        revisions: message.revisions.clone(),
+                    },
+
+                    _ => ChatResponse::UnknownMessage,
+                },
+
+                None => ChatResponse::UnknownChat,
+            },
+
+            ChatRequest::ListThread {
+                chat_id,
+                root_message_id,
+            } => match self.chats.get(&chat_id) {
+                Some(chat) => match chat.messages.iter().position(|m| m.id == root_message_id) {
+                    Some(position) => {
+                        // replies are added after the messages they reply
+                        // to, so the thread is found in a single pass
+
+                        let mut ids = HashSet::new();
+                        ids.insert(&root_message_id);
+
+                        let mut messages = vec![chat.messages[position].clone()];
+
+                        for message in chat.messages[position + 1..].iter() {
+                            if let Some(ref reply_to) = message.reply_to {
+                                if ids.contains(reply_to) {
+                                    ids.insert(&message.id);
+                                    messages.push(message.clone());
+                                }
+                            }
+                        }
+
+                        ChatResponse::ThreadListed { messages }
+                    }
+
+                    None => ChatResponse::UnknownMessage,
+                },
+
+                None => ChatResponse::UnknownChat,
+            },
+
+            ChatRequest::ListStarred { user_id } => ChatResponse::StarredListed {
+                messages: self.starred(user_id),
+            },
+
+            ChatRequest::ListMentions {
+                user_id,
+                after_cursor,
+            } => match after_cursor.map(|cursor| cursor.parse()) {
+                Some(Ok(after)) => ChatResponse::MentionsListed {
+                    mentions: self.mentions(user_id, after),
+                },
+
+                Some(Err(_)) => ChatResponse::CursorParsingError,
+
+                None => ChatResponse::MentionsListed {
+                    mentions: self.mentions(user_id, 0),
+                },
+            },
+
+            ChatRequest::Stats { active_minutes } => ChatResponse::StatsComputed {
+                stats: self.stats(active_minutes),
+            },
+
+            ChatRequest::DumpChat { chat_id } => match self.chats.get(&chat_id) {
+                Some(chat) => ChatResponse::ChatDumped {
+                    chat: serde_json::to_value(chat).unwrap_or_default(),
+                },
+
+                None => ChatResponse::UnknownChat,
+            },
+
+            ChatRequest::DumpContacts { user_id } => ChatResponse::ContactsDumped {
+                users: self.user_contacts(user_id),
+            },
+
+            ChatRequest::FetchPending { user_id, limit } => ChatResponse::PendingFetched {
+                messages: self
+                    .pending(user_id)
+                    .take(limit.unwrap_or(usize::MAX))
+                    .collect(),
+            },
+
+            ChatRequest::ListReports { include_resolved } => ChatResponse::ReportsListed {
+                reports: self
+                    .reports
+                    .iter()
+                    .filter(|report| include_resolved || report.resolution.is_none())
+                    .cloned()
+                    .collect(),
+            },
+
+            ChatRequest::PollEvents {
+                user_id,
+                after_cursor,
+                limit,
+                device_id,
+            } => self.poll_events(user_id, after_cursor, limit, device_id),
+
+            ChatRequest::CountPreKeys { user_id, device_id } => match self
+                .pre_keys
+                .get(&user_id)
+                .and_then(|keys| keys.get(&device_id))
+            {
+                Some(keys) => ChatResponse::PreKeysCounted {
+                    remaining: keys.remaining(),
+                },
+
+                None => ChatResponse::PreKeysUnavailable,
+            },
+
+            ChatRequest::ListDevices { user_id } => ChatResponse::DevicesListed {
+                devices: self
+                    .devices
+                    .get(&user_id)
+                    .map_or_else(Vec::new, Clone::clone),
+            },
+
+            ChatRequest::SearchMessages {
+                user_id,
+                query,
+                limit,
+            } => ChatResponse::MessagesFound {
+                results: self.search(user_id, &query, limit.unwrap_or(usize::MAX)),
+            },
+
+            ChatRequest::Authenticate {
+                user_id,
+                credential,
+            } => self.authenticate(user_id, &credential),
+
+            _ => ChatResponse::invalid(ErrorCode::UnsupportedRequest),
+        }
+    }
+
+    /// Internal API.
+    ///
+    /// Applies the supplied mutation, where `now` is the reading of
+    /// the server's clock, if it has one, when it was issued. The
+    /// changes it makes are described by the events it appends,
+    /// which are also appended to the feeds of the users they concern.
+    fn mutate(
+        &mut self,
+        command: ChatRequest,
+        now: Option<u64>,
+        events: &mut Vec<ChatEvent>,
+    ) -> ChatResponse {
+        let published = events.len();
+
+        let response = match command {
+            // queries are answered by `answer`, and batches are split
+            // up by `issue`
+            ChatRequest::Authenticate { .. }
+            | ChatRequest::Batch { .. }
+            | ChatRequest::CountPreKeys { .. }
+            | ChatRequest::ExportChat { .. }
+            | ChatRequest::FetchPending { .. }
+            | ChatRequest::ListChat { .. }
+            | ChatRequest::ListChats { .. }
+            | ChatRequest::ListContacts { .. }
+            | ChatRequest::ListDevices { .. }
+            | ChatRequest::ListMentions { .. }
+            | ChatRequest::ListMessageHistory { .. }
+            | ChatRequest::ListReports { .. }
+            | ChatRequest::ListStarred { .. }
+            | ChatRequest::ListThread { .. }
+            | ChatRequest::PollEvents { .. }
+            | ChatRequest::SearchMessages { .. }
+            | ChatRequest::Stats { .. }
+            | ChatRequest::DumpChat { .. }
+            | ChatRequest::DumpContacts { .. } => {
+                ChatResponse::invalid(ErrorCode::UnsupportedRequest)
+            }
+
+            ChatRequest::CreateChat {
+                id,
+                participant_ids,
+                title,
+                created_at,
+                creator,
+            } => {
+                let conflict = match id.filter(|id| self.chats.contains_key(id)) {
+                    Some(id) => Some((id, ChatConflict::Id)),
+                    None => self
+                        .chat_id(&participant_ids)
+                        .map(|id| (id, ChatConflict::Participants)),
+                };
+
+                if let Some((id, conflict)) = conflict {
+                    ChatResponse::ChatAlreadyExists { id, conflict }
+                } else if self.any_blocked(&participant_ids) {
+                    ChatResponse::UserBlocked
+                } else if let Some(response) = self.check_participants(&participant_ids) {
+                    response
+                } else if !creator.is_none_or(|creator| participant_ids.contains(&creator)) {
+                    ChatResponse::invalid(ErrorCode::InvalidCreator)
+                } else {
+                    let id = id.unwrap_or_else(|| self.allocate_chat_id());
+
+                    for participant_id in participant_ids.iter() {
+                        self.chats_by_user_id
+                            .entry(*participant_id)
+                            .or_default()
+                            .push(ChatRef {
+                                id,
+                                muted: None,
+                                archived: false,
+                                delivered_seq: 0,
+                            });
+                    }
+
+                    let chat = StoredChat {
+                        participant_ids,
+                        title,
+                        created_at,
+                        creator,
+                        latest_timestamp: None,
+                        messages: Vec::new(),
+                        message_ids: HashSet::new(),
+                        message_ttl: None,
+                        last_seq: 0,
+                        truncated: false,
+                    };
+
+                    events.push(ChatEvent::ChatCreated {
+                        chat: chat.to_chat(id),
+                    });
+
+                    self.chats.insert(id, chat);
+
+                    ChatResponse::ChatCreated { id }
+                }
+            }
+
+            ChatRequest::AddMessage {
+                id,
+                chat_id,
+                source_user_id,
+                destination_user_id,
+                timestamp,
+                message,
+                attachment_ids,
+                envelope,
+                reply_to,
+                kind,
+                previews,
+            } => {
+                if let Some(ref envelope) = envelope {
+                    if let Some(response) =
+                        self.check_envelope(envelope, &message, source_user_id, destination_user_id)
+                    {
+                        return response;
+                    }
+                }
+
+                let (timestamp, received_at) = self.timestamps(timestamp, now);
+
+                self.add_message(
+                    chat_id,
+                    ChatMessage {
+                        id,
+                        seq: 0,
+                        timestamp,
+                        message,
+                        attachment_ids,
+                        source_user_id,
+                        destination_user_id,
+                        edited_at: None,
+                        deleted: false,
+                        reactions: BTreeMap::new(),
+                        receipts: BTreeMap::new(),
+                        received_at,
+                        envelope,
+                        reply_to,
+                        kind,
+                        previews,
+                        forwarded_from: None,
+                        revisions: Vec::new(),
+                    },
+                    events,
+                )
+            }
+
+            ChatRequest::ForwardMessage {
+                from_chat_id,
+                message_id,
+                to_chat_id,
+                user_id,
+                timestamp,
+            } => {
+                let source = self
+                    .chats
+                    .get(&from_chat_id)
+                    .filter(|chat| chat.participant_ids.contains(&user_id))
+                    .map(|chat| (chat, chat.messages.iter().find(|m| m.id == message_id)));
+
+                let (text, attachment_ids, kind, previews) = match source {
+                    // envelopes are encrypted for their recipients, so
+                    // they can't be read by anyone else
+                    Some((_, Some(message))) if message.envelope.is_some() => {
+                        return ChatResponse::invalid(ErrorCode::EncryptedMessage);
+                    }
+
+                    // notices are about the chat they were sent to
+                    Some((_, Some(message))) if message.kind == MessageKind::System => {
+                        return ChatResponse::invalid(ErrorCode::InvalidMessageKind);
+                    }
+
+                    Some((_, Some(message))) if !message.deleted => (
+                        message.message.clone(),
+                        message.attachment_ids.clone(),
+                        message.kind,
+                        message.previews.clone(),
+                    ),
+
+                    Some(_) => return ChatResponse::UnknownMessage,
+
+                    None => return ChatResponse::UnknownChat,
+                };
+
+                let id = match self.chats.get(&to_chat_id) {
+                    Some(chat) => chat.generated_id("forwarded"),
+                    None => return ChatResponse::UnknownChat,
+                };
+
+                let (timestamp, received_at) = self.timestamps(timestamp, now);
+
+                self.add_message(
+                    to_chat_id,
+                    ChatMessage {
+                        id,
+                        seq: 0,
+                        timestamp,
+                        message: text,
+                        attachment_ids,
+                        source_user_id: user_id,
+                        destination_user_id: None,
+                        edited_at: None,
+                        deleted: false,
+                        reactions: BTreeMap::new(),
+                        receipts: BTreeMap::new(),
+                        received_at,
+                        envelope: None,
+                        reply_to: None,
+                        kind,
+                        previews,
+                        forwarded_from: Some(MessageRef {
+                            chat_id: from_chat_id,
+                            message_id,
+                        }),
+                        revisions: Vec::new(),
+                    },
+                    events,
+                )
+            }
+
+            ChatRequest::DeleteMessage {
+                chat_id,
+                message_id,
+                requested_by,
+            } => match self.chats.get(&chat_id) {
+                Some(chat) => match chat.messages.iter().find(|m| m.id == message_id) {
+                    Some(message) if message.source_user_id == requested_by => {
+                        let seq = message.seq;
+
+                        self.remove_message(chat_id, seq);
+
+                        let message = self.chats[&chat_id].shared_message(&message_id);
+
+                        events.push(ChatEvent::MessageDeleted {
+                            chat_id,
+                            message_id,
+                        });
+
+                        match message {
+                            Some(message) => ChatResponse::MessageDeleted { message },
+                            None => ChatResponse::UnknownMessage,
+                        }
+                    }
+
+                    Some(_) => ChatResponse::MessageForbidden,
+
+                    None => ChatResponse::UnknownMessage,
+                },
+
+                None => ChatResponse::UnknownChat,
+            },
+
+            ChatRequest::EditMessage {
+                chat_id,
+                message_id,
+                editor_user_id,
+                new_text,
+                edited_at,
+            } => match self.chats.get_mut(&chat_id) {
+                Some(chat) => match chat.message_mut(&message_id) {
+                    Some(message) if message.deleted => ChatResponse::UnknownMessage,
+
+                    // envelopes are replaced by sending another, as their
+                    // content can't be edited by the server
+                    Some(message) if message.envelope.is_some() => {
+                        ChatResponse::invalid(ErrorCode::EncryptedMessage)
+                    }
+
+                    // only text can be edited, as stickers and notices
+                    // are validated when they're added
+                    Some(message) if !message.kind.is_text() => {
+                        ChatResponse::invalid(ErrorCode::InvalidMessageKind)
+                    }
+
+                    Some(message) if message.source_user_id == editor_user_id => {
+                        self.index.remove((chat_id, message.seq), &message.message);
+                        self.index.insert((chat_id, message.seq), &new_text);
+
+                        message.revisions.push(Revision {
+                            message: std::mem::replace(&mut message.message, new_text),
+                            timestamp: message.edited_at.unwrap_or(message.timestamp),
+                        });
+
+                        if message.revisions.len() > self.max_revisions {
+                            let excess = message.revisions.len() - self.max_revisions;
+
+                            message.revisions.drain(..excess);
+                        }
+
+                        message.edited_at = Some(edited_at);
+
+                        events.push(ChatEvent::MessageEdited {
+                            chat_id,
+                            message: message.clone(),
+                        });
+
+                        match chat.shared_message(&message_id) {
+                            Some(message) => ChatResponse::MessageEdited { message },
+                            None => ChatResponse::UnknownMessage,
+                        }
+                    }
+
+                    Some(_) => ChatResponse::MessageForbidden,
+
+                    None => ChatResponse::UnknownMessage,
+                },
+
+                None => ChatResponse::UnknownChat,
+            },
+
+            ChatRequest::ImportChat { transcript } => self.import(transcript, events),
+
+            ChatRequest::ExpireMessages { now } => {
+                let mut count = 0;
+
+                for (chat_id, chat) in self.chats.iter_mut() {
+                    let ttl = match chat.message_ttl {
+                        Some(ttl) => ttl,
+                        None => continue,
+                    };
+
+                    let mut expired_ids = Vec::new();
+
+                    for message in chat.expire(now.saturating_sub(ttl)) {
+                        if !message.deleted {
+                            self.index.remove((*chat_id, message.seq), &message.message);
+                        }
+
+                        if message.blob_ids().next().is_some() {
+                            self.released_blobs = true;
+                        }
+
+                        expired_ids.push(message.id.clone());
+                    }
+
+                    if !expired_ids.is_empty() {
+                        count += expired_ids.len();
+
+                        events.push(ChatEvent::MessagesPurged {
+                            chat_id: *chat_id,
+                            message_ids: expired_ids,
+                        });
+                    }
+                }
+
+                ChatResponse::MessagesExpired { count }
+            }
+
+            ChatRequest::LeaveChat { chat_id, user_id } => match self.chats.get_mut(&chat_id) {
+                Some(chat) if chat.participant_ids.contains(&user_id) => {
+                    // the chat and its messages remain for the other participants,
+                    // but the user no longer sees it and cannot post to it
+
+                    chat.participant_ids.retain(|id| *id != user_id);
+
+                    if let Some(chat_refs) = self.chats_by_user_id.get_mut(&user_id) {
+                        chat_refs.retain(|r| r.id != chat_id);
+                    }
+
+                    if let Some(star_refs) = self.starred_by_user_id.get_mut(&user_id) {
+                        star_refs.retain(|r| r.chat_id != chat_id);
+                    }
+
+                    if let Some(pending_refs) = self.pending_by_user_id.get_mut(&user_id) {
+                        pending_refs.retain(|r| r.chat_id != chat_id);
+                    }
+
+                    events.push(ChatEvent::ChatLeft { chat_id, user_id });
+
+                    self.add_notice(chat_id, user_id, format!("{} left", user_id), now, events);
+
+                    ChatResponse::ChatLeft
+                }
+
+                _ => ChatResponse::UnknownChat,
+            },
+
+            ChatRequest::AckEvents {
+                user_id,
+                device_id,
+                cursor,
+            } => match (cursor.parse(), self.device_mut(user_id, device_id)) {
+                (_, None) => ChatResponse::UnknownDevice,
+
+                (Err(_), _) => ChatResponse::CursorParsingError,
+
+                (Ok(cursor), Some(device)) => {
+                    if cursor > device.feed_cursor {
+                        device.feed_cursor = cursor;
+                    }
+
+                    ChatResponse::EventsAcked
+                }
+            },
+
+            ChatRequest::AckPending { user_id, messages } => {
+                // those that can no longer be fetched are discarded too,
+                // so that they don't accumulate
+
+                let retained = self
+                    .pending(user_id)
+                    .filter(|pending| {
+                        !messages.iter().any(|r| {
+                            r.chat_id == pending.chat_id && r.message_id == pending.message.id
+                        })
+                    })
+                    .map(|pending| PendingRef {
+                        chat_id: pending.chat_id,
+                        seq: pending.message.seq,
+                    })
+                    .collect::<Vec<_>>();
+
+                if retained.is_empty() {
+                    self.pending_by_user_id.remove(&user_id);
+                } else {
+                    self.pending_by_user_id.insert(user_id, retained);
+                }
+
+                ChatResponse::PendingAcked
+            }
+
+            ChatRequest::MarkDelivered {
+                chat_id,
+                message_id,
+                user_id,
+                device_id,
+            } => self.update_receipt(
+                chat_id,
+                message_id,
+                user_id,
+                device_id,
+                ReceiptStatus::Delivered,
+                events,
+            ),
+
+            ChatRequest::MarkRead {
+                chat_id,
+                message_id,
+                user_id,
+                device_id,
+            } => self.update_receipt(
+                chat_id,
+                message_id,
+                user_id,
+                device_id,
+                ReceiptStatus::Read,
+                events,
+            ),
+
+            ChatRequest::AckMessages {
+                user_id,
+                chat_id,
+                up_to_seq,
+                device_id,
+            } => self.ack_messages(user_id, chat_id, up_to_seq, device_id, events),
+
+            ChatRequest::ReactToMessage {
+                chat_id,
+                message_id,
+                user_id,
+                emoji,
+            } => match self.chats.get_mut(&chat_id) {
+                Some(chat) if chat.participant_ids.contains(&user_id) => {
+                    match chat.message_mut(&message_id) {
+                        Some(message) if !message.deleted => {
+                            let user_ids = message.reactions.entry(emoji.clone()).or_default();
+
+                            if !user_ids.contains(&user_id) {
+                                user_ids.push(user_id);
+
+                                events.push(ChatEvent::ReactionAdded {
+                                    chat_id,
+                                    message_id,
+                                    user_id,
+                                    emoji,
+                                });
+                            }
+
+                            ChatResponse::ReactionAdded
+                        }
+
+                        _ => ChatResponse::UnknownMessage,
+                    }
+                }
+
+                _ => ChatResponse::UnknownChat,
+            },
+
+            ChatRequest::RemoveReaction {
+                chat_id,
+                message_id,
+                user_id,
+                emoji,
+            } => match self.chats.get_mut(&chat_id) {
+                Some(chat) if chat.participant_ids.contains(&user_id) => {
+                    match chat.message_mut(&message_id) {
+                        Some(message) => {
+                            if let Some(user_ids) = message.reactions.get_mut(&emoji) {
+                                let reacted = user_ids.contains(&user_id);
+
+                                user_ids.retain(|id| *id != user_id);
+
+                                if user_ids.is_empty() {
+                                    message.reactions.remove(&emoji);
+                                }
+
+                                if reacted {
+                                    events.push(ChatEvent::ReactionRemoved {
+                                        chat_id,
+                                        message_id,
+                                        user_id,
+                                        emoji,
+                                    });
+                                }
+                            }
+
+                            ChatResponse::ReactionRemoved
+                        }
+
+                        None => ChatResponse::UnknownMessage,
+                    }
+                }
+
+                _ => ChatResponse::UnknownChat,
+            },
+
+            ChatRequest::StoreContactList { id, list } => {
+                self.contact_lists.insert(id, list);
+
+                events.push(ChatEvent::ContactListStored { user_id: id });
+
+                ChatResponse::ContactListStored
+            }
+
+            ChatRequest::AddContact {
+                user_id,
+                contact_id,
+            } => {
+                self.add_contact(user_id, contact_id);
+
+                events.push(ChatEvent::ContactAdded {
+                    user_id,
+                    contact_id,
+                });
+
+                ChatResponse::ContactAdded
+            }
+
+            ChatRequest::RequestContact {
+                user_id,
+                contact_id,
+            } => {
+                if user_id == contact_id {
+                    ChatResponse::invalid(ErrorCode::SelfContact)
+                } else if self.blocked(user_id, contact_id) {
+                    ChatResponse::UserBlocked
+                } else if self.take_contact_request(user_id, contact_id) {
+                    self.add_contact(user_id, contact_id);
+                    self.add_contact(contact_id, user_id);
+
+                    events.push(ChatEvent::ContactAccepted {
+                        user_id,
+                        contact_id,
+                    });
+
+                    ChatResponse::ContactAccepted
+                } else {
+                    self.contact_requests
+                        .entry(contact_id)
+                        .or_default()
+                        .insert(user_id);
+
+                    events.push(ChatEvent::ContactRequested {
+                        user_id,
+                        contact_id,
+                    });
+
+                    ChatResponse::ContactRequested
+                }
+            }
+
+            ChatRequest::AcceptContact {
+                user_id,
+                contact_id,
+            } => {
+                if !self.take_contact_request(user_id, contact_id) {
+                    ChatResponse::UnknownContactRequest
+                } else if self.blocked(user_id, contact_id) {
+                    ChatResponse::UserBlocked
+                } else {
+                    self.add_contact(user_id, contact_id);
+                    self.add_contact(contact_id, user_id);
+
+                    events.push(ChatEvent::ContactAccepted {
+                        user_id,
+                        contact_id,
+                    });
+
+                    ChatResponse::ContactAccepted
+                }
+            }
+
+            ChatRequest::DeclineContact {
+                user_id,
+                contact_id,
+            } => {
+                if self.take_contact_request(user_id, contact_id) {
+                    events.push(ChatEvent::ContactDeclined {
+                        user_id,
+                        contact_id,
+                    });
+
+                    ChatResponse::ContactDeclined
+                } else {
+                    ChatResponse::UnknownContactRequest
+                }
+            }
+
+            ChatRequest::RemoveContact {
+                user_id,
+                contact_id,
+            } => {
+                if let Some(list) = self.contact_lists.get_mut(&user_id) {
+                    list.retain(|id| *id != contact_id);
+                }
+
+                events.push(ChatEvent::ContactRemoved {
+                    user_id,
+                    contact_id,
+                });
+
+                ChatResponse::ContactRemoved
+            }
+
+            ChatRequest::BlockUser {
+                user_id,
+                blocked_id,
+            } => {
+                self.blocklists
+                    .entry(user_id)
+                    .or_default()
+                    .insert(blocked_id);
+
+                events.push(ChatEvent::UserBlocked {
+                    user_id,
+                    blocked_id,
+                });
+
+                ChatResponse::BlocklistUpdated
+            }
+
+            ChatRequest::UnblockUser {
+                user_id,
+                blocked_id,
+            } => {
+                if let Some(blocklist) = self.blocklists.get_mut(&user_id) {
+                    blocklist.remove(&blocked_id);
+                }
+
+                events.push(ChatEvent::UserUnblocked {
+                    user_id,
+                    blocked_id,
+                });
+
+                ChatResponse::BlocklistUpdated
+            }
+
+            ChatRequest::UpdateChat {
+                id,
+                user_id,
+                title,
+                message_ttl,
+            } => match self.chats.get_mut(&id) {
+                Some(chat) if chat.participant_ids.contains(&user_id) => {
+                    chat.title = title;
+                    chat.message_ttl = message_ttl;
+
+                    events.push(ChatEvent::ChatUpdated {
+                        chat: chat.to_chat(id),
+                    });
+
+                    ChatResponse::ChatUpdated
+                }
+
+                _ => ChatResponse::UnknownChat,
+            },
+
+            ChatRequest::RegisterWebhook { user_id, url } => {
+                let urls = self.webhook_urls.get(&user_id);
+                let registered = urls.is_some_and(|urls| urls.contains(&url));
+
+                if !url.starts_with("http://") && !url.starts_with("https://") {
+                    ChatResponse::ChatValidationError {
+                        code: ErrorCode::InvalidUrl,
+                        detail: Some(
+                            "webhook URLs must start with http:// or https://".to_string(),
+                        ),
+                    }
+                } else if !registered && urls.map_or(0, Vec::len) >= MAX_WEBHOOKS_PER_USER {
+                    ChatResponse::ChatValidationError {
+                        code: ErrorCode::TooManyWebhooks,
+                        detail: Some(format!(
+                            "users can register at most {} webhooks",
+                            MAX_WEBHOOKS_PER_USER
+                        )),
+                    }
+                } else {
+                    if !registered {
+                        self.webhook_urls.entry(user_id).or_default().push(url);
+                    }
+
+                    ChatResponse::WebhookRegistered
+                }
+            }
+
+            ChatRequest::UnregisterWebhook { user_id, url } => {
+                if let Some(urls) = self.webhook_urls.get_mut(&user_id) {
+                    urls.retain(|u| *u != url);
+
+                    if urls.is_empty() {
+                        self.webhook_urls.remove(&user_id);
+                    }
+                }
+
+                ChatResponse::WebhookUnregistered
+            }
+
+            ChatRequest::RegisterDevice {
+                user_id,
+                device_id,
+                name,
+            } => {
+                let devices = self.devices.entry(user_id).or_default();
+
+                let device = match devices.binary_search_by_key(&device_id, |d| d.id) {
+                    Ok(position) => &mut devices[position],
+
+                    Err(position) => {
+                        devices.insert(
+                            position,
+                            Device {
+                                id: device_id,
+                                registered_at: now,
+                                ..Device::default()
+                            },
+                        );
+
+                        &mut devices[position]
+                    }
+                };
+
+                device.name = name;
+
+                events.push(ChatEvent::DeviceRegistered {
+                    user_id,
+                    device: device.clone(),
+                });
+
+                ChatResponse::DeviceRegistered
+            }
+
+            ChatRequest::UnregisterDevice { user_id, device_id } => {
+                match self.devices.get_mut(&user_id) {
+                    Some(devices) if devices.iter().any(|d| d.id == device_id) => {
+                        devices.retain(|d| d.id != device_id);
+
+                        if devices.is_empty() {
+                            self.devices.remove(&user_id);
+                        }
+
+                        if let Some(keys) = self.pre_keys.get_mut(&user_id) {
+                            keys.remove(&device_id);
+
+                            if keys.is_empty() {
+                                self.pre_keys.remove(&user_id);
+                            }
+                        }
+
+                        events.push(ChatEvent::DeviceUnregistered { user_id, device_id });
+
+                        ChatResponse::DeviceUnregistered
+                    }
+
+                    _ => ChatResponse::UnknownDevice,
+                }
+            }
+
+            ChatRequest::MuteChat {
+                user_id,
+                chat_id,
+                until,
+            } => match self.chat_ref_mut(user_id, chat_id) {
+                Some(chat_ref) => {
+                    chat_ref.muted = Some(Mute { until });
+
+                    events.push(ChatEvent::ChatMuted {
+                        user_id,
+                        chat_id,
+                        until,
+                    });
+
+                    ChatResponse::ChatMuted
+                }
+
+                None => ChatResponse::UnknownChat,
+            },
+
+            ChatRequest::UnmuteChat { user_id, chat_id } => {
+                match self.chat_ref_mut(user_id, chat_id) {
+                    Some(chat_ref) => {
+                        if chat_ref.muted.take().is_some() {
+                            events.push(ChatEvent::ChatUnmuted { user_id, chat_id });
+                        }
+
+                        ChatResponse::ChatUnmuted
+                    }
+
+                    None => ChatResponse::UnknownChat,
+                }
+            }
+
+            ChatRequest::EraseUser { user_id } => {
+                let erasure = self.erase_user(user_id, events);
+
+                // the events are published to the other participants
+                // first, as publishing them would otherwise recreate the
+                // user's feed
+
+                self.publish(&events[published..]);
+                self.feeds.remove(&user_id);
+
+                return ChatResponse::UserErased { erasure };
+            }
+
+            ChatRequest::ArchiveChat { user_id, chat_id } => {
+                match self.chat_ref_mut(user_id, chat_id) {
+                    Some(chat_ref) => {
+                        if !chat_ref.archived {
+                            chat_ref.archived = true;
+
+                            events.push(ChatEvent::ChatArchived { user_id, chat_id });
+                        }
+
+                        ChatResponse::ChatArchived
+                    }
+
+                    None => ChatResponse::UnknownChat,
+                }
+            }
+
+            ChatRequest::UnarchiveChat { user_id, chat_id } => {
+                match self.chat_ref_mut(user_id, chat_id) {
+                    Some(chat_ref) => {
+                        if chat_ref.archived {
+                            chat_ref.archived = false;
+
+                            events.push(ChatEvent::ChatUnarchived { user_id, chat_id });
+                        }
+
+                        ChatResponse::ChatUnarchived
+                    }
+
+                    None => ChatResponse::UnknownChat,
+                }
+            }
+
+            ChatRequest::ReportMessage {
+                reporter,
+                chat_id,
+                message_id,
+                reason,
+            } => match self.chats.get(&chat_id) {
+                Some(chat) if chat.participant_ids.contains(&reporter) => {
+                    match chat.messages.iter().find(|m| m.id == message_id) {
+                        Some(message) if !message.deleted => {
+                            let existing = self.reports.iter().find(|report| {
+                                report.reporter == reporter
+                                    && report.resolution.is_none()
+                                    && report.is_of(chat_id, &message_id)
+                            });
+
+                            match existing {
+                                Some(report) => ChatResponse::MessageReported { id: report.id },
+
+                                None => {
+                                    let id = self.reports.last().map_or(0, |r| r.id) + 1;
+
+                                    self.reports.push(Report {
+                                        id,
+                                        reporter,
+                                        chat_id,
+                                        message_id,
+                                        reason,
+                                        source_user_id: message.source_user_id,
+                                        content: message.message.clone(),
+                                        reported_at: now,
+                                        resolution: None,
+                                    });
+
+                                    ChatResponse::MessageReported { id }
+                                }
+                            }
+                        }
+
+                        _ => ChatResponse::UnknownMessage,
+                    }
+                }
+
+                _ => ChatResponse::UnknownChat,
+            },
+
+            ChatRequest::ResolveReport {
+                report_id,
+                resolution,
+            } => match self.reports.iter().find(|report| report.id == report_id) {
+                Some(report) => {
+                    let (chat_id, message_id) = (report.chat_id, report.message_id.clone());
+
+                    let seq = self
+                        .chats
+                        .get(&chat_id)
+                        .and_then(|chat| chat.shared_message(&message_id))
+                        .map(|message| message.seq);
+
+                    if resolution == Resolution::Removed
+                        && seq.is_some_and(|seq| self.remove_message(chat_id, seq))
+                    {
+                        events.push(ChatEvent::MessageDeleted {
+                            chat_id,
+                            message_id: message_id.clone(),
+                        });
+                    }
+
+                    for report in self.reports.iter_mut() {
+                        let resolves = report.id == report_id
+                            || (resolution == Resolution::Removed
+                                && report.resolution.is_none()
+                                && report.is_of(chat_id, &message_id));
+
+                        if resolves {
+                            report.resolution = Some(resolution);
+                        }
+                    }
+
+                    ChatResponse::ReportResolved
+                }
+
+                None => ChatResponse::UnknownReport,
+            },
+
+            ChatRequest::StarMessage {
+                user_id,
+                chat_id,
+                message_id,
+            } => match self.chats.get_mut(&chat_id) {
+                Some(chat) if chat.participant_ids.contains(&user_id) => {
+                    match chat.message_mut(&message_id) {
+                        Some(message) if !message.deleted => {
+                            let star_refs = self.starred_by_user_id.entry(user_id).or_default();
+                            let seq = message.seq;
+
+                            if !star_refs
+                                .iter()
+                                .any(|r| r.chat_id == chat_id && r.seq == seq)
+                            {
+                                star_refs.push(StarRef { chat_id, seq });
+
+                                events.push(ChatEvent::MessageStarred {
+                                    user_id,
+                                    chat_id,
+                                    message_id,
+                                });
+                            }
+
+                            ChatResponse::MessageStarred
+                        }
+
+                        _ => ChatResponse::UnknownMessage,
+                    }
+                }
+
+                _ => ChatResponse::UnknownChat,
+            },
+
+            ChatRequest::UnstarMessage {
+                user_id,
+                chat_id,
+                message_id,
+            } => {
+                let seq = self
+                    .chats
+                    .get_mut(&chat_id)
+                    .and_then(|chat| chat.message_mut(&message_id))
+                    .map(|message| message.seq);
+
+                if let (Some(seq), Some(star_refs)) =
+                    (seq, self.starred_by_user_id.get_mut(&user_id))
+                {
+                    let starred = star_refs.len();
+
+                    star_refs.retain(|r| r.chat_id != chat_id || r.seq != seq);
+
+                    if star_refs.len() < starred {
+                        events.push(ChatEvent::MessageUnstarred {
+                            user_id,
+                            chat_id,
+                            message_id,
+                        });
+                    }
+
+                    if star_refs.is_empty() {
+                        self.starred_by_user_id.remove(&user_id);
+                    }
+                }
+
+                ChatResponse::MessageUnstarred
+            }
+
+            ChatRequest::UploadPreKeys {
+                user_id,
+                device_id,
+                keys,
+            } => {
+                if self.device(user_id, device_id).is_none() {
+                    ChatResponse::UnknownDevice
+                } else if !keys.is_valid() {
+                    ChatResponse::invalid(ErrorCode::InvalidKeys)
+                } else {
+                    let device_keys = self.pre_keys.entry(user_id).or_default();
+
+                    match device_keys.get_mut(&device_id) {
+                        Some(existing) => {
+                            if existing.store(keys) {
+                                ChatResponse::PreKeysStored {
+                                    remaining: existing.remaining(),
+                                }
+                            } else {
+                                ChatResponse::ChatValidationError {
+                                    code: ErrorCode::TooManyKeys,
+                                    detail: Some(format!(
+                                        "devices can have at most {} one-time pre-keys",
+                                        MAX_ONE_TIME_PRE_KEYS
+                                    )),
+                                }
+                            }
+                        }
+
+                        None => {
+                            let new_keys = DeviceKeys::new(keys);
+                            let remaining = new_keys.remaining();
+
+                            device_keys.insert(device_id, new_keys);
+
+                            ChatResponse::PreKeysStored { remaining }
+                        }
+                    }
+                }
+            }
+
+            ChatRequest::FetchPreKeyBundle {
+                user_id,
+                device_id,
+                requested_by,
+            } => {
+                if self.blocked(user_id, requested_by) {
+                    ChatResponse::UserBlocked
+                } else {
+                    let mut bundles = Vec::new();
+
+                    if let Some(device_keys) = self.pre_keys.get_mut(&user_id) {
+                        for (id, keys) in device_keys.iter_mut() {
+                            if device_id.is_none_or(|device_id| device_id == *id) {
+                                let bundle = keys.take_bundle(user_id, *id);
+
+                                if bundle.one_time_pre_key.is_some()
+                                    && keys.remaining() < LOW_PRE_KEY_THRESHOLD
+                                {
+                                    events.push(ChatEvent::PreKeysLow {
+                                        user_id,
+                                        device_id: *id,
+                                        remaining: keys.remaining(),
+                                    });
+                                }
+
+                                bundles.push(bundle);
+                            }
+                        }
+                    }
+
+                    if bundles.is_empty() {
+                        ChatResponse::PreKeysUnavailable
+                    } else {
+                        ChatResponse::PreKeyBundlesFetched { bundles }
+                    }
+                }
+            }
+        };
+
+        self.publish(&events[published..]);
+
+        response
+    }
+
+    /// Internal API.
+    ///
+    /// Obtains the timestamp of a message that a client timestamped
+    /// with the supplied timestamp, and when the server received it,
+    /// depending on how the server uses its clock.
+    fn timestamps(&self, timestamp: u64, now: Option<u64>) -> (u64, Option<u64>) {
+        match (&self.server_timestamps, now) {
+            (Some((ServerTimestamps::Override, _)), Some(now)) => (now, None),
+            (Some((ServerTimestamps::Supplement, _)), Some(now)) => (timestamp, Some(now)),
+            _ => (timestamp, None),
+        }
+    }
+
+    /// Internal API.
+    ///
+    /// Adds the supplied message to the supplied chat, if its source
+    /// may send it there. See `store_message`.
+    fn add_message(
+        &mut self,
+        chat_id: Id,
+        mut message: ChatMessage,
+        events: &mut Vec<ChatEvent>,
+    ) -> ChatResponse {
+        // a message can't be sent to anyone who has blocked, or
+        // been blocked by, its source, nor anyone who is no longer
+        // one of its contacts. group messages without a destination
+        // are sent to every other participant
+
+        let (source_user_id, destination_user_id) =
+            (message.source_user_id, message.destination_user_id);
+
+        let recipient_ids = self.chats.get(&chat_id).map_or_else(Vec::new, |chat| {
+            chat.participant_ids
+                .iter()
+                .filter(|participant_id| {
+                    **participant_id != source_user_id
+                        && destination_user_id.is_none_or(|id| id == **participant_id)
+                })
+                .cloned()
+                .collect()
+        });
+
+        let blocked = recipient_ids
+            .iter()
+            .any(|id| self.blocked(source_user_id, *id));
+
+        let contacts = recipient_ids
+            .iter()
+            .all(|id| self.contacts(source_user_id, *id));
+
+        let moderator = &mut self.moderator;
+
+        let response = self
+            .chats
+            .get(&chat_id)
+            .filter(|chat| {
+                // the source must be a participant, and if the message is
+                // addressed to a specific user, they must be another one
+
+                chat.participant_ids.contains(&source_user_id)
+                    && destination_user_id.is_none_or(|destination_user_id| {
+                        destination_user_id != source_user_id
+                            && chat.participant_ids.contains(&destination_user_id)
+                    })
+            })
+            .map_or(ChatResponse::UnknownChat, |chat| {
+                // clients retry when they don't receive a response, so a
+                // message that was already added mustn't be added again
+
+                if chat.message_ids.contains(&message.id) {
+                    return ChatResponse::DuplicateMessage;
+                }
+
+                if !message.kind.permits(&message) {
+                    return ChatResponse::invalid(ErrorCode::InvalidMessageKind);
+                }
+
+                // envelopes can't be read, so previews would reveal what
+                // they link to
+
+                if !previews::validate(&message.previews)
+                    || (message.envelope.is_some() && !message.previews.is_empty())
+                {
+                    return ChatResponse::invalid(ErrorCode::InvalidLinkPreview);
+                }
+
+                // a reply must be to a message in the same chat
+
+                if let Some(ref reply_to) = message.reply_to {
+                    if !chat.message_ids.contains(reply_to) {
+                        return ChatResponse::invalid(ErrorCode::InvalidReply);
+                    }
+                }
+
+                if blocked {
+                    return ChatResponse::UserBlocked;
+                }
+
+                if !contacts {
+                    return ChatResponse::ContactRequired;
+                }
+
+                // envelopes can't be read, so there's nothing to moderate
+
+                let moderation = match moderator {
+                    Some(ref mut moderator) if message.envelope.is_none() => {
+                        moderator(chat_id, &message)
+                    }
+
+                    _ => Moderation::Accept,
+                };
+
+                match moderation {
+                    Moderation::Accept => {}
+
+                    Moderation::Replace(text) => {
+                        message.message = text;
+                    }
+
+                    Moderation::Reject(reason) => {
+                        return ChatResponse::MessageRejected { reason };
+                    }
+                }
+
+                ChatResponse::MessageAdded
+            });
+
+        if let ChatResponse::MessageAdded = response {
+            self.store_message(chat_id, message, recipient_ids, events);
+        }
+
+        response
+    }
+
+    /// Internal API.
+    ///
+    /// Adds a notice about the supplied chat concerning the supplied
+    /// user, e.g. that they left it, which is sent to every other
+    /// participant. Notices are written by the server rather than the
+    /// user, so they aren't subject to contacts, blocks or moderation.
+    /// They're timestamped by the server's clock if it has one, or
+    /// else as the chat's latest message.
+    fn add_notice(
+        &mut self,
+        chat_id: Id,
+        user_id: Id,
+        text: String,
+        now: Option<u64>,
+        events: &mut Vec<ChatEvent>,
+    ) {
+        let (id, timestamp, recipient_ids) = match self.chats.get(&chat_id) {
+            Some(chat) => (
+                chat.generated_id("notice"),
+                now.or(chat.latest_timestamp).unwrap_or(0),
+                chat.participant_ids
+                    .iter()
+                    .filter(|participant_id| **participant_id != user_id)
+                    .cloned()
+                    .collect(),
+            ),
+
+            None => return,
+        };
+
+        let message = ChatMessage {
+            id,
+            seq: 0,
+            timestamp,
+            message: text,
+            attachment_ids: Vec::new(),
+            source_user_id: user_id,
+            destination_user_id: None,
+            edited_at: None,
+            deleted: false,
+            reactions: BTreeMap::new(),
+            receipts: BTreeMap::new(),
+            received_at: None,
+            envelope: None,
+            reply_to: None,
+            kind: MessageKind::System,
+            previews: Vec::new(),
+            forwarded_from: None,
+            revisions: Vec::new(),
+        };
+
+        self.store_message(chat_id, message, recipient_ids, events);
+    }
+
+    /// Internal API.
+    ///
+    /// Stores the supplied message, which has been validated, in the
+    /// supplied chat, assigning its sequence number, and evicting the
+    /// chat's oldest messages if it then has too many.
+    fn store_message(
+        &mut self,
+        chat_id: Id,
+        message: ChatMessage,
+        recipient_ids: Vec<Id>,
+        events: &mut Vec<ChatEvent>,
+    ) {
+        let chat = match self.chats.get_mut(&chat_id) {
+            Some(chat) => chat,
+            None => return,
+        };
+
+        let message = chat.insert(message);
+        let (seq, mentioned_ids) = (message.seq, mentions::parse(&message.message));
+
+        self.index.insert((chat_id, seq), &message.message);
+
+        events.push(ChatEvent::MessageAdded {
+            chat_id,
+            message: message.clone(),
+        });
+
+        let mut evicted_ids = Vec::new();
+
+        for message in chat.evict(self.max_chat_messages, self.max_chat_bytes) {
+            if !message.deleted {
+                self.index.remove((chat_id, message.seq), &message.message);
+            }
+
+            if message.blob_ids().next().is_some() {
+                self.released_blobs = true;
+            }
+
+            evicted_ids.push(message.id.clone());
+        }
+
+        if !evicted_ids.is_empty() {
+            events.push(ChatEvent::MessagesPurged {
+                chat_id,
+                message_ids: evicted_ids,
+            });
+        }
+
+        // the message is pending delivery to each of its recipients
+        // until they acknowledge it, and those it mentions can list it
+
+        for recipient_id in recipient_ids {
+            if mentioned_ids.contains(&recipient_id) {
+                self.mentions_by_user_id
+                    .entry(recipient_id)
+                    .or_default()
+                    .push(chat_id, seq);
+            }
+
+            let pending_refs = self.pending_by_user_id.entry(recipient_id).or_default();
+
+            pending_refs.push(PendingRef { chat_id, seq });
+
+            if pending_refs.len() > MAX_PENDING_MESSAGES {
+                let excess = pending_refs.len() - MAX_PENDING_MESSAGES;
+
+                pending_refs.drain(..excess);
+            }
+        }
+    }
+
+    /// Internal API.
+    ///
+    /// Lists the messages of the supplied chat after the supplied
+    /// cursor, if any, and before the supplied sequence number, if
+    /// any.
+    fn list_chat(
+        &self,
+        id: Id,
+        cursor: Option<String>,
+        limit: Option<usize>,
+        before: Option<u64>,
+    ) -> ChatResponse {
+        match self.chats.get(&id) {
+            Some(chat) => {
+                let start = match cursor {
+                    Some(ref cursor) => match chat.cursor_position(cursor) {
+                        Some(start) => start,
+                        None => return ChatResponse::CursorParsingError,
+                    },
+
+                    None => 0,
+                };
+
+                let end = match before {
+                    Some(before) => match chat.messages.binary_search_by_key(&before, |m| m.seq) {
+                        Ok(end) | Err(end) => end.max(start),
+                    },
+
+                    None => chat.messages.len(),
+                };
+
+                let messages = &chat.messages[start..end];
+
+                // clients that list from the start of the chat are told
+                // when older messages have been evicted
+
+                let truncated = chat.truncated && start == 0;
+
+                match limit {
+                    // clients page backwards from the most recent messages
+                    // by listing those before the oldest they've seen
+                    Some(limit)
+                        if limit < messages.len() && before.is_some() && cursor.is_none() =>
+                    {
+                        ChatResponse::ChatListed {
+                            messages: messages[messages.len() - limit..].to_vec(),
+                            next_cursor: None,
+                            truncated: false,
+                        }
+                    }
+
+                    Some(limit) if limit < messages.len() => ChatResponse::ChatListed {
+                        messages: messages[..limit].to_vec(),
+                        next_cursor: messages[..limit].last().map(|m| StoredChat::cursor(m)),
+                        truncated,
+                    },
+
+                    _ => ChatResponse::ChatListed {
+                        messages: messages.to_vec(),
+                        next_cursor: None,
+                        truncated,
+                    },
+                }
+            }
+
+            None => ChatResponse::UnknownChat,
+        }
+    }
+
+    /// Internal API.
+    ///
+    /// Computes statistics about the server's state. Users are active
+    /// if they sent a message in the supplied number of minutes before
+    /// now, which is the reading of the server's clock if it has one,
+    /// or the latest time that a message was sent otherwise. Times are
+    /// in milliseconds, as the server's clock is.
+    fn stats(&self, active_minutes: u64) -> Stats {
+        let sent_at = |message: &ChatMessage| message.received_at.unwrap_or(message.timestamp);
+
+        let now = match self.server_timestamps {
+            Some((_, ref clock)) => clock(),
+
+            None => self
+                .chats
+                .values()
+                .flat_map(|chat| chat.messages.iter())
+                .map(|message| sent_at(message))
+                .max()
+                .unwrap_or(0),
+        };
+
+        let since = now.saturating_sub(active_minutes.saturating_mul(60_000));
+
+        let active_user_ids = self
+            .chats
+            .values()
+            .flat_map(|chat| chat.messages.iter())
+            .filter(|message| sent_at(message) >= since)
+            .map(|message| message.source_user_id)
+            .collect::<HashSet<_>>();
+
+        let user_ids = self
+            .chats_by_user_id
+            .keys()
+            .chain(self.contact_lists.keys())
+            .collect::<HashSet<_>>();
+
+        let chats = self
+            .chats
+            .iter()
+            .collect::<BTreeMap<_, _>>()
+            .into_iter()
+            .map(|(id, chat)| ChatStats {
+                id: *id,
+                participants: chat.participant_ids.len(),
+                messages: chat.messages.len(),
+                message_bytes: chat.messages.iter().map(|m| m.size()).sum(),
+            })
+            .collect::<Vec<_>>();
+
+        Stats {
+            total_chats: chats.len(),
+            total_messages: chats.iter().map(|c| c.messages).sum(),
+            total_users: user_ids.len(),
+            active_users: active_user_ids.len(),
+            active_minutes,
+            message_bytes: chats.iter().map(|c| c.message_bytes).sum(),
+            snapshot_bytes: serde_json::to_vec(&self.snapshot())
+                .map(|data| data.len())
+                .unwrap_or(0),
+            chats,
+        }
+    }
+
+    /// Internal API.
+    ///
+    /// Obtains the events in the supplied user's feed after the
+    /// supplied cursor, if any.
+    fn poll_events(
+        &self,
+        user_id: Id,
+        after_cursor: Option<String>,
+        limit: Option<usize>,
+        device_id: Option<Id>,
+    ) -> ChatResponse {
+        let device = match device_id {
+            Some(device_id) => match self.device(user_id, device_id) {
+                Some(device) => Some(device),
+                None => return ChatResponse::UnknownDevice,
+            },
+
+            None => None,
+        };
+
+        let after = match after_cursor.map(|cursor| cursor.parse()) {
+            Some(Ok(after)) => after,
+            Some(Err(_)) => return ChatResponse::CursorParsingError,
+            None => device.map_or(0, |device| device.feed_cursor),
+        };
+
+        let events = self
+            .feeds
+            .get(&user_id)
+            .map_or(&[][..], |feed| feed.after(after));
+
+        match limit {
+            Some(limit) if limit < events.len() => ChatResponse::EventsPolled {
+                events: events[..limit].to_vec(),
+                next_cursor: Some(events[limit - 1].seq.to_string()),
+            },
+
+            _ => ChatResponse::EventsPolled {
+                events: events.to_vec(),
+                next_cursor: None,
+            },
+        }
+    }
+
+    /// Internal API.
+    ///
+    /// Appends the supplied events to the feeds of the users they
+    /// concern, i.e. the participants of their chats, or the users
+    /// they name.
+    fn publish(&mut self, events: &[ChatEvent]) {
+        for event in events {
+            let user_ids = match event {
+                ChatEvent::ChatCreated { chat }
+                | ChatEvent::ChatImported { chat, .. }
+                | ChatEvent::ChatUpdated { chat } => chat.participant_ids.clone(),
+
+                ChatEvent::ChatLeft { chat_id, user_id } => {
+                    let mut user_ids = self.participant_ids(*chat_id);
+                    user_ids.push(*user_id);
+                    user_ids
+                }
+
+                ChatEvent::MessageAdded { chat_id, .. }
+                | ChatEvent::MessageDeleted { chat_id, .. }
+                | ChatEvent::MessageEdited { chat_id, .. }
+                | ChatEvent::MessagesPurged { chat_id, .. }
+                | ChatEvent::ReactionAdded { chat_id, .. }
+                | ChatEvent::ReactionRemoved { chat_id, .. }
+                | ChatEvent::ReceiptUpdated { chat_id, .. } => self.participant_ids(*chat_id),
+
+                ChatEvent::ContactAccepted {
+                    user_id,
+                    contact_id,
+                }
+                | ChatEvent::ContactAdded {
+                    user_id,
+                    contact_id,
+                }
+                | ChatEvent::ContactDeclined {
+                    user_id,
+                    contact_id,
+                }
+                | ChatEvent::ContactRemoved {
+                    user_id,
+                    contact_id,
+                }
+                | ChatEvent::ContactRequested {
+                    user_id,
+                    contact_id,
+                } => vec![*user_id, *contact_id],
+
+                ChatEvent::ChatArchived { user_id, .. }
+                | ChatEvent::ChatMuted { user_id, .. }
+                | ChatEvent::ChatUnarchived { user_id, .. }
+                | ChatEvent::ChatUnmuted { user_id, .. }
+                | ChatEvent::DeviceRegistered { user_id, .. }
+                | ChatEvent::DeviceUnregistered { user_id, .. }
+                | ChatEvent::MessageStarred { user_id, .. }
+                | ChatEvent::MessageUnstarred { user_id, .. }
+                | ChatEvent::PreKeysLow { user_id, .. }
+                | ChatEvent::UserBlocked { user_id, .. }
+                | ChatEvent::UserUnblocked { user_id, .. } => {
+                    vec![*user_id]
+                }
+
+                // contact lists are provisioned by the operator on every
+                // startup, rather than being changes that users sync
+                ChatEvent::ContactListStored { .. } => Vec::new(),
+            };
+
+            for user_id in user_ids {
+                // clients sync every event, but don't notify users of
+                // messages in the chats they've muted
+
+                let muted = match event {
+                    ChatEvent::MessageAdded { chat_id, message } => {
+                        self.muted(user_id, *chat_id, message.timestamp)
+                    }
+
+                    _ => false,
+                };
+
+                self.feeds
+                    .entry(user_id)
+                    .or_default()
+                    .push(event.clone(), muted);
+            }
+        }
+    }
+
+    /// Internal API.
+    ///
+    /// Obtains the supplied user's device with the supplied id.
+    fn device(&self, user_id: Id, device_id: Id) -> Option<&Device> {
+        self.devices
+            .get(&user_id)
+            .and_then(|devices| devices.iter().find(|d| d.id == device_id))
+    }
+
+    /// Internal API.
+    ///
+    /// Obtains the supplied user's device with the supplied id,
+    /// mutably.
+    fn device_mut(&mut self, user_id: Id, device_id: Id) -> Option<&mut Device> {
+        self.devices
+            .get_mut(&user_id)
+            .and_then(|devices| devices.iter_mut().find(|d| d.id == device_id))
+    }
+
+    /// Internal API.
+    ///
+    /// Obtains the messages that the supplied user has starred, and
+    /// which they can still see, most recently starred first.
+    fn starred(&self, user_id: Id) -> Vec<StarredMessage> {
+        self.starred_by_user_id
+            .get(&user_id)
+            .map_or(&[][..], |star_refs| star_refs.as_slice())
+            .iter()
+            .rev()
+            .filter_map(|r| {
+                self.chats
+                    .get(&r.chat_id)
+                    .filter(|chat| chat.participant_ids.contains(&user_id))
+                    .and_then(|chat| chat.message(r.seq))
+                    .filter(|message| !message.deleted)
+                    .map(|message| StarredMessage {
+                        chat_id: r.chat_id,
+                        message: message.clone(),
+                    })
+            })
+            .collect()
+    }
+
+    /// Internal API.
+    ///
+    /// Obtains the messages that are pending delivery to the supplied
+    /// user, and which they can still see, oldest first.
+    fn pending(&self, user_id: Id) -> impl Iterator<Item = PendingMessage> + '_ {
+        self.pending_by_user_id
+            .get(&user_id)
+            .map_or(&[][..], |pending_refs| pending_refs.as_slice())
+            .iter()
+            .filter_map(move |r| {
+                self.chats
+                    .get(&r.chat_id)
+                    .filter(|chat| chat.participant_ids.contains(&user_id))
+                    .and_then(|chat| chat.message(r.seq))
+                    .filter(|message| !message.deleted)
+                    .map(|message| PendingMessage {
+                        chat_id: r.chat_id,
+                        message: message.clone(),
+                    })
+            })
+    }
+
+    /// Internal API.
+    ///
+    /// Obtains the messages that mention the supplied user after the
+    /// supplied cursor, and which they can still see, oldest first.
+    fn mentions(&self, user_id: Id, after: u64) -> Vec<Mention> {
+        self.mentions_by_user_id
+            .get(&user_id)
+            .map_or(&[][..], |mentions| mentions.after(after))
+            .iter()
+            .filter_map(|r| {
+                self.chats
+                    .get(&r.chat_id)
+                    .filter(|chat| chat.participant_ids.contains(&user_id))
+                    .and_then(|chat| chat.message(r.message_seq))
+                    .filter(|message| !message.deleted)
+                    .map(|message| Mention {
+                        cursor: r.seq.to_string(),
+                        chat_id: r.chat_id,
+                        message: message.clone(),
+                    })
+            })
+            .collect()
+    }
+
+    /// Internal API.
+    ///
+    /// Obtains the supplied user's reference to the supplied chat,
+    /// which they must participate in, mutably.
+    fn chat_ref_mut(&mut self, user_id: Id, chat_id: Id) -> Option<&mut ChatRef> {
+        self.chats_by_user_id
+            .get_mut(&user_id)
+            .and_then(|chat_refs| chat_refs.iter_mut().find(|r| r.id == chat_id))
+    }
+
+    /// Internal API.
+    ///
+    /// Determines if the supplied user had muted the supplied chat
+    /// at the supplied time.
+    fn muted(&self, user_id: Id, chat_id: Id, at: u64) -> bool {
+        self.chats_by_user_id
+            .get(&user_id)
+            .and_then(|chat_refs| chat_refs.iter().find(|r| r.id == chat_id))
+            .and_then(|r| r.muted)
+            .is_some_and(|mute| mute.until.is_none_or(|until| at < until))
+    }
+
+    /// Internal API.
+    ///
+    /// Replaces the message with the supplied sequence number with a
+    /// tombstone, returning whether it was removed, i.e. it exists and
+    /// wasn't already.
+    fn remove_message(&mut self, chat_id: Id, seq: u64) -> bool {
+        let message = match self
+            .chats
+            .get_mut(&chat_id)
+            .and_then(|chat| chat.message_by_seq_mut(seq))
+        {
+            Some(message) if !message.deleted => message,
+            _ => return false,
+        };
+
+        if message.blob_ids().next().is_some() {
+            self.released_blobs = true;
+        }
+
+        self.index.remove((chat_id, message.seq), &message.message);
+
+        message.message.clear();
+        message.attachment_ids.clear();
+        message.previews.clear();
+        message.reactions.clear();
+        message.revisions.clear();
+        message.envelope = None;
+        message.deleted = true;
+
+        true
+    }
+
+    /// Internal API.
+    ///
+    /// Obtains the ids of the supplied chat's participants.
+    fn participant_ids(&self, chat_id: Id) -> Vec<Id> {
+        self.chats
+            .get(&chat_id)
+            .map_or_else(Vec::new, |chat| chat.participant_ids.clone())
+    }
+
+    /// Internal API.
+    ///
+    /// Allocates an id for a new chat. Ids are allocated from a
+    /// counter, skipping over any that clients have already used.
+    fn allocate_chat_id(&mut self) -> Id {
+        loop {
+            self.last_chat_id = self.last_chat_id.wrapping_add(1);
+
+            if !self.chats.contains_key(&self.last_chat_id) {
+                return self.last_chat_id;
+            }
+        }
+    }
+
+    /// Internal API.
+    ///
+    /// Advances the status of a message for one of its recipients,
+    /// who must be a participant other than the author, and the
+    /// destination user if the message has one. If a device is
+    /// supplied, its cursor in the chat is advanced too.
+    fn update_receipt(
+        &mut self,
+        chat_id: Id,
+        message_id: String,
+        user_id: Id,
+        device_id: Option<Id>,
+        status: ReceiptStatus,
+        events: &mut Vec<ChatEvent>,
+    ) -> ChatResponse {
+        // the device is borrowed from its field, rather than by
+        // `device_mut`, so that the chat can be borrowed alongside it
+
+        let device = match device_id {
+            Some(device_id) => match self
+                .devices
+                .get_mut(&user_id)
+                .and_then(|devices| devices.iter_mut().find(|d| d.id == device_id))
+            {
+                Some(device) => Some(device),
+                None => return ChatResponse::UnknownDevice,
+            },
+
+            None => None,
+        };
+
+        match self.chats.get_mut(&chat_id) {
+            Some(chat) if chat.participant_ids.contains(&user_id) => {
+                match chat.message_mut(&message_id) {
+                    Some(message)
+                        if message.source_user_id == user_id
+                            || message
+                                .destination_user_id
+                                .is_some_and(|destination_user_id| {
+                                    destination_user_id != user_id
+                                }) =>
+                    {
+                        ChatResponse::MessageForbidden
+                    }
+
+                    Some(message) => {
+                        if let Some(device) = device {
+                            device.advance(chat_id, message.seq, status);
+                        }
+
+                        let current = message
+                            .receipts
+                            .entry(user_id)
+                            .or_insert(ReceiptStatus::Sent);
+
+                        if status > *current {
+                            *current = status;
+
+                            events.push(ChatEvent::ReceiptUpdated {
+                                chat_id,
+                                message_id,
+                                user_id,
+                                status,
+                            });
+                        }
+
+                        ChatResponse::ReceiptUpdated
+                    }
+
+                    None => ChatResponse::UnknownMessage,
+                }
+            }
+
+            _ => ChatResponse::UnknownChat,
+        }
+    }
+
+    /// Internal API.
+    ///
+    /// Records that the messages in the supplied chat up to the one
+    /// with the supplied sequence number were delivered to the
+    /// supplied user. Only those after the user's delivery cursor in
+    /// the chat are considered, as the rest were already acknowledged.
+    fn ack_messages(
+        &mut self,
+        user_id: Id,
+        chat_id: Id,
+        up_to_seq: u64,
+        device_id: Option<Id>,
+        events: &mut Vec<ChatEvent>,
+    ) -> ChatResponse {
+        if let Some(device_id) = device_id {
+            if self.device(user_id, device_id).is_none() {
+                return ChatResponse::UnknownDevice;
+            }
+        }
+
+        let up_to_seq = match self.chats.get(&chat_id) {
+            Some(chat) if chat.participant_ids.contains(&user_id) => up_to_seq.min(chat.last_seq),
+            _ => return ChatResponse::UnknownChat,
+        };
+
+        let acked_seq = match self.chat_ref_mut(user_id, chat_id) {
+            Some(chat_ref) => {
+                let acked_seq = chat_ref.delivered_seq;
+
+                chat_ref.delivered_seq = acked_seq.max(up_to_seq);
+
+                acked_seq
+            }
+
+            None => return ChatResponse::UnknownChat,
+        };
+
+        if let Some(device) = device_id.and_then(|device_id| self.device_mut(user_id, device_id)) {
+            device.advance(chat_id, up_to_seq, ReceiptStatus::Delivered);
+        }
+
+        if let Some(chat) = self.chats.get_mut(&chat_id) {
+            for message in chat
+                .messages
+                .iter_mut()
+                .filter(|m| m.seq > acked_seq && m.seq <= up_to_seq)
+            {
+                let recipient = message.source_user_id != user_id
+                    && message
+                        .destination_user_id
+                        .is_none_or(|destination_user_id| destination_user_id == user_id);
+
+                let delivered = message
+                    .receipts
+                    .get(&user_id)
+                    .is_some_and(|status| *status >= ReceiptStatus::Delivered);
+
+                if recipient && !message.deleted && !delivered {
+                    Arc::make_mut(message)
+                        .receipts
+                        .insert(user_id, ReceiptStatus::Delivered);
+
+                    events.push(ChatEvent::ReceiptUpdated {
+                        chat_id,
+                        message_id: message.id.clone(),
+                        user_id,
+                        status: ReceiptStatus::Delivered,
+                    });
+                }
+            }
+        }
+
+        // the messages no longer need to be tracked for the user
+
+        if let Some(pending_refs) = self.pending_by_user_id.get_mut(&user_id) {
+            pending_refs.retain(|r| r.chat_id != chat_id || r.seq > up_to_seq);
+
+            if pending_refs.is_empty() {
+                self.pending_by_user_id.remove(&user_id);
+            }
+        }
+
+        ChatResponse::MessagesAcked
+    }
+
+    /// Internal API.
+    ///
+    /// Removes everything that is stored about the supplied user,
+    /// returning what was removed.
+    fn erase_user(&mut self, user_id: Id, events: &mut Vec<ChatEvent>) -> Erasure {
+        let mut erasure = Erasure {
+            contact_list_removed: self.contact_lists.remove(&user_id).is_some(),
+            ..Erasure::default()
+        };
+
+        for contact_list in self.contact_lists.values_mut() {
+            contact_list.retain(|id| *id != user_id);
+        }
+
+        self.blocklists.remove(&user_id);
+        self.contact_requests.remove(&user_id);
+
+        for user_ids in self
+            .blocklists
+            .values_mut()
+            .chain(self.contact_requests.values_mut())
+        {
+            user_ids.remove(&user_id);
+        }
+
+        let mut removed = Vec::new();
+
+        for (chat_id, chat) in self.chats.iter_mut() {
+            if chat.participant_ids.contains(&user_id) {
+                chat.participant_ids.retain(|id| *id != user_id);
+                erasure.chats_left += 1;
+
+                events.push(ChatEvent::ChatLeft {
+                    chat_id: *chat_id,
+                    user_id,
+                });
+            }
+
+            // their messages are removed, as are their reactions to, and
+            // receipts for, the messages of others
+
+            for message in chat.messages.iter_mut() {
+                if message.source_user_id == user_id {
+                    removed.push((*chat_id, message.id.clone(), message.seq));
+                    continue;
+                }
+
+                let reactions = message
+                    .reactions
+                    .values()
+                    .filter(|user_ids| user_ids.contains(&user_id))
+                    .count();
+
+                let receipt = message.receipts.contains_key(&user_id);
+
+                if reactions > 0 || receipt {
+                    let message = Arc::make_mut(message);
+
+                    for user_ids in message.reactions.values_mut() {
+                        user_ids.retain(|id| *id != user_id);
+                    }
+
+                    let emojis = message
+                        .reactions
+                        .iter()
+                        .filter(|(_, user_ids)| user_ids.is_empty())
+                        .map(|(emoji, _)| emoji.clone())
+                        .collect::<Vec<_>>();
+
+                    for emoji in emojis {
+                        message.reactions.remove(&emoji);
+                    }
+                    message.receipts.remove(&user_id);
+
+                    erasure.reactions_removed += reactions;
+                    erasure.receipts_removed += receipt as usize;
+                }
+            }
+        }
+
+        for (chat_id, message_id, seq) in removed {
+            if self.remove_message(chat_id, seq) {
+                erasure.messages_removed += 1;
+
+                events.push(ChatEvent::MessageDeleted {
+                    chat_id,
+                    message_id,
+                });
+            }
+        }
+
+        self.chats_by_user_id.remove(&user_id);
+        self.starred_by_user_id.remove(&user_id);
+        self.pending_by_user_id.remove(&user_id);
+        self.mentions_by_user_id.remove(&user_id);
+        self.pre_keys.remove(&user_id);
+
+        erasure.devices_removed = self.devices.remove(&user_id).map_or(0, |d| d.len());
+        erasure.webhooks_removed = self.webhook_urls.remove(&user_id).map_or(0, |w| w.len());
+
+        // other users' feeds retain copies of the user's messages, so
+        // those events are discarded too
+
+        self.feeds.remove(&user_id);
+
+        for feed in self.feeds.values_mut() {
+            erasure.events_removed += feed.retain(|event| match event {
+                ChatEvent::MessageAdded { message, .. }
+                | ChatEvent::MessageEdited { message, .. } => message.source_user_id != user_id,
+
+                _ => true,
+            });
+        }
+
+        // reports of their messages are kept for audit, but without
+        // the content that was reported
+
+        for report in self.reports.iter_mut() {
+            if report.source_user_id == user_id && !report.content.is_empty() {
+                report.content.clear();
+                erasure.reports_redacted += 1;
+            }
+        }
+
+        erasure
+    }
+
+    /// Internal API.
+    ///
+    /// Imports the supplied transcript, creating its chat if it
+    /// doesn't exist. An existing chat must have the same
+    /// participants as the transcript's.
+    fn import(&mut self, transcript: Transcript, events: &mut Vec<ChatEvent>) -> ChatResponse {
+        let Transcript {
+            chat: imported,
+            messages,
+        } = transcript;
+
+        let id = imported.id;
+
+        let mut participant_ids = imported.participant_ids.clone();
+        participant_ids.sort();
+        participant_ids.dedup();
+
+        if participant_ids.len() < 2 {
+            return ChatResponse::invalid(ErrorCode::TooFewParticipants);
+        }
+
+        match self.chats.get(&id) {
+            Some(chat) => {
+                let mut existing_ids = chat.participant_ids.clone();
+                existing_ids.sort();
+
+                if existing_ids != participant_ids {
+                    return ChatResponse::ChatAlreadyExists {
+                        id,
+                        conflict: ChatConflict::Id,
+                    };
+                }
+            }
+
+            None => {
+                for participant_id in participant_ids.iter() {
+                    self.chats_by_user_id
+                        .entry(*participant_id)
+                        .or_default()
+                        .push(ChatRef {
+                            id,
+                            muted: None,
+                            archived: false,
+                            delivered_seq: 0,
+                        });
+                }
+            }
+        }
+
+        let chat = self.chats.entry(id).or_insert_with(|| StoredChat {
+            participant_ids: imported.participant_ids,
+            title: imported.title,
+            created_at: imported.created_at,
+            creator: imported.creator,
+            latest_timestamp: None,
+            messages: Vec::new(),
+            message_ids: HashSet::new(),
+            message_ttl: imported.message_ttl,
+            last_seq: 0,
+            truncated: false,
+        });
+
+        let mut messages_imported = 0;
+
+        // messages keep everything but their sequence numbers, which
+        // are reassigned as they're appended after any existing ones
+
+        for message in messages.into_owned() {
+            if chat.message_ids.contains(&message.id) {
+                continue;
+            }
+
+            let message = chat.insert(Arc::try_unwrap(message).unwrap_or_else(|m| (*m).clone()));
+
+            if !message.deleted {
+                self.index.insert((id, message.seq), &message.message);
+            }
+
+            messages_imported += 1;
+        }
+
+        events.push(ChatEvent::ChatImported {
+            chat: chat.to_chat(id),
+            messages_imported,
+        });
+
+        ChatResponse::ChatImported {
+            id,
+            messages_imported,
+        }
+    }
+
+    /// Internal API.
+    ///
+    /// Finds the messages that match the supplied query in the
+    /// chats that the supplied user participates in, most recent
+    /// first. Deleted messages are removed from the index, so they
+    /// never match.
+    fn search(&self, user_id: Id, query: &str, limit: usize) -> Vec<SearchResult> {
+        let terms = search::terms(query);
+
+        let chat_ids: HashSet<Id> = self
+            .chats_by_user_id
+            .get(&user_id)
+            .map_or_else(HashSet::new, |refs| refs.iter().map(|r| r.id).collect());
+
+        let mut results: Vec<SearchResult> = self
+            .index
+            .search(&terms)
+            .into_iter()
+            .filter(|(chat_id, _)| chat_ids.contains(chat_id))
+            .filter_map(|(chat_id, seq)| {
+                let message = self.chats.get(&chat_id)?.message(seq)?;
+                let offset = search::find(&message.message, &terms)?;
+
+                Some(SearchResult {
+                    chat_id,
+                    message: message.clone(),
+                    snippet: search::snippet(&message.message, offset),
+                })
+            })
+            .collect();
+
+        results.sort_by_key(|r| Reverse((r.message.timestamp, r.chat_id, r.message.seq)));
+        results.truncate(limit);
+
+        results
+    }
+
+    /// Internal API.
+    ///
+    /// Given the IDs of a set of users, determines the ID of the
+    /// chat between exactly those users if there is one.
+    fn chat_id(&self, participant_ids: &[Id]) -> Option<Id> {
+        let mut participant_ids = participant_ids.to_vec();
+        participant_ids.sort();
+
+        participant_ids
+            .first()
+            .and_then(|user_id| self.chats_by_user_id.get(user_id))
+            .and_then(|chats| {
+                chats.iter().find(|r| {
+                    self.chats.get(&r.id).is_some_and(|chat| {
+                        let mut other_participant_ids = chat.participant_ids.clone();
+                        other_participant_ids.sort();
+
+                        other_participant_ids == participant_ids
+                    })
+                })
+            })
+            .map(|chat_ref| chat_ref.id)
+    }
+
+    /// Internal API.
+    ///
+    /// Obtains the webhook URLs to notify if the supplied request
+    /// adds a message, i.e. those of its recipients.
+    fn webhook_urls(&self, request: &ChatRequest) -> Vec<String> {
+        match request {
+            ChatRequest::AddMessage {
+                chat_id,
+                source_user_id,
+                destination_user_id,
+                timestamp,
+                ..
+            } => self
+                .participant_ids(*chat_id)
+                .into_iter()
+                .filter(|id| id != source_user_id && destination_user_id.is_none_or(|d| d == *id))
+                .filter(|id| !self.muted(*id, *chat_id, *timestamp))
+                .filter_map(|id| self.webhook_urls.get(&id))
+                .flat_map(|urls| urls.iter().cloned())
+                .collect(),
+
+            ChatRequest::ForwardMessage {
+                to_chat_id,
+                user_id,
+                timestamp,
+                ..
+            } => self
+                .participant_ids(*to_chat_id)
+                .into_iter()
+                .filter(|id| id != user_id)
+                .filter(|id| !self.muted(*id, *to_chat_id, *timestamp))
+                .filter_map(|id| self.webhook_urls.get(&id))
+                .flat_map(|urls| urls.iter().cloned())
+                .collect(),
+
+            _ => Vec::new(),
+        }
+    }
+
+    /// Internal API.
+    ///
+    /// Checks that the supplied envelope can be added to a message
+    /// with the supplied text, source, and destination, returning
+    /// the response to reject it with if not. Its device ids must be
+    /// those of registered devices, and it can only be addressed to
+    /// a device if the message is addressed to a user.
+    fn check_envelope(
+        &self,
+        envelope: &Envelope,
+        message: &str,
+        source_user_id: Id,
+        destination_user_id: Option<Id>,
+    ) -> Option<ChatResponse> {
+        match envelope.size() {
+            Some(size) if size > self.max_envelope_size => {
+                return Some(ChatResponse::EnvelopeTooLarge {
+                    max_size: self.max_envelope_size,
+                });
+            }
+
+            Some(_) if message.is_empty() => {}
+
+            _ => return Some(ChatResponse::invalid(ErrorCode::InvalidEnvelope)),
+        }
+
+        let source_known = envelope
+            .source_device_id
+            .is_none_or(|device_id| self.device(source_user_id, device_id).is_some());
+
+        let destination_known = match (envelope.destination_device_id, destination_user_id) {
+            (Some(device_id), Some(user_id)) => self.device(user_id, device_id).is_some(),
+            (Some(_), None) => {
+                return Some(ChatResponse::ChatValidationError {
+                    code: ErrorCode::InvalidEnvelope,
+                    detail: Some(
+                        "envelopes can only be addressed to devices in messages addressed to users"
+                            .to_string(),
+                    ),
+                });
+            }
+            (None, _) => true,
+        };
+
+        if source_known && destination_known {
+            None
+        } else {
+            Some(ChatResponse::UnknownDevice)
+        }
+    }
+
+    /// Internal API.
+    ///
+    /// Determines if either of the supplied users has blocked
+    /// the other.
+    fn blocked(&self, a: Id, b: Id) -> bool {
+        let blocks = |user_id, blocked_id| {
+            self.blocklists
+                .get(&user_id)
+                .is_some_and(|blocklist| blocklist.contains(&blocked_id))
+        };
+
+        blocks(a, b) || blocks(b, a)
+    }
+
+    /// Internal API.
+    ///
+    /// Determines if any of the supplied users has blocked
+    /// another.
+    fn any_blocked(&self, user_ids: &[Id]) -> bool {
+        user_ids
+            .iter()
+            .enumerate()
+            .any(|(i, a)| user_ids[i + 1..].iter().any(|b| self.blocked(*a, *b)))
+    }
+
+    /// Internal API.
+    ///
+    /// Adds a contact to a user's contact list, unless it's
+    /// already present.
+    fn add_contact(&mut self, user_id: Id, contact_id: Id) {
+        let list = self.contact_lists.entry(user_id).or_default();
+
+        if !list.contains(&contact_id) {
+            list.push(contact_id);
+        }
+    }
+
+    /// Internal API.
+    ///
+    /// Obtains the contact data that is stored for the supplied user,
+    /// or for each user that has any if none is supplied, in id order.
+    fn user_contacts(&self, user_id: Option<Id>) -> Vec<UserContacts> {
+        let sorted = |ids: Option<&HashSet<Id>>| {
+            let mut ids = ids.map_or_else(Vec::new, |ids| ids.iter().cloned().collect());
+            ids.sort();
+            ids
+        };
+
+        let user_ids = match user_id {
+            Some(user_id) => vec![user_id].into_iter().collect::<BTreeSet<_>>(),
+
+            None => self
+                .contact_lists
+                .keys()
+                .chain(self.blocklists.keys())
+                .chain(self.contact_requests.keys())
+                .cloned()
+                .collect(),
+        };
+
+        user_ids
+            .into_iter()
+            .map(|user_id| UserContacts {
+                user_id,
+                contacts: self
+                    .contact_lists
+                    .get(&user_id)
+                    .cloned()
+                    .unwrap_or_default(),
+                blocked: sorted(self.blocklists.get(&user_id)),
+                requested_by: sorted(self.contact_requests.get(&user_id)),
+            })
+            .collect()
+    }
+
+    /// Internal API.
+    ///
+    /// Removes a pending invitation to the supplied user from the
+    /// supplied contact, returning whether there was one.
+    fn take_contact_request(&mut self, user_id: Id, contact_id: Id) -> bool {
+        self.contact_requests
+            .get_mut(&user_id)
+            .is_some_and(|requests| requests.remove(&contact_id))
+    }
+
+    /// Internal API.
+    ///
+    /// Determines if the supplied users are distinct and have
+    /// each other in their contact lists.
+    fn contacts(&self, a: Id, b: Id) -> bool {
+        a != b && self.has_contact(a, b) && self.has_contact(b, a)
+    }
+
+    /// Internal API.
+    ///
+    /// Determines if the supplied user has the supplied contact in
+    /// their contact list.
+    fn has_contact(&self, user_id: Id, contact_id: Id) -> bool {
+        self.contact_lists
+            .get(&user_id)
+            .is_some_and(|list| list.contains(&contact_id))
+    }
+
+    /// Internal API.
+    ///
+    /// Checks that a chat can be created between the supplied users,
+    /// returning the response to reject it with if not -- there must
+    /// be at least two distinct users, and every one of them must
+    /// have every other in their contact list.
+    fn check_participants(&self, participant_ids: &[Id]) -> Option<ChatResponse> {
+        let mut distinct_ids = participant_ids.to_vec();
+        distinct_ids.sort_unstable();
+        distinct_ids.dedup();
+
+        if distinct_ids.len() != participant_ids.len() {
+            Some(ChatResponse::invalid(ErrorCode::DuplicateParticipants))
+        } else if participant_ids.len() < 2 {
+            Some(ChatResponse::invalid(ErrorCode::TooFewParticipants))
+        } else {
+            // report the first participant that lacks another, so
+            // that it's clear whose contact list needs to change
+
+            participant_ids
+                .iter()
+                .flat_map(|a| participant_ids.iter().map(move |b| (*a, *b)))
+                .find(|(a, b)| a != b && !self.has_contact(*a, *b))
+                .map(|(a, b)| ChatResponse::ChatValidationError {
+                    code: ErrorCode::NotInContactList,
+                    detail: Some(format!(
+                        "user {} doesn't have user {} in their contact list",
+                        a, b
+                    )),
+                })
+        }
+    }
+}
+
+impl ChatMessage {
+    /// Internal API.
+    ///
+    /// Obtains how many bytes of content this message has, which
+    /// is what retention limits count.
+    fn size(&self) -> usize {
+        self.message.len()
+            + self.envelope.as_ref().map_or(0, |e| e.content.len())
+            + self.previews.iter().map(LinkPreview::size).sum::<usize>()
+    }
+
+    /// Internal API.
+    ///
+    /// Obtains the ids of the blobs that this message refers to, i.e.
+    /// its attachments and the images of its previews.
+    pub(crate) fn blob_ids(&self) -> impl Iterator<Item = &String> {
+        self.attachment_ids
+            .iter()
+            .chain(self.previews.iter().filter_map(|p| p.image_id.as_ref()))
+    }
+}
+
+/// Internal API.
+///
+/// The in-memory representation of a chat, which consists of
+/// a vector of `ChatMessage`s in sequence order (and a set of
+/// their ids),
+/// a vector of the participants' ids, and the chat's metadata.
+///
+/// Messages are ordered by when they were received, so they're always
+/// appended, regardless of their timestamps. They're removed from the
+/// front when a chat is evicted, and from anywhere when they expire,
+/// which is a single pass over the chat. Deleting or erasing messages
+/// only replaces them with tombstones. A vector is therefore never
+/// shifted on insert, can be binary searched by sequence number, and
+/// can be sliced to list a page of messages. Each message is behind
+/// an `Arc`, so a page is listed by cloning its `Arc`s rather than
+/// copying their messages, as is any other response that includes
+/// messages. Messages are found by id with a linear scan, which is only
+/// done for a single message at a time.
+///
+/// See `benches/stored_chat.rs`, which compares this with a `BTreeMap`
+/// keyed by timestamp.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct StoredChat {
+    participant_ids: Vec<Id>,
+    title: Option<String>,
+    created_at: Option<u64>,
+    creator: Option<Id>,
+    latest_timestamp: Option<u64>,
+    messages: Vec<Arc<ChatMessage>>,
+    message_ids: HashSet<String>,
+
+    #[serde(default)]
+    message_ttl: Option<u64>,
+
+    #[serde(default)]
+    last_seq: u64,
+
+    #[serde(default)]
+    truncated: bool,
+}
+
+impl StoredChat {
+    /// Internal API.
+    ///
+    /// Insert a new chat message into this instance, assigning
+    /// it the next sequence number, and returning it.
+    fn insert(&mut self, mut message: ChatMessage) -> &ChatMessage {
+        // messages are ordered by when they were received rather than
+        // their timestamps, which are supplied by clients whose clocks
+        // may be skewed. sequence numbers start from 1, and aren't
+        // reused when messages expire, so they remain ordered
+
+        let timestamp = message.timestamp;
+
+        self.message_ids.insert(message.id.clone());
+
+        self.latest_timestamp = Some(
+            self.latest_timestamp
+                .map_or(timestamp, |latest| latest.max(timestamp)),
+        );
+
+        self.last_seq = self.last_seq.max(self.messages.last().map_or(0, |m| m.seq)) + 1;
+
+        message.seq = self.last_seq;
+
+        self.messages.push(Arc::new(message));
+
+        &self.messages[self.messages.len() - 1]
+    }
+
+    /// Internal API.
+    ///
+    /// Generates an id for a message that the server adds to this
+    /// chat, e.g. one that is forwarded to it, from the supplied prefix
+    /// and the sequence number it will be assigned. If a client has
+    /// already used that id, the next unused one is generated instead.
+    fn generated_id(&self, prefix: &str) -> String {
+        let mut seq = self.last_seq.max(self.messages.last().map_or(0, |m| m.seq)) + 1;
+
+        loop {
+            let id = format!("{}-{}", prefix, seq);
+
+            if !self.message_ids.contains(&id) {
+                return id;
+            }
+
+            seq += 1;
+        }
+    }
+
+    /// Internal API.
+    ///
+    /// Produces the response representation of this chat, which
+    /// excludes its messages.
+    fn to_chat(&self, id: Id) -> Chat {
+        Chat {
+            id,
+            participant_ids: self.participant_ids.clone(),
+            title: self.title.clone(),
+            created_at: self.created_at,
+            creator: self.creator,
+            message_ttl: self.message_ttl,
+            muted: None,
+            archived: false,
+        }
+    }
+
+    /// Internal API.
+    ///
+    /// Determines when the chat was last active, i.e. the latest
+    /// timestamp of its messages, or when it was created if it has
+    /// none.
+    fn last_activity(&self) -> u64 {
+        self.latest_timestamp.or(self.created_at).unwrap_or(0)
+    }
+
+    /// Internal API.
+    ///
+    /// Produces the cursor for a message, which is opaque to
+    /// clients but consists of its sequence number.
+    fn cursor(message: &ChatMessage) -> String {
+        message.seq.to_string()
+    }
+
+    /// Internal API.
+    ///
+    /// Determines the position of the message after the one
+    /// identified by the cursor, or `None` if the cursor can't
+    /// be parsed.
+    fn cursor_position(&self, cursor: &str) -> Option<usize> {
+        let seq: u64 = cursor.parse().ok()?;
+
+        // the message may have since expired, in which case the
+        // position is that of the next message that hasn't
+
+        match self.messages.binary_search_by_key(&seq, |m| m.seq) {
+            Ok(position) => Some(position + 1),
+            Err(position) => Some(position),
+        }
+    }
+
+    /// Internal API.
+    ///
+    /// Find the message with the supplied sequence number.
+    fn message(&self, seq: u64) -> Option<&Arc<ChatMessage>> {
+        self.messages
+            .binary_search_by_key(&seq, |m| m.seq)
+            .ok()
+            .map(|position| &self.messages[position])
+    }
+
+    /// Internal API.
+    ///
+    /// Removes the messages with timestamps before the supplied
+    /// cutoff, returning them.
+    fn expire(&mut self, cutoff: u64) -> Vec<Arc<ChatMessage>> {
+        let (expired, messages) = self.messages.drain(..).partition(|m| m.timestamp < cutoff);
+
+        self.messages = messages;
+
+        for message in expired.iter() {
+            self.message_ids.remove(&message.id);
+        }
+
+        expired
+    }
+
+    /// Internal API.
+    ///
+    /// Removes the oldest messages until this chat has at most the
+    /// supplied number of messages and bytes of message text, returning
+    /// them. The latest message is never removed.
+    fn evict(
+        &mut self,
+        max_messages: Option<usize>,
+        max_bytes: Option<usize>,
+    ) -> Vec<Arc<ChatMessage>> {
+        let mut count = max_messages.map_or(0, |max| self.messages.len().saturating_sub(max));
+
+        if let Some(max_bytes) = max_bytes {
+            let mut bytes: usize = self.messages[count..].iter().map(|m| m.size()).sum();
+
+            while bytes > max_bytes && count < self.messages.len() {
+                bytes -= self.messages[count].size();
+                count += 1;
+            }
+        }
+
+        count = count.min(self.messages.len().saturating_sub(1));
+
+        if count > 0 {
+            self.truncated = true;
+        }
+
+        let evicted: Vec<_> = self.messages.drain(..count).collect();
+
+        for message in evicted.iter() {
+            self.message_ids.remove(&message.id);
+        }
+
+        evicted
+    }
+
+    /// Internal API.
+    ///
+    /// Find the message with the supplied sequence number. It is
+    /// copied first if a response still shares it.
+    fn message_by_seq_mut(&mut self, seq: u64) -> Option<&mut ChatMessage> {
+        match self.messages.binary_search_by_key(&seq, |m| m.seq) {
+            Ok(position) => Some(Arc::make_mut(&mut self.messages[position])),
+            Err(_) => None,
+        }
+    }
+
+    /// Internal API.
+    ///
+    /// Find the message with the supplied id. It is copied first if
+    /// a response still shares it, so the response is unaffected.
+    fn message_mut(&mut self, id: &str) -> Option<&mut ChatMessage> {
+        self.messages
+            .iter_mut()
+            .find(|m| m.id == id)
+            .map(Arc::make_mut)
+    }
+
+    /// Internal API.
+    ///
+    /// Find the message with the supplied id, sharing it so that it
+    /// can be included in a response.
+    fn shared_message(&self, id: &str) -> Option<Arc<ChatMessage>> {
+        self.messages.iter().find(|m| m.id == id).cloned()
+    }
+}
+
+/// Internal API.
+///
+/// Representation of available chats for a particular user,
+/// whether they've muted or archived it, and the sequence number of
+/// the latest message they've acknowledged the delivery of.
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ChatRef {
+    id: Id,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    muted: Option<Mute>,
+
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    archived: bool,
+
+    #[serde(default)]
+    delivered_seq: u64,
+}
+
+/// Internal API.
+///
+/// Representation of a message that a particular user starred.
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct StarRef {
+    chat_id: Id,
+    seq: u64,
+}
+
+/// Internal API.
+///
+/// Representation of a message that is pending delivery to a
+/// particular user.
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct PendingRef {
+    chat_id: Id,
+    seq: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::chat::*;
+    use crate::envelope::EnvelopeType;
+    use crate::prekeys::{PreKey, SignedPreKey};
+
+    #[test]
+    fn test_chat_server() {
+        let mut server = ChatServer::new();
+
+        // first, given that there are no loaded contact lists,
+        // we assert that we can't create a chat
+
+        assert_eq!(
+            server.issue(ChatRequest::CreateChat {
+                id: Some(1),
+                participant_ids: vec![1, 2],
+                title: None,
+                created_at: None,
+                creator: None
+            }),
+            ChatResponse::ChatValidationError {
+                code: ErrorCode::NotInContactList,
+                detail: Some("user 1 doesn't have user 2 in their contact list".to_string()),
+            }
+        );
+
+        // then, we'll load a contact list and assert that we
+        // still cannot create a chat (must be symmetric)
+
+        assert_eq!(
+            server.issue(ChatRequest::StoreContactList {
+                id: 1,
+                list: vec![1, 2]
+            }),
+            ChatResponse::ContactListStored
+        );
+
+        assert_eq!(
+            server.issue(ChatRequest::CreateChat {
+                id: Some(1),
+                participant_ids: vec![1, 2],
+                title: None,
+                created_at: None,
+                creator: None
+            }),
+            ChatResponse::ChatValidationError {
+                code: ErrorCode::NotInContactList,
+                detail: Some("user 2 doesn't have user 1 in their contact list".to_string()),
+            }
+        );
+
+        // next, let's setup the other side and assert that we
+        // can now create a contact list
+
+        assert_eq!(
+            server.issue(ChatRequest::StoreContactList {
+                id: 2,
+                list: vec![2, 1]
+            }),
+            ChatResponse::ContactListStored
+        );
+
+        assert_eq!(
+            server.issue(ChatRequest::CreateChat {
+                id: Some(1),
+                participant_ids: vec![1, 2],
+                title: None,
+                created_at: None,
+                creator: None
+            }),
+            ChatResponse::ChatCreated { id: 1 }
+        );
+
+        // the chat should be visible for both users
+
+        assert_eq!(
+            server.issue(ChatRequest::ListChats {
+                user_id: 1,
+                include_archived: false,
+                limit: None,
+                before: None,
+                since: None,
+            }),
+            ChatResponse::ChatsListed {
+                chats: vec![Chat {
+                    id: 1,
+                    participant_ids: vec![1, 2],
+                    title: None,
+                    created_at: None,
+                    creator: None,
+                    message_ttl: None,
+                    muted: None,
+                    archived: false,
+                }]
+            }
+        );
+
+        assert_eq!(
+            server.issue(ChatRequest::ListChats {
+                user_id: 2,
+                include_archived: false,
+                limit: None,
+                before: None,
+                since: None,
+            }),
+            ChatResponse::ChatsListed {
+                chats: vec![Chat {
+                    id: 1,
+                    participant_ids: vec![1, 2],
+                    title: None,
+                    created_at: None,
+                    creator: None,
+                    message_ttl: None,
+                    muted: None,
+                    archived: false,
+                }]
+            }
+        );
+
+        // and visible by its id (no messages yet)
+
+        assert_eq!(
+            server.issue(ChatRequest::ListChat {
+                id: 1,
+                cursor: None,
+                limit: None,
+                before: None,
+            }),
+            ChatResponse::ChatListed {
+                messages: Vec::new(),
+                next_cursor: None,
+                truncated: false
+            }
+        );
+
+        // when we add messages, they should be visible
+        // and ordered by when they were received
+
+        assert_eq!(
+            server.issue(ChatRequest::AddMessage {
+                id: "aed531ba-7a41-46dd-8e5d-9a5f7c16bfee".to_string(),
+                chat_id: 1,
+                source_user_id: 1,
+                destination_user_id: Some(2),
+                timestamp: 0,
+                message: "zero".to_string(),
+                attachment_ids: Vec::new(),
+                envelope: None,
+                reply_to: None,
+                kind: MessageKind::Text,
+                previews: Vec::new(),
+            }),
+            ChatResponse::MessageAdded
+        );
+
+        assert_eq!(
+            server.issue(ChatRequest::AddMessage {
+                id: "b213468f-eed5-4119-be6c-bb780120502a".to_string(),
+                chat_id: 1,
+                source_user_id: 2,
+                destination_user_id: Some(1),
+                timestamp: 4,
+                message: "four".to_string(),
+                attachment_ids: Vec::new(),
+                envelope: None,
+                reply_to: None,
+                kind: MessageKind::Text,
+                previews: Vec::new(),
+            }),
+            ChatResponse::MessageAdded
+        );
+
+        assert_eq!(
+            server.issue(ChatRequest::AddMessage {
+                id: "16cce9af-4086-4219-a54b-8b082b3c42ef".to_string(),
+                chat_id: 1,
+                source_user_id: 1,
+                destination_user_id: Some(2),
+                timestamp: 3,
+                message: "three".to_string(),
+                attachment_ids: Vec::new(),
+                envelope: None,
+                reply_to: None,
+                kind: MessageKind::Text,
+                previews: Vec::new(),
+            }),
+            ChatResponse::MessageAdded
+        );
+
+        assert_eq!(
+            server.issue(ChatRequest::ListChat {
+                id: 1,
+                cursor: None,
+                limit: None,
+                before: None,
+            }),
+            ChatResponse::ChatListed {
+                messages: vec![
+                    Arc::new(ChatMessage {
+                        id: "aed531ba-7a41-46dd-8e5d-9a5f7c16bfee".to_string(),
+                        seq: 1,
+                        timestamp: 0,
+                        message: "zero".to_string(),
+                        attachment_ids: Vec::new(),
+                        source_user_id: 1,
+                        destination_user_id: Some(2),
+                        edited_at: None,
+                        deleted: false,
+                        reactions: BTreeMap::new(),
+                        receipts: BTreeMap::new(),
+                        received_at: None,
+                        envelope: None,
+                        reply_to: None,
+                        kind: MessageKind::Text,
+                        previews: Vec::new(),
+                        forwarded_from: None,
+                        revisions: Vec::new(),
+                    }),
+                    Arc::new(ChatMessage {
+                        id: "b213468f-eed5-4119-be6c-bb780120502a".to_string(),
+                        seq: 2,
+                        timestamp: 4,
+                        message: "four".to_string(),
+                        attachment_ids: Vec::new(),
+                        source_user_id: 2,
+                        destination_user_id: Some(1),
+                        edited_at: None,
+                        deleted: false,
+                        reactions: BTreeMap::new(),
+                        receipts: BTreeMap::new(),
+                        received_at: None,
+                        envelope: None,
+                        reply_to: None,
+                        kind: MessageKind::Text,
+                        previews: Vec::new(),
+                        forwarded_from: None,
+                        revisions: Vec::new(),
+                    }),
+                    Arc::new(ChatMessage {
+                        id: "16cce9af-4086-4219-a54b-8b082b3c42ef".to_string(),
+                        seq: 3,
+                        timestamp: 3,
+                        message: "three".to_string(),
+                        attachment_ids: Vec::new(),
+                        source_user_id: 1,
+                        destination_user_id: Some(2),
+                        edited_at: None,
+                        deleted: false,
+                        reactions: BTreeMap::new(),
+                        receipts: BTreeMap::new(),
+                        received_at: None,
+                        envelope: None,
+                        reply_to: None,
+                        kind: MessageKind::Text,
+                        previews: Vec::new(),
+                        forwarded_from: None,
+                        revisions: Vec::new(),
+                    })
+                ],
+                next_cursor: None,
+                truncated: false
+            }
+        );
+    }
+
+    #[test]
+    fn test_group_chat() {
+        let mut server = ChatServer::new();
+
+        for (id, list) in [(1, vec![2, 3]), (2, vec![1, 3]), (3, vec![1])].iter() {
+            server.issue(ChatRequest::StoreContactList {
+                id: *id,
+                list: list.clone(),
+            });
+        }
+
+        // every participant must have every other as a contact,
+        // and there must be at least two of them
+
+        assert_eq!(
+            server.issue(ChatRequest::CreateChat {
+                id: Some(1),
+                participant_ids: vec![1, 2, 3],
+                title: None,
+                created_at: None,
+                creator: None
+            }),
+            ChatResponse::ChatValidationError {
+                code: ErrorCode::NotInContactList,
+                detail: Some("user 3 doesn't have user 2 in their contact list".to_string()),
+            }
+        );
+
+        assert_eq!(
+            server.issue(ChatRequest::CreateChat {
+                id: Some(1),
+                participant_ids: vec![1],
+                title: None,
+                created_at: None,
+                creator: None
+            }),
+            ChatResponse::invalid(ErrorCode::TooFewParticipants)
+        );
+
+        assert_eq!(
+            server.issue(ChatRequest::CreateChat {
+                id: Some(1),
+                participant_ids: vec![1, 1],
+                title: None,
+                created_at: None,
+                creator: None
+            }),
+            ChatResponse::invalid(ErrorCode::DuplicateParticipants)
+        );
+
+        server.issue(ChatRequest::StoreContactList {
+            id: 3,
+            list: vec![1, 2],
+        });
+
+        assert_eq!(
+            server.issue(ChatRequest::CreateChat {
+                id: Some(1),
+                participant_ids: vec![1, 2, 3],
+                title: None,
+                created_at: None,
+                creator: None
+            }),
+            ChatResponse::ChatCreated { id: 1 }
+        );
+
+        // the same set of participants cannot have another chat,
+        // but a subset of them can
+
+        assert_eq!(
+            server.issue(ChatRequest::CreateChat {
+                id: Some(2),
+                participant_ids: vec![3, 1, 2],
+                title: None,
+                created_at: None,
+                creator: None
+            }),
+            ChatResponse::ChatAlreadyExists {
+                id: 1,
+                conflict: ChatConflict::Participants,
+            }
+        );
+
+        assert_eq!(
+            server.issue(ChatRequest::CreateChat {
+                id: Some(2),
+                participant_ids: vec![1, 3],
+                title: None,
+                created_at: None,
+                creator: None
+            }),
+            ChatResponse::ChatCreated { id: 2 }
+        );
+
+        assert_eq!(
+            server.issue(ChatRequest::ListChats {
+                user_id: 3,
+                include_archived: false,
+                limit: None,
+                before: None,
+                since: None,
+            }),
+            ChatResponse::ChatsListed {
+                chats: vec![
+                    Chat {
+                        id: 1,
+                        participant_ids: vec![1, 2, 3],
+                        title: None,
+                        created_at: None,
+                        creator: None,
+                        message_ttl: None,
+                        muted: None,
+                        archived: false,
+                    },
+                    Chat {
+                        id: 2,
+                        participant_ids: vec![1, 3],
+                        title: None,
+                        created_at: None,
+                        creator: None,
+                        message_ttl: None,
+                        muted: None,
+                        archived: false,
+                    }
+                ]
+            }
+        );
+
+        // messages are addressed to everyone, or another participant
+
+        assert_eq!(
+            server.issue(ChatRequest::AddMessage {
+                id: "a".to_string(),
+                chat_id: 1,
+                source_user_id: 2,
+                destination_user_id: None,
+                timestamp: 0,
+                message: "everyone".to_string(),
+                attachment_ids: Vec::new(),
+                envelope: None,
+                reply_to: None,
+                kind: MessageKind::Text,
+                previews: Vec::new(),
+            }),
+            ChatResponse::MessageAdded
+        );
+
+        assert_eq!(
+            server.issue(ChatRequest::AddMessage {
+                id: "b".to_string(),
+                chat_id: 1,
+                source_user_id: 3,
+                destination_user_id: Some(1),
+                timestamp: 1,
+                message: "one".to_string(),
+                attachment_ids: Vec::new(),
+                envelope: None,
+                reply_to: None,
+                kind: MessageKind::Text,
+                previews: Vec::new(),
+            }),
+            ChatResponse::MessageAdded
+        );
+
+        assert_eq!(
+            server.issue(ChatRequest::AddMessage {
+                id: "c".to_string(),
+                chat_id: 2,
+                source_user_id: 2,
+                destination_user_id: None,
+                timestamp: 2,
+                message: "not a participant".to_string(),
+                attachment_ids: Vec::new(),
+                envelope: None,
+                reply_to: None,
+                kind: MessageKind::Text,
+                previews: Vec::new(),
+            }),
+            ChatResponse::UnknownChat
+        );
+
+        assert_eq!(
+            server.issue(ChatRequest::AddMessage {
+                id: "d".to_string(),
+                chat_id: 1,
+                source_user_id: 3,
+                destination_user_id: Some(3),
+                timestamp: 3,
+                message: "myself".to_string(),
+                attachment_ids: Vec::new(),
+                envelope: None,
+                reply_to: None,
+                kind: MessageKind::Text,
+                previews: Vec::new(),
+            }),
+            ChatResponse::UnknownChat
+        );
+
+        assert_eq!(
+            server.issue(ChatRequest::ListChat {
+                id: 1,
+                cursor: None,
+                limit: None,
+                before: None,
+            }),
+            ChatResponse::ChatListed {
+                messages: vec![
+                    Arc::new(ChatMessage {
+                        id: "a".to_string(),
+                        seq: 1,
+                        timestamp: 0,
+                        message: "everyone".to_string(),
+                        attachment_ids: Vec::new(),
+                        source_user_id: 2,
+                        destination_user_id: None,
+                        edited_at: None,
+                        deleted: false,
+                        reactions: BTreeMap::new(),
+                        receipts: BTreeMap::new(),
+                        received_at: None,
+                        envelope: None,
+                        reply_to: None,
+                        kind: MessageKind::Text,
+                        previews: Vec::new(),
+                        forwarded_from: None,
+                        revisions: Vec::new(),
+                    }),
+                    Arc::new(ChatMessage {
+                        id: "b".to_string(),
+                        seq: 2,
+                        timestamp: 1,
+                        message: "one".to_string(),
+                        attachment_ids: Vec::new(),
+                        source_user_id: 3,
+                        destination_user_id: Some(1),
+                        edited_at: None,
+                        deleted: false,
+                        reactions: BTreeMap::new(),
+                        receipts: BTreeMap::new(),
+                        received_at: None,
+                        envelope: None,
+                        reply_to: None,
+                        kind: MessageKind::Text,
+                        previews: Vec::new(),
+                        forwarded_from: None,
+                        revisions: Vec::new(),
+                    })
+                ],
+                next_cursor: None,
+                truncated: false
+            }
+        );
+    }
+
+    #[test]
+    fn test_leave_chat() {
+        let mut server = ChatServer::new();
+
+        for (id, list) in [(1, vec![2, 3]), (2, vec![1, 3]), (3, vec![1, 2])].iter() {
+            server.issue(ChatRequest::StoreContactList {
+                id: *id,
+                list: list.clone(),
+            });
+        }
+
+        server.issue(ChatRequest::CreateChat {
+            id: Some(1),
+            participant_ids: vec![1, 2, 3],
+            title: None,
+            created_at: None,
+            creator: None,
+        });
+
+        assert_eq!(
+            server.issue(ChatRequest::LeaveChat {
+                chat_id: 1,
+                user_id: 4
+            }),
+            ChatResponse::UnknownChat
+        );
+
+        assert_eq!(
+            server.issue(ChatRequest::LeaveChat {
+                chat_id: 1,
+                user_id: 3
+            }),
+            ChatResponse::ChatLeft
+        );
+
+        // the chat is gone for the user that left, but not the others
+
+        assert_eq!(
+            server.issue(ChatRequest::ListChats {
+                user_id: 3,
+                include_archived: false,
+                limit: None,
+                before: None,
+                since: None,
+            }),
+            ChatResponse::ChatsListed { chats: Vec::new() }
+        );
+
+        assert_eq!(
+            server.issue(ChatRequest::ListChats {
+                user_id: 1,
+                include_archived: false,
+                limit: None,
+                before: None,
+                since: None,
+            }),
+            ChatResponse::ChatsListed {
+                chats: vec![Chat {
+                    id: 1,
+                    participant_ids: vec![1, 2],
+                    title: None,
+                    created_at: None,
+                    creator: None,
+                    message_ttl: None,
+                    muted: None,
+                    archived: false,
+                }]
+            }
+        );
+
+        // and they can no longer post to it, or be messaged in it
+
+        assert_eq!(
+            server.issue(ChatRequest::AddMessage {
+                id: "a".to_string(),
+                chat_id: 1,
+                source_user_id: 3,
+                destination_user_id: None,
+                timestamp: 0,
+                message: "test".to_string(),
+                attachment_ids: Vec::new(),
+                envelope: None,
+                reply_to: None,
+                kind: MessageKind::Text,
+                previews: Vec::new(),
+            }),
+            ChatResponse::UnknownChat
+        );
+
+        assert_eq!(
+            server.issue(ChatRequest::AddMessage {
+                id: "a".to_string(),
+                chat_id: 1,
+                source_user_id: 1,
+                destination_user_id: Some(3),
+                timestamp: 0,
+                message: "test".to_string(),
+                attachment_ids: Vec::new(),
+                envelope: None,
+                reply_to: None,
+                kind: MessageKind::Text,
+                previews: Vec::new(),
+            }),
+            ChatResponse::UnknownChat
+        );
+
+        assert_eq!(
+            server.issue(ChatRequest::AddMessage {
+                id: "a".to_string(),
+                chat_id: 1,
+                source_user_id: 1,
+                destination_user_id: None,
+                timestamp: 0,
+                message: "test".to_string(),
+                attachment_ids: Vec::new(),
+                envelope: None,
+                reply_to: None,
+                kind: MessageKind::Text,
+                previews: Vec::new(),
+            }),
+            ChatResponse::MessageAdded
+        );
+
+        assert_eq!(
+            server.issue(ChatRequest::LeaveChat {
+                chat_id: 1,
+                user_id: 3
+            }),
+            ChatResponse::UnknownChat
+        );
+    }
+
+    #[test]
+    fn test_chat_metadata() {
+        let mut server = ChatServer::new();
+
+        for (id, list) in [(1, vec![2]), (2, vec![1])].iter() {
+            server.issue(ChatRequest::StoreContactList {
+                id: *id,
+                list: list.clone(),
+            });
+        }
+
+        // the creator must be a participant
+
+        assert_eq!(
+            server.issue(ChatRequest::CreateChat {
+                id: Some(1),
+                participant_ids: vec![1, 2],
+                title: Some("plans".to_string()),
+                created_at: Some(1000),
+                creator: Some(3)
+            }),
+            ChatResponse::invalid(ErrorCode::InvalidCreator)
+        );
+
+        assert_eq!(
+            server.issue(ChatRequest::CreateChat {
+                id: Some(1),
+                participant_ids: vec![1, 2],
+                title: Some("plans".to_string()),
+                created_at: Some(1000),
+                creator: Some(1)
+            }),
+            ChatResponse::ChatCreated { id: 1 }
+        );
+
+        // only participants can change the title
+
+        assert_eq!(
+            server.issue(ChatRequest::UpdateChat {
+                id: 1,
+                user_id: 3,
+                title: None,
+                message_ttl: None,
+            }),
+            ChatResponse::UnknownChat
+        );
+
+        assert_eq!(
+            server.issue(ChatRequest::UpdateChat {
+                id: 1,
+                user_id: 2,
+                title: Some("weekend plans".to_string()),
+                message_ttl: None,
+            }),
+            ChatResponse::ChatUpdated
+        );
+
+        assert_eq!(
+            server.issue(ChatRequest::ListChats {
+                user_id: 1,
+                include_archived: false,
+                limit: None,
+                before: None,
+                since: None,
+            }),
+            ChatResponse::ChatsListed {
+                chats: vec![Chat {
+                    id: 1,
+                    participant_ids: vec![1, 2],
+                    title: Some("weekend plans".to_string()),
+                    created_at: Some(1000),
+                    creator: Some(1),
+                    message_ttl: None,
+                    muted: None,
+                    archived: false,
+                }]
+            }
+        );
+    }
+
+    #[test]
+    fn test_allocated_chat_ids() {
+        let mut server = ChatServer::new();
+
+        for (id, list) in [(1, vec![2, 3]), (2, vec![1, 3]), (3, vec![1, 2])].iter() {
+            server.issue(ChatRequest::StoreContactList {
+                id: *id,
+                list: list.clone(),
+            });
+        }
+
+        assert_eq!(
+            server.issue(ChatRequest::CreateChat {
+                id: None,
+                participant_ids: vec![1, 2],
+                title: None,
+                created_at: None,
+                creator: None
+            }),
+            ChatResponse::ChatCreated { id: 1 }
+        );
+
+        // ids that were chosen by clients are skipped
+
+        assert_eq!(
+            server.issue(ChatRequest::CreateChat {
+                id: Some(2),
+                participant_ids: vec![1, 3],
+                title: None,
+                created_at: None,
+                creator: None
+            }),
+            ChatResponse::ChatCreated { id: 2 }
+        );
+
+        assert_eq!(
+            server.issue(ChatRequest::CreateChat {
+                id: None,
+                participant_ids: vec![2, 3],
+                title: None,
+                created_at: None,
+                creator: None
+            }),
+            ChatResponse::ChatCreated { id: 3 }
+        );
+
+        assert_eq!(
+            server.issue(ChatRequest::CreateChat {
+                id: Some(3),
+                participant_ids: vec![1, 2, 3],
+                title: None,
+                created_at: None,
+                creator: None
+            }),
+            ChatResponse::ChatAlreadyExists {
+                id: 3,
+                conflict: ChatConflict::Id,
+            }
+        );
+    }
+
+    #[test]
+    fn test_edit_message() {
+        let mut server = ChatServer::new();
+
+        for (id, list) in [(1, vec![2]), (2, vec![1])].iter() {
+            server.issue(ChatRequest::StoreContactList {
+                id: *id,
+                list: list.clone(),
+            });
+        }
+
+        server.issue(ChatRequest::CreateChat {
+            id: Some(1),
+            participant_ids: vec![1, 2],
+            title: None,
+            created_at: None,
+            creator: None,
+        });
+
+        server.issue(ChatRequest::AddMessage {
+            id: "a".to_string(),
+            chat_id: 1,
+            source_user_id: 1,
+            destination_user_id: Some(2),
+            timestamp: 0,
+            message: "helo".to_string(),
+            attachment_ids: Vec::new(),
+            envelope: None,
+            reply_to: None,
+            kind: MessageKind::Text,
+            previews: Vec::new(),
+        });
+
+        assert_eq!(
+            server.issue(ChatRequest::EditMessage {
+                chat_id: 2,
+                message_id: "a".to_string(),
+                editor_user_id: 1,
+                new_text: "hello".to_string(),
+                edited_at: 5
+            }),
+            ChatResponse::UnknownChat
+        );
+
+        assert_eq!(
+            server.issue(ChatRequest::EditMessage {
+                chat_id: 1,
+                message_id: "b".to_string(),
+                editor_user_id: 1,
+                new_text: "hello".to_string(),
+                edited_at: 5
+            }),
+            ChatResponse::UnknownMessage
+        );
+
+        // only the author can edit the message
+
+        assert_eq!(
+            server.issue(ChatRequest::EditMessage {
+                chat_id: 1,
+                message_id: "a".to_string(),
+                editor_user_id: 2,
+                new_text: "hello".to_string(),
+                edited_at: 5
+            }),
+            ChatResponse::MessageForbidden
+        );
+
+        match server.issue(ChatRequest::EditMessage {
+            chat_id: 1,
+            message_id: "a".to_string(),
+            editor_user_id: 1,
+            new_text: "hello".to_string(),
+            edited_at: 5,
+        }) {
+            ChatResponse::MessageEdited { message } => {
+                assert_eq!(message.message, "hello");
+                assert_eq!(message.edited_at, Some(5));
+            }
+
+            other => panic!("unexpected response: {:?}", other),
+        }
+
+        assert_eq!(
+            server.issue(ChatRequest::ListChat {
+                id: 1,
+                cursor: None,
+                limit: None,
+                before: None,
+            }),
+            ChatResponse::ChatListed {
+                messages: vec![Arc::new(ChatMessage {
+                    id: "a".to_string(),
+                    seq: 1,
+                    timestamp: 0,
+                    message: "hello".to_string(),
+                    attachment_ids: Vec::new(),
+                    source_user_id: 1,
+                    destination_user_id: Some(2),
+                    edited_at: Some(5),
+                    deleted: false,
+                    reactions: BTreeMap::new(),
+                    receipts: BTreeMap::new(),
+                    received_at: None,
+                    envelope: None,
+                    reply_to: None,
+                    kind: MessageKind::Text,
+                    previews: Vec::new(),
+                    forwarded_from: None,
+                    revisions: vec![Revision {
+                        message: "helo".to_string(),
+                        timestamp: 0,
+                    }],
+                })],
+                next_cursor: None,
+                truncated: false
+            }
+        );
+
+        // only the most recent revisions are retained
+
+        server.set_max_revisions(2);
+
+        for (new_text, edited_at) in [("hello!", 6), ("hello!!", 7)].iter() {
+            server.issue(ChatRequest::EditMessage {
+                chat_id: 1,
+                message_id: "a".to_string(),
+                editor_user_id: 1,
+                new_text: new_text.to_string(),
+                edited_at: *edited_at,
+            });
+        }
+
+        assert_eq!(
+            server.issue(ChatRequest::ListMessageHistory {
+                chat_id: 1,
+                message_id: "a".to_string(),
+            }),
+            ChatResponse::MessageHistoryListed {
+                revisions: vec![
+                    Revision {
+                        message: "hello".to_string(),
+                        timestamp: 5,
+                    },
+                    Revision {
+                        message: "hello!".to_string(),
+                        timestamp: 6,
+                    },
+                ],
+            }
+        );
+
+        assert_eq!(
+            server.issue(ChatRequest::ListMessageHistory {
+                chat_id: 1,
+                message_id: "b".to_string(),
+            }),
+            ChatResponse::UnknownMessage
+        );
+    }
+
+    #[test]
+    fn test_list_chat_shares_messages() {
+        let mut server = ChatServer::new();
+
+        for (id, list) in [(1, vec![2]), (2, vec![1])].iter() {
+            server.issue(ChatRequest::StoreContactList {
+                id: *id,
+                list: list.clone(),
+            });
+        }
+
+        server.issue(ChatRequest::CreateChat {
+            id: Some(1),
+            participant_ids: vec![1, 2],
+            title: None,
+            created_at: None,
+            creator: None,
+        });
+
+        server.issue(ChatRequest::AddMessage {
+            id: "a".to_string(),
+            chat_id: 1,
+            source_user_id: 1,
+            destination_user_id: Some(2),
+            timestamp: 0,
+            message: "helo".to_string(),
+            attachment_ids: Vec::new(),
+            envelope: None,
+            reply_to: None,
+            kind: MessageKind::Text,
+            previews: Vec::new(),
+        });
+
+        let listed = match server.issue(ChatRequest::ListChat {
+            id: 1,
+            cursor: None,
+            limit: None,
+            before: None,
+        }) {
+            ChatResponse::ChatListed { messages, .. } => messages,
+            other => panic!("unexpected response: {:?}", other),
+        };
+
+        // the listed messages are shared with the server rather than
+        // copied, and it can be mutated whilst they're held without
+        // affecting them
+
+        assert!(Arc::ptr_eq(&listed[0], &server.chats[&1].messages[0]));
+
+        server.issue(ChatRequest::EditMessage {
+            chat_id: 1,
+            message_id: "a".to_string(),
+            editor_user_id: 1,
+            new_text: "hello".to_string(),
+            edited_at: 5,
+        });
+
+        assert_eq!(listed[0].message, "helo");
+        assert_eq!(server.chats[&1].messages[0].message, "hello");
+        assert!(!Arc::ptr_eq(&listed[0], &server.chats[&1].messages[0]));
+    }
+
+    #[test]
+    fn test_delete_message() {
+        let mut server = ChatServer::new();
+
+        for (id, list) in [(1, vec![2]), (2, vec![1])].iter() {
+            server.issue(ChatRequest::StoreContactList {
+                id: *id,
+                list: list.clone(),
+            });
+        }
+
+        server.issue(ChatRequest::CreateChat {
+            id: Some(1),
+            participant_ids: vec![1, 2],
+            title: None,
+            created_at: None,
+            creator: None,
+        });
+
+        for (id, timestamp) in [("a", 0), ("b", 1)].iter() {
+            server.issue(ChatRequest::AddMessage {
+                id: id.to_string(),
+                chat_id: 1,
+                source_user_id: 1,
+                destination_user_id: Some(2),
+                timestamp: *timestamp,
+                message: "test".to_string(),
+                attachment_ids: Vec::new(),
+                envelope: None,
+                reply_to: None,
+                kind: MessageKind::Text,
+                previews: Vec::new(),
+            });
+        }
+
+        // only the author can delete the message
+
+        assert_eq!(
+            server.issue(ChatRequest::DeleteMessage {
+                chat_id: 1,
+                message_id: "a".to_string(),
+                requested_by: 2
+            }),
+            ChatResponse::MessageForbidden
+        );
+
+        assert_eq!(
+            server.issue(ChatRequest::DeleteMessage {
+                chat_id: 1,
+                message_id: "c".to_string(),
+                requested_by: 1
+            }),
+            ChatResponse::UnknownMessage
+        );
+
+        match server.issue(ChatRequest::DeleteMessage {
+            chat_id: 1,
+            message_id: "a".to_string(),
+            requested_by: 1,
+        }) {
+            ChatResponse::MessageDeleted { message } => {
+                assert!(message.deleted);
+                assert!(message.message.is_empty());
+            }
+
+            other => panic!("unexpected response: {:?}", other),
+        }
+
+        // deleted messages can't be edited
+
+        assert_eq!(
+            server.issue(ChatRequest::EditMessage {
+                chat_id: 1,
+                message_id: "a".to_string(),
+                editor_user_id: 1,
+                new_text: "test".to_string(),
+                edited_at: 2
+            }),
+            ChatResponse::UnknownMessage
+        );
+
+        // the tombstone keeps its place
+
+        assert_eq!(
+            server.issue(ChatRequest::ListChat {
+                id: 1,
+                cursor: None,
+                limit: None,
+                before: None,
+            }),
+            ChatResponse::ChatListed {
+                messages: vec![
+                    Arc::new(ChatMessage {
+                        id: "a".to_string(),
+                        seq: 1,
+                        timestamp: 0,
+                        message: "".to_string(),
+                        attachment_ids: Vec::new(),
+                        source_user_id: 1,
+                        destination_user_id: Some(2),
+                        edited_at: None,
+                        deleted: true,
+                        reactions: BTreeMap::new(),
+                        receipts: BTreeMap::new(),
+                        received_at: None,
+                        envelope: None,
+                        reply_to: None,
+                        kind: MessageKind::Text,
+                        previews: Vec::new(),
+                        forwarded_from: None,
+                        revisions: Vec::new(),
+                    }),
+                    Arc::new(ChatMessage {
+                        id: "b".to_string(),
+                        seq: 2,
+                        timestamp: 1,
+                        message: "test".to_string(),
+                        attachment_ids: Vec::new(),
+                        source_user_id: 1,
+                        destination_user_id: Some(2),
+                        edited_at: None,
+                        deleted: false,
+                        reactions: BTreeMap::new(),
+                        receipts: BTreeMap::new(),
+                        received_at: None,
+                        envelope: None,
+                        reply_to: None,
+                        kind: MessageKind::Text,
+                        previews: Vec::new(),
+                        forwarded_from: None,
+                        revisions: Vec::new(),
+                    })
+                ],
+                next_cursor: None,
+                truncated: false
+            }
+        );
+    }
+
+    #[test]
+    fn test_reactions() {
+        let mut server = ChatServer::new();
+
+        for (id, list) in [(1, vec![2, 3]), (2, vec![1, 3]), (3, vec![1, 2])].iter() {
+            server.issue(ChatRequest::StoreContactList {
+                id: *id,
+                list: list.clone(),
+            });
+        }
+
+        server.issue(ChatRequest::CreateChat {
+            id: Some(1),
+            participant_ids: vec![1, 2, 3],
+            title: None,
+            created_at: None,
+            creator: None,
+        });
+
+        server.issue(ChatRequest::AddMessage {
+            id: "a".to_string(),
+            chat_id: 1,
+            source_user_id: 1,
+            destination_user_id: None,
+            timestamp: 0,
+            message: "test".to_string(),
+            attachment_ids: Vec::new(),
+            envelope: None,
+            reply_to: None,
+            kind: MessageKind::Text,
+            previews: Vec::new(),
+        });
+
+        // non-participants can't react
+
+        assert_eq!(
+            server.issue(ChatRequest::ReactToMessage {
+                chat_id: 1,
+                message_id: "a".to_string(),
+                user_id: 4,
+                emoji: "👍".to_string()
+            }),
+            ChatResponse::UnknownChat
+        );
+
+        assert_eq!(
+            server.issue(ChatRequest::ReactToMessage {
+                chat_id: 1,
+                message_id: "b".to_string(),
+                user_id: 2,
+                emoji: "👍".to_string()
+            }),
+            ChatResponse::UnknownMessage
+        );
+
+        // reacting twice with the same emoji only counts once
+
+        for (user_id, emoji) in [(2, "👍"), (3, "👍"), (3, "👍"), (3, "🎉")].iter() {
+            assert_eq!(
+                server.issue(ChatRequest::ReactToMessage {
+                    chat_id: 1,
+                    message_id: "a".to_string(),
+                    user_id: *user_id,
+                    emoji: emoji.to_string()
+                }),
+                ChatResponse::ReactionAdded
+            );
+        }
+
+        assert_eq!(
+            server.issue(ChatRequest::RemoveReaction {
+                chat_id: 1,
+                message_id: "a".to_string(),
+                user_id: 3,
+                emoji: "🎉".to_string()
+            }),
+            ChatResponse::ReactionRemoved
+        );
+
+        let mut reactions = BTreeMap::new();
+        reactions.insert("👍".to_string(), vec![2, 3]);
+
+        assert_eq!(
+            server.issue(ChatRequest::ListChat {
+                id: 1,
+                cursor: None,
+                limit: None,
+                before: None,
+            }),
+            ChatResponse::ChatListed {
+                messages: vec![Arc::new(ChatMessage {
+                    id: "a".to_string(),
+                    seq: 1,
+                    timestamp: 0,
+                    message: "test".to_string(),
+                    attachment_ids: Vec::new(),
+                    source_user_id: 1,
+                    destination_user_id: None,
+                    edited_at: None,
+                    deleted: false,
+                    reactions,
+                    receipts: BTreeMap::new(),
+                    received_at: None,
+                    envelope: None,
+                    reply_to: None,
+                    kind: MessageKind::Text,
+                    previews: Vec::new(),
+                    forwarded_from: None,
+                    revisions: Vec::new(),
+                })],
+                next_cursor: None,
+                truncated: false
+            }
+        );
+    }
+
+    #[test]
+    fn test_receipts() {
+        let mut server = ChatServer::new();
+
+        for (id, list) in [(1, vec![2, 3]), (2, vec![1, 3]), (3, vec![1, 2])].iter() {
+            server.issue(ChatRequest::StoreContactList {
+                id: *id,
+                list: list.clone(),
+            });
+        }
+
+        server.issue(ChatRequest::CreateChat {
+            id: Some(1),
+            participant_ids: vec![1, 2, 3],
+            title: None,
+            created_at: None,
+            creator: None,
+        });
+
+        server.issue(ChatRequest::AddMessage {
+            id: "a".to_string(),
+            chat_id: 1,
+            source_user_id: 1,
+            destination_user_id: Some(2),
+            timestamp: 0,
+            message: "test".to_string(),
+            attachment_ids: Vec::new(),
+            envelope: None,
+            reply_to: None,
+            kind: MessageKind::Text,
+            previews: Vec::new(),
+        });
+
+        // only the recipient can update the status
+
+        for user_id in [1, 3].iter() {
+            assert_eq!(
+                server.issue(ChatRequest::MarkDelivered {
+                    chat_id: 1,
+                    message_id: "a".to_string(),
+                    user_id: *user_id,
+                    device_id: None,
+                }),
+                ChatResponse::MessageForbidden
+            );
+        }
+
+        assert_eq!(
+            server.issue(ChatRequest::MarkDelivered {
+                chat_id: 1,
+                message_id: "a".to_string(),
+                user_id: 4,
+                device_id: None,
+            }),
+            ChatResponse::UnknownChat
+        );
+
+        // and it never goes backwards
+
+        assert_eq!(
+            server.issue(ChatRequest::MarkRead {
+                chat_id: 1,
+                message_id: "a".to_string(),
+                user_id: 2,
+                device_id: None,
+            }),
+            ChatResponse::ReceiptUpdated
+        );
+
+        assert_eq!(
+            server.issue(ChatRequest::MarkDelivered {
+                chat_id: 1,
+                message_id: "a".to_string(),
+                user_id: 2,
+                device_id: None,
+            }),
+            ChatResponse::ReceiptUpdated
+        );
+
+        let mut receipts = BTreeMap::new();
+        receipts.insert(2, ReceiptStatus::Read);
+
+        assert_eq!(
+            server.issue(ChatRequest::ListChat {
+                id: 1,
+                cursor: None,
+                limit: None,
+                before: None,
+            }),
+            ChatResponse::ChatListed {
+                messages: vec![Arc::new(ChatMessage {
+                    id: "a".to_string(),
+                    seq: 1,
+                    timestamp: 0,
+                    message: "test".to_string(),
+                    attachment_ids: Vec::new(),
+                    source_user_id: 1,
+                    destination_user_id: Some(2),
+                    edited_at: None,
+                    deleted: false,
+                    reactions: BTreeMap::new(),
+                    receipts,
+                    received_at: None,
+                    envelope: None,
+                    reply_to: None,
+                    kind: MessageKind::Text,
+                    previews: Vec::new(),
+                    forwarded_from: None,
+                    revisions: Vec::new(),
+                })],
+                next_cursor: None,
+                truncated: false
+            }
+        );
+    }
+
+    #[test]
+    fn test_list_chat_cursor() {
+        let mut server = ChatServer::new();
+
+        for (id, list) in [(1, vec![2]), (2, vec![1])].iter() {
+            server.issue(ChatRequest::StoreContactList {
+                id: *id,
+                list: list.clone(),
+            });
+        }
+
+        server.issue(ChatRequest::CreateChat {
+            id: Some(1),
+            participant_ids: vec![1, 2],
+            title: None,
+            created_at: None,
+            creator: None,
+        });
+
+        // several messages share a timestamp, which must not cause
+        // any to be skipped or repeated
+
+        for (id, timestamp) in [("a", 0), ("b", 1), ("c", 1), ("d", 1), ("e", 2)].iter() {
+            server.issue(ChatRequest::AddMessage {
+                id: id.to_string(),
+                chat_id: 1,
+                source_user_id: 1,
+                destination_user_id: Some(2),
+                timestamp: *timestamp,
+                message: "test".to_string(),
+                attachment_ids: Vec::new(),
+                envelope: None,
+                reply_to: None,
+                kind: MessageKind::Text,
+                previews: Vec::new(),
+            });
+        }
+
+        let mut cursor = None;
+        let mut ids = Vec::new();
+        let mut pages = 0;
+
+        loop {
+            match server.issue(ChatRequest::ListChat {
+                id: 1,
+                cursor: cursor.take(),
+                limit: Some(2),
+                before: None,
+            }) {
+                ChatResponse::ChatListed {
+                    messages,
+                    next_cursor,
+                    ..
+                } => {
+                    ids.extend(messages.iter().map(|m| m.id.clone()));
+                    pages += 1;
+
+                    match next_cursor {
+                        Some(next_cursor) => cursor = Some(next_cursor),
+                        None => break,
+                    }
+                }
+
+                other => panic!("unexpected response: {:?}", other),
+            }
+        }
+
+        assert_eq!(ids, vec!["a", "b", "c", "d", "e"]);
+        assert_eq!(pages, 3);
+
+        assert_eq!(
+            server.issue(ChatRequest::ListChat {
+                id: 1,
+                cursor: Some("nope".to_string()),
+                limit: None,
+                before: None,
+            }),
+            ChatResponse::CursorParsingError
+        );
+    }
+
+    #[test]
+    fn test_list_chats_by_activity() {
+        let mut server = ChatServer::new();
+
+        for (id, list) in [(1, vec![2, 3, 4]), (2, vec![1]), (3, vec![1]), (4, vec![1])].iter() {
+            server.issue(ChatRequest::StoreContactList {
+                id: *id,
+                list: list.clone(),
+            });
+        }
+
+        for (id, other_id, created_at) in [(1, 2, None), (2, 3, Some(5)), (3, 4, None)].iter() {
+            server.issue(ChatRequest::CreateChat {
+                id: Some(*id),
+                participant_ids: vec![1, *other_id],
+                title: None,
+                created_at: *created_at,
+                creator: None,
+            });
+        }
+
+        for (chat_id, timestamp) in [(1, 10), (3, 2), (1, 1)].iter() {
+            server.issue(ChatRequest::AddMessage {
+                id: timestamp.to_string(),
+                chat_id: *chat_id,
+                source_user_id: 1,
+                destination_user_id: None,
+                timestamp: *timestamp,
+                message: "test".to_string(),
+                attachment_ids: Vec::new(),
+                envelope: None,
+                reply_to: None,
+                kind: MessageKind::Text,
+                previews: Vec::new(),
+            });
+        }
+
+        match server.issue(ChatRequest::ListChats {
+            user_id: 1,
+            include_archived: false,
+            limit: None,
+            before: None,
+            since: None,
+        }) {
+            ChatResponse::ChatsListed { chats } => {
+                assert_eq!(
+                    chats.iter().map(|c| c.id).collect::<Vec<_>>(),
+                    vec![1, 2, 3]
+                );
+            }
+
+            other => panic!("unexpected response: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_duplicate_message() {
+        let mut server = ChatServer::new();
+
+        for (id, list) in [(1, vec![2]), (2, vec![1])].iter() {
+            server.issue(ChatRequest::StoreContactList {
+                id: *id,
+                list: list.clone(),
+            });
+        }
+
+        server.issue(ChatRequest::CreateChat {
+            id: Some(1),
+            participant_ids: vec![1, 2],
+            title: None,
+            created_at: None,
+            creator: None,
+        });
+
+        for expected in [ChatResponse::MessageAdded, ChatResponse::DuplicateMessage].iter() {
+            assert_eq!(
+                &server.issue(ChatRequest::AddMessage {
+                    id: "a".to_string(),
+                    chat_id: 1,
+                    source_user_id: 1,
+                    destination_user_id: Some(2),
+                    timestamp: 0,
+                    message: "test".to_string(),
+                    attachment_ids: Vec::new(),
+                    envelope: None,
+                    reply_to: None,
+                    kind: MessageKind::Text,
+                    previews: Vec::new(),
+                }),
+                expected
+            );
+        }
+
+        match server.issue(ChatRequest::ListChat {
+            id: 1,
+            cursor: None,
+            limit: None,
+            before: None,
+        }) {
+            ChatResponse::ChatListed { messages, .. } => assert_eq!(messages.len(), 1),
+            other => panic!("unexpected response: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_server_timestamps() {
+        let mut server = ChatServer::new();
+
+        for (id, list) in [(1, vec![2]), (2, vec![1])].iter() {
+            server.issue(ChatRequest::StoreContactList {
+                id: *id,
+                list: list.clone(),
+            });
+        }
+
+        server.issue(ChatRequest::CreateChat {
+            id: Some(1),
+            participant_ids: vec![1, 2],
+            title: None,
+            created_at: None,
+            creator: None,
+        });
+
+        for (id, mode) in [
+            ("a", ServerTimestamps::Override),
+            ("b", ServerTimestamps::Supplement),
+        ]
+        .iter()
+        {
+            server.set_server_timestamps(*mode, || 500);
+
+            server.issue(ChatRequest::AddMessage {
+                id: id.to_string(),
+                chat_id: 1,
+                source_user_id: 1,
+                destination_user_id: Some(2),
+                timestamp: 100,
+                message: "test".to_string(),
+                attachment_ids: Vec::new(),
+                envelope: None,
+                reply_to: None,
+                kind: MessageKind::Text,
+                previews: Vec::new(),
+            });
+        }
+
+        match server.issue(ChatRequest::ListChat {
+            id: 1,
+            cursor: None,
+            limit: None,
+            before: None,
+        }) {
+            ChatResponse::ChatListed { messages, .. } => {
+                assert_eq!(
+                    messages
+                        .iter()
+                        .map(|m| (m.timestamp, m.received_at))
+                        .collect::<Vec<_>>(),
+                    vec![(500, None), (100, Some(500))]
+                );
+            }
+
+            other => panic!("unexpected response: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_blocklists() {
+        let mut server = ChatServer::new();
+
+        for (id, list) in [(1, vec![2, 3]), (2, vec![1, 3]), (3, vec![1, 2])].iter() {
+            server.issue(ChatRequest::StoreContactList {
+                id: *id,
+                list: list.clone(),
+            });
+        }
+
+        server.issue(ChatRequest::CreateChat {
+            id: Some(1),
+            participant_ids: vec![1, 2, 3],
+            title: None,
+            created_at: None,
+            creator: None,
+        });
+
+        assert_eq!(
+            server.issue(ChatRequest::BlockUser {
+                user_id: 2,
+                blocked_id: 1,
+            }),
+            ChatResponse::BlocklistUpdated
+        );
+
+        // blocks apply in both directions, even though the users
+        // remain in each other's contact lists
+
+        assert_eq!(
+            server.issue(ChatRequest::CreateChat {
+                id: Some(2),
+                participant_ids: vec![1, 2],
+                title: None,
+                created_at: None,
+                creator: None,
+            }),
+            ChatResponse::UserBlocked
+        );
+
+        for (id, source_user_id, destination_user_id, response) in [
+            ("a", 1, Some(2), ChatResponse::UserBlocked),
+            ("b", 2, Some(1), ChatResponse::UserBlocked),
+            ("c", 1, None, ChatResponse::UserBlocked),
+            ("d", 1, Some(3), ChatResponse::MessageAdded),
+            ("e", 3, None, ChatResponse::MessageAdded),
+        ]
+        .iter()
+        {
+            assert_eq!(
+                &server.issue(ChatRequest::AddMessage {
+                    id: id.to_string(),
+                    chat_id: 1,
+                    source_user_id: *source_user_id,
+                    destination_user_id: *destination_user_id,
+                    timestamp: 100,
+                    message: "test".to_string(),
+                    attachment_ids: Vec::new(),
+                    envelope: None,
+                    reply_to: None,
+                    kind: MessageKind::Text,
+                    previews: Vec::new(),
+                }),
+                response
+            );
+        }
+
+        assert_eq!(
+            server.issue(ChatRequest::UnblockUser {
+                user_id: 2,
+                blocked_id: 1,
+            }),
+            ChatResponse::BlocklistUpdated
+        );
+
+        assert_eq!(
+            server.issue(ChatRequest::AddMessage {
+                id: "f".to_string(),
+                chat_id: 1,
+                source_user_id: 1,
+                destination_user_id: Some(2),
+                timestamp: 100,
+                message: "test".to_string(),
+                attachment_ids: Vec::new(),
+                envelope: None,
+                reply_to: None,
+                kind: MessageKind::Text,
+                previews: Vec::new(),
+            }),
+            ChatResponse::MessageAdded
+        );
+
+        assert_eq!(
+            server.issue(ChatRequest::CreateChat {
+                id: Some(2),
+                participant_ids: vec![1, 2],
+                title: None,
+                created_at: None,
+                creator: None,
+            }),
+            ChatResponse::ChatCreated { id: 2 }
+        );
+    }
+
+    #[test]
+    fn test_contact_mutation() {
+        let mut server = ChatServer::new();
+
+        for (user_id, contact_id) in [(1, 2), (2, 1), (2, 1)].iter() {
+            assert_eq!(
+                server.issue(ChatRequest::AddContact {
+                    user_id: *user_id,
+                    contact_id: *contact_id,
+                }),
+                ChatResponse::ContactAdded
+            );
+        }
+
+        assert_eq!(server.contact_lists.get(&2), Some(&vec![1]));
+
+        assert_eq!(
+            server.issue(ChatRequest::CreateChat {
+                id: Some(1),
+                participant_ids: vec![1, 2],
+                title: None,
+                created_at: None,
+                creator: None,
+            }),
+            ChatResponse::ChatCreated { id: 1 }
+        );
+
+        assert_eq!(
+            server.issue(ChatRequest::RemoveContact {
+                user_id: 2,
+                contact_id: 1,
+            }),
+            ChatResponse::ContactRemoved
+        );
+
+        // the chat remains, but neither user can post to the other
+
+        for (id, source_user_id, destination_user_id) in [("a", 1, 2), ("b", 2, 1)].iter() {
+            assert_eq!(
+                server.issue(ChatRequest::AddMessage {
+                    id: id.to_string(),
+                    chat_id: 1,
+                    source_user_id: *source_user_id,
+                    destination_user_id: Some(*destination_user_id),
+                    timestamp: 100,
+                    message: "test".to_string(),
+                    attachment_ids: Vec::new(),
+                    envelope: None,
+                    reply_to: None,
+                    kind: MessageKind::Text,
+                    previews: Vec::new(),
+                }),
+                ChatResponse::ContactRequired
+            );
+        }
+
+        server.issue(ChatRequest::AddContact {
+            user_id: 2,
+            contact_id: 1,
+        });
+
+        assert_eq!(
+            server.issue(ChatRequest::AddMessage {
+                id: "c".to_string(),
+                chat_id: 1,
+                source_user_id: 2,
+                destination_user_id: Some(1),
+                timestamp: 100,
+                message: "test".to_string(),
+                attachment_ids: Vec::new(),
+                envelope: None,
+                reply_to: None,
+                kind: MessageKind::Text,
+                previews: Vec::new(),
+            }),
+            ChatResponse::MessageAdded
+        );
+    }
+
+    #[test]
+    fn test_contact_requests() {
+        let mut server = ChatServer::new();
+
+        assert_eq!(
+            server.issue(ChatRequest::RequestContact {
+                user_id: 1,
+                contact_id: 2,
+            }),
+            ChatResponse::ContactRequested
+        );
+
+        // nothing changes until the invitation is accepted
+
+        assert_eq!(server.contact_lists.get(&1), None);
+
+        assert_eq!(
+            server.issue(ChatRequest::AcceptContact {
+                user_id: 1,
+                contact_id: 2,
+            }),
+            ChatResponse::UnknownContactRequest
+        );
+
+        assert_eq!(
+            server.issue(ChatRequest::AcceptContact {
+                user_id: 2,
+                contact_id: 1,
+            }),
+            ChatResponse::ContactAccepted
+        );
+
+        assert_eq!(server.contact_lists.get(&1), Some(&vec![2]));
+        assert_eq!(server.contact_lists.get(&2), Some(&vec![1]));
+
+        // invitations can only be answered once
+
+        assert_eq!(
+            server.issue(ChatRequest::DeclineContact {
+                user_id: 2,
+                contact_id: 1,
+            }),
+            ChatResponse::UnknownContactRequest
+        );
+
+        server.issue(ChatRequest::RequestContact {
+            user_id: 3,
+            contact_id: 1,
+        });
+
+        assert_eq!(
+            server.issue(ChatRequest::DeclineContact {
+                user_id: 1,
+                contact_id: 3,
+            }),
+            ChatResponse::ContactDeclined
+        );
+
+        assert_eq!(server.contact_lists.get(&3), None);
+
+        // inviting a user that has already sent an invitation accepts it
+
+        server.issue(ChatRequest::RequestContact {
+            user_id: 3,
+            contact_id: 2,
+        });
+
+        assert_eq!(
+            server.issue(ChatRequest::RequestContact {
+                user_id: 2,
+                contact_id: 3,
+            }),
+            ChatResponse::ContactAccepted
+        );
+
+        assert_eq!(server.contact_lists.get(&3), Some(&vec![2]));
+
+        server.issue(ChatRequest::BlockUser {
+            user_id: 4,
+            blocked_id: 1,
+        });
+
+        assert_eq!(
+            server.issue(ChatRequest::RequestContact {
+                user_id: 1,
+                contact_id: 4,
+            }),
+            ChatResponse::UserBlocked
+        );
+    }
+
+    #[test]
+    fn test_list_contacts() {
+        let mut server = ChatServer::new();
+
+        server.issue(ChatRequest::StoreContactList {
+            id: 1,
+            list: vec![2, 3],
+        });
+
+        assert_eq!(
+            server.issue(ChatRequest::ListContacts { user_id: 1 }),
+            ChatResponse::ContactsListed {
+                contacts: vec![2, 3]
+            }
+        );
+
+        assert_eq!(
+            server.issue(ChatRequest::ListContacts { user_id: 2 }),
+            ChatResponse::ContactsListed { contacts: vec![] }
+        );
+    }
+
+    #[test]
+    fn test_search_messages() {
+        fn search(server: &mut ChatServer, user_id: Id, query: &str) -> Vec<(Id, String, String)> {
+            match server.issue(ChatRequest::SearchMessages {
+                user_id,
+                query: query.to_string(),
+                limit: Some(2),
+            }) {
+                ChatResponse::MessagesFound { results } => results
+                    .iter()
+                    .map(|r| (r.chat_id, r.message.id.clone(), r.snippet.clone()))
+                    .collect(),
+
+                other => panic!("unexpected response: {:?}", other),
+            }
+        }
+
+        let mut server = ChatServer::new();
+
+        for (id, list) in [(1, vec![2]), (2, vec![1, 3]), (3, vec![2])].iter() {
+            server.issue(ChatRequest::StoreContactList {
+                id: *id,
+                list: list.clone(),
+            });
+        }
+
+        for participant_ids in [vec![1, 2], vec![2, 3]].iter() {
+            server.issue(ChatRequest::CreateChat {
+                id: None,
+                participant_ids: participant_ids.clone(),
+                title: None,
+                created_at: None,
+                creator: None,
+            });
+        }
+
+        for (id, chat_id, source_user_id, timestamp, message) in [
+            ("a", 1, 1, 100, "Hello, World!"),
+            ("b", 1, 2, 200, "hello again"),
+            ("c", 1, 1, 300, "goodbye"),
+            ("d", 2, 3, 400, "hello from the other chat"),
+        ]
+        .iter()
+        {
+            assert_eq!(
+                server.issue(ChatRequest::AddMessage {
+                    id: id.to_string(),
+                    chat_id: *chat_id,
+                    source_user_id: *source_user_id,
+                    destination_user_id: None,
+                    timestamp: *timestamp,
+                    message: message.to_string(),
+                    attachment_ids: Vec::new(),
+                    envelope: None,
+                    reply_to: None,
+                    kind: MessageKind::Text,
+                    previews: Vec::new(),
+                }),
+                ChatResponse::MessageAdded
+            );
+        }
+
+        // users only find messages in their own chats, most recent first
+
+        assert_eq!(
+            search(&mut server, 1, "HELLO"),
+            vec![
+                (1, "b".to_string(), "hello again".to_string()),
+                (1, "a".to_string(), "Hello, World!".to_string())
+            ]
+        );
+
+        assert_eq!(
+            search(&mut server, 2, "hello"),
+            vec![
+                (2, "d".to_string(), "hello from the other chat".to_string()),
+                (1, "b".to_string(), "hello again".to_string())
+            ]
+        );
+
+        assert_eq!(
+            search(&mut server, 1, "wor hel"),
+            vec![(1, "a".to_string(), "Hello, World!".to_string())]
+        );
+
+        assert_eq!(search(&mut server, 3, "again"), vec![]);
+        assert_eq!(search(&mut server, 1, ""), vec![]);
+
+        // deleted messages aren't found
+
+        server.issue(ChatRequest::DeleteMessage {
+            chat_id: 1,
+            message_id: "a".to_string(),
+            requested_by: 1,
+        });
+
+        assert_eq!(search(&mut server, 1, "world"), vec![]);
+
+        // edits are reflected, as is the state of a restored server
+
+        server.issue(ChatRequest::EditMessage {
+            chat_id: 1,
+            message_id: "c".to_string(),
+            editor_user_id: 1,
+            new_text: "farewell, world".to_string(),
+            edited_at: 500,
+        });
+
+        let mut restored = ChatServer::new();
+        restored.restore(
+            serde_json::from_str(&serde_json::to_string(&server.snapshot()).unwrap()).unwrap(),
+        );
+
+        for server in [&mut server, &mut restored].iter_mut() {
+            assert_eq!(search(server, 1, "good"), vec![]);
+
+            assert_eq!(
+                search(server, 1, "world"),
+                vec![(1, "c".to_string(), "farewell, world".to_string())]
+            );
+        }
+    }
+
+    #[test]
+    fn test_export_chat() {
+        let mut server = ChatServer::new();
+
+        for (id, list) in [(1, vec![2]), (2, vec![1])].iter() {
+            server.issue(ChatRequest::StoreContactList {
+                id: *id,
+                list: list.clone(),
+            });
+        }
+
+        server.issue(ChatRequest::CreateChat {
+            id: Some(1),
+            participant_ids: vec![1, 2],
+            title: Some("Plans".to_string()),
+            created_at: Some(50),
+            creator: Some(1),
+        });
+
+        server.issue(ChatRequest::AddMessage {
+            id: "a".to_string(),
+            chat_id: 1,
+            source_user_id: 1,
+            destination_user_id: Some(2),
+            timestamp: 100,
+            message: "Hello, there!".to_string(),
+            attachment_ids: Vec::new(),
+            envelope: None,
+            reply_to: None,
+            kind: MessageKind::Text,
+            previews: Vec::new(),
+        });
+
+        assert_eq!(
+            server.issue(ChatRequest::ExportChat {
+                chat_id: 1,
+                format: TranscriptFormat::Json,
+            }),
+            ChatResponse::ChatExported {
+                format: TranscriptFormat::Json,
+                transcript: "{\"chat\":{\"id\":1,\"participantIds\":[1,2],\"title\":\"Plans\",\"createdAt\":50,\"creator\":1},\"messages\":[{\"id\":\"a\",\"seq\":1,\"timestamp\":100,\"message\":\"Hello, there!\",\"sourceUserId\":1,\"destinationUserId\":2}]}".to_string()
+            }
+        );
+
+        assert_eq!(
+            server.issue(ChatRequest::ExportChat {
+                chat_id: 1,
+                format: TranscriptFormat::Csv,
+            }),
+            ChatResponse::ChatExported {
+                format: TranscriptFormat::Csv,
+                transcript: "chatId,title,participantIds,id,seq,timestamp,sourceUserId,destinationUserId,editedAt,deleted,attachmentIds,message\r\n1,Plans,1 2,a,1,100,1,2,,false,,\"Hello, there!\"\r\n".to_string()
+            }
+        );
+
+        assert_eq!(
+            server.issue(ChatRequest::ExportChat {
+                chat_id: 2,
+                format: TranscriptFormat::Json,
+            }),
+            ChatResponse::UnknownChat
+        );
+    }
+
+    #[test]
+    fn test_import_chat() {
+        let mut source = ChatServer::new();
+
+        for (id, list) in [(1, vec![2]), (2, vec![1])].iter() {
+            source.issue(ChatRequest::StoreContactList {
+                id: *id,
+                list: list.clone(),
+            });
+        }
+
+        source.issue(ChatRequest::CreateChat {
+            id: Some(7),
+            participant_ids: vec![1, 2],
+            title: Some("Plans".to_string()),
+            created_at: None,
+            creator: None,
+        });
+
+        for id in ["a", "b"].iter() {
+            source.issue(ChatRequest::AddMessage {
+                id: id.to_string(),
+                chat_id: 7,
+                source_user_id: 1,
+                destination_user_id: Some(2),
+                timestamp: 100,
+                message: format!("message {}", id),
+                attachment_ids: Vec::new(),
+                envelope: None,
+                reply_to: None,
+                kind: MessageKind::Text,
+                previews: Vec::new(),
+            });
+        }
+
+        let export = |server: &mut ChatServer| match server.issue(ChatRequest::ExportChat {
+            chat_id: 7,
+            format: TranscriptFormat::Json,
+        }) {
+            ChatResponse::ChatExported { transcript, .. } => transcript,
+            other => panic!("unexpected response: {:?}", other),
+        };
+
+        let transcript = export(&mut source);
+
+        // contacts aren't required, as the chat already existed on the
+        // server it was exported from, and importing it again is a no-op
+
+        let mut destination = ChatServer::new();
+
+        for messages_imported in [2, 0].iter() {
+            assert_eq!(
+                destination.issue(ChatRequest::ImportChat {
+                    transcript: serde_json::from_str(&transcript).unwrap(),
+                }),
+                ChatResponse::ChatImported {
+                    id: 7,
+                    messages_imported: *messages_imported,
+                }
+            );
+        }
+
+        assert_eq!(export(&mut destination), transcript);
+
+        assert_eq!(
+            destination.issue(ChatRequest::ListChats {
+                user_id: 2,
+                include_archived: false,
+                limit: None,
+                before: None,
+                since: None,
+            }),
+            ChatResponse::ChatsListed {
+                chats: vec![Chat {
+                    id: 7,
+                    participant_ids: vec![1, 2],
+                    title: Some("Plans".to_string()),
+                    created_at: None,
+                    creator: None,
+                    message_ttl: None,
+                    muted: None,
+                    archived: false,
+                }]
+            }
+        );
+
+        // messages that were added since are merged in
+
+        source.issue(ChatRequest::AddMessage {
+            id: "c".to_string(),
+            chat_id: 7,
+            source_user_id: 2,
+            destination_user_id: Some(1),
+            timestamp: 200,
+            message: "message c".to_string(),
+            attachment_ids: Vec::new(),
+            envelope: None,
+            reply_to: None,
+            kind: MessageKind::Text,
+            previews: Vec::new(),
+        });
+
+        let transcript = export(&mut source);
+
+        assert_eq!(
+            destination.issue(ChatRequest::ImportChat {
+                transcript: serde_json::from_str(&transcript).unwrap(),
+            }),
+            ChatResponse::ChatImported {
+                id: 7,
+                messages_imported: 1,
+            }
+        );
+
+        assert_eq!(export(&mut destination), transcript);
+
+        // a chat with other participants can't be merged into
+
+        let transcript = transcript.replace("[1,2]", "[1,3]");
+
+        assert_eq!(
+            destination.issue(ChatRequest::ImportChat {
+                transcript: serde_json::from_str(&transcript).unwrap(),
+            }),
+            ChatResponse::ChatAlreadyExists {
+                id: 7,
+                conflict: ChatConflict::Id,
+            }
+        );
+    }
+
+    #[test]
+    fn test_expire_messages() {
+        fn seqs(server: &mut ChatServer, cursor: Option<&str>) -> Vec<u64> {
+            match server.issue(ChatRequest::ListChat {
+                id: 1,
+                cursor: cursor.map(str::to_string),
+                limit: None,
+                before: None,
+            }) {
+                ChatResponse::ChatListed { messages, .. } => {
+                    messages.iter().map(|m| m.seq).collect()
+                }
+                other => panic!("unexpected response: {:?}", other),
+            }
+        }
+
+        let mut server = ChatServer::new();
+
+        for (id, list) in [(1, vec![2]), (2, vec![1])].iter() {
+            server.issue(ChatRequest::StoreContactList {
+                id: *id,
+                list: list.clone(),
+            });
+        }
+
+        server.issue(ChatRequest::CreateChat {
+            id: Some(1),
+            participant_ids: vec![1, 2],
+            title: None,
+            created_at: None,
+            creator: None,
+        });
+
+        assert_eq!(
+            server.issue(ChatRequest::UpdateChat {
+                id: 1,
+                user_id: 1,
+                title: None,
+                message_ttl: Some(100),
+            }),
+            ChatResponse::ChatUpdated
+        );
+
+        assert_eq!(
+            server.issue(ChatRequest::ListChats {
+                user_id: 2,
+                include_archived: false,
+                limit: None,
+                before: None,
+                since: None,
+            }),
+            ChatResponse::ChatsListed {
+                chats: vec![Chat {
+                    id: 1,
+                    participant_ids: vec![1, 2],
+                    title: None,
+                    created_at: None,
+                    creator: None,
+                    message_ttl: Some(100),
+                    muted: None,
+                    archived: false,
+                }]
+            }
+        );
+
+        let add_message = |server: &mut ChatServer, id: &str, timestamp| {
+            server.issue(ChatRequest::AddMessage {
+                id: id.to_string(),
+                chat_id: 1,
+                source_user_id: 1,
+                destination_user_id: Some(2),
+                timestamp,
+                message: format!("message {}", id),
+                attachment_ids: Vec::new(),
+                envelope: None,
+                reply_to: None,
+                kind: MessageKind::Text,
+                previews: Vec::new(),
+            });
+        };
+
+        add_message(&mut server, "a", 100);
+        add_message(&mut server, "b", 200);
+        add_message(&mut server, "c", 300);
+
+        assert_eq!(server.expire(150), 0);
+        assert_eq!(server.expire(350), 2);
+        assert_eq!(server.expire(350), 0);
+
+        // sequence numbers aren't reused, and cursors for purged
+        // messages still work
+
+        add_message(&mut server, "a", 400);
+
+        assert_eq!(seqs(&mut server, None), vec![3, 4]);
+        assert_eq!(seqs(&mut server, Some("1")), vec![3, 4]);
+        assert_eq!(seqs(&mut server, Some("3")), vec![4]);
+
+        assert_eq!(
+            server.issue(ChatRequest::SearchMessages {
+                user_id: 1,
+                query: "message b".to_string(),
+                limit: None,
+            }),
+            ChatResponse::MessagesFound {
+                results: Vec::new()
+            }
+        );
+    }
+
+    #[test]
+    fn test_evict_messages() {
+        fn list(server: &mut ChatServer, cursor: Option<&str>) -> (Vec<u64>, bool) {
+            match server.issue(ChatRequest::ListChat {
+                id: 1,
+                cursor: cursor.map(str::to_string),
+                limit: None,
+                before: None,
+            }) {
+                ChatResponse::ChatListed {
+                    messages,
+                    truncated,
+                    ..
+                } => (messages.iter().map(|m| m.seq).collect(), truncated),
+                other => panic!("unexpected response: {:?}", other),
+            }
+        }
+
+        let mut server = ChatServer::new();
+        server.set_max_chat_messages(3);
+        server.set_max_chat_bytes(10);
+
+        for (id, list) in [(1, vec![2]), (2, vec![1])].iter() {
+            server.issue(ChatRequest::StoreContactList {
+                id: *id,
+                list: list.clone(),
+            });
+        }
+
+        server.issue(ChatRequest::CreateChat {
+            id: Some(1),
+            participant_ids: vec![1, 2],
+            title: None,
+            created_at: None,
+            creator: None,
+        });
+
+        let add_message = |server: &mut ChatServer, id: &str, message: &str| {
+            server.issue(ChatRequest::AddMessage {
+                id: id.to_string(),
+                chat_id: 1,
+                source_user_id: 1,
+                destination_user_id: Some(2),
+                timestamp: 0,
+                message: message.to_string(),
+                attachment_ids: Vec::new(),
+                envelope: None,
+                reply_to: None,
+                kind: MessageKind::Text,
+                previews: Vec::new(),
+            });
+        };
+
+        add_message(&mut server, "a", "one");
+        add_message(&mut server, "b", "two");
+        add_message(&mut server, "c", "six");
+
+        assert_eq!(list(&mut server, None), (vec![1, 2, 3], false));
+
+        // the oldest message is evicted once there are too many
+
+        add_message(&mut server, "d", "ten");
+
+        assert_eq!(list(&mut server, None), (vec![2, 3, 4], true));
+        assert_eq!(list(&mut server, Some("2")), (vec![3, 4], false));
+
+        // ...or once their text is too large, but the latest is kept
+
+        add_message(&mut server, "e", "a long message");
+
+        assert_eq!(list(&mut server, None), (vec![5], true));
+
+        assert_eq!(
+            server.issue(ChatRequest::SearchMessages {
+                user_id: 1,
+                query: "ten".to_string(),
+                limit: None,
+            }),
+            ChatResponse::MessagesFound {
+                results: Vec::new()
+            }
+        );
+    }
+
+    #[test]
+    fn test_metrics() {
+        let mut server = ChatServer::new();
+
+        for (id, list) in [(1, vec![2]), (2, vec![1])].iter() {
+            server.issue(ChatRequest::StoreContactList {
+                id: *id,
+                list: list.clone(),
+            });
+        }
+
+        server.issue(ChatRequest::CreateChat {
+            id: Some(1),
+            participant_ids: vec![1, 2],
+            title: None,
+            created_at: None,
+            creator: None,
+        });
+
+        server.issue(ChatRequest::CreateChat {
+            id: None,
+            participant_ids: vec![1, 1],
+            title: None,
+            created_at: None,
+            creator: None,
+        });
+
+        for chat_id in [1, 2].iter() {
+            server.issue(ChatRequest::AddMessage {
+                id: "a".to_string(),
+                chat_id: *chat_id,
+                source_user_id: 1,
+                destination_user_id: Some(2),
+                timestamp: 0,
+                message: "hello".to_string(),
+                attachment_ids: Vec::new(),
+                envelope: None,
+                reply_to: None,
+                kind: MessageKind::Text,
+                previews: Vec::new(),
+            });
+        }
+
+        let metrics = server.metrics();
+
+        assert_eq!(metrics.requests, 6);
+        assert_eq!(metrics.chats_created, 1);
+        assert_eq!(metrics.messages_added, 1);
+        assert_eq!(metrics.validation_failures, 1);
+        assert_eq!(metrics.unknown_chats, 1);
+        assert_eq!(metrics.latency.count, 6);
+    }
+
+    #[test]
+    fn test_subscribe() {
+        let mut server = ChatServer::new();
+        let events = Arc::new(Mutex::new(Vec::new()));
+
+        {
+            let events = events.clone();
+
+            server.subscribe(move |event| events.lock().unwrap().push(event.clone()));
+        }
+
+        for (id, list) in [(1, vec![2]), (2, vec![1])].iter() {
+            server.issue(ChatRequest::StoreContactList {
+                id: *id,
+                list: list.clone(),
+            });
+        }
+
+        server.issue(ChatRequest::CreateChat {
+            id: Some(1),
+            participant_ids: vec![1, 2],
+            title: None,
+            created_at: None,
+            creator: None,
+        });
+
+        // requests that fail or are queries change nothing, so they
+        // don't produce events
+
+        for chat_id in [1, 2].iter() {
+            server.issue(ChatRequest::AddMessage {
+                id: "a".to_string(),
+                chat_id: *chat_id,
+                source_user_id: 1,
+                destination_user_id: Some(2),
+                timestamp: 0,
+                message: "hello".to_string(),
+                attachment_ids: Vec::new(),
+                envelope: None,
+                reply_to: None,
+                kind: MessageKind::Text,
+                previews: Vec::new(),
+            });
+        }
+
+        server.issue(ChatRequest::ListChats {
+            user_id: 1,
+            include_archived: false,
+            limit: None,
+            before: None,
+            since: None,
+        });
+
+        server.issue(ChatRequest::DeleteMessage {
+            chat_id: 1,
+            message_id: "a".to_string(),
+            requested_by: 1,
+        });
+
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec![
+                ChatEvent::ContactListStored { user_id: 1 },
+                ChatEvent::ContactListStored { user_id: 2 },
+                ChatEvent::ChatCreated {
+                    chat: Chat {
+                        id: 1,
+                        participant_ids: vec![1, 2],
+                        title: None,
+                        created_at: None,
+                        creator: None,
+                        message_ttl: None,
+                        muted: None,
+                        archived: false,
+                    }
+                },
+                ChatEvent::MessageAdded {
+                    chat_id: 1,
+                    message: ChatMessage {
+                        id: "a".to_string(),
+                        seq: 1,
+                        timestamp: 0,
+                        message: "hello".to_string(),
+                        attachment_ids: Vec::new(),
+                        source_user_id: 1,
+                        destination_user_id: Some(2),
+                        edited_at: None,
+                        deleted: false,
+                        reactions: BTreeMap::new(),
+                        receipts: BTreeMap::new(),
+                        received_at: None,
+                        envelope: None,
+                        reply_to: None,
+                        kind: MessageKind::Text,
+                        previews: Vec::new(),
+                        forwarded_from: None,
+                        revisions: Vec::new(),
+                    }
+                },
+                ChatEvent::MessageDeleted {
+                    chat_id: 1,
+                    message_id: "a".to_string(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_poll_events() {
+        fn poll(server: &mut ChatServer, after: Option<&str>, limit: Option<usize>) -> Vec<u64> {
+            match server.issue(ChatRequest::PollEvents {
+                user_id: 2,
+                after_cursor: after.map(str::to_string),
+                limit,
+                device_id: None,
+            }) {
+                ChatResponse::EventsPolled { events, .. } => events.iter().map(|e| e.seq).collect(),
+                other => panic!("unexpected response: {:?}", other),
+            }
+        }
+
+        let mut server = ChatServer::new();
+
+        for (id, list) in [(1, vec![2]), (2, vec![1])].iter() {
+            server.issue(ChatRequest::StoreContactList {
+                id: *id,
+                list: list.clone(),
+            });
+        }
+
+        server.issue(ChatRequest::CreateChat {
+            id: Some(1),
+            participant_ids: vec![1, 2],
+            title: None,
+            created_at: None,
+            creator: None,
+        });
+
+        server.issue(ChatRequest::AddMessage {
+            id: "a".to_string(),
+            chat_id: 1,
+            source_user_id: 1,
+            destination_user_id: Some(2),
+            timestamp: 0,
+            message: "hello".to_string(),
+            attachment_ids: Vec::new(),
+            envelope: None,
+            reply_to: None,
+            kind: MessageKind::Text,
+            previews: Vec::new(),
+        });
+
+        server.issue(ChatRequest::EditMessage {
+            chat_id: 1,
+            message_id: "a".to_string(),
+            editor_user_id: 1,
+            new_text: "hello!".to_string(),
+            edited_at: 1,
+        });
+
+        assert_eq!(poll(&mut server, None, None), vec![1, 2, 3]);
+        assert_eq!(poll(&mut server, Some("1"), None), vec![2, 3]);
+        assert!(poll(&mut server, Some("3"), None).is_empty());
+
+        assert_eq!(
+            server.issue(ChatRequest::PollEvents {
+                user_id: 2,
+                after_cursor: None,
+                limit: Some(1),
+                device_id: None,
+            }),
+            ChatResponse::EventsPolled {
+                events: vec![FeedEvent {
+                    seq: 1,
+                    event: ChatEvent::ChatCreated {
+                        chat: Chat {
+                            id: 1,
+                            participant_ids: vec![1, 2],
+                            title: None,
+                            created_at: None,
+                            creator: None,
+                            message_ttl: None,
+                            muted: None,
+                            archived: false,
+                        },
+                    },
+                    muted: false,
+                }],
+                next_cursor: Some("1".to_string()),
+            }
+        );
+
+        assert_eq!(
+            server.issue(ChatRequest::PollEvents {
+                user_id: 2,
+                after_cursor: Some("x".to_string()),
+                limit: None,
+                device_id: None,
+            }),
+            ChatResponse::CursorParsingError
+        );
+
+        // feeds are included in snapshots
+
+        let mut restored = ChatServer::new();
+
+        restored.restore(
+            serde_json::from_str(&serde_json::to_string(&server.snapshot()).unwrap()).unwrap(),
+        );
+
+        assert_eq!(poll(&mut restored, Some("2"), None), vec![3]);
+    }
+
+    #[test]
+    fn test_devices() {
+        let mut server = ChatServer::new();
+
+        for (id, list) in [(1, vec![2]), (2, vec![1])].iter() {
+            server.issue(ChatRequest::StoreContactList {
+                id: *id,
+                list: list.clone(),
+            });
+        }
+
+        for (device_id, name) in [(2, "Laptop"), (1, "Phone"), (2, "Desktop")].iter() {
+            assert_eq!(
+                server.issue(ChatRequest::RegisterDevice {
+                    user_id: 2,
+                    device_id: *device_id,
+                    name: Some(name.to_string()),
+                }),
+                ChatResponse::DeviceRegistered
+            );
+        }
+
+        match server.issue(ChatRequest::ListDevices { user_id: 2 }) {
+            ChatResponse::DevicesListed { devices } => assert_eq!(
+                devices
+                    .iter()
+                    .map(|d| (d.id, d.name.as_ref().unwrap().as_str()))
+                    .collect::<Vec<_>>(),
+                vec![(1, "Phone"), (2, "Desktop")]
+            ),
+
+            other => panic!("unexpected response: {:?}", other),
+        }
+
+        server.issue(ChatRequest::CreateChat {
+            id: Some(1),
+            participant_ids: vec![1, 2],
+            title: None,
+            created_at: None,
+            creator: None,
+        });
+
+        for id in ["a", "b"].iter() {
+            server.issue(ChatRequest::AddMessage {
+                id: id.to_string(),
+                chat_id: 1,
+                source_user_id: 1,
+                destination_user_id: Some(2),
+                timestamp: 0,
+                message: "hello".to_string(),
+                attachment_ids: Vec::new(),
+                envelope: None,
+                reply_to: None,
+                kind: MessageKind::Text,
+                previews: Vec::new(),
+            });
+        }
+
+        // each device has its own cursors, whilst the receipt reflects
+        // the furthest that any of them got
+
+        assert_eq!(
+            server.issue(ChatRequest::MarkRead {
+                chat_id: 1,
+                message_id: "b".to_string(),
+                user_id: 2,
+                device_id: Some(1),
+            }),
+            ChatResponse::ReceiptUpdated
+        );
+
+        assert_eq!(
+            server.issue(ChatRequest::MarkDelivered {
+                chat_id: 1,
+                message_id: "a".to_string(),
+                user_id: 2,
+                device_id: Some(2),
+            }),
+            ChatResponse::ReceiptUpdated
+        );
+
+        assert_eq!(
+            server.issue(ChatRequest::MarkRead {
+                chat_id: 1,
+                message_id: "a".to_string(),
+                user_id: 2,
+                device_id: Some(3),
+            }),
+            ChatResponse::UnknownDevice
+        );
+
+        let phone = server.device(2, 1).unwrap();
+        let desktop = server.device(2, 2).unwrap();
+
+        assert_eq!(phone.read_cursors.get(&1), Some(&2));
+        assert_eq!(phone.delivered_cursors.get(&1), Some(&2));
+        assert_eq!(desktop.read_cursors.get(&1), None);
+        assert_eq!(desktop.delivered_cursors.get(&1), Some(&1));
+
+        // devices resume polling from the cursor they acknowledged
+
+        let poll =
+            |server: &mut ChatServer, device_id| match server.issue(ChatRequest::PollEvents {
+                user_id: 2,
+                after_cursor: None,
+                limit: None,
+                device_id: Some(device_id),
+            }) {
+                ChatResponse::EventsPolled { events, .. } => events.len(),
+                other => panic!("unexpected response: {:?}", other),
+            };
+
+        let all = poll(&mut server, 1);
+
+        assert_eq!(
+            server.issue(ChatRequest::AckEvents {
+                user_id: 2,
+                device_id: 1,
+                cursor: "4".to_string(),
+            }),
+            ChatResponse::EventsAcked
+        );
+
+        assert_eq!(poll(&mut server, 1), all - 4);
+        assert_eq!(poll(&mut server, 2), all);
+
+        assert_eq!(
+            server.issue(ChatRequest::PollEvents {
+                user_id: 2,
+                after_cursor: None,
+                limit: None,
+                device_id: Some(3),
+            }),
+            ChatResponse::UnknownDevice
+        );
+
+        // devices are included in snapshots
+
+        let mut restored = ChatServer::new();
+
+        restored.restore(
+            serde_json::from_str(&serde_json::to_string(&server.snapshot()).unwrap()).unwrap(),
+        );
+
+        assert_eq!(restored.devices, server.devices);
+
+        assert_eq!(
+            server.issue(ChatRequest::UnregisterDevice {
+                user_id: 2,
+                device_id: 1,
+            }),
+            ChatResponse::DeviceUnregistered
+        );
+
+        assert_eq!(
+            server.issue(ChatRequest::UnregisterDevice {
+                user_id: 2,
+                device_id: 1,
+            }),
+            ChatResponse::UnknownDevice
+        );
+
+        assert!(server.device(2, 1).is_none());
+    }
+
+    #[test]
+    fn test_pre_keys() {
+        let upload = |key_ids: &[Id]| PreKeyUpload {
+            identity_key: "aWRlbnRpdHk=".to_string(),
+            signed_pre_key: SignedPreKey {
+                key_id: 1,
+                public_key: "c2lnbmVk".to_string(),
+                signature: "c2lnbmF0dXJl".to_string(),
+            },
+            one_time_pre_keys: key_ids
+                .iter()
+                .map(|key_id| PreKey {
+                    key_id: *key_id,
+                    public_key: "a2V5".to_string(),
+                })
+                .collect(),
+        };
+
+        let mut server = ChatServer::new();
+
+        assert_eq!(
+            server.issue(ChatRequest::UploadPreKeys {
+                user_id: 2,
+                device_id: 1,
+                keys: upload(&[1]),
+            }),
+            ChatResponse::UnknownDevice
+        );
+
+        for device_id in [1, 2].iter() {
+            server.issue(ChatRequest::RegisterDevice {
+                user_id: 2,
+                device_id: *device_id,
+                name: None,
+            });
+        }
+
+        let key_ids = (1..=LOW_PRE_KEY_THRESHOLD as Id).collect::<Vec<_>>();
+
+        assert_eq!(
+            server.issue(ChatRequest::UploadPreKeys {
+                user_id: 2,
+                device_id: 1,
+                keys: upload(&key_ids),
+            }),
+            ChatResponse::PreKeysStored {
+                remaining: LOW_PRE_KEY_THRESHOLD,
+            }
+        );
+
+        assert_eq!(
+            server.issue(ChatRequest::UploadPreKeys {
+                user_id: 2,
+                device_id: 2,
+                keys: upload(&[]),
+            }),
+            ChatResponse::PreKeysStored { remaining: 0 }
+        );
+
+        let mut invalid = upload(&[]);
+        invalid.identity_key = "not base64!".to_string();
+
+        assert_eq!(
+            server.issue(ChatRequest::UploadPreKeys {
+                user_id: 2,
+                device_id: 1,
+                keys: invalid,
+            }),
+            ChatResponse::invalid(ErrorCode::InvalidKeys)
+        );
+
+        // a bundle is fetched for each device, consuming a one-time
+        // pre-key from those that have any left
+
+        match server.issue(ChatRequest::FetchPreKeyBundle {
+            user_id: 2,
+            device_id: None,
+            requested_by: 1,
+        }) {
+            ChatResponse::PreKeyBundlesFetched { bundles } => assert_eq!(
+                bundles
+                    .iter()
+                    .map(|b| (b.device_id, b.one_time_pre_key.as_ref().map(|k| k.key_id)))
+                    .collect::<Vec<_>>(),
+                vec![(1, Some(1)), (2, None)]
+            ),
+
+            other => panic!("unexpected response: {:?}", other),
+        }
+
+        assert_eq!(
+            server.issue(ChatRequest::CountPreKeys {
+                user_id: 2,
+                device_id: 1,
+            }),
+            ChatResponse::PreKeysCounted {
+                remaining: LOW_PRE_KEY_THRESHOLD - 1,
+            }
+        );
+
+        // the device is told that it's running low
+
+        match server.issue(ChatRequest::PollEvents {
+            user_id: 2,
+            after_cursor: None,
+            limit: None,
+            device_id: None,
+        }) {
+            ChatResponse::EventsPolled { events, .. } => assert_eq!(
+                events.last().map(|e| &e.event),
+                Some(&ChatEvent::PreKeysLow {
+                    user_id: 2,
+                    device_id: 1,
+                    remaining: LOW_PRE_KEY_THRESHOLD - 1,
+                })
+            ),
+
+            other => panic!("unexpected response: {:?}", other),
+        }
+
+        server.issue(ChatRequest::BlockUser {
+            user_id: 2,
+            blocked_id: 3,
+        });
+
+        assert_eq!(
+            server.issue(ChatRequest::FetchPreKeyBundle {
+                user_id: 2,
+                device_id: Some(1),
+                requested_by: 3,
+            }),
+            ChatResponse::UserBlocked
+        );
+
+        // unregistering a device discards its keys
+
+        server.issue(ChatRequest::UnregisterDevice {
+            user_id: 2,
+            device_id: 1,
+        });
+
+        assert_eq!(
+            server.issue(ChatRequest::FetchPreKeyBundle {
+                user_id: 2,
+                device_id: Some(1),
+                requested_by: 1,
+            }),
+            ChatResponse::PreKeysUnavailable
+        );
+    }
+
+    #[test]
+    fn test_envelopes() {
+        let mut server = ChatServer::new();
+
+        for (id, list) in [(1, vec![2]), (2, vec![1])].iter() {
+            server.issue(ChatRequest::StoreContactList {
+                id: *id,
+                list: list.clone(),
+            });
+        }
+
+        server.issue(ChatRequest::CreateChat {
+            id: Some(1),
+            participant_ids: vec![1, 2],
+            title: None,
+            created_at: None,
+            creator: None,
+        });
+
+        server.issue(ChatRequest::RegisterDevice {
+            user_id: 2,
+            device_id: 1,
+            name: None,
+        });
+
+        server.set_max_envelope_size(4);
+        server.set_moderator(|_, _| Moderation::Reject("unreadable".to_string()));
+
+        let envelope = |content: &[u8], destination_device_id| Envelope {
+            envelope_type: EnvelopeType::Ciphertext,
+            content: base64::encode(content),
+            source_device_id: None,
+            destination_device_id,
+        };
+
+        fn add(server: &mut ChatServer, message: &str, envelope: Envelope) -> ChatResponse {
+            server.issue(ChatRequest::AddMessage {
+                id: "a".to_string(),
+                chat_id: 1,
+                source_user_id: 1,
+                destination_user_id: Some(2),
+                timestamp: 0,
+                message: message.to_string(),
+                attachment_ids: Vec::new(),
+                envelope: Some(envelope),
+                reply_to: None,
+                kind: MessageKind::Text,
+                previews: Vec::new(),
+            })
+        }
+
+        assert_eq!(
+            add(&mut server, "", envelope(&[1, 2, 3, 4, 5], None)),
+            ChatResponse::EnvelopeTooLarge { max_size: 4 }
+        );
+
+        assert_eq!(
+            add(&mut server, "plaintext", envelope(&[1], None)),
+            ChatResponse::invalid(ErrorCode::InvalidEnvelope)
+        );
+
+        assert_eq!(
+            add(&mut server, "", envelope(&[], None)),
+            ChatResponse::invalid(ErrorCode::InvalidEnvelope)
+        );
+
+        assert_eq!(
+            add(&mut server, "", envelope(&[1], Some(2))),
+            ChatResponse::UnknownDevice
+        );
+
+        // envelopes bypass the moderator, as it can't read them
+
+        assert_eq!(
+            add(&mut server, "", envelope(&[1, 2], Some(1))),
+            ChatResponse::MessageAdded
+        );
+
+        match server.issue(ChatRequest::ListChat {
+            id: 1,
+            cursor: None,
+            limit: None,
+            before: None,
+        }) {
+            ChatResponse::ChatListed { messages, .. } => {
+                assert_eq!(messages[0].envelope, Some(envelope(&[1, 2], Some(1))));
+            }
+
+            other => panic!("unexpected response: {:?}", other),
+        }
+
+        assert_eq!(
+            server.issue(ChatRequest::EditMessage {
+                chat_id: 1,
+                message_id: "a".to_string(),
+                editor_user_id: 1,
+                new_text: "hello".to_string(),
+                edited_at: 1,
+            }),
+            ChatResponse::invalid(ErrorCode::EncryptedMessage)
+        );
+
+        server.issue(ChatRequest::DeleteMessage {
+            chat_id: 1,
+            message_id: "a".to_string(),
+            requested_by: 1,
+        });
+
+        assert_eq!(server.chats[&1].messages[0].envelope, None);
+    }
+
+    #[test]
+    fn test_mute_chat() {
+        let mut server = ChatServer::new();
+
+        for (id, list) in [(1, vec![2]), (2, vec![1])].iter() {
+            server.issue(ChatRequest::StoreContactList {
+                id: *id,
+                list: list.clone(),
+            });
+        }
+
+        server.issue(ChatRequest::CreateChat {
+            id: Some(1),
+            participant_ids: vec![1, 2],
+            title: None,
+            created_at: None,
+            creator: None,
+        });
+
+        server.issue(ChatRequest::RegisterWebhook {
+            user_id: 2,
+            url: "http://a/hook".to_string(),
+        });
+
+        assert_eq!(
+            server.issue(ChatRequest::MuteChat {
+                user_id: 2,
+                chat_id: 1,
+                until: Some(100),
+            }),
+            ChatResponse::ChatMuted
+        );
+
+        assert_eq!(
+            server.issue(ChatRequest::MuteChat {
+                user_id: 3,
+                chat_id: 1,
+                until: None,
+            }),
+            ChatResponse::UnknownChat
+        );
+
+        match server.issue(ChatRequest::ListChats {
+            user_id: 2,
+            include_archived: false,
+            limit: None,
+            before: None,
+            since: None,
+        }) {
+            ChatResponse::ChatsListed { chats } => {
+                assert_eq!(chats[0].muted, Some(Mute { until: Some(100) }));
+            }
+
+            other => panic!("unexpected response: {:?}", other),
+        }
+
+        match server.issue(ChatRequest::ListChats {
+            user_id: 1,
+            include_archived: false,
+            limit: None,
+            before: None,
+            since: None,
+        }) {
+            ChatResponse::ChatsListed { chats } => assert_eq!(chats[0].muted, None),
+            other => panic!("unexpected response: {:?}", other),
+        }
+
+        // messages sent before the mute ends don't notify, though
+        // they're still in the feed
+
+        let add_message = |id: &str, timestamp| ChatRequest::AddMessage {
+            id: id.to_string(),
+            chat_id: 1,
+            source_user_id: 1,
+            destination_user_id: Some(2),
+            timestamp,
+            message: "hello".to_string(),
+            attachment_ids: Vec::new(),
+            envelope: None,
+            reply_to: None,
+            kind: MessageKind::Text,
+            previews: Vec::new(),
+        };
+
+        assert!(server.webhook_urls(&add_message("a", 50)).is_empty());
+        assert_eq!(server.webhook_urls(&add_message("a", 100)).len(), 1);
+
+        server.issue(add_message("a", 50));
+        server.issue(add_message("b", 100));
+
+        match server.issue(ChatRequest::PollEvents {
+            user_id: 2,
+            after_cursor: None,
+            limit: None,
+            device_id: None,
+        }) {
+            ChatResponse::EventsPolled { events, .. } => assert_eq!(
+                events[events.len() - 2..]
+                    .iter()
+                    .map(|e| e.muted)
+                    .collect::<Vec<_>>(),
+                vec![true, false]
+            ),
+
+            other => panic!("unexpected response: {:?}", other),
+        }
+
+        // an indefinite mute lasts until the chat is unmuted
+
+        server.issue(ChatRequest::MuteChat {
+            user_id: 2,
+            chat_id: 1,
+            until: None,
+        });
+
+        assert!(server.webhook_urls(&add_message("c", u64::MAX)).is_empty());
+
+        assert_eq!(
+            server.issue(ChatRequest::UnmuteChat {
+                user_id: 2,
+                chat_id: 1,
+            }),
+            ChatResponse::ChatUnmuted
+        );
+
+        assert_eq!(server.webhook_urls(&add_message("c", 0)).len(), 1);
+    }
+
+    #[test]
+    fn test_archive_chat() {
+        fn listed(server: &mut ChatServer, include_archived: bool) -> Vec<(Id, bool)> {
+            match server.issue(ChatRequest::ListChats {
+                user_id: 1,
+                include_archived,
+                limit: None,
+                before: None,
+                since: None,
+            }) {
+                ChatResponse::ChatsListed { chats } => {
+                    chats.iter().map(|chat| (chat.id, chat.archived)).collect()
+                }
+
+                other => panic!("unexpected response: {:?}", other),
+            }
+        }
+
+        let mut server = ChatServer::new();
+
+        for (id, list) in [(1, vec![2, 3]), (2, vec![1]), (3, vec![1])].iter() {
+            server.issue(ChatRequest::StoreContactList {
+                id: *id,
+                list: list.clone(),
+            });
+        }
+
+        for id in 1..=2 {
+            server.issue(ChatRequest::CreateChat {
+                id: Some(id),
+                participant_ids: vec![1, id + 1],
+                title: None,
+                created_at: Some(id),
+                creator: None,
+            });
+        }
+
+        assert_eq!(
+            server.issue(ChatRequest::ArchiveChat {
+                user_id: 1,
+                chat_id: 2,
+            }),
+            ChatResponse::ChatArchived
+        );
+
+        assert_eq!(
+            server.issue(ChatRequest::ArchiveChat {
+                user_id: 3,
+                chat_id: 1,
+            }),
+            ChatResponse::UnknownChat
+        );
+
+        // archiving only hides the chat from the user who archived it
+
+        assert_eq!(listed(&mut server, false), vec![(1, false)]);
+        assert_eq!(listed(&mut server, true), vec![(2, true), (1, false)]);
+
+        match server.issue(ChatRequest::ListChats {
+            user_id: 3,
+            include_archived: false,
+            limit: None,
+            before: None,
+            since: None,
+        }) {
+            ChatResponse::ChatsListed { chats } => assert_eq!(chats.len(), 1),
+            other => panic!("unexpected response: {:?}", other),
+        }
+
+        match server.issue(ChatRequest::PollEvents {
+            user_id: 1,
+            after_cursor: None,
+            limit: None,
+            device_id: None,
+        }) {
+            ChatResponse::EventsPolled { events, .. } => assert_eq!(
+                events.last().map(|e| &e.event),
+                Some(&ChatEvent::ChatArchived {
+                    user_id: 1,
+                    chat_id: 2,
+                })
+            ),
+
+            other => panic!("unexpected response: {:?}", other),
+        }
+
+        assert_eq!(
+            server.issue(ChatRequest::UnarchiveChat {
+                user_id: 1,
+                chat_id: 2,
+            }),
+            ChatResponse::ChatUnarchived
+        );
+
+        assert_eq!(listed(&mut server, false), vec![(2, false), (1, false)]);
+    }
+
+    #[test]
+    fn test_erase_user() {
+        let mut server = ChatServer::new();
+        server.set_replication_backlog(100);
+
+        for (id, list) in [(1, vec![2, 3]), (2, vec![1, 3]), (3, vec![1, 2])].iter() {
+            server.issue(ChatRequest::StoreContactList {
+                id: *id,
+                list: list.clone(),
+            });
+        }
+
+        server.issue(ChatRequest::CreateChat {
+            id: Some(1),
+            participant_ids: vec![1, 2, 3],
+            title: None,
+            created_at: None,
+            creator: None,
+        });
+
+        for (id, source_user_id, message) in [("a", 1, "secret"), ("b", 2, "hello")].iter() {
+            server.issue(ChatRequest::AddMessage {
+                id: id.to_string(),
+                chat_id: 1,
+                source_user_id: *source_user_id,
+                destination_user_id: None,
+                timestamp: 0,
+                message: message.to_string(),
+                attachment_ids: Vec::new(),
+                envelope: None,
+                reply_to: None,
+                kind: MessageKind::Text,
+                previews: Vec::new(),
+            });
+        }
+
+        let requests = vec![
+            ChatRequest::ReactToMessage {
+                chat_id: 1,
+                message_id: "b".to_string(),
+                user_id: 1,
+                emoji: "+1".to_string(),
+            },
+            ChatRequest::MarkRead {
+                chat_id: 1,
+                message_id: "b".to_string(),
+                user_id: 1,
+                device_id: None,
+            },
+            ChatRequest::RegisterDevice {
+                user_id: 1,
+                device_id: 1,
+                name: None,
+            },
+            ChatRequest::BlockUser {
+                user_id: 3,
+                blocked_id: 1,
+            },
+            ChatRequest::ReportMessage {
+                reporter: 2,
+                chat_id: 1,
+                message_id: "a".to_string(),
+                reason: "spam".to_string(),
+            },
+        ];
+
+        for request in requests {
+            assert!(!server.issue(request).is_error());
+        }
+
+        assert_eq!(
+            server.issue(ChatRequest::EraseUser { user_id: 1 }),
+            ChatResponse::UserErased {
+                erasure: Erasure {
+                    contact_list_removed: true,
+                    chats_left: 1,
+                    messages_removed: 1,
+                    reactions_removed: 1,
+                    receipts_removed: 1,
+                    devices_removed: 1,
+                    webhooks_removed: 0,
+                    events_removed: 2,
+                    reports_redacted: 1,
+                },
+            }
+        );
+
+        assert_eq!(server.participant_ids(1), vec![2, 3]);
+        assert_eq!(server.contact_lists[&2], vec![3]);
+        assert!(server.blocklists[&3].is_empty());
+        assert!(!server.feeds.contains_key(&1));
+        assert!(!server.devices.contains_key(&1));
+
+        let message = |id| {
+            server.chats[&1]
+                .messages
+                .iter()
+                .find(|m| m.id == id)
+                .unwrap()
+        };
+
+        assert!(message("a").deleted);
+        assert!(message("b").reactions.is_empty());
+        assert!(message("b").receipts.is_empty());
+
+        // nothing that the server retains includes their messages, and
+        // followers must restore a snapshot
+
+        assert!(!serde_json::to_string(&server.snapshot())
+            .unwrap()
+            .contains("secret"));
+
+        assert!(server.replicated_entries(0).is_none());
+
+        match server.issue(ChatRequest::SearchMessages {
+            user_id: 2,
+            query: "secret".to_string(),
+            limit: None,
+        }) {
+            ChatResponse::MessagesFound { results } => assert!(results.is_empty()),
+            other => panic!("unexpected response: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_mentions() {
+        fn mentions(
+            server: &mut ChatServer,
+            user_id: Id,
+            after_cursor: Option<&str>,
+        ) -> Vec<(String, String)> {
+            match server.issue(ChatRequest::ListMentions {
+                user_id,
+                after_cursor: after_cursor.map(str::to_string),
+            }) {
+                ChatResponse::MentionsListed { mentions } => mentions
+                    .iter()
+                    .map(|m| (m.cursor.clone(), m.message.id.clone()))
+                    .collect(),
+
+                other => panic!("unexpected response: {:?}", other),
+            }
+        }
+
+        let mut server = ChatServer::new();
+
+        for (id, list) in [(1, vec![2, 3]), (2, vec![1, 3]), (3, vec![1, 2])].iter() {
+            server.issue(ChatRequest::StoreContactList {
+                id: *id,
+                list: list.clone(),
+            });
+        }
+
+        server.issue(ChatRequest::CreateChat {
+            id: Some(1),
+            participant_ids: vec![1, 2, 3],
+            title: None,
+            created_at: None,
+            creator: None,
+        });
+
+        let messages = [
+            ("a", 1, None, "hi @2 and @3"),
+            ("b", 2, None, "@2 talking to myself"),
+            ("c", 1, Some(3), "just for @3, not @2"),
+            ("d", 3, None, "@4 isn't here, but @2 is"),
+        ];
+
+        for (id, source_user_id, destination_user_id, message) in messages.iter() {
+            server.issue(ChatRequest::AddMessage {
+                id: id.to_string(),
+                chat_id: 1,
+                source_user_id: *source_user_id,
+                destination_user_id: *destination_user_id,
+                timestamp: 0,
+                message: message.to_string(),
+                attachment_ids: Vec::new(),
+                envelope: None,
+                reply_to: None,
+                kind: MessageKind::Text,
+                previews: Vec::new(),
+            });
+        }
+
+        // users are only mentioned by messages that they can see, and
+        // that others sent
+
+        assert_eq!(
+            mentions(&mut server, 2, None),
+            vec![
+                ("1".to_string(), "a".to_string()),
+                ("2".to_string(), "d".to_string())
+            ]
+        );
+
+        assert_eq!(
+            mentions(&mut server, 3, None),
+            vec![
+                ("1".to_string(), "a".to_string()),
+                ("2".to_string(), "c".to_string())
+            ]
+        );
+
+        assert!(mentions(&mut server, 4, None).is_empty());
+
+        assert_eq!(
+            mentions(&mut server, 2, Some("1")),
+            vec![("2".to_string(), "d".to_string())]
+        );
+
+        assert_eq!(
+            server.issue(ChatRequest::ListMentions {
+                user_id: 2,
+                after_cursor: Some("x".to_string()),
+            }),
+            ChatResponse::CursorParsingError
+        );
+
+        // deleted messages are omitted, and mentions are included in
+        // snapshots
+
+        server.issue(ChatRequest::DeleteMessage {
+            chat_id: 1,
+            message_id: "a".to_string(),
+            requested_by: 1,
+        });
+
+        let mut restored = ChatServer::new();
+
+        restored.restore(
+            serde_json::from_str(&serde_json::to_string(&server.snapshot()).unwrap()).unwrap(),
+        );
+
+        assert_eq!(
+            mentions(&mut restored, 2, None),
+            vec![("2".to_string(), "d".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_stats() {
+        let mut server = ChatServer::new();
+
+        for (id, list) in [(1, vec![2, 3]), (2, vec![1]), (3, vec![1]), (4, vec![])].iter() {
+            server.issue(ChatRequest::StoreContactList {
+                id: *id,
+                list: list.clone(),
+            });
+        }
+
+        for id in 1..=2 {
+            server.issue(ChatRequest::CreateChat {
+                id: Some(id),
+                participant_ids: vec![1, id + 1],
+                title: None,
+                created_at: None,
+                creator: None,
+            });
+        }
+
+        for (chat_id, id, source_user_id, minutes) in
+            [(1, "a", 1, 0), (1, "b", 2, 20), (2, "c", 3, 30)].iter()
+        {
+            server.issue(ChatRequest::AddMessage {
+                id: id.to_string(),
+                chat_id: *chat_id,
+                source_user_id: *source_user_id,
+                destination_user_id: None,
+                timestamp: minutes * 60_000,
+                message: "hello".to_string(),
+                attachment_ids: Vec::new(),
+                envelope: None,
+                reply_to: None,
+                kind: MessageKind::Text,
+                previews: Vec::new(),
+            });
+        }
+
+        let stats = |server: &mut ChatServer, active_minutes| match server
+            .issue(ChatRequest::Stats { active_minutes })
+        {
+            ChatResponse::StatsComputed { stats } => stats,
+            other => panic!("unexpected response: {:?}", other),
+        };
+
+        let computed = stats(&mut server, 15);
+
+        assert_eq!(computed.total_chats, 2);
+        assert_eq!(computed.total_messages, 3);
+        assert_eq!(computed.total_users, 4);
+        assert_eq!(computed.message_bytes, 15);
+        assert!(computed.snapshot_bytes > 0);
+
+        assert_eq!(
+            computed.chats,
+            vec![
+                ChatStats {
+                    id: 1,
+                    participants: 2,
+                    messages: 2,
+                    message_bytes: 10,
+                },
+                ChatStats {
+                    id: 2,
+                    participants: 2,
+                    messages: 1,
+                    message_bytes: 5,
+                },
+            ]
+        );
+
+        // without a clock, activity is relative to the latest message
+
+        assert_eq!(computed.active_users, 2);
+        assert_eq!(stats(&mut server, 5).active_users, 1);
+        assert_eq!(stats(&mut server, 60).active_users, 3);
+
+        server.set_server_timestamps(ServerTimestamps::Supplement, || 40 * 60_000);
+
+        assert_eq!(stats(&mut server, 5).active_users, 0);
+        assert_eq!(stats(&mut server, 15).active_users, 1);
+
+        assert!(ChatRequest::Stats { active_minutes: 1 }.requires_operator());
+    }
+
+    #[test]
+    fn test_batch() {
+        let mut server = ChatServer::new();
+
+        let add_message = |id: &str, chat_id| ChatRequest::AddMessage {
+            id: id.to_string(),
+            chat_id,
+            source_user_id: 1,
+            destination_user_id: None,
+            timestamp: 0,
+            message: "hello".to_string(),
+            attachment_ids: Vec::new(),
+            envelope: None,
+            reply_to: None,
+            kind: MessageKind::Text,
+            previews: Vec::new(),
+        };
+
+        let batch = |stop_on_error| ChatRequest::Batch {
+            requests: vec![
+                ChatRequest::StoreContactList {
+                    id: 1,
+                    list: vec![2],
+                },
+                ChatRequest::StoreContactList {
+                    id: 2,
+                    list: vec![1],
+                },
+                ChatRequest::CreateChat {
+                    id: Some(1),
+                    participant_ids: vec![1, 2],
+                    title: None,
+                    created_at: None,
+                    creator: None,
+                },
+                add_message("a", 2),
+                add_message("a", 1),
+                ChatRequest::ListChats {
+                    user_id: 1,
+                    include_archived: false,
+                    limit: None,
+                    before: None,
+                    since: None,
+                },
+                add_message("a", 1),
+            ],
+            stop_on_error,
+        };
+
+        assert_eq!(
+            server.issue(batch(true)),
+            ChatResponse::Batch {
+                responses: vec![
+                    ChatResponse::ContactListStored,
+                    ChatResponse::ContactListStored,
+                    ChatResponse::ChatCreated { id: 1 },
+                    ChatResponse::UnknownChat,
+                ],
+            }
+        );
+
+        // queries can't be batched, and retried messages are
+        // duplicates rather than errors
+
+        assert_eq!(
+            server.issue(batch(false)),
+            ChatResponse::Batch {
+                responses: vec![
+                    ChatResponse::ContactListStored,
+                    ChatResponse::ContactListStored,
+                    ChatResponse::ChatAlreadyExists {
+                        id: 1,
+                        conflict: ChatConflict::Id,
+                    },
+                    ChatResponse::UnknownChat,
+                    ChatResponse::MessageAdded,
+                    ChatResponse::invalid(ErrorCode::UnsupportedRequest),
+                    ChatResponse::DuplicateMessage,
+                ],
+            }
+        );
+
+        assert_eq!(server.metrics().messages_added, 1);
+    }
+
+    #[test]
+    fn test_forward_message() {
+        let mut server = ChatServer::new();
+
+        for (id, list) in [(1, vec![2, 3]), (2, vec![1]), (3, vec![1])].iter() {
+            server.issue(ChatRequest::StoreContactList {
+                id: *id,
+                list: list.clone(),
+            });
+        }
+
+        for (id, participant_ids) in [(1, vec![1, 2]), (2, vec![1, 3])].iter() {
+            server.issue(ChatRequest::CreateChat {
+                id: Some(*id),
+                participant_ids: participant_ids.clone(),
+                title: None,
+                created_at: None,
+                creator: None,
+            });
+        }
+
+        server.issue(ChatRequest::AddMessage {
+            id: "a".to_string(),
+            chat_id: 1,
+            source_user_id: 2,
+            destination_user_id: None,
+            timestamp: 10,
+            message: "hello".to_string(),
+            attachment_ids: Vec::new(),
+            envelope: None,
+            reply_to: None,
+            kind: MessageKind::Text,
+            previews: Vec::new(),
+        });
+
+        let forward =
+            |from_chat_id, message_id: &str, to_chat_id, user_id| ChatRequest::ForwardMessage {
+                from_chat_id,
+                message_id: message_id.to_string(),
+                to_chat_id,
+                user_id,
+                timestamp: 20,
+            };
+
+        assert_eq!(
+            server.issue(forward(1, "a", 2, 1)),
+            ChatResponse::MessageAdded
+        );
+
+        // the forwarder must be in both chats, and the message
+        // must exist
+
+        assert_eq!(
+            server.issue(forward(1, "a", 2, 2)),
+            ChatResponse::UnknownChat
+        );
+        assert_eq!(
+            server.issue(forward(1, "a", 3, 1)),
+            ChatResponse::UnknownChat
+        );
+        assert_eq!(
+            server.issue(forward(1, "b", 2, 1)),
+            ChatResponse::UnknownMessage
+        );
+
+        // forwarding again creates another copy
+
+        assert_eq!(
+            server.issue(forward(1, "a", 2, 1)),
+            ChatResponse::MessageAdded
+        );
+
+        match server.issue(ChatRequest::ListChat {
+            id: 2,
+            cursor: None,
+            limit: None,
+            before: None,
+        }) {
+            ChatResponse::ChatListed { messages, .. } => {
+                assert_eq!(
+                    messages
+                        .iter()
+                        .map(|m| (m.id.as_str(), m.seq, m.source_user_id))
+                        .collect::<Vec<_>>(),
+                    vec![("forwarded-1", 1, 1), ("forwarded-2", 2, 1)]
+                );
+
+                assert_eq!(messages[0].message, "hello");
+                assert_eq!(messages[0].timestamp, 20);
+
+                assert_eq!(
+                    messages[0].forwarded_from,
+                    Some(MessageRef {
+                        chat_id: 1,
+                        message_id: "a".to_string(),
+                    })
+                );
+            }
+
+            other => panic!("unexpected response: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_star_messages() {
+        fn starred(server: &mut ChatServer) -> Vec<(Id, String)> {
+            match server.issue(ChatRequest::ListStarred { user_id: 2 }) {
+                ChatResponse::StarredListed { messages } => messages
+                    .iter()
+                    .map(|m| (m.chat_id, m.message.id.clone()))
+                    .collect(),
+
+                other => panic!("unexpected response: {:?}", other),
+            }
+        }
+
+        let mut server = ChatServer::new();
+
+        for (id, list) in [(1, vec![2, 3]), (2, vec![1, 3]), (3, vec![1, 2])].iter() {
+            server.issue(ChatRequest::StoreContactList {
+                id: *id,
+                list: list.clone(),
+            });
+        }
+
+        for (chat_id, participant_ids) in [(1, vec![1, 2]), (2, vec![2, 3])].iter() {
+            server.issue(ChatRequest::CreateChat {
+                id: Some(*chat_id),
+                participant_ids: participant_ids.clone(),
+                title: None,
+                created_at: None,
+                creator: None,
+            });
+        }
+
+        for (chat_id, id, source_user_id) in [(1, "a", 1), (1, "b", 1), (2, "c", 3)].iter() {
+            server.issue(ChatRequest::AddMessage {
+                id: id.to_string(),
+                chat_id: *chat_id,
+                source_user_id: *source_user_id,
+                destination_user_id: None,
+                timestamp: 0,
+                message: "hello".to_string(),
+                attachment_ids: Vec::new(),
+                envelope: None,
+                reply_to: None,
+                kind: MessageKind::Text,
+                previews: Vec::new(),
+            });
+        }
+
+        for (chat_id, message_id) in [(1, "a"), (2, "c"), (1, "b"), (1, "a")].iter() {
+            assert_eq!(
+                server.issue(ChatRequest::StarMessage {
+                    user_id: 2,
+                    chat_id: *chat_id,
+                    message_id: message_id.to_string(),
+                }),
+                ChatResponse::MessageStarred
+            );
+        }
+
+        assert_eq!(
+            server.issue(ChatRequest::StarMessage {
+                user_id: 2,
+                chat_id: 1,
+                message_id: "z".to_string(),
+            }),
+            ChatResponse::UnknownMessage
+        );
+
+        assert_eq!(
+            server.issue(ChatRequest::StarMessage {
+                user_id: 3,
+                chat_id: 1,
+                message_id: "a".to_string(),
+            }),
+            ChatResponse::UnknownChat
+        );
+
+        assert_eq!(
+            starred(&mut server),
+            vec![
+                (1, "b".to_string()),
+                (2, "c".to_string()),
+                (1, "a".to_string())
+            ]
+        );
+
+        // deleted messages, and those in chats that were left, are omitted
+
+        server.issue(ChatRequest::DeleteMessage {
+            chat_id: 1,
+            message_id: "b".to_string(),
+            requested_by: 1,
+        });
+
+        server.issue(ChatRequest::LeaveChat {
+            chat_id: 2,
+            user_id: 2,
+        });
+
+        assert_eq!(starred(&mut server), vec![(1, "a".to_string())]);
+
+        // stars are included in snapshots
+
+        let mut restored = ChatServer::new();
+
+        restored.restore(
+            serde_json::from_str(&serde_json::to_string(&server.snapshot()).unwrap()).unwrap(),
+        );
+
+        assert_eq!(starred(&mut restored), vec![(1, "a".to_string())]);
+
+        assert_eq!(
+            server.issue(ChatRequest::UnstarMessage {
+                user_id: 2,
+                chat_id: 1,
+                message_id: "a".to_string(),
+            }),
+            ChatResponse::MessageUnstarred
+        );
+
+        assert!(starred(&mut server).is_empty());
+    }
+
+    #[test]
+    fn test_pending_messages() {
+        fn pending(server: &mut ChatServer, user_id: Id, limit: Option<usize>) -> Vec<String> {
+            match server.issue(ChatRequest::FetchPending { user_id, limit }) {
+                ChatResponse::PendingFetched { messages } => {
+                    messages.iter().map(|m| m.message.id.clone()).collect()
+                }
+
+                other => panic!("unexpected response: {:?}", other),
+            }
+        }
+
+        let mut server = ChatServer::new();
+
+        for (id, list) in [(1, vec![2, 3]), (2, vec![1, 3]), (3, vec![1, 2])].iter() {
+            server.issue(ChatRequest::StoreContactList {
+                id: *id,
+                list: list.clone(),
+            });
+        }
+
+        server.issue(ChatRequest::CreateChat {
+            id: Some(1),
+            participant_ids: vec![1, 2, 3],
+            title: None,
+            created_at: None,
+            creator: None,
+        });
+
+        for (id, source_user_id, destination_user_id) in [
+            ("a", 1, None),
+            ("b", 2, None),
+            ("c", 1, Some(3)),
+            ("d", 1, None),
+        ]
+        .iter()
+        {
+            server.issue(ChatRequest::AddMessage {
+                id: id.to_string(),
+                chat_id: 1,
+                source_user_id: *source_user_id,
+                destination_user_id: *destination_user_id,
+                timestamp: 0,
+                message: "hello".to_string(),
+                attachment_ids: Vec::new(),
+                envelope: None,
+                reply_to: None,
+                kind: MessageKind::Text,
+                previews: Vec::new(),
+            });
+        }
+
+        // messages are pending for their recipients, but not their source
+
+        assert_eq!(pending(&mut server, 1, None), vec!["b"]);
+        assert_eq!(pending(&mut server, 2, None), vec!["a", "d"]);
+        assert_eq!(pending(&mut server, 3, None), vec!["a", "b", "c", "d"]);
+        assert_eq!(pending(&mut server, 3, Some(2)), vec!["a", "b"]);
+
+        assert_eq!(
+            server.issue(ChatRequest::AckPending {
+                user_id: 3,
+                messages: vec![
+                    MessageRef {
+                        chat_id: 1,
+                        message_id: "a".to_string(),
+                    },
+                    MessageRef {
+                        chat_id: 1,
+                        message_id: "z".to_string(),
+                    },
+                ],
+            }),
+            ChatResponse::PendingAcked
+        );
+
+        assert_eq!(pending(&mut server, 3, None), vec!["b", "c", "d"]);
+        assert_eq!(pending(&mut server, 2, None), vec!["a", "d"]);
+
+        // deleted messages, and those in chats that were left, are omitted
+
+        server.issue(ChatRequest::DeleteMessage {
+            chat_id: 1,
+            message_id: "d".to_string(),
+            requested_by: 1,
+        });
+
+        assert_eq!(pending(&mut server, 3, None), vec!["b", "c"]);
+
+        // pending messages are included in snapshots
+
+        let mut restored = ChatServer::new();
+
+        restored.restore(
+            serde_json::from_str(&serde_json::to_string(&server.snapshot()).unwrap()).unwrap(),
+        );
+
+        assert_eq!(pending(&mut restored, 3, None), vec!["b", "c"]);
+
+        server.issue(ChatRequest::LeaveChat {
+            chat_id: 1,
+            user_id: 2,
+        });
+
+        assert!(pending(&mut server, 2, None).is_empty());
+
+        // the others are notified that they left
+
+        let notice = pending(&mut server, 3, None).pop().unwrap();
+
+        assert!(notice.starts_with("notice-"));
+
+        server.issue(ChatRequest::AckPending {
+            user_id: 3,
+            messages: vec![MessageRef {
+                chat_id: 1,
+                message_id: notice,
+            }],
+        });
+
+        // acknowledging also discards those that can no longer be fetched
+
+        server.issue(ChatRequest::AckPending {
+            user_id: 3,
+            messages: vec![MessageRef {
+                chat_id: 1,
+                message_id: "b".to_string(),
+            }],
+        });
+
+        assert_eq!(server.pending_by_user_id[&3].len(), 1);
+
+        server.issue(ChatRequest::AckPending {
+            user_id: 3,
+            messages: vec![MessageRef {
+                chat_id: 1,
+                message_id: "c".to_string(),
+            }],
+        });
+
+        assert!(!server.pending_by_user_id.contains_key(&3));
+    }
+
+    #[test]
+    fn test_ack_messages() {
+        fn delivered(server: &mut ChatServer, user_id: Id) -> Vec<String> {
+            match server.issue(ChatRequest::ListChat {
+                id: 1,
+                cursor: None,
+                limit: None,
+                before: None,
+            }) {
+                ChatResponse::ChatListed { messages, .. } => messages
+                    .iter()
+                    .filter(|m| m.receipts.get(&user_id) == Some(&ReceiptStatus::Delivered))
+                    .map(|m| m.id.clone())
+                    .collect(),
+
+                other => panic!("unexpected response: {:?}", other),
+            }
+        }
+
+        let mut server = ChatServer::new();
+
+        for (id, list) in [(1, vec![2, 3]), (2, vec![1, 3]), (3, vec![1, 2])].iter() {
+            server.issue(ChatRequest::StoreContactList {
+                id: *id,
+                list: list.clone(),
+            });
+        }
+
+        server.issue(ChatRequest::CreateChat {
+            id: Some(1),
+            participant_ids: vec![1, 2],
+            title: None,
+            created_at: None,
+            creator: None,
+        });
+
+        server.issue(ChatRequest::RegisterDevice {
+            user_id: 2,
+            device_id: 1,
+            name: None,
+        });
+
+        for (id, source_user_id) in [("a", 1), ("b", 2), ("c", 1), ("d", 1)].iter() {
+            server.issue(ChatRequest::AddMessage {
+                id: id.to_string(),
+                chat_id: 1,
+                source_user_id: *source_user_id,
+                destination_user_id: None,
+                timestamp: 0,
+                message: "hello".to_string(),
+                attachment_ids: Vec::new(),
+                envelope: None,
+                reply_to: None,
+                kind: MessageKind::Text,
+                previews: Vec::new(),
+            });
+        }
+
+        let ack = |user_id, up_to_seq, device_id| ChatRequest::AckMessages {
+            user_id,
+            chat_id: 1,
+            up_to_seq,
+            device_id,
+        };
+
+        assert_eq!(server.issue(ack(3, 2, None)), ChatResponse::UnknownChat);
+
+        assert_eq!(
+            server.issue(ack(2, 2, Some(2))),
+            ChatResponse::UnknownDevice
+        );
+
+        let receipts = Arc::new(Mutex::new(Vec::new()));
+
+        server.subscribe({
+            let receipts = receipts.clone();
+
+            move |event| {
+                if let ChatEvent::ReceiptUpdated { message_id, .. } = event {
+                    receipts.lock().unwrap().push(message_id.clone());
+                }
+            }
+        });
+
+        // only the messages that the user received are marked delivered
+
+        assert_eq!(
+            server.issue(ack(2, 3, Some(1))),
+            ChatResponse::MessagesAcked
+        );
+        assert_eq!(delivered(&mut server, 2), vec!["a", "c"]);
+        assert_eq!(*receipts.lock().unwrap(), vec!["a", "c"]);
+
+        match server.issue(ChatRequest::FetchPending {
+            user_id: 2,
+            limit: None,
+        }) {
+            ChatResponse::PendingFetched { messages } => {
+                assert_eq!(messages.len(), 1);
+                assert_eq!(messages[0].message.id, "d");
+            }
+
+            other => panic!("unexpected response: {:?}", other),
+        }
+
+        assert_eq!(
+            server.device(2, 1).unwrap().delivered_cursors.get(&1),
+            Some(&3)
+        );
+
+        // acknowledging again only considers the messages after the
+        // user's cursor, which doesn't go past the last message
+
+        assert_eq!(server.issue(ack(2, 10, None)), ChatResponse::MessagesAcked);
+        assert_eq!(delivered(&mut server, 2), vec!["a", "c", "d"]);
+        assert_eq!(*receipts.lock().unwrap(), vec!["a", "c", "d"]);
+        assert!(!server.pending_by_user_id.contains_key(&2));
+        assert_eq!(server.chat_ref_mut(2, 1).unwrap().delivered_seq, 4);
+
+        server.issue(ack(2, 4, None));
+
+        assert_eq!(receipts.lock().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn test_register_webhooks() {
+        let mut server = ChatServer::new();
+
+        for (id, list) in [(1, vec![2, 3]), (2, vec![1, 3]), (3, vec![1, 2])].iter() {
+            server.issue(ChatRequest::StoreContactList {
+                id: *id,
+                list: list.clone(),
+            });
+        }
+
+        server.issue(ChatRequest::CreateChat {
+            id: Some(1),
+            participant_ids: vec![1, 2, 3],
+            title: None,
+            created_at: None,
+            creator: None,
+        });
+
+        for (user_id, url) in [
+            (2, "http://a/hook"),
+            (2, "http://a/hook"),
+            (3, "http://b/hook"),
+        ]
+        .iter()
+        {
+            assert_eq!(
+                server.issue(ChatRequest::RegisterWebhook {
+                    user_id: *user_id,
+                    url: url.to_string(),
+                }),
+                ChatResponse::WebhookRegistered
+            );
+        }
+
+        assert_eq!(
+            server.issue(ChatRequest::RegisterWebhook {
+                user_id: 2,
+                url: "ftp://a/hook".to_string(),
+            }),
+            ChatResponse::ChatValidationError {
+                code: ErrorCode::InvalidUrl,
+                detail: Some("webhook URLs must start with http:// or https://".to_string()),
+            }
+        );
+
+        for i in 0..MAX_WEBHOOKS_PER_USER {
+            server.issue(ChatRequest::RegisterWebhook {
+                user_id: 4,
+                url: format!("https://c/hook/{}", i),
+            });
+        }
+
+        assert_eq!(
+            server.issue(ChatRequest::RegisterWebhook {
+                user_id: 4,
+                url: "https://c/hook/0".to_string(),
+            }),
+            ChatResponse::WebhookRegistered
+        );
+
+        assert_eq!(
+            server.issue(ChatRequest::RegisterWebhook {
+                user_id: 4,
+                url: "https://c/hook/extra".to_string(),
+            }),
+            ChatResponse::ChatValidationError {
+                code: ErrorCode::TooManyWebhooks,
+                detail: Some(format!(
+                    "users can register at most {} webhooks",
+                    MAX_WEBHOOKS_PER_USER
+                )),
+            }
+        );
+
+        let add_message = |destination_user_id| ChatRequest::AddMessage {
+            id: "a".to_string(),
+            chat_id: 1,
+            source_user_id: 2,
+            destination_user_id,
+            timestamp: 0,
+            message: "hello".to_string(),
+            attachment_ids: Vec::new(),
+            envelope: None,
+            reply_to: None,
+            kind: MessageKind::Text,
+            previews: Vec::new(),
+        };
+
+        // the sender isn't notified of their own message
+
+        assert_eq!(
+            server.webhook_urls(&add_message(None)),
+            vec!["http://b/hook".to_string()]
+        );
+        assert!(server.webhook_urls(&add_message(Some(1))).is_empty());
+
+        // webhooks are included in snapshots
+
+        let mut restored = ChatServer::new();
+
+        restored.restore(
+            serde_json::from_str(&serde_json::to_string(&server.snapshot()).unwrap()).unwrap(),
+        );
+
+        assert_eq!(restored.webhook_urls, server.webhook_urls);
+
+        assert_eq!(
+            server.issue(ChatRequest::UnregisterWebhook {
+                user_id: 3,
+                url: "http://b/hook".to_string(),
+            }),
+            ChatResponse::WebhookUnregistered
+        );
+
+        assert!(server.webhook_urls(&add_message(None)).is_empty());
+        assert!(!server.webhook_urls.contains_key(&3));
+    }
+
+    #[test]
+    fn test_moderation() {
+        let mut server = ChatServer::new();
+
+        for (id, list) in [(1, vec![2]), (2, vec![1])].iter() {
+            server.issue(ChatRequest::StoreContactList {
+                id: *id,
+                list: list.clone(),
+            });
+        }
+
+        server.issue(ChatRequest::CreateChat {
+            id: Some(1),
+            participant_ids: vec![1, 2],
+            title: None,
+            created_at: None,
+            creator: None,
+        });
+
+        server.set_moderator(|chat_id, message| {
+            assert_eq!(chat_id, 1);
+
+            if message.message.contains("spam") {
+                Moderation::Reject("looks like spam".to_string())
+            } else if message.message.contains("darn") {
+                Moderation::Replace(message.message.replace("darn", "****"))
+            } else {
+                Moderation::Accept
+            }
+        });
+
+        for (id, message, response) in [
+            ("a", "hello", ChatResponse::MessageAdded),
+            ("b", "darn it", ChatResponse::MessageAdded),
+            (
+                "c",
+                "buy spam",
+                ChatResponse::MessageRejected {
+                    reason: "looks like spam".to_string(),
+                },
+            ),
+        ]
+        .iter()
+        {
+            assert_eq!(
+                &server.issue(ChatRequest::AddMessage {
+                    id: id.to_string(),
+                    chat_id: 1,
+                    source_user_id: 1,
+                    destination_user_id: Some(2),
+                    timestamp: 100,
+                    message: message.to_string(),
+                    attachment_ids: Vec::new(),
+                    envelope: None,
+                    reply_to: None,
+                    kind: MessageKind::Text,
+                    previews: Vec::new(),
+                }),
+                response
+            );
+        }
+
+        match server.issue(ChatRequest::ListChat {
+            id: 1,
+            cursor: None,
+            limit: None,
+            before: None,
+        }) {
+            ChatResponse::ChatListed { messages, .. } => {
+                assert_eq!(
+                    messages
+                        .iter()
+                        .map(|m| (m.seq, m.message.as_str()))
+                        .collect::<Vec<_>>(),
+                    vec![(1, "hello"), (2, "**** it")]
+                );
+            }
+
+            other => panic!("unexpected response: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_state_digest() {
+        let requests = || {
+            vec![
+                ChatRequest::StoreContactList {
+                    id: 1,
+                    list: vec![2, 3],
+                },
+                ChatRequest::StoreContactList {
+                    id: 2,
+                    list: vec![1],
+                },
+                ChatRequest::BlockUser {
+                    user_id: 1,
+                    blocked_id: 4,
+                },
+                ChatRequest::BlockUser {
+                    user_id: 1,
+                    blocked_id: 5,
+                },
+            ]
+        };
+
+        let mut server = ChatServer::new();
+        let mut other = ChatServer::new();
+
+        for request in requests() {
+            server.issue(request);
+        }
+
+        // the order of unrelated requests doesn't matter
+
+        for request in requests().into_iter().rev() {
+            other.issue(request);
+        }
+
+        assert_eq!(server.state_digest(), other.state_digest());
+
+        for server in [&mut server, &mut other].iter_mut() {
+            server.issue(ChatRequest::CreateChat {
+                id: Some(1),
+                participant_ids: vec![1, 2],
+                title: None,
+                created_at: None,
+                creator: None,
+            });
+
+            server.issue(ChatRequest::AddMessage {
+                id: "a".to_string(),
+                chat_id: 1,
+                source_user_id: 1,
+                destination_user_id: Some(2),
+                timestamp: 0,
+                message: "hello".to_string(),
+                attachment_ids: Vec::new(),
+                envelope: None,
+                reply_to: None,
+                kind: MessageKind::Text,
+                previews: Vec::new(),
+            });
+        }
+
+        assert_eq!(server.state_digest(), other.state_digest());
+        assert_eq!(server.state_digest().len(), 64);
+
+        let mut restored = ChatServer::new();
+
+        restored.restore(
+            serde_json::from_str(&serde_json::to_string(&server.snapshot()).unwrap()).unwrap(),
+        );
+
+        assert_eq!(restored.state_digest(), server.state_digest());
+
+        other.issue(ChatRequest::EditMessage {
+            chat_id: 1,
+            message_id: "a".to_string(),
+            editor_user_id: 1,
+            new_text: "hello!".to_string(),
+            edited_at: 5,
+        });
+
+        assert_ne!(server.state_digest(), other.state_digest());
+    }
+
+    #[test]
+    fn test_report_message() {
+        let mut server = ChatServer::new();
+
+        for (id, list) in [(1, vec![2, 3]), (2, vec![1, 3]), (3, vec![1, 2])].iter() {
+            server.issue(ChatRequest::StoreContactList {
+                id: *id,
+                list: list.clone(),
+            });
+        }
+
+        server.issue(ChatRequest::CreateChat {
+            id: Some(1),
+            participant_ids: vec![1, 2, 3],
+            title: None,
+            created_at: None,
+            creator: None,
+        });
+
+        for id in ["a", "b"].iter() {
+            server.issue(ChatRequest::AddMessage {
+                id: id.to_string(),
+                chat_id: 1,
+                source_user_id: 1,
+                destination_user_id: None,
+                timestamp: 0,
+                message: format!("offensive {}", id),
+                attachment_ids: Vec::new(),
+                envelope: None,
+                reply_to: None,
+                kind: MessageKind::Text,
+                previews: Vec::new(),
+            });
+        }
+
+        let report = |server: &mut ChatServer, reporter, message_id: &str| match server.issue(
+            ChatRequest::ReportMessage {
+                reporter,
+                chat_id: 1,
+                message_id: message_id.to_string(),
+                reason: "abuse".to_string(),
+            },
+        ) {
+            ChatResponse::MessageReported { id } => Ok(id),
+            response => Err(response.error().map(|e| e.code)),
+        };
+
+        assert_eq!(report(&mut server, 2, "a"), Ok(1));
+        assert_eq!(report(&mut server, 3, "a"), Ok(2));
+        assert_eq!(report(&mut server, 2, "b"), Ok(3));
+
+        // reporting a message again has no effect
+
+        assert_eq!(report(&mut server, 2, "a"), Ok(1));
+
+        // only participants can report a chat's messages
 
         assert_eq!(
-            server.issue(ChatRequest::StoreContactList {
-                id: 1,
-                list: vec![1, 2]
-            }),
-            ChatResponse::ContactListStored
+            report(&mut server, 4, "a"),
+            Err(Some(ErrorCode::UnknownChat))
         );
-
         assert_eq!(
-            server.issue(ChatRequest::CreateChat {
-                id: 1,
-                participant_ids: [1, 2]
-            }),
-            ChatResponse::ChatValidationError
+            report(&mut server, 2, "c"),
+            Err(Some(ErrorCode::UnknownMessage))
         );
 
-        // next, let's setup the other side and assert that we
-        // can now create a contact list
+        let reports = |server: &ChatServer, include_resolved| match server
+            .query(ChatRequest::ListReports { include_resolved })
+        {
+            ChatResponse::ReportsListed { reports } => reports
+                .into_iter()
+                .map(|report| (report.id, report.resolution))
+                .collect::<Vec<_>>(),
+
+            _ => Vec::new(),
+        };
 
         assert_eq!(
-            server.issue(ChatRequest::StoreContactList {
-                id: 2,
-                list: vec![2, 1]
-            }),
-            ChatResponse::ContactListStored
+            reports(&server, false),
+            vec![(1, None), (2, None), (3, None)]
         );
 
+        let resolve = |server: &mut ChatServer, report_id, resolution| {
+            server
+                .issue(ChatRequest::ResolveReport {
+                    report_id,
+                    resolution,
+                })
+                .error()
+                .map(|e| e.code)
+        };
+
         assert_eq!(
-            server.issue(ChatRequest::CreateChat {
-                id: 1,
-                participant_ids: [1, 2]
-            }),
-            ChatResponse::ChatCreated
+            resolve(&mut server, 4, Resolution::Dismissed),
+            Some(ErrorCode::UnknownReport)
         );
+        assert_eq!(resolve(&mut server, 3, Resolution::Dismissed), None);
 
-        // the chat should be visible for both users
+        // removing a message resolves every report of it
 
-        assert_eq!(
-            server.issue(ChatRequest::ListChats { user_id: 1 }),
-            ChatResponse::ChatsListed {
-                chats: vec![Chat {
-                    id: 1,
-                    participant_ids: [1, 2]
-                }]
+        assert_eq!(resolve(&mut server, 1, Resolution::Removed), None);
+
+        match server.issue(ChatRequest::ListChat {
+            id: 1,
+            cursor: None,
+            limit: None,
+            before: None,
+        }) {
+            ChatResponse::ChatListed { messages, .. } => {
+                assert!(messages[0].deleted && messages[0].message.is_empty());
+                assert!(!messages[1].deleted);
             }
-        );
 
+            _ => panic!("expected the chat to be listed"),
+        }
+
+        assert_eq!(reports(&server, false), Vec::new());
         assert_eq!(
-            server.issue(ChatRequest::ListChats { user_id: 2 }),
-            ChatResponse::ChatsListed {
-                chats: vec![Chat {
-                    id: 1,
-                    participant_ids: [1, 2]
-                }]
-            }
+            reports(&server, true),
+            vec![
+                (1, Some(Resolution::Removed)),
+                (2, Some(Resolution::Removed)),
+                (3, Some(Resolution::Dismissed))
+            ]
         );
 
-        // and visible by its id (no messages yet)
+        // reports retain the content of the message for audit, and
+        // are included in snapshots
 
-        assert_eq!(
-            server.issue(ChatRequest::ListChat { id: 1 }),
-            ChatResponse::ChatListed {
-                messages: &Vec::new()
-            }
+        let mut restored = ChatServer::new();
+
+        restored.restore(
+            serde_json::from_str(&serde_json::to_string(&server.snapshot()).unwrap()).unwrap(),
         );
 
-        // when we add messages, they should be visible
-        // and ordered
+        match restored.query(ChatRequest::ListReports {
+            include_resolved: true,
+        }) {
+            ChatResponse::ReportsListed { reports } => {
+                assert_eq!(reports.len(), 3);
+                assert_eq!(reports[0].content, "offensive a");
+                assert_eq!(reports[0].source_user_id, 1);
+            }
 
+            _ => panic!("expected the reports to be listed"),
+        }
+
+        assert!(ChatRequest::ListReports {
+            include_resolved: false
+        }
+        .requires_operator());
+    }
+
+    #[test]
+    fn test_authenticate() {
+        let mut server = ChatServer::new();
+        server.set_replication_backlog(10);
+
+        let authenticate = |server: &mut ChatServer, user_id, credential: &str| match server.issue(
+            ChatRequest::Authenticate {
+                user_id,
+                credential: credential.to_string(),
+            },
+        ) {
+            ChatResponse::Authenticated { token, expires_in } => Ok((token, expires_in)),
+            response => Err(response.error().map(|e| e.code)),
+        };
+
+        assert!(!server.requires_authentication());
         assert_eq!(
-            server.issue(ChatRequest::AddMessage {
-                id: "aed531ba-7a41-46dd-8e5d-9a5f7c16bfee".to_string(),
-                chat_id: 1,
-                source_user_id: 1,
-                destination_user_id: 2,
-                timestamp: 0,
-                message: "zero".to_string()
-            }),
-            ChatResponse::MessageAdded
+            authenticate(&mut server, 1, "secret"),
+            Err(Some(ErrorCode::UnsupportedRequest))
         );
 
+        server.set_authenticator(|user_id, credential| user_id == 1 && credential == "secret");
+        server.set_token_ttl(Duration::from_secs(60));
+
+        assert!(server.requires_authentication());
         assert_eq!(
-            server.issue(ChatRequest::AddMessage {
-                id: "b213468f-eed5-4119-be6c-bb780120502a".to_string(),
-                chat_id: 1,
-                source_user_id: 2,
-                destination_user_id: 1,
-                timestamp: 4,
-                message: "four".to_string()
-            }),
-            ChatResponse::MessageAdded
+            authenticate(&mut server, 1, "wrong"),
+            Err(Some(ErrorCode::AuthenticationFailed))
+        );
+        assert_eq!(
+            authenticate(&mut server, 2, "secret"),
+            Err(Some(ErrorCode::AuthenticationFailed))
         );
 
+        let (token, expires_in) = authenticate(&mut server, 1, "secret").unwrap();
+
+        assert_eq!(expires_in, 60);
+        assert_eq!(server.verify_token(&token), Some(1));
+        assert_eq!(server.verify_token("unknown"), None);
+
+        // credentials and tokens aren't logged
+
+        assert_eq!(server.log_index(), 0);
+
         assert_eq!(
-            server.issue(ChatRequest::AddMessage {
-                id: "16cce9af-4086-4219-a54b-8b082b3c42ef".to_string(),
+            ChatRequest::AddMessage {
+                id: "a".to_string(),
                 chat_id: 1,
-                source_user_id: 1,
-                destination_user_id: 2,
-                timestamp: 3,
-                message: "three".to_string()
-            }),
-            ChatResponse::MessageAdded
+                source_user_id: 2,
+                destination_user_id: None,
+                timestamp: 0,
+                message: "hello".to_string(),
+                attachment_ids: Vec::new(),
+                envelope: None,
+                reply_to: None,
+                kind: MessageKind::Text,
+                previews: Vec::new(),
+            }
+            .user_id(),
+            Some(2)
         );
 
         assert_eq!(
-            server.issue(ChatRequest::ListChat { id: 1 }),
-            ChatResponse::ChatListed {
-                messages: &[
-                    ChatMessage {
-                        id: "aed531ba-7a41-46dd-8e5d-9a5f7c16bfee".to_string(),
-                        timestamp: 0,
-                        message: "zero".to_string(),
-                        source_user_id: 1,
-                        destination_user_id: 2
-                    },
-                    ChatMessage {
-                        id: "16cce9af-4086-4219-a54b-8b082b3c42ef".to_string(),
-                        timestamp: 3,
-                        message: "three".to_string(),
-                        source_user_id: 1,
-                        destination_user_id: 2
-                    },
-                    ChatMessage {
-                        id: "b213468f-eed5-4119-be6c-bb780120502a".to_string(),
-                        timestamp: 4,
-                        message: "four".to_string(),
-                        source_user_id: 2,
-                        destination_user_id: 1
-                    }
-                ]
+            ChatRequest::ListChat {
+                id: 1,
+                cursor: None,
+                limit: None,
+                before: None,
             }
+            .user_id(),
+            None
         );
     }
 
     #[test]
     fn test_chart_insert() {
         let mut chat = StoredChat {
-            participant_ids: [0, 1],
+            participant_ids: vec![0, 1],
+            title: None,
+            created_at: None,
+            creator: None,
+            latest_timestamp: None,
             messages: Vec::new(),
+            message_ids: HashSet::new(),
+            message_ttl: None,
+            last_seq: 0,
+            truncated: false,
         };
 
         let data = [
@@ -461,18 +10214,492 @@ mod tests {
         ];
 
         for (timestamp, message) in data.iter() {
-            chat.insert("".to_string(), 0, 0, *timestamp, message.to_string());
+            chat.insert(ChatMessage {
+                id: "".to_string(),
+                seq: 0,
+                timestamp: *timestamp,
+                message: message.to_string(),
+                attachment_ids: Vec::new(),
+                source_user_id: 0,
+                destination_user_id: None,
+                edited_at: None,
+                deleted: false,
+                reactions: BTreeMap::new(),
+                receipts: BTreeMap::new(),
+                received_at: None,
+                envelope: None,
+                reply_to: None,
+                kind: MessageKind::Text,
+                previews: Vec::new(),
+                forwarded_from: None,
+                revisions: Vec::new(),
+            });
         }
 
+        // messages remain in the order they were received, regardless
+        // of their timestamps
+
         assert_eq!(
             chat.messages
                 .iter()
-                .map(|msg| msg.message.as_str())
+                .map(|msg| (msg.seq, msg.message.as_str()))
                 .collect::<Vec<_>>(),
             vec![
-                "test5", "test9", "test1", "test7", "test3", "test2", "test4", "test6", "test8",
-                "test10"
+                (1, "test1"),
+                (2, "test2"),
+                (3, "test3"),
+                (4, "test4"),
+                (5, "test5"),
+                (6, "test6"),
+                (7, "test7"),
+                (8, "test8"),
+                (9, "test9"),
+                (10, "test10")
             ]
         );
+
+        assert_eq!(chat.last_activity(), 9);
+    }
+
+    #[test]
+    fn test_reply_to_and_list_thread() {
+        let mut server = ChatServer::new();
+
+        for (id, list) in [(1, vec![2]), (2, vec![1])].iter() {
+            server.issue(ChatRequest::StoreContactList {
+                id: *id,
+                list: list.clone(),
+            });
+        }
+
+        server.issue(ChatRequest::CreateChat {
+            id: Some(1),
+            participant_ids: vec![1, 2],
+            title: None,
+            created_at: None,
+            creator: None,
+        });
+
+        let add = |id: &str, reply_to: Option<&str>| ChatRequest::AddMessage {
+            id: id.to_string(),
+            chat_id: 1,
+            source_user_id: 1,
+            destination_user_id: None,
+            timestamp: 0,
+            message: id.to_string(),
+            attachment_ids: Vec::new(),
+            envelope: None,
+            reply_to: reply_to.map(str::to_string),
+            kind: MessageKind::Text,
+            previews: Vec::new(),
+        };
+
+        for (id, reply_to) in [
+            ("a", None),
+            ("b", Some("a")),
+            ("c", None),
+            ("d", Some("b")),
+            ("e", Some("c")),
+            ("f", Some("a")),
+        ]
+        .iter()
+        {
+            assert_eq!(server.issue(add(id, *reply_to)), ChatResponse::MessageAdded);
+        }
+
+        // replies must be to messages that exist in the chat
+
+        assert_eq!(
+            server.issue(add("g", Some("z"))).error().map(|e| e.code),
+            Some(ErrorCode::InvalidReply)
+        );
+
+        let thread =
+            |server: &ChatServer, chat_id, root: &str| match server.query(ChatRequest::ListThread {
+                chat_id,
+                root_message_id: root.to_string(),
+            }) {
+                ChatResponse::ThreadListed { messages } => {
+                    Ok(messages.iter().map(|m| m.id.clone()).collect::<Vec<_>>())
+                }
+
+                other => Err(format!("{:?}", other)),
+            };
+
+        assert_eq!(
+            thread(&server, 1, "a"),
+            Ok(vec![
+                "a".to_string(),
+                "b".to_string(),
+                "d".to_string(),
+                "f".to_string()
+            ])
+        );
+
+        assert_eq!(
+            thread(&server, 1, "c"),
+            Ok(vec!["c".to_string(), "e".to_string()])
+        );
+
+        assert_eq!(thread(&server, 1, "d"), Ok(vec!["d".to_string()]));
+
+        assert_eq!(
+            thread(&server, 1, "z"),
+            Err(format!("{:?}", ChatResponse::UnknownMessage))
+        );
+
+        assert_eq!(
+            thread(&server, 2, "a"),
+            Err(format!("{:?}", ChatResponse::UnknownChat))
+        );
+    }
+
+    #[test]
+    fn test_message_kinds() {
+        let mut server = ChatServer::new();
+
+        for (id, list) in [(1, vec![2, 3]), (2, vec![1, 3]), (3, vec![1, 2])].iter() {
+            server.issue(ChatRequest::StoreContactList {
+                id: *id,
+                list: list.clone(),
+            });
+        }
+
+        server.issue(ChatRequest::CreateChat {
+            id: Some(1),
+            participant_ids: vec![1, 2, 3],
+            title: None,
+            created_at: None,
+            creator: None,
+        });
+
+        let add =
+            |id: &str, kind, text: &str, attachment_ids: Vec<String>, reply_to: Option<&str>| {
+                ChatRequest::AddMessage {
+                    id: id.to_string(),
+                    chat_id: 1,
+                    source_user_id: 1,
+                    destination_user_id: None,
+                    timestamp: 0,
+                    message: text.to_string(),
+                    attachment_ids,
+                    envelope: None,
+                    reply_to: reply_to.map(str::to_string),
+                    kind,
+                    previews: Vec::new(),
+                }
+            };
+
+        let code = |response: ChatResponse| response.error().map(|e| e.code);
+
+        assert_eq!(
+            server.issue(add("a", MessageKind::Text, "hello", Vec::new(), None)),
+            ChatResponse::MessageAdded
+        );
+        assert_eq!(
+            server.issue(add(
+                "b",
+                MessageKind::Sticker,
+                "cat-waving",
+                Vec::new(),
+                Some("a")
+            )),
+            ChatResponse::MessageAdded
+        );
+
+        // stickers are ids without attachments, and users can't send
+        // notices
+
+        for request in [
+            add("d", MessageKind::Sticker, "cat waving", Vec::new(), None),
+            add("d", MessageKind::Sticker, "", Vec::new(), None),
+            add("d", MessageKind::Sticker, &"a".repeat(65), Vec::new(), None),
+            add(
+                "d",
+                MessageKind::Sticker,
+                "cat",
+                vec!["blob".to_string()],
+                None,
+            ),
+            add("d", MessageKind::System, "2 joined", Vec::new(), None),
+            add("d", MessageKind::System, "2 left", Vec::new(), Some("a")),
+        ] {
+            assert_eq!(
+                code(server.issue(request)),
+                Some(ErrorCode::InvalidMessageKind)
+            );
+        }
+
+        // the server sends a notice when a user leaves
+
+        assert_eq!(
+            server.issue(ChatRequest::LeaveChat {
+                chat_id: 1,
+                user_id: 3,
+            }),
+            ChatResponse::ChatLeft
+        );
+
+        // only text can be edited, and notices can't be forwarded
+
+        let edit = |message_id: &str| ChatRequest::EditMessage {
+            chat_id: 1,
+            message_id: message_id.to_string(),
+            editor_user_id: 1,
+            new_text: "changed".to_string(),
+            edited_at: 10,
+        };
+
+        assert_eq!(code(server.issue(edit("a"))), None);
+        assert_eq!(
+            code(server.issue(edit("b"))),
+            Some(ErrorCode::InvalidMessageKind)
+        );
+
+        assert_eq!(
+            code(server.issue(ChatRequest::ForwardMessage {
+                from_chat_id: 1,
+                message_id: "notice-3".to_string(),
+                to_chat_id: 1,
+                user_id: 1,
+                timestamp: 20,
+            })),
+            Some(ErrorCode::InvalidMessageKind)
+        );
+
+        match server.issue(ChatRequest::ListChat {
+            id: 1,
+            cursor: None,
+            limit: None,
+            before: None,
+        }) {
+            ChatResponse::ChatListed { messages, .. } => {
+                assert_eq!(
+                    messages.iter().map(|m| m.kind).collect::<Vec<_>>(),
+                    vec![MessageKind::Text, MessageKind::Sticker, MessageKind::System]
+                );
+
+                assert_eq!(
+                    (messages[2].message.as_str(), messages[2].source_user_id),
+                    ("3 left", 3)
+                );
+
+                // the kind is only included when it isn't text
+
+                let json = serde_json::to_string(&messages[1]).unwrap();
+
+                assert!(json.contains("\"kind\":\"sticker\""));
+                assert!(!serde_json::to_string(&messages[0])
+                    .unwrap()
+                    .contains("kind"));
+
+                assert_eq!(
+                    serde_json::from_str::<ChatMessage>(&json).unwrap(),
+                    *messages[1]
+                );
+            }
+
+            other => panic!("unexpected response: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_link_previews() {
+        let mut server = ChatServer::new();
+
+        for (id, list) in [(1, vec![2]), (2, vec![1])].iter() {
+            server.issue(ChatRequest::StoreContactList {
+                id: *id,
+                list: list.clone(),
+            });
+        }
+
+        server.issue(ChatRequest::CreateChat {
+            id: Some(1),
+            participant_ids: vec![1, 2],
+            title: None,
+            created_at: None,
+            creator: None,
+        });
+
+        let preview = |url: &str| LinkPreview {
+            url: url.to_string(),
+            title: Some("Example".to_string()),
+            description: Some("An example".to_string()),
+            image_id: Some("image".to_string()),
+        };
+
+        let add = |id: &str, kind, previews| ChatRequest::AddMessage {
+            id: id.to_string(),
+            chat_id: 1,
+            source_user_id: 1,
+            destination_user_id: None,
+            timestamp: 0,
+            message: "see https://example.com".to_string(),
+            attachment_ids: Vec::new(),
+            envelope: None,
+            reply_to: None,
+            kind,
+            previews,
+        };
+
+        assert_eq!(
+            server.issue(add(
+                "a",
+                MessageKind::Text,
+                vec![preview("https://example.com")]
+            )),
+            ChatResponse::MessageAdded
+        );
+
+        assert_eq!(
+            server
+                .issue(add("b", MessageKind::Text, vec![preview("file:///etc")]))
+                .error()
+                .map(|e| e.code),
+            Some(ErrorCode::InvalidLinkPreview)
+        );
+
+        assert_eq!(
+            server
+                .issue(add(
+                    "b",
+                    MessageKind::Sticker,
+                    vec![preview("https://example.com")]
+                ))
+                .error()
+                .map(|e| e.code),
+            Some(ErrorCode::InvalidMessageKind)
+        );
+
+        match server.issue(ChatRequest::ListChat {
+            id: 1,
+            cursor: None,
+            limit: None,
+            before: None,
+        }) {
+            ChatResponse::ChatListed { messages, .. } => {
+                assert_eq!(messages.len(), 1);
+                assert_eq!(messages[0].previews, vec![preview("https://example.com")]);
+            }
+
+            other => panic!("unexpected response: {:?}", other),
+        }
+
+        // a preview's image is referenced like an attachment, until
+        // its message is deleted
+
+        assert!(server.referenced_blobs().contains("image"));
+
+        server.issue(ChatRequest::DeleteMessage {
+            chat_id: 1,
+            message_id: "a".to_string(),
+            requested_by: 1,
+        });
+
+        assert!(server.referenced_blobs().is_empty());
+        assert!(server.take_released_blobs());
+    }
+
+    #[test]
+    fn test_list_filters() {
+        let mut server = ChatServer::new();
+
+        for (id, list) in [(1, vec![2, 3, 4]), (2, vec![1]), (3, vec![1]), (4, vec![1])].iter() {
+            server.issue(ChatRequest::StoreContactList {
+                id: *id,
+                list: list.clone(),
+            });
+        }
+
+        for id in 1..=3 {
+            server.issue(ChatRequest::CreateChat {
+                id: Some(id),
+                participant_ids: vec![1, id + 1],
+                title: None,
+                created_at: Some(id * 100),
+                creator: None,
+            });
+        }
+
+        for seq in 1..=5 {
+            server.issue(ChatRequest::AddMessage {
+                id: seq.to_string(),
+                chat_id: 1,
+                source_user_id: 1,
+                destination_user_id: None,
+                timestamp: 1000 + seq,
+                message: "hello".to_string(),
+                attachment_ids: Vec::new(),
+                envelope: None,
+                reply_to: None,
+                kind: MessageKind::Text,
+                previews: Vec::new(),
+            });
+        }
+
+        let chats =
+            |server: &ChatServer, limit, before, since| match server.query(ChatRequest::ListChats {
+                user_id: 1,
+                include_archived: false,
+                limit,
+                before,
+                since,
+            }) {
+                ChatResponse::ChatsListed { chats } => {
+                    chats.iter().map(|c| c.id).collect::<Vec<_>>()
+                }
+                _ => Vec::new(),
+            };
+
+        // chats are last active when their latest message was sent, or
+        // otherwise when they were created
+
+        assert_eq!(chats(&server, None, None, None), vec![1, 3, 2]);
+        assert_eq!(chats(&server, Some(2), None, None), vec![1, 3]);
+        assert_eq!(chats(&server, None, Some(1000), None), vec![3, 2]);
+        assert_eq!(chats(&server, None, None, Some(200)), vec![1, 3]);
+        assert_eq!(chats(&server, Some(1), Some(1000), Some(100)), vec![3]);
+
+        let messages = |server: &ChatServer, cursor: Option<&str>, limit, before| match server
+            .query(ChatRequest::ListChat {
+                id: 1,
+                cursor: cursor.map(str::to_string),
+                limit,
+                before,
+            }) {
+            ChatResponse::ChatListed {
+                messages,
+                next_cursor,
+                ..
+            } => (
+                messages.iter().map(|m| m.seq).collect::<Vec<_>>(),
+                next_cursor,
+            ),
+
+            _ => (Vec::new(), None),
+        };
+
+        assert_eq!(
+            messages(&server, None, Some(2), None),
+            (vec![1, 2], Some("2".to_string()))
+        );
+
+        // without a cursor, the most recent messages before the
+        // supplied one are listed
+
+        assert_eq!(
+            messages(&server, None, Some(2), Some(5)),
+            (vec![3, 4], None)
+        );
+        assert_eq!(messages(&server, None, None, Some(3)), (vec![1, 2], None));
+        assert_eq!(
+            messages(&server, Some("1"), Some(2), Some(5)),
+            (vec![2, 3], Some("3".to_string()))
+        );
+        assert_eq!(
+            messages(&server, Some("4"), None, Some(2)),
+            (Vec::new(), None)
+        );
     }
 }
@@ -0,0 +1,86 @@
+//! Provides parsing and validation of contact list documents,
+//! which map user ids to the ids of their contacts, e.g.
+//!
+//! ```json
+//! { "51201": [22307, 28463], "22307": [51201] }
+//! ```
+
+use crate::chat::Id;
+use serde_json::Value;
+use std::io::Error as IoError;
+use std::io::ErrorKind as IoErrorKind;
+use std::io::Result as IoResult;
+
+/// Parses and validates the supplied contact list document,
+/// returning each user's id and their list of contacts.
+///
+/// Unlike a lenient parse, any malformed entry causes the
+/// whole document to be rejected, so that a bad document
+/// from a remote source can't partially seed a server.
+pub fn parse_contact_lists(data: &str) -> IoResult<Vec<(Id, Vec<Id>)>> {
+    let obj = match serde_json::from_str(data)? {
+        Value::Object(obj) => obj,
+
+        _ => return Err(invalid("contact lists must be a JSON object")),
+    };
+
+    let mut lists = Vec::with_capacity(obj.len());
+
+    for (id, list_value) in obj.into_iter() {
+        let id = id
+            .parse()
+            .map_err(|_| invalid(format!("invalid user id: {}", id)))?;
+
+        let list = match list_value {
+            Value::Array(list) => list,
+
+            _ => return Err(invalid(format!("contacts for {} must be an array", id))),
+        };
+
+        let mut contacts = Vec::with_capacity(list.len());
+
+        for other_id in list {
+            match other_id.as_u64() {
+                Some(other_id) => contacts.push(other_id),
+
+                None => {
+                    return Err(invalid(format!(
+                        "contacts for {} contains an invalid id: {}",
+                        id, other_id
+                    )))
+                }
+            }
+        }
+
+        lists.push((id, contacts));
+    }
+
+    Ok(lists)
+}
+
+fn invalid<S: Into<String>>(message: S) -> IoError {
+    IoError::new(IoErrorKind::InvalidData, message.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::contacts::*;
+
+    #[test]
+    fn test_parse_contact_lists() {
+        let mut lists = parse_contact_lists("{ \"1\": [2, 3], \"2\": [1] }").unwrap();
+
+        lists.sort();
+
+        assert_eq!(lists, vec![(1, vec![2, 3]), (2, vec![1])]);
+    }
+
+    #[test]
+    fn test_parse_contact_lists_invalid() {
+        assert!(parse_contact_lists("[]").is_err());
+        assert!(parse_contact_lists("{ \"a\": [2] }").is_err());
+        assert!(parse_contact_lists("{ \"1\": 2 }").is_err());
+        assert!(parse_contact_lists("{ \"1\": [\"2\"] }").is_err());
+        assert!(parse_contact_lists("{ \"1\": [-2] }").is_err());
+    }
+}
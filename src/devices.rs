@@ -0,0 +1,76 @@
+//! Provides the registry of users' devices, so that each of a
+//! user's clients can sync independently of the others.
+//!
+//! Every device tracks its own read and delivery cursors in each
+//! chat, i.e. the sequence number of the latest message it has read
+//! or had delivered, as well as its cursor in the user's feed, so a
+//! device that reconnects resumes from where it left off rather than
+//! from where the user's most recently active device did.
+
+use crate::chat::{Id, ReceiptStatus};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// A device that a user has registered.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Device {
+    pub(crate) id: Id,
+    pub(crate) name: Option<String>,
+    pub(crate) registered_at: Option<u64>,
+
+    #[serde(default)]
+    pub(crate) read_cursors: BTreeMap<Id, u64>,
+
+    #[serde(default)]
+    pub(crate) delivered_cursors: BTreeMap<Id, u64>,
+
+    #[serde(default)]
+    pub(crate) feed_cursor: u64,
+}
+
+impl Device {
+    /// Internal API.
+    ///
+    /// Advances this device's cursor in the supplied chat for the
+    /// supplied status to the message with the supplied sequence
+    /// number. Cursors only advance, and a message that has been
+    /// read has also been delivered.
+    pub(crate) fn advance(&mut self, chat_id: Id, seq: u64, status: ReceiptStatus) {
+        let advance = |cursors: &mut BTreeMap<Id, u64>| {
+            let cursor = cursors.entry(chat_id).or_insert(0);
+
+            if seq > *cursor {
+                *cursor = seq;
+            }
+        };
+
+        if status >= ReceiptStatus::Delivered {
+            advance(&mut self.delivered_cursors);
+        }
+
+        if status >= ReceiptStatus::Read {
+            advance(&mut self.read_cursors);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::devices::*;
+
+    #[test]
+    fn test_advance() {
+        let mut device = Device::default();
+
+        device.advance(1, 3, ReceiptStatus::Delivered);
+        device.advance(1, 2, ReceiptStatus::Read);
+        device.advance(2, 5, ReceiptStatus::Read);
+        device.advance(1, 1, ReceiptStatus::Delivered);
+
+        assert_eq!(device.delivered_cursors.get(&1), Some(&3));
+        assert_eq!(device.read_cursors.get(&1), Some(&2));
+        assert_eq!(device.delivered_cursors.get(&2), Some(&5));
+        assert_eq!(device.read_cursors.get(&2), Some(&5));
+    }
+}
@@ -0,0 +1,394 @@
+//! Provides authenticated encryption for data that is persisted
+//! to disk, i.e. write-ahead log records and snapshots.
+//!
+//! Keys are supplied as a keyring, a document with one key per
+//! line consisting of an id and 32 hex-encoded bytes, e.g.
+//!
+//! ```text
+//! 2024-02 4f1c...
+//! 2024-01 9a0b...
+//! ```
+//!
+//! The first key is the active one, which new records are encrypted
+//! with. Each record embeds the id of the key it was encrypted with,
+//! so the remaining keys are only needed to decrypt older records --
+//! to rotate keys, add a new one to the top, and once everything
+//! has been rewritten (e.g. a snapshot has been taken), remove the
+//! old one.
+//!
+//! Each record is also bound to its kind and log index, so that
+//! records can't be swapped for one another or reordered, and once
+//! a keyring is supplied, plaintext records are rejected unless
+//! they're explicitly allowed whilst migrating existing data.
+
+use crate::auth::random_bytes;
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io::Error as IoError;
+use std::io::ErrorKind as IoErrorKind;
+use std::io::Result as IoResult;
+use std::process::Command;
+
+/// Length of a key, in bytes.
+const KEY_LENGTH: usize = 32;
+
+/// Length of a nonce, in bytes.
+const NONCE_LENGTH: usize = 12;
+
+/// A set of keys, one of which is used to encrypt new records.
+pub struct Keyring {
+    active_id: String,
+    ciphers: HashMap<String, ChaCha20Poly1305>,
+}
+
+/// An encrypted record, which is stored as JSON.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Sealed {
+    key_id: String,
+    index: u64,
+    nonce: String,
+    ciphertext: String,
+}
+
+/// Internal API.
+///
+/// A value that is persisted as a record, which is bound to its kind
+/// and the index of the log entry that it is, or includes up to.
+pub(crate) trait Record {
+    /// The kind of record, e.g. `log`.
+    const KIND: &'static str;
+
+    /// Obtains the index of the record's log entry.
+    fn index(&self) -> u64;
+}
+
+impl Sealed {
+    /// Obtains the index of the log entry that the record is bound to.
+    pub fn index(&self) -> u64 {
+        self.index
+    }
+}
+
+impl Keyring {
+    /// Parses a keyring document, failing if it has no keys, or any
+    /// of them are malformed.
+    pub fn parse(data: &str) -> IoResult<Self> {
+        let mut active_id = None;
+        let mut ciphers = HashMap::new();
+
+        for line in data.lines().map(str::trim).filter(|l| !l.is_empty()) {
+            let mut parts = line.split_whitespace();
+
+            let (id, key) = match (parts.next(), parts.next(), parts.next()) {
+                (Some(id), Some(key), None) => (id, key),
+                _ => return Err(invalid_key("expected an id and a key")),
+            };
+
+            let key = hex::decode(key).map_err(|_| invalid_key("keys must be hex-encoded"))?;
+
+            if key.len() != KEY_LENGTH {
+                return Err(invalid_key("keys must be 32 bytes"));
+            }
+
+            if ciphers.contains_key(id) {
+                return Err(invalid_key("key ids must be unique"));
+            }
+
+            ciphers.insert(id.to_string(), ChaCha20Poly1305::new(Key::from_slice(&key)));
+
+            active_id.get_or_insert_with(|| id.to_string());
+        }
+
+        match active_id {
+            Some(active_id) => Ok(Self { active_id, ciphers }),
+            None => Err(invalid_key("the keyring has no keys")),
+        }
+    }
+
+    /// Loads a keyring from the supplied source, which is one of:
+    ///
+    /// * `env:<name>` -- the environment variable with that name
+    /// * `file:<path>` -- the file at that path
+    /// * `command:<command>` -- the output of running that command
+    ///   with `sh`, e.g. a KMS client
+    pub fn load(source: &str) -> IoResult<Self> {
+        let mut parts = source.splitn(2, ':');
+
+        let data = match (parts.next(), parts.next()) {
+            (Some("env"), Some(name)) => {
+                env::var(name).map_err(|_| invalid_key(&format!("{} is not set", name)))?
+            }
+
+            (Some("file"), Some(path)) => fs::read_to_string(path)?,
+
+            (Some("command"), Some(command)) => {
+                let output = Command::new("sh").arg("-c").arg(command).output()?;
+
+                if !output.status.success() {
+                    return Err(IoError::other(format!(
+                        "key command failed with {}",
+                        output.status
+                    )));
+                }
+
+                String::from_utf8(output.stdout)
+                    .map_err(|_| invalid_key("key command output must be UTF-8"))?
+            }
+
+            _ => {
+                return Err(IoError::new(
+                    IoErrorKind::InvalidInput,
+                    "key source must be env:<name>, file:<path> or command:<command>",
+                ));
+            }
+        };
+
+        Self::parse(&data)
+    }
+
+    /// Encrypts the supplied data with the active key, binding it to
+    /// the supplied kind of record and log index.
+    pub fn seal(&self, kind: &str, index: u64, data: &[u8]) -> IoResult<Sealed> {
+        let mut nonce = [0u8; NONCE_LENGTH];
+
+        random_bytes(&mut nonce);
+
+        let ciphertext = self.ciphers[&self.active_id]
+            .encrypt(
+                Nonce::from_slice(&nonce),
+                Payload {
+                    msg: data,
+                    aad: &associated_data(&self.active_id, kind, index),
+                },
+            )
+            .map_err(|_| IoError::other("encryption failed"))?;
+
+        Ok(Sealed {
+            key_id: self.active_id.clone(),
+            index,
+            nonce: hex::encode(nonce),
+            ciphertext: hex::encode(ciphertext),
+        })
+    }
+
+    /// Decrypts the supplied record, failing if its key isn't in
+    /// this keyring, it isn't of the supplied kind, or it has been
+    /// tampered with.
+    pub fn open(&self, kind: &str, sealed: &Sealed) -> IoResult<Vec<u8>> {
+        let cipher = self
+            .ciphers
+            .get(&sealed.key_id)
+            .ok_or_else(|| invalid_data(&format!("unknown encryption key: {}", sealed.key_id)))?;
+
+        let nonce = hex::decode(&sealed.nonce).map_err(|_| invalid_data("invalid nonce"))?;
+
+        if nonce.len() != NONCE_LENGTH {
+            return Err(invalid_data("invalid nonce"));
+        }
+
+        let ciphertext =
+            hex::decode(&sealed.ciphertext).map_err(|_| invalid_data("invalid ciphertext"))?;
+
+        cipher
+            .decrypt(
+                Nonce::from_slice(&nonce),
+                Payload {
+                    msg: &ciphertext,
+                    aad: &associated_data(&sealed.key_id, kind, sealed.index),
+                },
+            )
+            .map_err(|_| invalid_data("decryption failed"))
+    }
+}
+
+/// Internal API.
+///
+/// Determines if the supplied JSON document is an encrypted record
+/// rather than plaintext.
+pub(crate) fn is_sealed(data: &str) -> bool {
+    data.trim_start().starts_with("{\"keyId\"")
+}
+
+/// Internal API.
+///
+/// Decodes the supplied JSON document, decrypting it first if it's
+/// an encrypted record. If a keyring is supplied, plaintext is
+/// rejected unless `allow_plaintext` is set.
+pub(crate) fn decode<T>(data: &str, keyring: Option<&Keyring>, allow_plaintext: bool) -> IoResult<T>
+where
+    T: for<'de> Deserialize<'de> + Record,
+{
+    match (keyring, is_sealed(data)) {
+        (Some(keyring), true) => {
+            let sealed: Sealed = serde_json::from_str(data)?;
+            let value: T = serde_json::from_slice(&keyring.open(T::KIND, &sealed)?)?;
+
+            if value.index() != sealed.index {
+                return Err(invalid_data("record doesn't match its index"));
+            }
+
+            Ok(value)
+        }
+
+        (Some(_), false) if !allow_plaintext => {
+            Err(invalid_data("data isn't encrypted, but keys were supplied"))
+        }
+
+        (None, true) => Err(invalid_data("data is encrypted, but no keys were supplied")),
+
+        _ => Ok(serde_json::from_str(data)?),
+    }
+}
+
+/// Internal API.
+///
+/// Encodes the supplied value as a JSON document, encrypting it
+/// if a keyring is supplied.
+pub(crate) fn encode<T>(value: &T, keyring: Option<&Keyring>) -> IoResult<Vec<u8>>
+where
+    T: Serialize + Record,
+{
+    let data = serde_json::to_vec(value)?;
+
+    match keyring {
+        Some(keyring) => Ok(serde_json::to_vec(&keyring.seal(
+            T::KIND,
+            value.index(),
+            &data,
+        )?)?),
+
+        None => Ok(data),
+    }
+}
+
+/// Builds the data that is authenticated along with a record: the
+/// id of the key it was encrypted with, so that it can't be passed
+/// off as having been encrypted with another key, and its kind and
+/// index, so that it can't be passed off as another record. Key ids
+/// can't contain whitespace, so this is unambiguous.
+fn associated_data(key_id: &str, kind: &str, index: u64) -> Vec<u8> {
+    format!("{}\n{}\n{}", key_id, kind, index).into_bytes()
+}
+
+fn invalid_key(message: &str) -> IoError {
+    IoError::new(IoErrorKind::InvalidInput, message.to_string())
+}
+
+fn invalid_data(message: &str) -> IoError {
+    IoError::new(IoErrorKind::InvalidData, message.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::encryption::*;
+    use std::str;
+
+    const OLD_KEY: &str = "old 000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+    const NEW_KEY: &str = "new 1f1e1d1c1b1a191817161514131211100f0e0d0c0b0a09080706050403020100";
+
+    #[derive(Debug, Deserialize, PartialEq, Serialize)]
+    struct Entry(u64, String);
+
+    impl Record for Entry {
+        const KIND: &'static str = "entry";
+
+        fn index(&self) -> u64 {
+            self.0
+        }
+    }
+
+    #[test]
+    fn test_seal_and_open() {
+        let old = Keyring::parse(OLD_KEY).unwrap();
+        let rotated = Keyring::parse(&format!("{}\n{}", NEW_KEY, OLD_KEY)).unwrap();
+
+        let sealed = old.seal("log", 1, b"hello").unwrap();
+
+        assert_eq!(sealed.key_id, "old");
+        assert_eq!(sealed.index(), 1);
+        assert!(!sealed.ciphertext.contains(&hex::encode("hello")));
+
+        // records encrypted with an old key can still be read after
+        // rotation, but new ones use the new key
+
+        assert_eq!(rotated.open("log", &sealed).unwrap(), b"hello");
+        assert_eq!(rotated.seal("log", 1, b"hello").unwrap().key_id, "new");
+
+        let sealed = rotated.seal("log", 1, b"hello").unwrap();
+
+        assert!(old.open("log", &sealed).is_err());
+
+        // tampering is detected, including claiming another key,
+        // kind or index
+
+        let mut tampered = old.seal("log", 1, b"hello").unwrap();
+        tampered.key_id = "new".to_string();
+
+        assert!(rotated.open("log", &tampered).is_err());
+
+        let mut tampered = old.seal("log", 1, b"hello").unwrap();
+
+        assert!(old.open("snapshot", &tampered).is_err());
+
+        tampered.index = 2;
+
+        assert!(old.open("log", &tampered).is_err());
+    }
+
+    #[test]
+    fn test_encode_and_decode() {
+        let keyring = Keyring::parse(OLD_KEY).unwrap();
+
+        let entry = Entry(1, "hello".to_string());
+
+        let plain = encode(&entry, None).unwrap();
+        let plain = str::from_utf8(&plain).unwrap();
+        let sealed = encode(&entry, Some(&keyring)).unwrap();
+        let sealed = str::from_utf8(&sealed).unwrap();
+
+        assert_eq!(plain, "[1,\"hello\"]");
+        assert!(is_sealed(sealed));
+
+        assert_eq!(decode::<Entry>(plain, None, false).unwrap(), entry);
+        assert_eq!(
+            decode::<Entry>(sealed, Some(&keyring), false).unwrap(),
+            entry
+        );
+
+        // once there are keys, plaintext is only read when migrating,
+        // and encrypted data can't be read without them
+
+        assert!(decode::<Entry>(plain, Some(&keyring), false).is_err());
+        assert_eq!(decode::<Entry>(plain, Some(&keyring), true).unwrap(), entry);
+        assert!(decode::<Entry>(sealed, None, true).is_err());
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        for data in [
+            "",
+            "id",
+            "id 00",
+            "id zz",
+            &format!("{}\n{}", OLD_KEY, OLD_KEY),
+        ]
+        .iter()
+        {
+            assert!(Keyring::parse(data).is_err());
+        }
+
+        assert!(Keyring::load("nope").is_err());
+
+        assert_eq!(
+            Keyring::load(&format!("command:echo {}", NEW_KEY))
+                .unwrap()
+                .active_id,
+            "new"
+        );
+    }
+}
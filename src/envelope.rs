@@ -0,0 +1,100 @@
+//! Provides envelopes, which carry content that is opaque to the
+//! server, e.g. ciphertext from end-to-end encrypted clients.
+//!
+//! An envelope is attached to a message in place of its text. Its
+//! content is base64 in JSON, and is relayed exactly as it was
+//! received. Its type tells the recipient how to decrypt it, and its
+//! device ids which of the sender's and recipient's devices it is
+//! from and for. As the server can't read it, envelopes aren't
+//! moderated or indexed for search.
+
+use crate::chat::Id;
+use serde::{Deserialize, Serialize};
+
+/// The default for the most bytes that an envelope's content can
+/// have once it is decoded.
+pub(crate) const DEFAULT_MAX_ENVELOPE_SIZE: usize = 256 * 1024;
+
+/// Describes how an envelope's content was produced, so that its
+/// recipient knows how to process it.
+#[derive(Copy, Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum EnvelopeType {
+    /// A message encrypted with an established session.
+    Ciphertext,
+
+    /// A message that establishes a session, using a pre-key bundle
+    /// that the sender fetched.
+    PreKeyMessage,
+
+    /// A message encrypted with a sender key, for group chats.
+    SenderKeyMessage,
+
+    /// Content that isn't encrypted, e.g. a decryption error report.
+    Plaintext,
+}
+
+/// Opaque content, along with what's needed to route it.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Envelope {
+    #[serde(rename = "type")]
+    pub(crate) envelope_type: EnvelopeType,
+
+    pub(crate) content: String,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) source_device_id: Option<Id>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) destination_device_id: Option<Id>,
+}
+
+impl Envelope {
+    /// Internal API.
+    ///
+    /// Obtains the size of this envelope's content once decoded, or
+    /// `None` if it isn't base64 or is empty.
+    pub(crate) fn size(&self) -> Option<usize> {
+        base64::decode(&self.content)
+            .ok()
+            .map(|content| content.len())
+            .filter(|size| *size > 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::envelope::*;
+
+    #[test]
+    fn test_size() {
+        let mut envelope = Envelope {
+            envelope_type: EnvelopeType::Ciphertext,
+            content: base64::encode([0u8, 255, 7]),
+            source_device_id: None,
+            destination_device_id: Some(1),
+        };
+
+        assert_eq!(envelope.size(), Some(3));
+
+        envelope.content = "not base64!".to_string();
+
+        assert_eq!(envelope.size(), None);
+
+        envelope.content = String::new();
+
+        assert_eq!(envelope.size(), None);
+
+        assert_eq!(
+            serde_json::to_string(&Envelope {
+                envelope_type: EnvelopeType::PreKeyMessage,
+                content: "AA==".to_string(),
+                source_device_id: None,
+                destination_device_id: None,
+            })
+            .unwrap(),
+            "{\"type\":\"preKeyMessage\",\"content\":\"AA==\"}"
+        );
+    }
+}
@@ -0,0 +1,325 @@
+//! Provides federation, which allows users who are homed on
+//! different servers to chat with each other.
+//!
+//! Each server is configured with its own name, the peers it is
+//! federated with, and which users are homed on each of them. Users
+//! that aren't mapped to a peer are homed on the server itself.
+//! A federated chat is expected to exist, with the same id and
+//! participants, on the home server of each of its participants.
+//!
+//! When a local user adds a message to a chat that has participants
+//! homed on peers, it is relayed to each of their home servers, and
+//! when a local user receives or reads a message whose author is
+//! homed on a peer, the receipt is relayed to the author's home
+//! server, so that delivery status is reconciled across the link.
+//!
+//! Relayed requests are POSTed to a peer's `/federation/relay`
+//! route, and are signed with the secret that the two servers share
+//! as signed requests are, see `signing`, so a relayed request can't
+//! be replayed: its timestamp must be within a window of the peer's
+//! clock, and its nonce can't be reused within that window. A peer
+//! only accepts requests on behalf of users who are homed on the
+//! server that relayed them. Relays are delivered in the background,
+//! like webhooks, so they're retried if the peer is unavailable, and
+//! are signed afresh each time they're attempted.
+
+use crate::chat::{ChatMessage, ChatRequest, Id};
+use crate::http::HttpMethod;
+use crate::http_client;
+use crate::signing::{self, SignedRequest};
+use crate::webhooks::{WebhookQueue, Webhooks};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::io::Error as IoError;
+use std::io::ErrorKind as IoErrorKind;
+use std::io::Result as IoResult;
+use std::sync::{Mutex, PoisonError};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The header that names the server that relayed a request.
+pub const SERVER_HEADER: &str = "X-Federation-Server";
+
+/// The header that carries the time a relayed request was signed at,
+/// in seconds since the Unix epoch.
+pub const TIMESTAMP_HEADER: &str = "X-Federation-Timestamp";
+
+/// The header that carries a relayed request's nonce, which is unique
+/// for each attempt to relay it.
+pub const NONCE_HEADER: &str = "X-Federation-Nonce";
+
+/// The header that carries the signature of a relayed request.
+pub const SIGNATURE_HEADER: &str = "X-Federation-Signature";
+
+/// A server's federation configuration, and the nonces of the
+/// requests that its peers have relayed recently.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Federation {
+    name: String,
+
+    #[serde(default)]
+    peers: HashMap<String, Peer>,
+
+    #[serde(default)]
+    homes: HashMap<Id, String>,
+
+    #[serde(skip)]
+    nonces: Mutex<HashMap<(String, String), u64>>,
+}
+
+/// Internal API.
+///
+/// A server that is federated with this one, and the secret that
+/// signs the requests that are relayed between them.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Peer {
+    url: String,
+    secret: String,
+}
+
+impl Federation {
+    /// Parses the supplied JSON configuration, e.g.
+    ///
+    /// ```text
+    /// {
+    ///   "name": "a",
+    ///   "peers": { "b": { "url": "http://b:8080", "secret": "..." } },
+    ///   "homes": { "2": "b" }
+    /// }
+    /// ```
+    pub fn parse(json: &str) -> IoResult<Self> {
+        let federation: Self = serde_json::from_str(json)?;
+
+        if let Some((user_id, home)) = federation
+            .homes
+            .iter()
+            .find(|(_, home)| **home != federation.name && !federation.peers.contains_key(*home))
+        {
+            return Err(IoError::new(
+                IoErrorKind::InvalidData,
+                format!("user {} is homed on unknown server {}", user_id, home),
+            ));
+        }
+
+        Ok(federation)
+    }
+
+    /// Obtains the name of this server.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Spawns the thread that delivers relayed requests, which uses
+    /// the supplied configuration to retry them.
+    pub fn spawn(self, webhooks: Webhooks) -> IoResult<Relay> {
+        Ok(Relay {
+            federation: self,
+            queue: webhooks.spawn()?,
+        })
+    }
+
+    /// Obtains the name of the peer that the supplied user is homed
+    /// on, or `None` if they're homed on this server.
+    pub fn home(&self, user_id: Id) -> Option<&str> {
+        self.homes
+            .get(&user_id)
+            .filter(|home| **home != self.name)
+            .map(String::as_str)
+    }
+
+    /// Determines if the supplied relayed request, whose client is
+    /// the name of the peer that relayed it, was signed by that peer
+    /// at the supplied time, in seconds since the Unix epoch, and
+    /// hasn't been seen before. Its body must be the supplied request,
+    /// on behalf of a user homed on the peer. Its nonce is remembered
+    /// if it is.
+    pub fn verify(&self, relayed: &SignedRequest, request: &ChatRequest, now: u64) -> bool {
+        let peer = match self.peers.get(relayed.client) {
+            Some(peer) => peer,
+            None => return false,
+        };
+
+        let user_id = match request {
+            ChatRequest::AddMessage { source_user_id, .. } => *source_user_id,
+            ChatRequest::MarkDelivered { user_id, .. } => *user_id,
+            ChatRequest::MarkRead { user_id, .. } => *user_id,
+            _ => return false,
+        };
+
+        if self.home(user_id) != Some(relayed.client) {
+            return false;
+        }
+
+        let timestamp = match signing::fresh_timestamp(relayed, now) {
+            Some(timestamp) => timestamp,
+            None => return false,
+        };
+
+        if !signing::is_signed(relayed, &peer.secret) {
+            return false;
+        }
+
+        let mut nonces = self.nonces.lock().unwrap_or_else(PoisonError::into_inner);
+
+        signing::remember_nonce(&mut nonces, relayed, timestamp, now)
+    }
+}
+
+/// Relays requests to peers, on behalf of a `ChatServer`.
+pub struct Relay {
+    federation: Federation,
+    queue: WebhookQueue,
+}
+
+impl Relay {
+    /// Obtains the configuration that requests are relayed with.
+    pub fn federation(&self) -> &Federation {
+        &self.federation
+    }
+
+    /// Internal API.
+    ///
+    /// Relays the supplied message, which was added to the supplied
+    /// chat, to the supplied peer.
+    pub(crate) fn relay_message(&self, chat_id: Id, message: &ChatMessage, home: &str) {
+        self.relay(
+            &ChatRequest::AddMessage {
+                id: message.id.clone(),
+                chat_id,
+                source_user_id: message.source_user_id,
+                destination_user_id: message.destination_user_id,
+                timestamp: message.timestamp,
+                message: message.message.clone(),
+                attachment_ids: message.attachment_ids.clone(),
+                envelope: message.envelope.clone(),
+                reply_to: message.reply_to.clone(),
+                kind: message.kind,
+                previews: message.previews.clone(),
+            },
+            home,
+        );
+    }
+
+    /// Internal API.
+    ///
+    /// Relays the supplied request to the supplied peer, signing it
+    /// with the secret that they share each time it's attempted, so
+    /// that retries are still within the window of the peer's clock.
+    pub(crate) fn relay(&self, request: &ChatRequest, home: &str) {
+        let peer = match self.federation.peers.get(home) {
+            Some(peer) => peer,
+            None => return,
+        };
+
+        let body = match serde_json::to_string(request) {
+            Ok(body) => body,
+            Err(_) => return,
+        };
+
+        let url = format!("{}/federation/relay", peer.url.trim_end_matches('/'));
+
+        let path = match http_client::path(&url) {
+            Ok(path) => path.to_string(),
+            Err(_) => return,
+        };
+
+        let name = self.federation.name.clone();
+        let secret = peer.secret.clone();
+
+        self.queue.send(&url, body, move |body| {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|now| now.as_secs())
+                .unwrap_or_default();
+
+            let (timestamp, nonce, signature) = signing::sign_request(
+                &secret,
+                HttpMethod::POST.as_str(),
+                &path,
+                body.as_bytes(),
+                now,
+            );
+
+            vec![
+                (SERVER_HEADER.to_string(), name.clone()),
+                (TIMESTAMP_HEADER.to_string(), timestamp),
+                (NONCE_HEADER.to_string(), nonce),
+                (SIGNATURE_HEADER.to_string(), signature),
+            ]
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::federation::*;
+
+    #[test]
+    fn test_verify() {
+        let federation = Federation::parse(
+            "{\"name\":\"a\",\"peers\":{\"b\":{\"url\":\"http://127.0.0.1:1\",\"secret\":\"s\"}},\"homes\":{\"1\":\"a\",\"2\":\"b\"}}",
+        )
+        .unwrap();
+
+        assert_eq!(federation.home(1), None);
+        assert_eq!(federation.home(2), Some("b"));
+        assert_eq!(federation.home(3), None);
+
+        let request = |user_id| ChatRequest::MarkRead {
+            chat_id: 1,
+            message_id: "a".to_string(),
+            user_id,
+            device_id: None,
+        };
+
+        let (timestamp, nonce, signature) =
+            signing::sign_request("s", "POST", "/federation/relay", b"{}", 1000);
+
+        let relayed = |nonce, signature| SignedRequest {
+            client: "b",
+            timestamp: &timestamp,
+            nonce,
+            signature,
+            method: "POST",
+            path: "/federation/relay",
+            body: b"{}",
+        };
+
+        assert!(federation.verify(&relayed(&nonce, &signature), &request(2), 1100));
+
+        // it can't be replayed, whilst its nonce is remembered
+
+        assert!(!federation.verify(&relayed(&nonce, &signature), &request(2), 1100));
+
+        // the user must be homed on the peer, the signature must be
+        // the peer's, and must cover the whole request
+
+        let (_, nonce, signature) =
+            signing::sign_request("s", "POST", "/federation/relay", b"{}", 1000);
+
+        assert!(!federation.verify(&relayed(&nonce, &signature), &request(1), 1100));
+        assert!(!federation.verify(&relayed(&nonce, "0a1b"), &request(2), 1100));
+
+        let mut unknown = relayed(&nonce, &signature);
+        unknown.client = "c";
+
+        assert!(!federation.verify(&unknown, &request(2), 1100));
+
+        let mut tampered = relayed(&nonce, &signature);
+        tampered.body = b"{ }";
+
+        assert!(!federation.verify(&tampered, &request(2), 1100));
+
+        // and its timestamp must be within the window
+
+        assert!(!federation.verify(
+            &relayed(&nonce, &signature),
+            &request(2),
+            1000 + signing::TIMESTAMP_WINDOW + 1
+        ));
+        assert!(federation.verify(&relayed(&nonce, &signature), &request(2), 1000));
+
+        assert!(Federation::parse("{\"name\":\"a\",\"homes\":{\"2\":\"c\"}}").is_err());
+    }
+}
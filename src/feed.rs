@@ -0,0 +1,119 @@
+//! Provides per-user feeds of events, which clients poll to sync
+//! the changes they haven't seen yet, e.g. after reconnecting.
+//!
+//! Each of a user's events is assigned the next sequence number
+//! in their feed, which is also its cursor. Feeds are part of a
+//! `ChatServer`'s state, so they survive restarts, but only the
+//! most recent events are retained.
+
+use crate::chat::ChatEvent;
+use serde::{Deserialize, Serialize};
+
+/// The most events that a feed retains.
+const MAX_FEED_LENGTH: usize = 1000;
+
+/// An event in a user's feed, along with its sequence number. It
+/// is muted if the user shouldn't be notified of it, e.g. because
+/// it's a message in a chat they've muted.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FeedEvent {
+    pub(crate) seq: u64,
+    pub(crate) event: ChatEvent,
+
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub(crate) muted: bool,
+}
+
+/// Internal API.
+///
+/// A user's feed, which retains their most recent events in
+/// sequence order.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct Feed {
+    last_seq: u64,
+    events: Vec<FeedEvent>,
+}
+
+impl Feed {
+    /// Internal API.
+    ///
+    /// Appends the supplied event, discarding the oldest event if
+    /// the feed is full.
+    pub(crate) fn push(&mut self, event: ChatEvent, muted: bool) {
+        self.last_seq += 1;
+
+        self.events.push(FeedEvent {
+            seq: self.last_seq,
+            event,
+            muted,
+        });
+
+        if self.events.len() > MAX_FEED_LENGTH {
+            let excess = self.events.len() - MAX_FEED_LENGTH;
+
+            self.events.drain(..excess);
+        }
+    }
+
+    /// Internal API.
+    ///
+    /// Discards the events that the supplied function rejects,
+    /// returning how many were discarded. Sequence numbers aren't
+    /// reused, so cursors remain valid.
+    pub(crate) fn retain<F>(&mut self, f: F) -> usize
+    where
+        F: Fn(&ChatEvent) -> bool,
+    {
+        let length = self.events.len();
+
+        self.events.retain(|e| f(&e.event));
+
+        length - self.events.len()
+    }
+
+    /// Internal API.
+    ///
+    /// Obtains the sequence number of the latest event that was
+    /// appended, even if it has since been discarded.
+    pub(crate) fn last_seq(&self) -> u64 {
+        self.last_seq
+    }
+
+    /// Internal API.
+    ///
+    /// Obtains the events after the one with the supplied sequence
+    /// number, which may have since been discarded.
+    pub(crate) fn after(&self, seq: u64) -> &[FeedEvent] {
+        let start = match self.events.binary_search_by_key(&seq, |e| e.seq) {
+            Ok(position) => position + 1,
+            Err(position) => position,
+        };
+
+        &self.events[start..]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::feed::*;
+
+    #[test]
+    fn test_push_and_after() {
+        let mut feed = Feed::default();
+
+        for user_id in 0..MAX_FEED_LENGTH as u64 + 2 {
+            feed.push(ChatEvent::ContactListStored { user_id }, false);
+        }
+
+        // the oldest events were discarded, but their sequence
+        // numbers are still valid cursors
+
+        assert_eq!(feed.events.len(), MAX_FEED_LENGTH);
+        assert_eq!(feed.after(0)[0].seq, 3);
+        assert_eq!(feed.after(1)[0].seq, 3);
+        assert_eq!(feed.after(500)[0].seq, 501);
+        assert!(feed.after(feed.last_seq).is_empty());
+    }
+}
@@ -0,0 +1,91 @@
+//! Provides gossip of presence between the nodes of a deployment, so
+//! that a node knows which users are online, or typing, on any of
+//! them rather than only those whose clients it serves. See
+//! `presence`.
+//!
+//! Every `GOSSIP_INTERVAL`, a node sends a digest of the presence it
+//! knows of to each of its peers, which merges it with its own and
+//! replies with the result, which the node then merges in turn. As
+//! merging keeps the latest time that each device was seen, and that
+//! each user was typing, nodes converge on the same presence within a
+//! round, and a device that goes offline, or a user who stops typing,
+//! lapses on every node once its TTL has elapsed.
+//!
+//! Presence concerns every user, so a node only accepts digests from
+//! operators, or requests that passed its Basic auth, as with
+//! replication. A node authenticates with Basic credentials, or by
+//! signing its requests as one of its peers' signing clients whose
+//! user is an operator, or both.
+
+use crate::http::HttpMethod;
+use crate::http_client::ClientAuth;
+use crate::presence::Digest;
+use crate::signing::Signer;
+use std::io::Error as IoError;
+use std::io::Result as IoResult;
+use std::time::Duration;
+
+/// How often a node sends its digest to its peers.
+pub const GOSSIP_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How long each exchange with a peer may take to connect, write,
+/// and read.
+const EXCHANGE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Exchanges digests of presence with a node's peers, over HTTP.
+pub struct Gossip {
+    peer_urls: Vec<String>,
+    auth: ClientAuth,
+}
+
+impl Gossip {
+    /// Creates a gossip with the peers at the supplied URLs, e.g.
+    /// `http://10.0.0.2:8080`.
+    pub fn new<I: IntoIterator<Item = String>>(peer_urls: I) -> Self {
+        Self {
+            peer_urls: peer_urls
+                .into_iter()
+                .map(|url| url.trim_end_matches('/').to_string())
+                .collect(),
+            auth: ClientAuth::default(),
+        }
+    }
+
+    /// Configures the gossip to authenticate with the supplied Basic
+    /// credentials, e.g. `node:secret`, for peers whose `/admin`
+    /// routes require Basic auth.
+    pub fn set_basic_auth(&mut self, credentials: &str) {
+        self.auth.set_basic_auth(credentials);
+    }
+
+    /// Configures the gossip to sign its requests, for peers that
+    /// configure it as a signing client whose user is an operator.
+    pub fn set_signer(&mut self, signer: Signer) {
+        self.auth.set_signer(signer);
+    }
+
+    /// Obtains the URLs of the peers.
+    pub fn peer_urls(&self) -> &[String] {
+        &self.peer_urls
+    }
+
+    /// Sends the supplied digest to the peer at the supplied URL,
+    /// returning the peer's digest once it has merged it.
+    pub fn exchange(&self, peer_url: &str, digest: &Digest) -> IoResult<Digest> {
+        let response = self.auth.request(
+            HttpMethod::POST,
+            &format!("{}/v1/admin/presence", peer_url),
+            Some(&serde_json::to_string(digest)?),
+            EXCHANGE_TIMEOUT,
+        )?;
+
+        if !response.is_success() {
+            return Err(IoError::other(format!(
+                "unexpected status {} from peer",
+                response.status
+            )));
+        }
+
+        Ok(serde_json::from_str(&response.body)?)
+    }
+}
@@ -5,19 +5,23 @@
 //!
 //! * keep-alive
 //! * timeouts
-//! * request size limits
 //! * streaming
-//! * methods beyond GET/POST
+//! * methods beyond GET/POST/PUT/PATCH/DELETE/OPTIONS
 //! * fairness
 
 use mio::net::TcpStream;
 use mio::*;
+use serde::{Deserialize, Serialize};
+use std::any::Any;
+use std::borrow::Cow;
 use std::collections::HashMap;
+use std::fmt;
 use std::io::Error as IoError;
 use std::io::ErrorKind as IoErrorKind;
 use std::io::{Read, Result as IoResult, Write};
+use std::net::SocketAddr;
 use std::str;
-use std::usize;
+use std::time::Duration;
 
 /// Data is written/read from a connection's
 /// socket in chunks of upto this many bytes.
@@ -28,23 +32,68 @@ const CHUNK_SIZE: usize = 8192;
 /// memory usage vs reducing reallocations.
 const HEADERS_INITIAL_SIZE: usize = 8;
 
-#[derive(Debug, PartialEq)]
+/// Default for the largest request body that is read, in bytes.
+const DEFAULT_MAX_BODY_SIZE: usize = 16 * 1024 * 1024;
+
+/// Bytes allowed for a request's line and headers, beyond its
+/// body, before the request is rejected as too large.
+const MAX_HEAD_SIZE: usize = 64 * 1024;
+
+#[derive(Debug)]
 pub enum BodyContent {
     Str(&'static str),
     String(String),
+    Bytes(Vec<u8>),
 }
 
-#[derive(Copy, Clone, Debug, PartialEq)]
+impl BodyContent {
+    /// Obtain this body as bytes, however it's held.
+    pub fn as_bytes(&self) -> &[u8] {
+        match self {
+            BodyContent::Str(str) => str.as_bytes(),
+            BodyContent::String(string) => string.as_bytes(),
+            BodyContent::Bytes(bytes) => bytes,
+        }
+    }
+}
+
+/// Bodies are equal if their bytes are, e.g. JSON that was
+/// serialized to bytes rather than a string.
+impl PartialEq for BodyContent {
+    fn eq(&self, other: &Self) -> bool {
+        self.as_bytes() == other.as_bytes()
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Deserialize, Serialize)]
 pub enum HttpMethod {
     GET,
     POST,
+    PUT,
+    PATCH,
+    DELETE,
+    OPTIONS,
+}
+
+impl HttpMethod {
+    /// Obtain the method's name as it appears on the wire, e.g. "GET"
+    pub fn as_str(self) -> &'static str {
+        match self {
+            HttpMethod::GET => "GET",
+            HttpMethod::POST => "POST",
+            HttpMethod::PUT => "PUT",
+            HttpMethod::PATCH => "PATCH",
+            HttpMethod::DELETE => "DELETE",
+            HttpMethod::OPTIONS => "OPTIONS",
+        }
+    }
 }
 
 /// Represents a fully formed HTTP
 /// request.
 #[derive(Debug, PartialEq)]
 pub struct HttpRequest<'a> {
-    pub(crate) body: Option<&'a str>,
+    pub(crate) body: Option<Cow<'a, [u8]>>,
     pub(crate) headers: Vec<(&'a str, &'a str)>,
     pub(crate) method: HttpMethod,
     pub(crate) path: &'a str,
@@ -52,9 +101,17 @@ pub struct HttpRequest<'a> {
 }
 
 impl<'a> HttpRequest<'a> {
-    /// Get the request body, if one is present.
-    pub fn body(&self) -> Option<&'a str> {
+    /// Get the request body, if one is present. A body that isn't
+    /// UTF-8, e.g. MessagePack, is empty as text.
+    pub fn body(&self) -> Option<&str> {
         self.body
+            .as_ref()
+            .map(|body| str::from_utf8(body).unwrap_or_default())
+    }
+
+    /// Get the request body's bytes, if one is present.
+    pub fn body_bytes(&self) -> Option<&[u8]> {
+        self.body.as_ref().map(|body| body.as_ref())
     }
 
     /// Get the value of the specified header, if present.
@@ -63,7 +120,7 @@ impl<'a> HttpRequest<'a> {
 
         for (n, v) in self.headers.iter() {
             if &name == n {
-                return Some(&v);
+                return Some(v);
             }
         }
 
@@ -80,6 +137,27 @@ impl<'a> HttpRequest<'a> {
         self.path
     }
 
+    /// Get the value of the specified query parameter, if present. If
+    /// it's present more than once, the first value is obtained, and
+    /// values aren't percent-decoded.
+    pub fn query<S: AsRef<str>>(&self, name: S) -> Option<&'a str> {
+        let name = name.as_ref();
+        let query = self.path.split_once('?')?.1;
+
+        query
+            .split('&')
+            .map(|pair| {
+                let mut parts = pair.splitn(2, '=');
+
+                (
+                    parts.next().unwrap_or_default(),
+                    parts.next().unwrap_or_default(),
+                )
+            })
+            .find(|(n, _)| *n == name)
+            .map(|(_, v)| v)
+    }
+
     /// Obtain the version string for this request, e.g. "HTTP/1.1"
     pub fn version(&self) -> &'a str {
         self.version
@@ -91,18 +169,41 @@ impl<'a> HttpRequest<'a> {
     ///
     /// `Ok(None)` means we haven't received enough data yet
     /// `Ok(Some(_))` means we've successfully parsed the request
-    /// `Err(_)` means that the parsing has failed and will never succeed
-    fn parse(data: &str, done: bool) -> IoResult<Option<HttpRequest>> {
+    /// `Err(_)` means that the parsing has failed and will never succeed,
+    /// which is a `FileTooLarge` error if the body, or the length that
+    /// its `Content-Length` declares, exceeds `max_body_size`
+    fn parse(data: &[u8], done: bool, max_body_size: usize) -> IoResult<Option<HttpRequest<'_>>> {
         // ref: https://www.w3.org/Protocols/rfc2616/rfc2616-sec5.html
 
+        // the request line and headers are text, but the body may be
+        // binary, e.g. MessagePack
+
+        let head_len = data
+            .windows(4)
+            .position(|window| window == b"\r\n\r\n")
+            .map_or(data.len(), |i| i + 4);
+
+        let head = match str::from_utf8(&data[..head_len]) {
+            Ok(head) => head,
+
+            Err(ref e) if !done && e.error_len().is_none() => return Ok(None),
+
+            Err(_) => {
+                return Err(IoError::new(
+                    IoErrorKind::InvalidInput,
+                    "cannot parse request",
+                ))
+            }
+        };
+
         enum State {
             ReadingRequestLine,
             ReadingHeaderLines,
             DoneReadingHeaderLines,
         }
 
-        let mut body = "";
-        let mut body_len = None;
+        let mut body: &[u8] = &[];
+        let mut body_len: Option<usize> = None;
         let mut body_start = 0;
         let mut headers: Vec<(&str, &str)> = Vec::with_capacity(HEADERS_INITIAL_SIZE);
         let mut method: Option<HttpMethod> = None;
@@ -110,7 +211,7 @@ impl<'a> HttpRequest<'a> {
         let mut state = State::ReadingRequestLine;
         let mut version: Option<&str> = None;
 
-        for line in data.split("\r\n") {
+        for line in head.split("\r\n") {
             body_start += line.len() + 2; // 2 = \r\n
 
             match state {
@@ -123,6 +224,10 @@ impl<'a> HttpRequest<'a> {
                                 method = match section {
                                     "GET" => Some(HttpMethod::GET),
                                     "POST" => Some(HttpMethod::POST),
+                                    "PUT" => Some(HttpMethod::PUT),
+                                    "PATCH" => Some(HttpMethod::PATCH),
+                                    "DELETE" => Some(HttpMethod::DELETE),
+                                    "OPTIONS" => Some(HttpMethod::OPTIONS),
                                     _ => None,
                                 }
                             }
@@ -174,6 +279,18 @@ impl<'a> HttpRequest<'a> {
             body = &data[body_start..];
         }
 
+        // the declared length is checked as soon as the headers have
+        // been read, so an oversized body is rejected before it's read
+
+        if let State::DoneReadingHeaderLines = state {
+            if body_len.is_some_and(|length| length > max_body_size) || body.len() > max_body_size {
+                return Err(IoError::new(
+                    IoErrorKind::FileTooLarge,
+                    format!("request bodies must be at most {} bytes", max_body_size),
+                ));
+            }
+        }
+
         match (state, method, path, version) {
             (State::ReadingRequestLine, _, _, _) if !done => Ok(None),
 
@@ -199,11 +316,33 @@ impl<'a> HttpRequest<'a> {
                 }))
             }
 
+            // a DELETE or OPTIONS only has a body if its length is supplied
+            (
+                State::DoneReadingHeaderLines,
+                Some(method @ HttpMethod::DELETE),
+                Some(path),
+                Some(version),
+            )
+            | (
+                State::DoneReadingHeaderLines,
+                Some(method @ HttpMethod::OPTIONS),
+                Some(path),
+                Some(version),
+            ) if body_len.is_none() => Ok(Some(HttpRequest {
+                body: None,
+                headers,
+                method,
+                path,
+                version,
+            })),
+
+            // without a length, a body is whatever has been received so
+            // far, rather than waiting for the peer to stop sending
             (State::DoneReadingHeaderLines, Some(method), Some(path), Some(version))
-                if done || body_len.map_or(false, |l: usize| body.len() == l) =>
+                if done || body_len.is_none_or(|length| length == body.len()) =>
             {
                 Ok(Some(HttpRequest {
-                    body: Some(body),
+                    body: Some(Cow::Borrowed(body)),
                     headers,
                     method,
                     path,
@@ -221,14 +360,51 @@ impl<'a> HttpRequest<'a> {
     }
 }
 
+/// The state of a stream, which identifies what has been streamed
+/// to it so far. It's opaque to the `HttpServer`, and is only
+/// interpreted by its stream handler.
+pub struct StreamState(Box<dyn Any + Send>);
+
+impl StreamState {
+    /// Creates a new `StreamState` that holds the supplied value.
+    pub fn new<T: Any + Send>(state: T) -> Self {
+        Self(Box::new(state))
+    }
+
+    /// Obtain the value this holds, if it's a `T`.
+    pub fn downcast_ref<T: Any>(&self) -> Option<&T> {
+        self.0.downcast_ref()
+    }
+
+    /// Obtain the value this holds mutably, if it's a `T`.
+    pub fn downcast_mut<T: Any>(&mut self) -> Option<&mut T> {
+        self.0.downcast_mut()
+    }
+}
+
+impl fmt::Debug for StreamState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("StreamState")
+    }
+}
+
+/// States are only equal to themselves, since the values they hold
+/// can't be compared.
+impl PartialEq for StreamState {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
 /// Represents an `HttpResponse`
 #[derive(Debug, PartialEq)]
 pub struct HttpResponse<'a> {
     body: BodyContent,
     status: u16,
     status_text: &'static str,
-    headers: Vec<(&'static str, &'static str)>,
+    headers: Vec<(&'static str, Cow<'static, str>)>,
     version: &'a str,
+    stream: Option<StreamState>,
 }
 
 impl<'a> HttpResponse<'a> {
@@ -244,18 +420,101 @@ impl<'a> HttpResponse<'a> {
             body,
             status,
             status_text: match status {
+                101 => "Switching Protocols",
                 200 => "OK",
+                204 => "No Content",
+                304 => "Not Modified",
                 400 => "Bad Request",
+                401 => "Unauthorized",
+                403 => "Forbidden",
                 404 => "Not Found",
+                405 => "Method Not Allowed",
+                406 => "Not Acceptable",
+                410 => "Gone",
+                413 => "Payload Too Large",
+                415 => "Unsupported Media Type",
+                429 => "Too Many Requests",
+                500 => "Internal Server Error",
                 501 => "Not Implemented",
+                503 => "Service Unavailable",
                 _ => "",
             },
-            headers: headers.to_vec(),
+            headers: headers
+                .iter()
+                .map(|(name, value)| (*name, Cow::Borrowed(*value)))
+                .collect(),
             version,
+            stream: None,
+        }
+    }
+
+    /// Adds the supplied header to this response, whose value may
+    /// be computed, e.g. a list of methods.
+    pub fn add_header<V: Into<Cow<'static, str>>>(&mut self, name: &'static str, value: V) {
+        self.headers.push((name, value.into()));
+    }
+
+    /// Replaces the value of the supplied header, adding it if this
+    /// response doesn't have it.
+    pub fn set_header<V: Into<Cow<'static, str>>>(&mut self, name: &'static str, value: V) {
+        match self.headers.iter_mut().find(|(n, _)| *n == name) {
+            Some((_, v)) => *v = value.into(),
+            None => self.add_header(name, value),
         }
     }
 
-    fn unparse(&self) -> String {
+    /// Replaces the body of this response, e.g. with an encoding of
+    /// it that the client prefers.
+    pub fn set_body(&mut self, body: BodyContent) {
+        self.body = body;
+    }
+
+    /// Makes this response a stream, whose body is followed by data
+    /// that is obtained later, e.g. server-sent events. Its connection
+    /// remains open once its body has been written, and the supplied
+    /// state, which identifies what has been streamed so far, is
+    /// supplied to the `HttpServer`'s stream handler to obtain that
+    /// data.
+    pub fn set_stream(&mut self, state: StreamState) {
+        self.stream = Some(state);
+    }
+
+    /// Obtain the state of this response's stream, if it is one.
+    pub fn stream(&self) -> Option<&StreamState> {
+        self.stream.as_ref()
+    }
+
+    /// Takes the state of this response's stream, if it is one,
+    /// e.g. to feed it without an `HttpServer`.
+    pub fn take_stream(&mut self) -> Option<StreamState> {
+        self.stream.take()
+    }
+
+    /// Get the value of the specified header, if present.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| *n == name)
+            .map(|(_, v)| v.as_ref())
+    }
+
+    /// Obtain the status code for this response
+    pub fn status(&self) -> u16 {
+        self.status
+    }
+
+    /// Obtain the body of this response, which is empty if it isn't
+    /// UTF-8, e.g. MessagePack.
+    pub fn body(&self) -> &str {
+        str::from_utf8(self.body_bytes()).unwrap_or_default()
+    }
+
+    /// Obtain the body of this response as bytes
+    pub fn body_bytes(&self) -> &[u8] {
+        self.body.as_bytes()
+    }
+
+    fn unparse(&self) -> Vec<u8> {
         let mut resp = String::new();
 
         resp.push_str(self.version);
@@ -272,90 +531,191 @@ impl<'a> HttpResponse<'a> {
             resp.push_str("\r\n");
         }
 
-        match &self.body {
-            BodyContent::Str(s) => {
-                resp.push_str(&format!("Content-Length: {}\r\n", &s.len()));
-            }
+        let body = self.body_bytes();
 
-            BodyContent::String(s) => {
-                resp.push_str(&format!("Content-Length: {}\r\n", &s.len()));
-            }
+        // a 204 or 304 mustn't have a body, so it doesn't declare its
+        // length, and a stream's length isn't known until it's closed
+
+        if self.status != 204 && self.status != 304 && self.stream.is_none() {
+            resp.push_str(&format!("Content-Length: {}\r\n", body.len()));
         }
 
-        resp.push_str("Connection: Close\r\n\r\n");
+        // a response that upgrades its connection, e.g. to a
+        // WebSocket, declares so instead of it being closed
 
-        match &self.body {
-            BodyContent::Str(str) => {
-                resp.push_str(str);
-            }
-
-            BodyContent::String(string) => {
-                resp.push_str(&string);
-            }
+        if self.header("Connection").is_none() {
+            resp.push_str("Connection: Close\r\n");
         }
 
+        resp.push_str("\r\n");
+
+        let mut resp = resp.into_bytes();
+
+        resp.extend_from_slice(body);
+
         resp
     }
 }
 
+/// Describes something that happened to a connection, which
+/// is supplied to the tracer of an `HttpServer` if it has one.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum TraceEvent {
+    Accepted,
+    Readable,
+    Writable,
+    BytesRead(usize),
+    BytesWritten(usize),
+    RequestParsed,
+    RequestIncomplete,
+    RequestInvalid,
+    RequestTooLarge,
+    StartedWriting,
+    StartedStreaming,
+    Closed,
+    Reset,
+}
+
+impl fmt::Display for TraceEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TraceEvent::Accepted => write!(f, "accepted"),
+            TraceEvent::Readable => write!(f, "readable"),
+            TraceEvent::Writable => write!(f, "writable"),
+            TraceEvent::BytesRead(n) => write!(f, "read {} bytes", n),
+            TraceEvent::BytesWritten(n) => write!(f, "wrote {} bytes", n),
+            TraceEvent::RequestParsed => write!(f, "parsed request"),
+            TraceEvent::RequestIncomplete => write!(f, "request incomplete"),
+            TraceEvent::RequestInvalid => write!(f, "request invalid"),
+            TraceEvent::RequestTooLarge => write!(f, "request too large"),
+            TraceEvent::StartedWriting => write!(f, "reading -> writing"),
+            TraceEvent::StartedStreaming => write!(f, "writing -> streaming"),
+            TraceEvent::Closed => write!(f, "closed"),
+            TraceEvent::Reset => write!(f, "reset"),
+        }
+    }
+}
+
+/// Produces the response to each request, given the address of its
+/// peer, if known.
+type Handler = Box<dyn FnMut(HttpRequest, Option<SocketAddr>) -> HttpResponse>;
+
+/// Feeds a stream, given its state and the data received from its
+/// peer that it hasn't consumed yet, returning the data to write to
+/// it.
+type StreamHandler = Box<dyn FnMut(&mut Option<StreamState>, &mut Vec<u8>) -> Vec<u8>>;
+
+/// Receives each `TraceEvent` along with the connection's token and
+/// the address of its peer, if known.
+type Tracer = Box<dyn FnMut(Token, Option<SocketAddr>, TraceEvent)>;
+
 #[derive(PartialEq)]
 enum ConnectionMode {
     Reading,
     Writing,
+    Streaming,
 }
 
 struct Connection {
     buffer: Vec<u8>,
     buffer_idx: usize,
+    state: Option<StreamState>,
+    input: Vec<u8>,
     mode: ConnectionMode,
+    peer: Option<SocketAddr>,
     stream: TcpStream,
 }
 
 pub struct HttpServer {
     connections: HashMap<Token, Connection>,
-    handler: Box<FnMut(HttpRequest) -> HttpResponse>,
+    handler: Handler,
+    max_body_size: usize,
+    stream_handler: Option<StreamHandler>,
+    tracer: Option<Tracer>,
 }
 
 /// Provides a simple HTTP implementation that is driven
 /// by calls to `connection_accepted`, `connection_writable`,
 /// and `connection_readable`.
 impl HttpServer {
-    /// Creates a new `HttpServer` that passes incoming requests
-    /// to the suplied handler and responds with the produced
-    /// response.
-    pub fn new<F: FnMut(HttpRequest) -> HttpResponse>(handler: F) -> Self
+    /// Creates a new `HttpServer` that passes incoming requests,
+    /// along with the address of their peer if known, to the
+    /// suplied handler and responds with the produced response.
+    pub fn new<F>(handler: F) -> Self
     where
-        F: 'static,
+        F: FnMut(HttpRequest, Option<SocketAddr>) -> HttpResponse + 'static,
     {
         Self {
             connections: HashMap::new(),
             handler: Box::new(handler),
+            max_body_size: DEFAULT_MAX_BODY_SIZE,
+            stream_handler: None,
+            tracer: None,
         }
     }
 
+    /// Configures the largest request body that is read, in bytes.
+    /// Larger requests are responded to with a 413, without reading
+    /// the rest of their body if its length was declared.
+    pub fn set_max_body_size(&mut self, max_body_size: usize) {
+        self.max_body_size = max_body_size;
+    }
+
+    /// Supplies the handler that feeds streams, i.e. connections
+    /// whose responses are streams. It's called with a stream's
+    /// state, and the data received from its peer that it hasn't
+    /// consumed yet, which it drains as it consumes it, e.g. WebSocket
+    /// frames. It returns the data to write to the stream, and takes
+    /// the state if the stream should be closed once that's written.
+    pub fn set_stream_handler<F>(&mut self, stream_handler: F)
+    where
+        F: FnMut(&mut Option<StreamState>, &mut Vec<u8>) -> Vec<u8> + 'static,
+    {
+        self.stream_handler = Some(Box::new(stream_handler));
+    }
+
+    /// Supplies a tracer that is invoked for everything that
+    /// happens to each connection -- readiness events, bytes
+    /// transferred, parse outcomes, and state transitions.
+    pub fn set_tracer<F>(&mut self, tracer: F)
+    where
+        F: FnMut(Token, Option<SocketAddr>, TraceEvent) + 'static,
+    {
+        self.tracer = Some(Box::new(tracer));
+    }
+
     /// A new connection was accepted and will now be managed by this
     /// instance.
     ///
     /// The connection's status can be queried by using the `is_connection_active`
     /// method.
     pub fn connection_accepted(&mut self, token: Token, stream: TcpStream) {
-        self.connections.insert(
-            token,
-            Connection {
-                buffer: Vec::new(),
-                buffer_idx: 0,
-                mode: ConnectionMode::Reading,
-                stream,
-            },
-        );
+        let cx = Connection {
+            buffer: Vec::new(),
+            buffer_idx: 0,
+            state: None,
+            input: Vec::new(),
+            mode: ConnectionMode::Reading,
+            peer: stream.peer_addr().ok(),
+            stream,
+        };
+
+        Self::trace(&mut self.tracer, token, &cx, TraceEvent::Accepted);
+
+        self.connections.insert(token, cx);
     }
 
     /// Signals to the server that data can now be written
     /// to the specified connection.
     pub fn connection_writable(&mut self, token: Token) {
         if let Some(cx) = self.connections.get_mut(&token) {
-            if cx.mode == ConnectionMode::Writing && Self::perform_writes(cx) {
-                self.connections.remove(&token);
+            Self::trace(&mut self.tracer, token, cx, TraceEvent::Writable);
+
+            if cx.mode == ConnectionMode::Writing
+                && Self::perform_traced_writes(&mut self.tracer, token, cx)
+                && Self::finish_writing(&mut self.tracer, token, cx)
+            {
+                self.close(token);
             }
         }
     }
@@ -364,34 +724,131 @@ impl HttpServer {
     /// from the connection.
     pub fn connection_readable(&mut self, token: Token) {
         if let Some(cx) = self.connections.get_mut(&token) {
-            if let ConnectionMode::Reading { .. } = cx.mode {
-                match Self::perform_reads(cx) {
+            Self::trace(&mut self.tracer, token, cx, TraceEvent::Readable);
+
+            if let ConnectionMode::Reading = cx.mode {
+                let start = cx.buffer_idx;
+
+                match Self::perform_reads(cx, self.max_body_size + MAX_HEAD_SIZE) {
                     Ok(done) => {
+                        let read = cx.buffer_idx - start;
+
+                        Self::trace(&mut self.tracer, token, cx, TraceEvent::BytesRead(read));
+
                         if done {
                             cx.mode = ConnectionMode::Writing;
                         }
 
-                        Self::try_parse_request(&mut self.handler, cx);
-
-                        if cx.mode == ConnectionMode::Writing && Self::perform_writes(cx) {
-                            self.connections.remove(&token);
+                        Self::try_parse_request(
+                            &mut self.handler,
+                            &mut self.tracer,
+                            self.max_body_size,
+                            token,
+                            cx,
+                        );
+
+                        if cx.mode == ConnectionMode::Writing {
+                            Self::trace(&mut self.tracer, token, cx, TraceEvent::StartedWriting);
+
+                            if Self::perform_traced_writes(&mut self.tracer, token, cx)
+                                && Self::finish_writing(&mut self.tracer, token, cx)
+                            {
+                                self.close(token);
+                            }
                         }
                     }
 
                     Err(_) => {
                         cx.mode = ConnectionMode::Writing;
-                        self.connections.remove(&token);
+                        self.close(token);
                     }
                 }
+            } else if cx.state.is_some() {
+                // a stream's peer may send it data, e.g. WebSocket
+                // frames, which it is fed
+
+                let start = cx.input.len();
+                let closed = Self::perform_stream_reads(cx);
+                let read = cx.input.len() - start;
+
+                Self::trace(&mut self.tracer, token, cx, TraceEvent::BytesRead(read));
+
+                if closed {
+                    self.close(token);
+                } else if read > 0 {
+                    self.feed_stream(token);
+                }
             }
         }
     }
 
+    /// Feeds every stream, e.g. because there may be more data for
+    /// them, returning the tokens of those that were closed.
+    pub fn poll_streams(&mut self) -> Vec<Token> {
+        self.streams()
+            .into_iter()
+            .filter(|token| self.feed_stream(*token))
+            .collect()
+    }
+
+    /// Closes every stream, e.g. because the server is shutting
+    /// down, returning their tokens.
+    pub fn close_streams(&mut self) -> Vec<Token> {
+        let streams = self.streams();
+
+        for token in streams.iter() {
+            self.close(*token);
+        }
+
+        streams
+    }
+
     /// Determines if the connection is active.
     pub fn is_connection_active(&self, token: Token) -> bool {
         self.connections.contains_key(&token)
     }
 
+    /// Obtain the number of connections that are being managed.
+    pub fn active_connections(&self) -> usize {
+        self.connections.len()
+    }
+
+    /// Forcibly resets every connection that is being managed,
+    /// i.e. closing them without waiting for any pending data
+    /// to be sent, and returns how many there were.
+    pub fn reset_connections(&mut self) -> usize {
+        let reset = self.connections.len();
+
+        for (token, cx) in self.connections.drain() {
+            // a zero linger causes an RST to be sent when the
+            // socket is closed, rather than the usual FIN
+
+            let _ = cx.stream.set_linger(Some(Duration::from_secs(0)));
+
+            Self::trace(&mut self.tracer, token, &cx, TraceEvent::Reset);
+        }
+
+        reset
+    }
+
+    /// Internal API.
+    ///
+    /// Stop managing the connection, closing it.
+    fn close(&mut self, token: Token) {
+        if let Some(cx) = self.connections.remove(&token) {
+            Self::trace(&mut self.tracer, token, &cx, TraceEvent::Closed);
+        }
+    }
+
+    /// Internal API.
+    ///
+    /// Supplies the event to the tracer, if there is one.
+    fn trace(tracer: &mut Option<Tracer>, token: Token, cx: &Connection, event: TraceEvent) {
+        if let Some(tracer) = tracer {
+            tracer(token, cx.peer, event);
+        }
+    }
+
     /// Internal API.
     ///
     /// Reads all data available from the connection,
@@ -401,8 +858,11 @@ impl HttpServer {
     /// This should only be called if it's known that
     /// data is available -- i.e. an MIO event has
     /// been received.
-    fn perform_reads(cx: &mut Connection) -> IoResult<bool> {
-        loop {
+    fn perform_reads(cx: &mut Connection, limit: usize) -> IoResult<bool> {
+        // reading stops once more than `limit` bytes have been read, as
+        // the request is then rejected regardless of what follows
+
+        while cx.buffer_idx <= limit {
             if cx.buffer.len() - cx.buffer_idx == 0 {
                 cx.buffer.resize(cx.buffer.len() + CHUNK_SIZE, 0);
             }
@@ -426,7 +886,130 @@ impl HttpServer {
             }
         }
 
-        Ok(true)
+        // the peer may still be sending, e.g. a body that spans several
+        // reads, so the request is parsed again once there's more data
+
+        Ok(false)
+    }
+
+    /// Internal API.
+    ///
+    /// Obtains the tokens of the connections whose responses are
+    /// streams.
+    fn streams(&self) -> Vec<Token> {
+        self.connections
+            .iter()
+            .filter(|(_, cx)| cx.mode != ConnectionMode::Reading && cx.state.is_some())
+            .map(|(token, _)| *token)
+            .collect()
+    }
+
+    /// Internal API.
+    ///
+    /// Supplies the stream's state and unconsumed input to the
+    /// stream handler, and writes the data it returns, returning
+    /// whether the stream was closed.
+    fn feed_stream(&mut self, token: Token) -> bool {
+        let cx = match self.connections.get_mut(&token) {
+            Some(cx) => cx,
+            None => return false,
+        };
+
+        let data = match self.stream_handler {
+            Some(ref mut stream_handler) => stream_handler(&mut cx.state, &mut cx.input),
+
+            None => {
+                cx.input.clear();
+
+                Vec::new()
+            }
+        };
+
+        if data.is_empty() && cx.state.is_some() {
+            return false;
+        }
+
+        cx.buffer.drain(..cx.buffer_idx);
+        cx.buffer.extend_from_slice(&data);
+        cx.buffer_idx = 0;
+        cx.mode = ConnectionMode::Writing;
+
+        if Self::perform_traced_writes(&mut self.tracer, token, cx)
+            && Self::finish_writing(&mut self.tracer, token, cx)
+        {
+            self.close(token);
+
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Internal API.
+    ///
+    /// Reads all data available from a stream's connection into its
+    /// input, returning whether the read side has been closed, or
+    /// has failed.
+    fn perform_stream_reads(cx: &mut Connection) -> bool {
+        let mut buffer = [0; CHUNK_SIZE];
+
+        loop {
+            match cx.stream.read(&mut buffer) {
+                Ok(0) => {
+                    return true;
+                }
+
+                Ok(bytes_read) => {
+                    cx.input.extend_from_slice(&buffer[..bytes_read]);
+                }
+
+                Err(ref e) if e.kind() == IoErrorKind::WouldBlock => {
+                    return false;
+                }
+
+                Err(_) => {
+                    return true;
+                }
+            }
+        }
+    }
+
+    /// Internal API.
+    ///
+    /// Called once all of a connection's data has been written, and
+    /// returns whether it should be closed, which it isn't if its
+    /// response is a stream that is still open. Instead, it awaits
+    /// more data.
+    fn finish_writing(tracer: &mut Option<Tracer>, token: Token, cx: &mut Connection) -> bool {
+        if cx.state.is_none() {
+            return true;
+        }
+
+        if cx.mode != ConnectionMode::Streaming {
+            cx.mode = ConnectionMode::Streaming;
+
+            Self::trace(tracer, token, cx, TraceEvent::StartedStreaming);
+        }
+
+        false
+    }
+
+    /// Internal API.
+    ///
+    /// Performs writes as per `perform_writes`, supplying
+    /// the number of bytes written to the tracer.
+    fn perform_traced_writes(
+        tracer: &mut Option<Tracer>,
+        token: Token,
+        cx: &mut Connection,
+    ) -> bool {
+        let start = cx.buffer_idx;
+        let done = Self::perform_writes(cx);
+        let written = cx.buffer_idx - start;
+
+        Self::trace(tracer, token, cx, TraceEvent::BytesWritten(written));
+
+        done
     }
 
     /// Internal API.
@@ -466,34 +1049,72 @@ impl HttpServer {
     /// the request and must produce a response. The
     /// connection will then be switched into writing
     /// mode and begin writing data.
-    fn try_parse_request(handler: &mut FnMut(HttpRequest) -> HttpResponse, cx: &mut Connection) {
-        if let Ok(req) = str::from_utf8(&cx.buffer[0..cx.buffer_idx]) {
-            match HttpRequest::parse(req, cx.mode == ConnectionMode::Writing) {
-                Ok(Some(req)) => {
-                    let response = handler(req);
-
-                    cx.buffer = response.unparse().as_bytes().to_vec();
-                    cx.buffer_idx = 0;
-                    cx.mode = ConnectionMode::Writing;
-                }
+    fn try_parse_request(
+        handler: &mut dyn FnMut(HttpRequest, Option<SocketAddr>) -> HttpResponse,
+        tracer: &mut Option<Tracer>,
+        max_body_size: usize,
+        token: Token,
+        cx: &mut Connection,
+    ) {
+        let done = cx.mode == ConnectionMode::Writing;
+        let data = &cx.buffer[0..cx.buffer_idx];
+
+        // a request that is still incomplete once reading has stopped,
+        // see `perform_reads`, is just as large
+
+        let result = match HttpRequest::parse(data, done, max_body_size) {
+            Ok(None) if data.len() > max_body_size + MAX_HEAD_SIZE => Err(IoError::new(
+                IoErrorKind::FileTooLarge,
+                "request is too large",
+            )),
 
-                Ok(None) => {
-                    // not ready yet
-                }
+            result => result,
+        };
 
-                Err(_) => {
-                    let response = HttpResponse {
-                        body: BodyContent::Str(""),
-                        status: 400,
-                        status_text: "Bad Request",
-                        headers: Vec::new(),
-                        version: "HTTP/1.1",
-                    };
-
-                    cx.buffer = response.unparse().as_bytes().to_vec();
-                    cx.buffer_idx = 0;
-                    cx.mode = ConnectionMode::Writing;
-                }
+        match result {
+            Ok(Some(req)) => {
+                Self::trace(tracer, token, cx, TraceEvent::RequestParsed);
+
+                let response = handler(req, cx.peer);
+                let data = response.unparse();
+
+                cx.state = response.stream;
+                cx.buffer = data;
+                cx.buffer_idx = 0;
+                cx.mode = ConnectionMode::Writing;
+            }
+
+            Ok(None) => {
+                // not ready yet
+
+                Self::trace(tracer, token, cx, TraceEvent::RequestIncomplete);
+            }
+
+            Err(ref e) if e.kind() == IoErrorKind::FileTooLarge => {
+                Self::trace(tracer, token, cx, TraceEvent::RequestTooLarge);
+
+                let response = HttpResponse::new("HTTP/1.1", 413, &[], BodyContent::Str(""));
+
+                cx.buffer = response.unparse();
+                cx.buffer_idx = 0;
+                cx.mode = ConnectionMode::Writing;
+            }
+
+            Err(_) => {
+                Self::trace(tracer, token, cx, TraceEvent::RequestInvalid);
+
+                let response = HttpResponse {
+                    body: BodyContent::Str(""),
+                    status: 400,
+                    status_text: "Bad Request",
+                    headers: Vec::new(),
+                    version: "HTTP/1.1",
+                    stream: None,
+                };
+
+                cx.buffer = response.unparse();
+                cx.buffer_idx = 0;
+                cx.mode = ConnectionMode::Writing;
             }
         }
     }
@@ -505,20 +1126,20 @@ mod tests {
 
     #[test]
     fn test_invalid() {
-        assert!(HttpRequest::parse("", true).is_err(),);
+        assert!(HttpRequest::parse(b"", true, usize::MAX).is_err(),);
 
-        assert!(HttpRequest::parse("GET /chats\r\n", false).is_err(),);
+        assert!(HttpRequest::parse(b"GET /chats\r\n", false, usize::MAX).is_err(),);
     }
 
     #[test]
     fn test_incomplete() {
-        assert_eq!(HttpRequest::parse("", false).unwrap(), None)
+        assert_eq!(HttpRequest::parse(b"", false, usize::MAX).unwrap(), None)
     }
 
     #[test]
     fn test_http_request_parse_get() {
         assert_eq!(
-            HttpRequest::parse("GET /chats/1/messages HTTP/1.0\r\nMy-Header: hello!\r\nMy-Other-Header: goodbye!\r\n\r\n", true)
+            HttpRequest::parse(b"GET /chats/1/messages HTTP/1.0\r\nMy-Header: hello!\r\nMy-Other-Header: goodbye!\r\n\r\n", true, usize::MAX)
                 .unwrap(),
 
             Some(HttpRequest {
@@ -537,14 +1158,191 @@ mod tests {
     #[test]
     fn test_http_request_parse_post() {
         assert_eq!(
-            HttpRequest::parse("POST /chats/1/messages HTTP/1.1\r\n\r\ntest\r\n", true).unwrap(),
+            HttpRequest::parse(
+                b"POST /chats/1/messages HTTP/1.1\r\n\r\ntest\r\n",
+                true,
+                usize::MAX
+            )
+            .unwrap(),
             Some(HttpRequest {
-                body: Some("test\r\n"),
+                body: Some(Cow::Borrowed(b"test\r\n")),
                 headers: Vec::new(),
                 method: HttpMethod::POST,
                 path: "/chats/1/messages",
                 version: "HTTP/1.1"
             })
         );
+
+        // bodies may be binary, e.g. MessagePack, but the rest of the
+        // request may not
+
+        let request = HttpRequest::parse(
+            b"POST /chats HTTP/1.1\r\nContent-Length: 3\r\n\r\n\x82\xa2\xff",
+            false,
+            usize::MAX,
+        )
+        .unwrap()
+        .unwrap();
+
+        assert_eq!(request.body_bytes(), Some(&b"\x82\xa2\xff"[..]));
+        assert_eq!(request.body(), Some(""));
+
+        assert!(HttpRequest::parse(b"POST /ch\xffts HTTP/1.1\r\n\r\n", false, usize::MAX).is_err());
+        assert_eq!(
+            HttpRequest::parse(b"POST /ch\xc3", false, usize::MAX).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_body_size() {
+        // a declared length that's too large is rejected before the
+        // body has been received
+
+        let error = HttpRequest::parse(
+            b"POST /blobs HTTP/1.1\r\nContent-Length: 5\r\n\r\n",
+            false,
+            4,
+        )
+        .unwrap_err();
+
+        assert_eq!(error.kind(), IoErrorKind::FileTooLarge);
+
+        // as is a body without a length that turns out to be too large
+
+        let error = HttpRequest::parse(b"POST /blobs HTTP/1.1\r\n\r\ntests", true, 4).unwrap_err();
+
+        assert_eq!(error.kind(), IoErrorKind::FileTooLarge);
+
+        assert_eq!(
+            HttpRequest::parse(
+                b"POST /blobs HTTP/1.1\r\nContent-Length: 4\r\n\r\nte",
+                false,
+                4
+            )
+            .unwrap(),
+            None
+        );
+
+        assert_eq!(
+            HttpRequest::parse(
+                b"POST /blobs HTTP/1.1\r\nContent-Length: 4\r\n\r\ntest",
+                false,
+                4
+            )
+            .unwrap()
+            .and_then(|request| request.body().map(str::to_string)),
+            Some("test".to_string())
+        );
+
+        // without a length, a body needn't be waited for
+
+        assert_eq!(
+            HttpRequest::parse(b"POST /chats HTTP/1.1\r\n\r\n", false, 4)
+                .unwrap()
+                .and_then(|request| request.body().map(str::to_string)),
+            Some(String::new())
+        );
+    }
+
+    #[test]
+    fn test_query() {
+        let request = HttpRequest::parse(
+            b"GET /chats?userId=1&limit=&archived&userId=2 HTTP/1.1\r\n\r\n",
+            true,
+            usize::MAX,
+        )
+        .unwrap()
+        .unwrap();
+
+        assert_eq!(request.query("userId"), Some("1"));
+        assert_eq!(request.query("limit"), Some(""));
+        assert_eq!(request.query("archived"), Some(""));
+        assert_eq!(request.query("since"), None);
+
+        let request = HttpRequest::parse(b"GET /chats HTTP/1.1\r\n\r\n", true, usize::MAX)
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(request.query("userId"), None);
+    }
+
+    #[test]
+    fn test_http_request_parse_patch_and_delete() {
+        assert_eq!(
+            HttpRequest::parse(
+                b"PATCH /chats/1/messages/a HTTP/1.1\r\nContent-Length: 4\r\n\r\ntest",
+                false,
+                usize::MAX
+            )
+            .unwrap()
+            .map(|request| (request.method(), request.body().map(str::to_string))),
+            Some((HttpMethod::PATCH, Some("test".to_string())))
+        );
+
+        // a DELETE without a length doesn't wait for a body
+
+        assert_eq!(
+            HttpRequest::parse(
+                b"DELETE /chats/1/messages/a HTTP/1.1\r\n\r\n",
+                false,
+                usize::MAX
+            )
+            .unwrap(),
+            Some(HttpRequest {
+                body: None,
+                headers: Vec::new(),
+                method: HttpMethod::DELETE,
+                path: "/chats/1/messages/a",
+                version: "HTTP/1.1"
+            })
+        );
+
+        // nor does an OPTIONS, whose response usually has no content
+
+        assert_eq!(
+            HttpRequest::parse(b"OPTIONS /chats HTTP/1.1\r\n\r\n", false, usize::MAX)
+                .unwrap()
+                .map(|request| (request.method(), request.body().map(str::to_string))),
+            Some((HttpMethod::OPTIONS, None))
+        );
+
+        assert_eq!(
+            HttpResponse::new("HTTP/1.1", 204, &[], BodyContent::Str("")).unparse(),
+            b"HTTP/1.1 204 No Content\r\nConnection: Close\r\n\r\n".to_vec()
+        );
+    }
+
+    #[test]
+    fn test_stream_unparse() {
+        let mut response = HttpResponse::new("HTTP/1.1", 200, &[], BodyContent::Str("a"));
+
+        assert_eq!(response.stream(), None);
+
+        response.set_stream(StreamState::new(1u64));
+
+        assert_eq!(
+            response.stream().and_then(StreamState::downcast_ref),
+            Some(&1u64)
+        );
+        assert_eq!(
+            response.unparse(),
+            b"HTTP/1.1 200 OK\r\nConnection: Close\r\n\r\na".to_vec()
+        );
+
+        let mut response = HttpResponse::new(
+            "HTTP/1.1",
+            101,
+            &[("Upgrade", "websocket"), ("Connection", "Upgrade")],
+            BodyContent::Str(""),
+        );
+
+        response.set_stream(StreamState::new(1u64));
+
+        assert_eq!(
+            response.unparse(),
+            b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\r\n"
+                .to_vec()
+        );
     }
 }
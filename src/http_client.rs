@@ -0,0 +1,458 @@
+//! Provides a simple blocking HTTP client, used for fetching
+//! configuration and making outbound calls.
+//!
+//! `https://` URLs are supported with the `tls` feature, which
+//! verifies servers' certificates against the Mozilla root
+//! certificates that `webpki-roots` bundles. Without it, place a
+//! TLS-terminating proxy in front of the target.
+//!
+//! Simple as in the following are not supported:
+//!
+//! * keep-alive
+//! * redirects
+
+use crate::http::HttpMethod;
+use crate::signing::Signer;
+use std::io::Error as IoError;
+use std::io::ErrorKind as IoErrorKind;
+use std::io::{Read, Result as IoResult, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::str;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+#[cfg(feature = "tls")]
+use std::convert::TryFrom;
+#[cfg(feature = "tls")]
+use std::sync::Arc;
+
+/// Represents a response received by the client.
+#[derive(Debug, PartialEq)]
+pub struct HttpClientResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+}
+
+impl HttpClientResponse {
+    /// Get the value of the specified header, if present. Names
+    /// are compared case-insensitively.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Determines if the status code indicates success.
+    pub fn is_success(&self) -> bool {
+        self.status >= 200 && self.status < 300
+    }
+}
+
+/// The credentials that requests are authenticated with, i.e. Basic
+/// credentials, a signing client, or both, e.g. for the routes that
+/// a server's nodes sync with each other over.
+#[derive(Debug, Default)]
+pub struct ClientAuth {
+    authorization: Option<String>,
+    signer: Option<Signer>,
+}
+
+impl ClientAuth {
+    /// Authenticates requests with the supplied Basic credentials,
+    /// e.g. `follower:secret`.
+    pub fn set_basic_auth(&mut self, credentials: &str) {
+        self.authorization = Some(format!("Basic {}", base64::encode(credentials.trim())));
+    }
+
+    /// Authenticates requests by signing them as the supplied client.
+    pub fn set_signer(&mut self, signer: Signer) {
+        self.signer = Some(signer);
+    }
+
+    /// Issue a request to the supplied URL with these credentials,
+    /// whose body, if any, is JSON. See `request`.
+    pub fn request(
+        &self,
+        method: HttpMethod,
+        url: &str,
+        body: Option<&str>,
+        timeout: Duration,
+    ) -> IoResult<HttpClientResponse> {
+        let mut headers = Vec::new();
+
+        if body.is_some() {
+            headers.push(("Content-Type", "application/json".to_string()));
+        }
+
+        if let Some(ref authorization) = self.authorization {
+            headers.push(("Authorization", authorization.clone()));
+        }
+
+        if let Some(ref signer) = self.signer {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_err(IoError::other)?;
+
+            headers.extend(signer.headers(
+                method,
+                path(url)?,
+                body.unwrap_or_default().as_bytes(),
+                now.as_secs(),
+            ));
+        }
+
+        let headers = headers
+            .iter()
+            .map(|(name, value)| (*name, value.as_str()))
+            .collect::<Vec<_>>();
+
+        request(method, url, &headers, body, timeout)
+    }
+}
+
+/// Issue a GET request to the supplied URL.
+pub fn get(url: &str, timeout: Duration) -> IoResult<HttpClientResponse> {
+    request(HttpMethod::GET, url, &[], None, timeout)
+}
+
+/// Issue a request to the supplied URL, waiting at most `timeout`
+/// for each connect, read, and write operation.
+pub fn request(
+    method: HttpMethod,
+    url: &str,
+    headers: &[(&str, &str)],
+    body: Option<&str>,
+    timeout: Duration,
+) -> IoResult<HttpClientResponse> {
+    let addr = resolve(url)?
+        .into_iter()
+        .next()
+        .ok_or_else(|| IoError::new(IoErrorKind::NotFound, "cannot resolve host"))?;
+
+    request_at(addr, method, url, headers, body, timeout)
+}
+
+/// Resolves the host of the supplied URL to its addresses.
+pub fn resolve(url: &str) -> IoResult<Vec<SocketAddr>> {
+    let url = Url::parse(url)?;
+
+    Ok((url.host, url.port).to_socket_addrs()?.collect())
+}
+
+/// Issue a request to the supplied URL as `request` does, but
+/// connect to the supplied address rather than resolving its host,
+/// e.g. one that has already been checked.
+pub fn request_at(
+    addr: SocketAddr,
+    method: HttpMethod,
+    url: &str,
+    headers: &[(&str, &str)],
+    body: Option<&str>,
+    timeout: Duration,
+) -> IoResult<HttpClientResponse> {
+    let url = Url::parse(url)?;
+
+    let mut stream = TcpStream::connect_timeout(&addr, timeout)?;
+
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+
+    let mut req = String::new();
+
+    req.push_str(method.as_str());
+    req.push(' ');
+    req.push_str(url.path);
+    req.push_str(" HTTP/1.1\r\nHost: ");
+    req.push_str(url.host);
+    req.push_str("\r\nConnection: close\r\n");
+
+    for (name, value) in headers {
+        req.push_str(name);
+        req.push_str(": ");
+        req.push_str(value);
+        req.push_str("\r\n");
+    }
+
+    if let Some(body) = body {
+        req.push_str(&format!("Content-Length: {}\r\n\r\n", body.len()));
+        req.push_str(body);
+    } else {
+        req.push_str("\r\n");
+    }
+
+    let data = if url.tls {
+        exchange_tls(url.host, stream, req.as_bytes())?
+    } else {
+        stream.write_all(req.as_bytes())?;
+
+        let mut data = Vec::new();
+
+        stream.read_to_end(&mut data)?;
+
+        data
+    };
+
+    parse_response(&data)
+}
+
+/// Obtains the path of the supplied URL, as it's requested, e.g. so
+/// that a request for it can be signed.
+pub fn path(url: &str) -> IoResult<&str> {
+    Ok(Url::parse(url)?.path)
+}
+
+/// Obtains the host of the supplied URL, without its port.
+pub fn host(url: &str) -> IoResult<&str> {
+    Ok(Url::parse(url)?.host)
+}
+
+/// Internal API.
+///
+/// The components of an `http://` or `https://` URL that are
+/// needed to issue a request.
+#[derive(Debug, PartialEq)]
+struct Url<'a> {
+    tls: bool,
+    host: &'a str,
+    port: u16,
+    path: &'a str,
+}
+
+impl<'a> Url<'a> {
+    fn parse(url: &'a str) -> IoResult<Url<'a>> {
+        let (tls, rest, default_port) = if let Some(rest) = url.strip_prefix("https://") {
+            (true, rest, 443)
+        } else if let Some(rest) = url.strip_prefix("http://") {
+            (false, rest, 80)
+        } else {
+            return Err(IoError::new(IoErrorKind::InvalidInput, "invalid URL"));
+        };
+
+        let (authority, path) = match rest.find('/') {
+            Some(i) => (&rest[..i], &rest[i..]),
+            None => (rest, "/"),
+        };
+
+        let (host, port) = match authority.rfind(':') {
+            Some(i) => (
+                &authority[..i],
+                authority[i + 1..]
+                    .parse()
+                    .map_err(|_| IoError::new(IoErrorKind::InvalidInput, "invalid port"))?,
+            ),
+
+            None => (authority, default_port),
+        };
+
+        if host.is_empty() {
+            Err(IoError::new(IoErrorKind::InvalidInput, "invalid URL"))
+        } else {
+            Ok(Url {
+                tls,
+                host,
+                port,
+                path,
+            })
+        }
+    }
+}
+
+/// Internal API.
+///
+/// Writes the supplied request to the supplied host over TLS on the
+/// supplied stream, and reads its response until the host closes
+/// the connection.
+#[cfg(feature = "tls")]
+fn exchange_tls(host: &str, mut stream: TcpStream, req: &[u8]) -> IoResult<Vec<u8>> {
+    let other = |e| IoError::other(e);
+
+    let roots = rustls::RootCertStore {
+        roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+    };
+
+    let config = rustls::ClientConfig::builder_with_provider(Arc::new(
+        rustls::crypto::ring::default_provider(),
+    ))
+    .with_safe_default_protocol_versions()
+    .map_err(other)?
+    .with_root_certificates(roots)
+    .with_no_client_auth();
+
+    let name = rustls::pki_types::ServerName::try_from(host.to_string())
+        .map_err(|_| IoError::new(IoErrorKind::InvalidInput, "invalid host"))?;
+
+    let mut connection = rustls::ClientConnection::new(Arc::new(config), name).map_err(other)?;
+    let mut tls = rustls::Stream::new(&mut connection, &mut stream);
+
+    tls.write_all(req)?;
+
+    let mut data = Vec::new();
+
+    // some hosts close the connection without notifying the client
+    // first, which is only an error if the response was truncated.
+    // parsing it detects that if its length is known, i.e. it has a
+    // Content-Length or is chunked
+
+    match tls.read_to_end(&mut data) {
+        Err(ref e) if e.kind() == IoErrorKind::UnexpectedEof && !data.is_empty() => Ok(data),
+        Err(e) => Err(e),
+        Ok(_) => Ok(data),
+    }
+}
+
+/// Internal API.
+///
+/// Fails, as `https://` URLs require the `tls` feature.
+#[cfg(not(feature = "tls"))]
+fn exchange_tls(_: &str, _: TcpStream, _: &[u8]) -> IoResult<Vec<u8>> {
+    Err(IoError::new(
+        IoErrorKind::InvalidInput,
+        "https requires the tls feature; use a TLS-terminating proxy",
+    ))
+}
+
+/// Internal API.
+///
+/// Parse a complete response, decoding a chunked body if
+/// necessary.
+fn parse_response(data: &[u8]) -> IoResult<HttpClientResponse> {
+    let data = str::from_utf8(data)
+        .map_err(|_| IoError::new(IoErrorKind::InvalidData, "response is not UTF-8"))?;
+
+    let invalid = || IoError::new(IoErrorKind::InvalidData, "cannot parse response");
+
+    let header_end = data.find("\r\n\r\n").ok_or_else(invalid)?;
+    let mut lines = data[..header_end].split("\r\n");
+
+    let status = lines
+        .next()
+        .and_then(|line| line.split(' ').nth(1))
+        .and_then(|status| status.parse().ok())
+        .ok_or_else(invalid)?;
+
+    let mut headers = Vec::new();
+
+    for line in lines {
+        let mut parts = line.splitn(2, ':');
+
+        if let (Some(name), Some(value)) = (parts.next(), parts.next()) {
+            headers.push((name.to_string(), value.trim().to_string()));
+        }
+    }
+
+    let mut response = HttpClientResponse {
+        status,
+        headers,
+        body: String::new(),
+    };
+
+    let body = &data[header_end + 4..];
+
+    if response
+        .header("Transfer-Encoding")
+        .is_some_and(|v| v.eq_ignore_ascii_case("chunked"))
+    {
+        let mut remaining = body;
+
+        loop {
+            let line_end = remaining.find("\r\n").ok_or_else(invalid)?;
+            let size =
+                usize::from_str_radix(remaining[..line_end].trim(), 16).map_err(|_| invalid())?;
+
+            if size == 0 {
+                break;
+            }
+
+            let start = line_end + 2;
+            let chunk = remaining.get(start..start + size).ok_or_else(invalid)?;
+
+            response.body.push_str(chunk);
+            remaining = remaining.get(start + size + 2..).ok_or_else(invalid)?;
+        }
+    } else {
+        let length = response
+            .header("Content-Length")
+            .and_then(|length| length.parse::<usize>().ok());
+
+        if length.is_some_and(|length| body.len() < length) {
+            return Err(invalid());
+        }
+
+        response.body.push_str(body);
+    }
+
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::http_client::*;
+
+    #[test]
+    fn test_url_parse() {
+        assert_eq!(
+            Url::parse("http://config.local:8081/contacts.json").unwrap(),
+            Url {
+                tls: false,
+                host: "config.local",
+                port: 8081,
+                path: "/contacts.json"
+            }
+        );
+
+        assert_eq!(
+            Url::parse("http://config.local").unwrap(),
+            Url {
+                tls: false,
+                host: "config.local",
+                port: 80,
+                path: "/"
+            }
+        );
+
+        assert_eq!(
+            Url::parse("https://config.local/contacts.json").unwrap(),
+            Url {
+                tls: true,
+                host: "config.local",
+                port: 443,
+                path: "/contacts.json"
+            }
+        );
+
+        assert!(Url::parse("ftp://config.local/").is_err());
+        assert!(Url::parse("http://:80/").is_err());
+    }
+
+    #[test]
+    fn test_parse_response() {
+        assert_eq!(
+            parse_response(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\n{}").unwrap(),
+            HttpClientResponse {
+                status: 200,
+                headers: vec![("Content-Length".to_string(), "2".to_string())],
+                body: "{}".to_string()
+            }
+        );
+
+        assert_eq!(
+            parse_response(
+                b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n2\r\nde\r\n0\r\n\r\n"
+            )
+            .unwrap()
+            .body,
+            "abcde"
+        );
+
+        assert!(parse_response(b"HTTP/1.1 200 OK\r\n").is_err());
+
+        // responses that were cut short are rejected
+
+        assert!(parse_response(b"HTTP/1.1 200 OK\r\nContent-Length: 3\r\n\r\n{}").is_err());
+        assert!(
+            parse_response(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nab")
+                .is_err()
+        );
+    }
+}
@@ -1,3 +1,36 @@
+pub mod auth;
+pub mod blobs;
 pub mod chat;
 pub mod chat_http;
+pub mod contacts;
+pub mod devices;
+pub mod encryption;
+pub mod envelope;
+pub mod federation;
+pub mod feed;
+pub mod gossip;
 pub mod http;
+pub mod http_client;
+pub mod mentions;
+pub mod metrics;
+pub mod negotiation;
+pub mod openapi;
+pub mod prekeys;
+pub mod presence;
+pub mod previews;
+pub mod rate_limit;
+pub mod recording;
+pub mod replication;
+pub mod reports;
+pub mod router;
+pub mod search;
+pub mod seed;
+pub mod shared;
+pub mod signing;
+#[cfg(feature = "sled")]
+pub mod sled_store;
+pub mod stats;
+pub mod storage;
+pub mod transcript;
+pub mod webhooks;
+pub mod websocket;
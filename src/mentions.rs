@@ -0,0 +1,143 @@
+//! Provides mentions, i.e. references to users in the text of a
+//! message, such as "@51201", so that clients can show users the
+//! messages that mention them. Users don't have profiles, so they
+//! can only be mentioned by their ids rather than their names.
+//!
+//! Mentions are found when a message is added, and each user that
+//! it mentions, and who can see it, has it appended to their list of
+//! mentions, where it is assigned the next sequence number in that
+//! list, which is also its cursor. Like feeds, lists of mentions are
+//! part of a `ChatServer`'s state, but only the most recent mentions
+//! are retained.
+
+use crate::chat::Id;
+use serde::{Deserialize, Serialize};
+
+/// The most mentions that are retained for each user.
+const MAX_MENTIONS: usize = 1000;
+
+/// Internal API.
+///
+/// A reference to a message that mentions a user, along with its
+/// sequence number in their list of mentions.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct MentionRef {
+    pub(crate) seq: u64,
+    pub(crate) chat_id: Id,
+    pub(crate) message_seq: u64,
+}
+
+/// Internal API.
+///
+/// A user's list of mentions, which retains the most recent in
+/// sequence order.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct Mentions {
+    last_seq: u64,
+    refs: Vec<MentionRef>,
+}
+
+impl Mentions {
+    /// Internal API.
+    ///
+    /// Appends a mention by the supplied message, discarding the
+    /// oldest mention if the list is full.
+    pub(crate) fn push(&mut self, chat_id: Id, message_seq: u64) {
+        self.last_seq += 1;
+
+        self.refs.push(MentionRef {
+            seq: self.last_seq,
+            chat_id,
+            message_seq,
+        });
+
+        if self.refs.len() > MAX_MENTIONS {
+            let excess = self.refs.len() - MAX_MENTIONS;
+
+            self.refs.drain(..excess);
+        }
+    }
+
+    /// Internal API.
+    ///
+    /// Obtains the mentions after the one with the supplied sequence
+    /// number, which may have since been discarded.
+    pub(crate) fn after(&self, seq: u64) -> &[MentionRef] {
+        let start = match self.refs.binary_search_by_key(&seq, |r| r.seq) {
+            Ok(position) => position + 1,
+            Err(position) => position,
+        };
+
+        &self.refs[start..]
+    }
+}
+
+/// Internal API.
+///
+/// Finds the ids of the users that the supplied text mentions, in
+/// the order they're first mentioned. A mention is an `@` followed
+/// by a user's id, which doesn't follow a letter or digit, so that
+/// e.g. email addresses aren't mistaken for mentions.
+pub(crate) fn parse(text: &str) -> Vec<Id> {
+    let mut user_ids = Vec::new();
+    let mut previous = None;
+
+    for (position, c) in text.char_indices() {
+        let mentions = c == '@' && previous.is_none_or(|p: char| !p.is_alphanumeric());
+
+        previous = Some(c);
+
+        if !mentions {
+            continue;
+        }
+
+        let digits = &text[position + 1..];
+        let end = digits
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(digits.len());
+
+        // the id must end the word, e.g. "@12ab" isn't a mention
+
+        let ends_word = digits[end..]
+            .chars()
+            .next()
+            .is_none_or(|c| !c.is_alphanumeric());
+
+        if let (true, Ok(user_id)) = (ends_word, digits[..end].parse()) {
+            if !user_ids.contains(&user_id) {
+                user_ids.push(user_id);
+            }
+        }
+    }
+
+    user_ids
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mentions::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse("hi @12 and @3, @12!"), vec![12, 3]);
+        assert_eq!(parse("@7"), vec![7]);
+        assert!(parse("a@1.com @ @x @12ab @").is_empty());
+        assert!(parse("@99999999999999999999999").is_empty());
+    }
+
+    #[test]
+    fn test_push_and_after() {
+        let mut mentions = Mentions::default();
+
+        for message_seq in 0..MAX_MENTIONS as u64 + 2 {
+            mentions.push(1, message_seq);
+        }
+
+        assert_eq!(mentions.refs.len(), MAX_MENTIONS);
+        assert_eq!(mentions.after(0)[0].seq, 3);
+        assert_eq!(mentions.after(500)[0].message_seq, 500);
+        assert!(mentions.after(mentions.last_seq).is_empty());
+    }
+}
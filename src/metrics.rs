@@ -0,0 +1,330 @@
+//! Provides counters of a `ChatServer`'s domain activity, so that
+//! operators can see what it's doing, e.g. by exporting them to
+//! Prometheus.
+//!
+//! Counters are updated after every request is issued, and a
+//! `ChatMetrics` snapshot of them can be taken at any time. Request
+//! latencies are counted in histogram buckets, as Prometheus expects,
+//! and snapshots can be rendered in its text exposition format.
+//!
+//! A `ChatHttpServer` also counts the requests it answers by route,
+//! so that e.g. a slow or failing route can be found.
+
+use crate::chat::ChatResponse;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// The prefix of the name of each metric that is rendered.
+const PREFIX: &str = "signal_chat";
+
+/// The upper bounds of the request latency buckets, in microseconds.
+const LATENCY_BUCKETS: [u64; 12] = [
+    100, 250, 500, 1_000, 2_500, 5_000, 10_000, 25_000, 50_000, 100_000, 250_000, 1_000_000,
+];
+
+/// A point-in-time copy of a `ChatServer`'s counters.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ChatMetrics {
+    pub requests: u64,
+    pub chats_created: u64,
+    pub messages_added: u64,
+    pub validation_failures: u64,
+    pub unknown_chats: u64,
+    pub latency: LatencyHistogram,
+}
+
+/// A histogram of request latencies. Each bucket has an upper
+/// bound and the number of requests that took at most that long,
+/// so the counts are cumulative.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LatencyHistogram {
+    pub buckets: Vec<(Duration, u64)>,
+    pub count: u64,
+    pub sum: Duration,
+}
+
+/// Counts of the HTTP requests that have been answered, by the
+/// method and pattern of the route they matched, e.g.
+/// `GET /v1/chats/{chat_id}`, the `ChatRequest` they issued, e.g.
+/// `ListChat`, and the status of their response. Either of the
+/// first two is empty if the request didn't have one.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RouteMetrics {
+    pub requests: BTreeMap<(String, String, u16), u64>,
+}
+
+/// Internal API.
+///
+/// The live counters, which are atomic so that they can be updated
+/// by queries, which only have shared access to the server.
+#[derive(Debug, Default)]
+pub(crate) struct Counters {
+    requests: AtomicU64,
+    chats_created: AtomicU64,
+    messages_added: AtomicU64,
+    validation_failures: AtomicU64,
+    unknown_chats: AtomicU64,
+    latency_buckets: [AtomicU64; 12],
+    latency_sum: AtomicU64,
+}
+
+impl ChatMetrics {
+    /// Renders these metrics in Prometheus' text exposition format,
+    /// where latencies are in seconds. Rates, e.g. of messages per
+    /// second, are left to Prometheus to compute from the counters.
+    pub fn to_prometheus(&self) -> String {
+        let mut text = String::new();
+
+        let counters = [
+            ("requests", "Requests issued", self.requests),
+            ("chats_created", "Chats created", self.chats_created),
+            ("messages_added", "Messages added", self.messages_added),
+            (
+                "validation_failures",
+                "Requests that failed validation",
+                self.validation_failures,
+            ),
+            (
+                "unknown_chats",
+                "Requests for chats that don't exist",
+                self.unknown_chats,
+            ),
+        ];
+
+        for (name, help, value) in counters.iter() {
+            let _ = write!(
+                text,
+                "# HELP {0}_{1}_total {2}\n# TYPE {0}_{1}_total counter\n{0}_{1}_total {3}\n",
+                PREFIX, name, help, value
+            );
+        }
+
+        let histogram = format!("{}_request_duration_seconds", PREFIX);
+
+        let _ = write!(
+            text,
+            "# HELP {0} How long requests took to issue\n# TYPE {0} histogram\n",
+            histogram
+        );
+
+        for (bound, count) in self.latency.buckets.iter() {
+            let _ = writeln!(
+                text,
+                "{}_bucket{{le=\"{}\"}} {}",
+                histogram,
+                seconds(*bound),
+                count
+            );
+        }
+
+        let _ = write!(
+            text,
+            "{0}_bucket{{le=\"+Inf\"}} {1}\n{0}_sum {2}\n{0}_count {1}\n",
+            histogram,
+            self.latency.count,
+            seconds(self.latency.sum)
+        );
+
+        text
+    }
+}
+
+impl RouteMetrics {
+    /// Counts a request that matched the supplied route, issued the
+    /// supplied `ChatRequest`, and was answered with the supplied
+    /// status.
+    pub fn record(&mut self, route: &str, request: &str, status: u16) {
+        *self
+            .requests
+            .entry((route.to_string(), request.to_string(), status))
+            .or_insert(0) += 1;
+    }
+
+    /// Renders these metrics in Prometheus' text exposition format,
+    /// with a label for each part of what's counted.
+    pub fn to_prometheus(&self) -> String {
+        let name = format!("{}_http_requests_total", PREFIX);
+
+        let mut text = format!(
+            "# HELP {0} HTTP requests answered\n# TYPE {0} counter\n",
+            name
+        );
+
+        for ((route, request, status), count) in self.requests.iter() {
+            let _ = writeln!(
+                text,
+                "{}{{route=\"{}\",request=\"{}\",status=\"{}\"}} {}",
+                name, route, request, status, count
+            );
+        }
+
+        text
+    }
+}
+
+impl Counters {
+    /// Internal API.
+    ///
+    /// Counts a request that produced the supplied response and
+    /// took `elapsed` to do so.
+    pub(crate) fn record(&self, response: &ChatResponse, elapsed: Duration) {
+        let counter = match response {
+            ChatResponse::ChatCreated { .. } => Some(&self.chats_created),
+            ChatResponse::MessageAdded => Some(&self.messages_added),
+            ChatResponse::ChatValidationError { .. } => Some(&self.validation_failures),
+            ChatResponse::UnknownChat => Some(&self.unknown_chats),
+            _ => None,
+        };
+
+        if let Some(counter) = counter {
+            counter.fetch_add(1, Ordering::Relaxed);
+        }
+
+        let micros = elapsed.as_micros() as u64;
+
+        // requests slower than the last bound are only included in
+        // the count, i.e. the implicit +Inf bucket
+
+        if let Some(bucket) = LATENCY_BUCKETS.iter().position(|bound| micros <= *bound) {
+            self.latency_buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        }
+
+        self.latency_sum.fetch_add(micros, Ordering::Relaxed);
+        self.requests.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Internal API.
+    ///
+    /// Takes a snapshot of the counters.
+    pub(crate) fn snapshot(&self) -> ChatMetrics {
+        let requests = self.requests.load(Ordering::Relaxed);
+        let mut cumulative = 0;
+
+        let buckets = LATENCY_BUCKETS
+            .iter()
+            .zip(self.latency_buckets.iter())
+            .map(|(bound, count)| {
+                cumulative += count.load(Ordering::Relaxed);
+
+                (Duration::from_micros(*bound), cumulative)
+            })
+            .collect();
+
+        ChatMetrics {
+            requests,
+            chats_created: self.chats_created.load(Ordering::Relaxed),
+            messages_added: self.messages_added.load(Ordering::Relaxed),
+            validation_failures: self.validation_failures.load(Ordering::Relaxed),
+            unknown_chats: self.unknown_chats.load(Ordering::Relaxed),
+            latency: LatencyHistogram {
+                buckets,
+                count: requests,
+                sum: Duration::from_micros(self.latency_sum.load(Ordering::Relaxed)),
+            },
+        }
+    }
+}
+
+/// Internal API.
+///
+/// Obtains the supplied duration in seconds, e.g. `0.00025`.
+fn seconds(duration: Duration) -> f64 {
+    duration.as_micros() as f64 / 1e6
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::metrics::*;
+
+    #[test]
+    fn test_latency_histogram() {
+        let counters = Counters::default();
+
+        counters.record(&ChatResponse::MessageAdded, Duration::from_micros(50));
+        counters.record(&ChatResponse::UnknownChat, Duration::from_micros(300));
+        counters.record(&ChatResponse::UnknownChat, Duration::from_secs(2));
+
+        let metrics = counters.snapshot();
+
+        assert_eq!(metrics.requests, 3);
+        assert_eq!(metrics.messages_added, 1);
+        assert_eq!(metrics.unknown_chats, 2);
+        assert_eq!(metrics.latency.count, 3);
+        assert_eq!(metrics.latency.sum, Duration::from_micros(2_000_350));
+
+        assert_eq!(
+            metrics.latency.buckets[..4],
+            [
+                (Duration::from_micros(100), 1),
+                (Duration::from_micros(250), 1),
+                (Duration::from_micros(500), 2),
+                (Duration::from_millis(1), 2),
+            ]
+        );
+
+        assert_eq!(metrics.latency.buckets[11], (Duration::from_secs(1), 2));
+    }
+
+    #[test]
+    fn test_to_prometheus() {
+        let counters = Counters::default();
+
+        counters.record(&ChatResponse::MessageAdded, Duration::from_micros(50));
+        counters.record(&ChatResponse::UnknownChat, Duration::from_micros(300));
+        counters.record(&ChatResponse::UnknownChat, Duration::from_secs(2));
+
+        let text = counters.snapshot().to_prometheus();
+        let lines = text.lines().collect::<Vec<_>>();
+
+        assert_eq!(
+            lines[..3],
+            [
+                "# HELP signal_chat_requests_total Requests issued",
+                "# TYPE signal_chat_requests_total counter",
+                "signal_chat_requests_total 3",
+            ]
+        );
+
+        for line in [
+            "signal_chat_messages_added_total 1",
+            "signal_chat_unknown_chats_total 2",
+            "# TYPE signal_chat_request_duration_seconds histogram",
+            "signal_chat_request_duration_seconds_bucket{le=\"0.0001\"} 1",
+            "signal_chat_request_duration_seconds_bucket{le=\"0.00025\"} 1",
+            "signal_chat_request_duration_seconds_bucket{le=\"0.0005\"} 2",
+            "signal_chat_request_duration_seconds_bucket{le=\"1\"} 2",
+            "signal_chat_request_duration_seconds_bucket{le=\"+Inf\"} 3",
+            "signal_chat_request_duration_seconds_sum 2.00035",
+            "signal_chat_request_duration_seconds_count 3",
+        ]
+        .iter()
+        {
+            assert!(lines.contains(line), "{} is missing", line);
+        }
+
+        assert!(text.ends_with('\n'));
+    }
+
+    #[test]
+    fn test_route_metrics() {
+        let mut metrics = RouteMetrics::default();
+
+        metrics.record("GET /v1/chats/{chat_id}", "ListChat", 200);
+        metrics.record("GET /v1/chats/{chat_id}", "ListChat", 200);
+        metrics.record("GET /v1/chats/{chat_id}", "ListChat", 404);
+        metrics.record("", "", 404);
+
+        assert_eq!(
+            metrics.to_prometheus().lines().collect::<Vec<_>>(),
+            [
+                "# HELP signal_chat_http_requests_total HTTP requests answered",
+                "# TYPE signal_chat_http_requests_total counter",
+                "signal_chat_http_requests_total{route=\"\",request=\"\",status=\"404\"} 1",
+                "signal_chat_http_requests_total{route=\"GET /v1/chats/{chat_id}\",request=\"ListChat\",status=\"200\"} 2",
+                "signal_chat_http_requests_total{route=\"GET /v1/chats/{chat_id}\",request=\"ListChat\",status=\"404\"} 1",
+            ]
+        );
+    }
+}
@@ -0,0 +1,330 @@
+//! Provides content negotiation, which chooses the format of a
+//! response from those that a request's `Accept` header lists, and
+//! determines the format of a request's body from its `Content-Type`.
+//!
+//! Bodies are JSON unless a client prefers MessagePack or CBOR, which
+//! are more compact and cheaper to parse, e.g. for mobile clients on
+//! metered connections, or constrained devices. Responses are
+//! serialized straight into the chosen format, and request bodies are
+//! deserialized straight from theirs, with the same shape as JSON.
+//! MessagePack and CBOR are only supported with the `msgpack` and
+//! `cbor` features, which are on by default.
+//!
+//! Each media range in an `Accept` header may have a quality, e.g.
+//! `application/msgpack, application/json;q=0.5`. The supported
+//! format with the highest quality is chosen, preferring the one that
+//! is listed first if several have the same quality. Ranges with a
+//! quality of zero aren't acceptable.
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+#[cfg(any(feature = "msgpack", feature = "cbor"))]
+use std::io::Error as IoError;
+#[cfg(any(feature = "msgpack", feature = "cbor"))]
+use std::io::ErrorKind as IoErrorKind;
+use std::io::Result as IoResult;
+
+/// A format that a request or response body can be encoded in.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Format {
+    Json,
+    #[cfg(feature = "msgpack")]
+    MessagePack,
+    #[cfg(feature = "cbor")]
+    Cbor,
+}
+
+impl Format {
+    /// Every supported format, preferring those that are listed
+    /// first.
+    pub const ALL: &'static [Format] = &[
+        Format::Json,
+        #[cfg(feature = "msgpack")]
+        Format::MessagePack,
+        #[cfg(feature = "cbor")]
+        Format::Cbor,
+    ];
+
+    /// Lists the media types of every supported format, e.g. for
+    /// problems that name them.
+    pub fn media_types() -> String {
+        let types = Self::ALL
+            .iter()
+            .map(|format| format.content_type())
+            .collect::<Vec<_>>();
+
+        match types.split_last() {
+            Some((last, rest)) if !rest.is_empty() => format!("{} or {}", rest.join(", "), last),
+            _ => types.join(""),
+        }
+    }
+
+    /// Chooses the format that the supplied `Accept` header prefers,
+    /// which is JSON if there isn't one, or `None` if it doesn't
+    /// accept any supported format.
+    pub fn negotiate(accept: Option<&str>) -> Option<Self> {
+        let accept = match accept {
+            Some(accept) => accept,
+            None => return Some(Format::Json),
+        };
+
+        let mut chosen: Option<(Self, f32)> = None;
+
+        for range in accept.split(',') {
+            let mut parts = range.split(';');
+            let media_type = parts.next().unwrap_or_default().trim();
+
+            let format = match Self::from_media_type(media_type) {
+                Some(format) => format,
+                None => continue,
+            };
+
+            let quality = match quality(parts) {
+                Some(quality) if quality > 0.0 => quality,
+                _ => continue,
+            };
+
+            if chosen.is_none_or(|(_, q)| quality > q) {
+                chosen = Some((format, quality));
+            }
+        }
+
+        chosen.map(|(format, _)| format)
+    }
+
+    /// Obtains the format of a body with the supplied `Content-Type`
+    /// header, ignoring any parameters such as its charset, or `None`
+    /// if it isn't supported. Wildcards aren't formats of a body.
+    pub fn from_content_type(content_type: &str) -> Option<Self> {
+        let media_type = content_type.split(';').next().unwrap_or_default().trim();
+
+        if media_type.contains('*') {
+            None
+        } else {
+            Self::from_media_type(media_type)
+        }
+    }
+
+    /// Obtains the media type of this format, e.g. for a response's
+    /// `Content-Type` header.
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Format::Json => "application/json",
+            #[cfg(feature = "msgpack")]
+            Format::MessagePack => "application/msgpack",
+            #[cfg(feature = "cbor")]
+            Format::Cbor => "application/cbor",
+        }
+    }
+
+    /// Serializes the supplied value in this format, e.g. the body of
+    /// a response, with the same shape as its JSON.
+    pub fn encode<T: Serialize>(self, value: &T) -> IoResult<Vec<u8>> {
+        match self {
+            Format::Json => Ok(serde_json::to_vec(value)?),
+
+            #[cfg(feature = "msgpack")]
+            Format::MessagePack => rmp_serde::to_vec_named(value)
+                .map_err(|e| IoError::new(IoErrorKind::InvalidData, e.to_string())),
+
+            #[cfg(feature = "cbor")]
+            Format::Cbor => {
+                let mut data = Vec::new();
+
+                ciborium::ser::into_writer(value, &mut data)
+                    .map_err(|e| IoError::new(IoErrorKind::InvalidData, e.to_string()))?;
+
+                Ok(data)
+            }
+        }
+    }
+
+    /// Deserializes the supplied body, which is in this format, e.g.
+    /// the body of a request, which has the same shape as its JSON.
+    pub fn decode<T: DeserializeOwned>(self, body: &[u8]) -> IoResult<T> {
+        match self {
+            Format::Json => Ok(serde_json::from_slice(body)?),
+
+            #[cfg(feature = "msgpack")]
+            Format::MessagePack => rmp_serde::from_slice(body)
+                .map_err(|e| IoError::new(IoErrorKind::InvalidData, e.to_string())),
+
+            #[cfg(feature = "cbor")]
+            Format::Cbor => ciborium::de::from_reader(body)
+                .map_err(|e| IoError::new(IoErrorKind::InvalidData, e.to_string())),
+        }
+    }
+
+    /// Internal API.
+    ///
+    /// Obtains the format of the supplied media range, where wildcards
+    /// are JSON, or `None` if it isn't supported.
+    fn from_media_type(media_type: &str) -> Option<Self> {
+        match media_type.to_ascii_lowercase().as_str() {
+            "application/json" | "application/*" | "*/*" => Some(Format::Json),
+
+            #[cfg(feature = "msgpack")]
+            "application/msgpack" | "application/x-msgpack" | "application/vnd.msgpack" => {
+                Some(Format::MessagePack)
+            }
+
+            #[cfg(feature = "cbor")]
+            "application/cbor" => Some(Format::Cbor),
+
+            _ => None,
+        }
+    }
+}
+
+/// Internal API.
+///
+/// Obtains the quality of a media range from its parameters, which
+/// is 1 if it isn't supplied, or `None` if it can't be parsed.
+fn quality<'a, I: Iterator<Item = &'a str>>(params: I) -> Option<f32> {
+    for param in params {
+        let mut parts = param.splitn(2, '=');
+
+        if parts.next().unwrap_or_default().trim() == "q" {
+            return parts
+                .next()
+                .and_then(|q| q.trim().parse().ok())
+                .filter(|q| (0.0..=1.0).contains(q));
+        }
+    }
+
+    Some(1.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::negotiation::*;
+
+    /// A value that is encoded and decoded in tests, in the shape of
+    /// a chat.
+    #[cfg(any(feature = "msgpack", feature = "cbor"))]
+    #[derive(Debug, PartialEq, serde::Deserialize, Serialize)]
+    #[serde(rename_all = "camelCase")]
+    struct Chat {
+        id: u64,
+        participant_ids: Vec<u64>,
+        title: Option<String>,
+    }
+
+    #[cfg(feature = "msgpack")]
+    #[test]
+    fn test_negotiate() {
+        assert_eq!(Format::negotiate(None), Some(Format::Json));
+        assert_eq!(Format::negotiate(Some("*/*")), Some(Format::Json));
+        assert_eq!(
+            Format::negotiate(Some("application/msgpack")),
+            Some(Format::MessagePack)
+        );
+        assert_eq!(
+            Format::negotiate(Some("application/json, application/x-msgpack")),
+            Some(Format::Json)
+        );
+        assert_eq!(
+            Format::negotiate(Some("application/json;q=0.5, Application/MsgPack")),
+            Some(Format::MessagePack)
+        );
+        assert_eq!(
+            Format::negotiate(Some("text/html, */*;q=0.8")),
+            Some(Format::Json)
+        );
+
+        assert_eq!(Format::negotiate(Some("text/plain")), None);
+        assert_eq!(Format::negotiate(Some("application/json;q=0")), None);
+        assert_eq!(Format::negotiate(Some("application/json;q=2")), None);
+    }
+
+    #[cfg(feature = "msgpack")]
+    #[test]
+    fn test_encode() {
+        let json = "{\"id\":1,\"participantIds\":[1,2],\"title\":null}";
+        let chat = Chat {
+            id: 1,
+            participant_ids: vec![1, 2],
+            title: None,
+        };
+
+        assert_eq!(Format::Json.encode(&chat).unwrap(), json.as_bytes());
+
+        let encoded = Format::MessagePack.encode(&chat).unwrap();
+
+        assert_eq!(
+            rmp_serde::from_slice::<serde_json::Value>(&encoded).unwrap(),
+            serde_json::from_str::<serde_json::Value>(json).unwrap()
+        );
+        assert!(encoded.len() < json.len());
+    }
+
+    #[cfg(feature = "msgpack")]
+    #[test]
+    fn test_decode() {
+        assert_eq!(
+            Format::from_content_type("application/json; charset=utf-8"),
+            Some(Format::Json)
+        );
+        assert_eq!(
+            Format::from_content_type("Application/MsgPack"),
+            Some(Format::MessagePack)
+        );
+        assert_eq!(Format::from_content_type("application/*"), None);
+        assert_eq!(Format::from_content_type("text/plain"), None);
+
+        let json = "{\"id\":1,\"participantIds\":[1,2],\"title\":null}";
+        let chat = Chat {
+            id: 1,
+            participant_ids: vec![1, 2],
+            title: None,
+        };
+        let encoded = Format::MessagePack.encode(&chat).unwrap();
+
+        assert_eq!(Format::MessagePack.decode::<Chat>(&encoded).unwrap(), chat);
+        assert_eq!(Format::Json.decode::<Chat>(json.as_bytes()).unwrap(), chat);
+
+        assert!(Format::MessagePack.decode::<Chat>(&encoded[..4]).is_err());
+        assert!(Format::Json.decode::<Chat>(&encoded).is_err());
+    }
+
+    #[cfg(feature = "cbor")]
+    #[test]
+    fn test_cbor() {
+        assert_eq!(
+            Format::negotiate(Some("application/cbor, application/json;q=0.9")),
+            Some(Format::Cbor)
+        );
+        assert_eq!(
+            Format::from_content_type("application/cbor"),
+            Some(Format::Cbor)
+        );
+
+        let json = "{\"id\":1,\"participantIds\":[1,2],\"title\":null}";
+        let chat = Chat {
+            id: 1,
+            participant_ids: vec![1, 2],
+            title: None,
+        };
+        let encoded = Format::Cbor.encode(&chat).unwrap();
+
+        assert_eq!(
+            ciborium::de::from_reader::<serde_json::Value, _>(encoded.as_slice()).unwrap(),
+            serde_json::from_str::<serde_json::Value>(json).unwrap()
+        );
+        assert!(encoded.len() < json.len());
+
+        assert_eq!(Format::Cbor.decode::<Chat>(&encoded).unwrap(), chat);
+        assert!(Format::Cbor.decode::<Chat>(&encoded[..4]).is_err());
+    }
+
+    #[test]
+    fn test_media_types() {
+        assert!(Format::media_types().starts_with("application/json"));
+
+        #[cfg(all(feature = "msgpack", feature = "cbor"))]
+        assert_eq!(
+            Format::media_types(),
+            "application/json, application/msgpack or application/cbor"
+        );
+    }
+}
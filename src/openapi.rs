@@ -0,0 +1,596 @@
+//! Provides an OpenAPI 3 document that describes the HTTP API, so
+//! that clients, e.g. SDKs, can be generated from it.
+//!
+//! The document's paths are generated from the routes of a router,
+//! so that every route is described, including those that are added
+//! later. Each operation is described by the entry in a table that
+//! has its method and pattern, which names the schemas of its request
+//! and response bodies, where the schemas are described in the
+//! document's components. Routes without an entry are described by
+//! their parameters alone.
+//!
+//! Every operation may fail with a problem, i.e. an RFC 7807 body
+//! that is extended with the error's code.
+
+use crate::http::HttpMethod;
+use serde_json::{json, Map, Value};
+
+/// The version of the API that the document describes.
+const API_VERSION: &str = "1";
+
+/// The path parameters whose values are integers, e.g. ids, where the
+/// others are strings.
+const INTEGER_PARAMS: &[&str] = &[
+    "active_minutes",
+    "after",
+    "chat_id",
+    "device_id",
+    "report_id",
+    "user_id",
+];
+
+/// Internal API.
+///
+/// Describes an operation, i.e. a route, including the schemas of its
+/// request and response bodies, where a response without a schema is
+/// plain text. Query parameters are listed with their schema types.
+struct Operation {
+    method: HttpMethod,
+    pattern: &'static str,
+    summary: &'static str,
+    query: &'static [(&'static str, &'static str)],
+    request: Option<&'static str>,
+    response: Option<&'static str>,
+}
+
+/// Internal API.
+///
+/// The operations that are described, by their method and pattern.
+const OPERATIONS: &[Operation] = &[
+    Operation {
+        method: HttpMethod::POST,
+        pattern: "/v1/chats",
+        summary: "Creates a chat",
+        query: &[],
+        request: Some("NewChat"),
+        response: Some("ChatCreated"),
+    },
+    Operation {
+        method: HttpMethod::GET,
+        pattern: "/v1/chats",
+        summary: "Lists a user's chats",
+        query: &[
+            ("userId", "integer"),
+            ("includeArchived", "boolean"),
+            ("limit", "integer"),
+            ("before", "integer"),
+            ("since", "integer"),
+        ],
+        request: None,
+        response: Some("ChatList"),
+    },
+    Operation {
+        method: HttpMethod::POST,
+        pattern: "/v1/chats/{chat_id}",
+        summary: "Updates a chat's title or message TTL",
+        query: &[],
+        request: Some("UpdateChat"),
+        response: None,
+    },
+    Operation {
+        method: HttpMethod::POST,
+        pattern: "/v1/chats/{chat_id}/messages",
+        summary: "Adds a message to a chat",
+        query: &[],
+        request: Some("ChatMessage"),
+        response: None,
+    },
+    Operation {
+        method: HttpMethod::GET,
+        pattern: "/v1/chats/{chat_id}/messages",
+        summary: "Lists a page of a chat's messages",
+        query: &[
+            ("limit", "integer"),
+            ("before", "integer"),
+            ("cursor", "string"),
+            ("since", "string"),
+        ],
+        request: None,
+        response: Some("MessagePage"),
+    },
+    Operation {
+        method: HttpMethod::POST,
+        pattern: "/v1/chats/{chat_id}/messages/{message_id}",
+        summary: "Edits a message",
+        query: &[],
+        request: Some("EditMessage"),
+        response: Some("ChatMessage"),
+    },
+    Operation {
+        method: HttpMethod::PATCH,
+        pattern: "/v1/chats/{chat_id}/messages/{message_id}",
+        summary: "Edits a message",
+        query: &[],
+        request: Some("EditMessage"),
+        response: Some("ChatMessage"),
+    },
+    Operation {
+        method: HttpMethod::DELETE,
+        pattern: "/v1/chats/{chat_id}/messages/{message_id}",
+        summary: "Deletes a message, leaving a tombstone",
+        query: &[("userId", "integer")],
+        request: None,
+        response: Some("ChatMessage"),
+    },
+    Operation {
+        method: HttpMethod::POST,
+        pattern: "/v1/chats/{chat_id}/forward",
+        summary: "Forwards a message from another chat",
+        query: &[],
+        request: Some("ForwardMessage"),
+        response: None,
+    },
+    Operation {
+        method: HttpMethod::POST,
+        pattern: "/v1/chats/{chat_id}/ack",
+        summary: "Acknowledges the delivery of a chat's messages",
+        query: &[],
+        request: Some("AckMessages"),
+        response: None,
+    },
+    Operation {
+        method: HttpMethod::POST,
+        pattern: "/v1/chats/{chat_id}/leave",
+        summary: "Leaves a chat",
+        query: &[],
+        request: Some("ChatUser"),
+        response: None,
+    },
+    Operation {
+        method: HttpMethod::POST,
+        pattern: "/v1/chats/{chat_id}/mute",
+        summary: "Mutes a chat",
+        query: &[],
+        request: Some("MuteChat"),
+        response: None,
+    },
+    Operation {
+        method: HttpMethod::POST,
+        pattern: "/v1/chats/{chat_id}/unmute",
+        summary: "Unmutes a chat",
+        query: &[],
+        request: Some("MuteChat"),
+        response: None,
+    },
+    Operation {
+        method: HttpMethod::POST,
+        pattern: "/v1/chats/{chat_id}/archive",
+        summary: "Archives a chat",
+        query: &[],
+        request: Some("ChatUser"),
+        response: None,
+    },
+    Operation {
+        method: HttpMethod::POST,
+        pattern: "/v1/chats/{chat_id}/unarchive",
+        summary: "Unarchives a chat",
+        query: &[],
+        request: Some("ChatUser"),
+        response: None,
+    },
+    Operation {
+        method: HttpMethod::GET,
+        pattern: "/v1/chats/{chat_id}/thread/{message_id}",
+        summary: "Lists a message and its replies",
+        query: &[],
+        request: None,
+        response: Some("MessageList"),
+    },
+    Operation {
+        method: HttpMethod::GET,
+        pattern: "/v1/chats/{chat_id}/history/{message_id}",
+        summary: "Lists the prior revisions of a message",
+        query: &[],
+        request: None,
+        response: Some("RevisionList"),
+    },
+    Operation {
+        method: HttpMethod::POST,
+        pattern: "/v1/chats/{chat_id}/typing",
+        summary: "Says that a user is typing in a chat",
+        query: &[],
+        request: Some("ChatUser"),
+        response: None,
+    },
+    Operation {
+        method: HttpMethod::GET,
+        pattern: "/v1/chats/{chat_id}/typing",
+        summary: "Lists the users who are typing in a chat",
+        query: &[],
+        request: None,
+        response: Some("ChatTyping"),
+    },
+    Operation {
+        method: HttpMethod::GET,
+        pattern: "/openapi.json",
+        summary: "Describes the API",
+        query: &[],
+        request: None,
+        response: None,
+    },
+];
+
+/// Creates the document that describes the routes with the supplied
+/// methods and patterns, e.g. those of a router.
+pub fn document(routes: &[(HttpMethod, String)]) -> Value {
+    let mut paths = Map::new();
+
+    for (method, pattern) in routes.iter() {
+        let path = paths
+            .entry(pattern.clone())
+            .or_insert_with(|| Value::Object(Map::new()));
+
+        if let Value::Object(path) = path {
+            path.insert(
+                method.as_str().to_ascii_lowercase(),
+                operation(*method, pattern),
+            );
+        }
+    }
+
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "signal-http",
+            "version": API_VERSION,
+        },
+        "paths": paths,
+        "components": {
+            "schemas": schemas(),
+        },
+    })
+}
+
+/// Internal API.
+///
+/// Describes the operation with the supplied method and pattern,
+/// using its entry in the table if it has one.
+fn operation(method: HttpMethod, pattern: &str) -> Value {
+    let entry = OPERATIONS
+        .iter()
+        .find(|o| o.method == method && o.pattern == pattern);
+
+    let mut parameters = pattern
+        .split('/')
+        .filter(|segment| segment.starts_with('{') && segment.ends_with('}'))
+        .map(|segment| {
+            let name = &segment[1..segment.len() - 1];
+            let schema_type = if INTEGER_PARAMS.contains(&name) {
+                "integer"
+            } else {
+                "string"
+            };
+
+            json!({
+                "name": name,
+                "in": "path",
+                "required": true,
+                "schema": { "type": schema_type },
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let mut operation = Map::new();
+
+    if let Some(entry) = entry {
+        for (name, schema_type) in entry.query.iter() {
+            parameters.push(json!({
+                "name": name,
+                "in": "query",
+                "schema": { "type": schema_type },
+            }));
+        }
+
+        operation.insert("summary".to_string(), json!(entry.summary));
+
+        if let Some(request) = entry.request {
+            operation.insert(
+                "requestBody".to_string(),
+                json!({
+                    "required": true,
+                    "content": { "application/json": { "schema": reference(request) } },
+                }),
+            );
+        }
+    }
+
+    let success = match entry.and_then(|e| e.response) {
+        Some(response) => json!({
+            "description": "The request succeeded",
+            "content": { "application/json": { "schema": reference(response) } },
+        }),
+
+        None => json!({
+            "description": "The request succeeded",
+            "content": { "text/plain": { "schema": { "type": "string" } } },
+        }),
+    };
+
+    operation.insert("parameters".to_string(), Value::Array(parameters));
+    operation.insert(
+        "responses".to_string(),
+        json!({
+            "200": success,
+            "default": {
+                "description": "The request failed",
+                "content": { "application/problem+json": { "schema": reference("Problem") } },
+            },
+        }),
+    );
+
+    Value::Object(operation)
+}
+
+/// Internal API.
+///
+/// A reference to the schema with the supplied name.
+fn reference(name: &str) -> Value {
+    json!({ "$ref": format!("#/components/schemas/{}", name) })
+}
+
+/// Internal API.
+///
+/// Describes the schemas of the bodies that operations exchange, by
+/// their names.
+fn schemas() -> Value {
+    let id = json!({ "type": "integer", "format": "int64" });
+    let timestamp = json!({ "type": "integer", "format": "int64" });
+    let ids = json!({ "type": "array", "items": id });
+
+    json!({
+        "Problem": {
+            "type": "object",
+            "required": ["type", "title", "status", "code"],
+            "properties": {
+                "type": { "type": "string" },
+                "title": { "type": "string" },
+                "status": { "type": "integer" },
+                "code": { "type": "string" },
+                "detail": { "type": "string" },
+                "requestId": { "type": "string" },
+            },
+        },
+        "Chat": {
+            "type": "object",
+            "required": ["id", "participantIds"],
+            "properties": {
+                "id": id,
+                "participantIds": ids,
+                "title": { "type": "string" },
+                "createdAt": timestamp,
+                "creator": id,
+                "messageTtl": { "type": "integer", "format": "int64" },
+                "muted": {
+                    "type": "object",
+                    "properties": { "until": timestamp },
+                },
+                "archived": { "type": "boolean" },
+            },
+        },
+        "ChatList": {
+            "type": "array",
+            "items": reference("Chat"),
+        },
+        "NewChat": {
+            "type": "object",
+            "required": ["participantIds"],
+            "properties": {
+                "id": id,
+                "participantIds": ids,
+                "title": { "type": "string" },
+                "createdAt": timestamp,
+                "creator": id,
+            },
+        },
+        "ChatCreated": {
+            "type": "object",
+            "required": ["id"],
+            "properties": { "id": id },
+        },
+        "UpdateChat": {
+            "type": "object",
+            "required": ["userId"],
+            "properties": {
+                "userId": id,
+                "title": { "type": "string" },
+                "messageTtl": { "type": "integer", "format": "int64" },
+            },
+        },
+        "ChatUser": {
+            "type": "object",
+            "required": ["userId"],
+            "properties": { "userId": id },
+        },
+        "MuteChat": {
+            "type": "object",
+            "required": ["userId"],
+            "properties": { "userId": id, "until": timestamp },
+        },
+        "AckMessages": {
+            "type": "object",
+            "required": ["userId", "upToSeq"],
+            "properties": {
+                "userId": id,
+                "upToSeq": { "type": "integer", "format": "int64" },
+                "deviceId": id,
+            },
+        },
+        "ChatMessage": {
+            "type": "object",
+            "required": ["id", "timestamp", "message", "sourceUserId"],
+            "properties": {
+                "id": { "type": "string" },
+                "seq": { "type": "integer", "format": "int64" },
+                "timestamp": timestamp,
+                "message": { "type": "string" },
+                "attachmentIds": { "type": "array", "items": { "type": "string" } },
+                "sourceUserId": id,
+                "destinationUserId": id,
+                "editedAt": timestamp,
+                "deleted": { "type": "boolean" },
+                "reactions": { "type": "object", "additionalProperties": ids },
+                "receipts": {
+                    "type": "object",
+                    "additionalProperties": {
+                        "type": "string",
+                        "enum": ["sent", "delivered", "read"],
+                    },
+                },
+                "receivedAt": timestamp,
+                "envelope": reference("Envelope"),
+                "forwardedFrom": reference("MessageRef"),
+                "replyTo": { "type": "string" },
+                "kind": { "type": "string", "enum": ["text", "sticker", "system"] },
+                "previews": { "type": "array", "items": reference("LinkPreview") },
+                "revisions": { "type": "array", "items": reference("Revision") },
+            },
+        },
+        "MessageList": {
+            "type": "array",
+            "items": reference("ChatMessage"),
+        },
+        "MessagePage": {
+            "type": "object",
+            "required": ["messages"],
+            "properties": {
+                "messages": { "type": "array", "items": reference("ChatMessage") },
+                "nextCursor": { "type": "string" },
+            },
+        },
+        "EditMessage": {
+            "type": "object",
+            "required": ["editorUserId", "message", "timestamp"],
+            "properties": {
+                "editorUserId": id,
+                "message": { "type": "string" },
+                "timestamp": timestamp,
+            },
+        },
+        "ForwardMessage": {
+            "type": "object",
+            "required": ["userId", "fromChatId", "messageId", "timestamp"],
+            "properties": {
+                "userId": id,
+                "fromChatId": id,
+                "messageId": { "type": "string" },
+                "timestamp": timestamp,
+            },
+        },
+        "Envelope": {
+            "type": "object",
+            "required": ["type", "content"],
+            "properties": {
+                "type": { "type": "string", "enum": ["ciphertext", "preKeyMessage"] },
+                "content": { "type": "string" },
+                "sourceDeviceId": id,
+                "destinationDeviceId": id,
+            },
+        },
+        "MessageRef": {
+            "type": "object",
+            "required": ["chatId", "messageId"],
+            "properties": {
+                "chatId": id,
+                "messageId": { "type": "string" },
+            },
+        },
+        "LinkPreview": {
+            "type": "object",
+            "required": ["url"],
+            "properties": {
+                "url": { "type": "string" },
+                "title": { "type": "string" },
+                "description": { "type": "string" },
+                "imageId": { "type": "string" },
+            },
+        },
+        "Revision": {
+            "type": "object",
+            "required": ["message", "timestamp"],
+            "properties": {
+                "message": { "type": "string" },
+                "timestamp": timestamp,
+            },
+        },
+        "RevisionList": {
+            "type": "array",
+            "items": reference("Revision"),
+        },
+        "ChatTyping": {
+            "type": "object",
+            "required": ["chatId", "userIds"],
+            "properties": { "chatId": id, "userIds": ids },
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::openapi::*;
+
+    #[test]
+    fn test_document() {
+        let document = document(&[
+            (HttpMethod::GET, "/v1/chats".to_string()),
+            (HttpMethod::POST, "/v1/chats".to_string()),
+            (
+                HttpMethod::DELETE,
+                "/v1/chats/{chat_id}/messages/{message_id}".to_string(),
+            ),
+            (HttpMethod::GET, "/v1/blobs/{id}".to_string()),
+        ]);
+
+        let paths = document["paths"].as_object().unwrap();
+
+        assert_eq!(paths.len(), 3);
+        assert_eq!(document["paths"]["/v1/chats"].as_object().unwrap().len(), 2);
+
+        let delete = &document["paths"]["/v1/chats/{chat_id}/messages/{message_id}"]["delete"];
+
+        assert_eq!(
+            delete["parameters"],
+            json!([
+                { "name": "chat_id", "in": "path", "required": true, "schema": { "type": "integer" } },
+                { "name": "message_id", "in": "path", "required": true, "schema": { "type": "string" } },
+                { "name": "userId", "in": "query", "schema": { "type": "integer" } },
+            ])
+        );
+        assert_eq!(
+            delete["responses"]["200"]["content"]["application/json"]["schema"]["$ref"],
+            "#/components/schemas/ChatMessage"
+        );
+
+        // routes without an entry are described by their parameters
+
+        let download = &document["paths"]["/v1/blobs/{id}"]["get"];
+
+        assert!(download.get("summary").is_none());
+        assert_eq!(download["parameters"][0]["name"], "id");
+        assert_eq!(
+            download["responses"]["default"]["content"]["application/problem+json"]["schema"]
+                ["$ref"],
+            "#/components/schemas/Problem"
+        );
+
+        // every reference resolves
+
+        let schemas = schemas();
+        let text = serde_json::to_string(&(&schemas, &document)).unwrap();
+
+        for reference in text.split("\"$ref\":\"#/components/schemas/").skip(1) {
+            let name = reference.split('"').next().unwrap();
+
+            assert!(schemas.get(name).is_some(), "{} is unknown", name);
+        }
+    }
+}
@@ -0,0 +1,244 @@
+//! Provides the storage of the public keys that clients publish so
+//! that others can start end-to-end encrypted sessions with them,
+//! in the manner of the Signal protocol.
+//!
+//! Each of a user's devices uploads its identity key, a signed
+//! pre-key, and a batch of one-time pre-keys. When another user
+//! fetches a bundle for the device, one of its one-time pre-keys is
+//! consumed, so each is only ever handed out once. Once a device's
+//! one-time pre-keys run low, it is expected to upload more; until
+//! it does, and once they're exhausted, bundles only include its
+//! signed pre-key.
+//!
+//! Keys are opaque to the server, other than having to be base64.
+
+use crate::chat::Id;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// How few one-time pre-keys a device must have left for it to be
+/// told to upload more.
+pub(crate) const LOW_PRE_KEY_THRESHOLD: usize = 10;
+
+/// The most one-time pre-keys that a device can have stored.
+pub(crate) const MAX_ONE_TIME_PRE_KEYS: usize = 1000;
+
+/// A one-time pre-key, which is identified by an id that the
+/// device that generated it chose.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PreKey {
+    pub(crate) key_id: Id,
+    pub(crate) public_key: String,
+}
+
+/// A medium-term pre-key, which is signed by its device's
+/// identity key.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SignedPreKey {
+    pub(crate) key_id: Id,
+    pub(crate) public_key: String,
+    pub(crate) signature: String,
+}
+
+/// The keys that a device uploads. Its identity key and signed
+/// pre-key replace any it previously uploaded, whereas its one-time
+/// pre-keys are added to those it still has.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PreKeyUpload {
+    pub(crate) identity_key: String,
+    pub(crate) signed_pre_key: SignedPreKey,
+
+    #[serde(default)]
+    pub(crate) one_time_pre_keys: Vec<PreKey>,
+}
+
+/// The keys needed to start a session with one of a user's devices.
+/// The one-time pre-key is absent if the device has none left.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PreKeyBundle {
+    pub(crate) user_id: Id,
+    pub(crate) device_id: Id,
+    pub(crate) identity_key: String,
+    pub(crate) signed_pre_key: SignedPreKey,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) one_time_pre_key: Option<PreKey>,
+}
+
+/// Internal API.
+///
+/// The keys that a device has uploaded, and the one-time pre-keys
+/// it has left, ordered by their ids.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct DeviceKeys {
+    identity_key: String,
+    signed_pre_key: SignedPreKey,
+    one_time_pre_keys: BTreeMap<Id, String>,
+}
+
+impl PreKeyUpload {
+    /// Internal API.
+    ///
+    /// Determines if every key is base64, and the one-time pre-keys
+    /// have distinct ids and aren't too many to store.
+    pub(crate) fn is_valid(&self) -> bool {
+        let mut key_ids = self
+            .one_time_pre_keys
+            .iter()
+            .map(|k| k.key_id)
+            .collect::<Vec<_>>();
+
+        key_ids.sort_unstable();
+        key_ids.dedup();
+
+        is_key(&self.identity_key)
+            && is_key(&self.signed_pre_key.public_key)
+            && is_key(&self.signed_pre_key.signature)
+            && self.one_time_pre_keys.iter().all(|k| is_key(&k.public_key))
+            && key_ids.len() == self.one_time_pre_keys.len()
+            && key_ids.len() <= MAX_ONE_TIME_PRE_KEYS
+    }
+}
+
+impl DeviceKeys {
+    /// Internal API.
+    ///
+    /// Creates the keys of a device that hasn't uploaded any before.
+    pub(crate) fn new(upload: PreKeyUpload) -> Self {
+        Self {
+            identity_key: upload.identity_key,
+            signed_pre_key: upload.signed_pre_key,
+            one_time_pre_keys: upload
+                .one_time_pre_keys
+                .into_iter()
+                .map(|k| (k.key_id, k.public_key))
+                .collect(),
+        }
+    }
+
+    /// Internal API.
+    ///
+    /// Stores the supplied keys, returning false without storing
+    /// them if the device would then have too many one-time pre-keys.
+    pub(crate) fn store(&mut self, upload: PreKeyUpload) -> bool {
+        let added = upload
+            .one_time_pre_keys
+            .iter()
+            .filter(|k| !self.one_time_pre_keys.contains_key(&k.key_id))
+            .count();
+
+        if self.one_time_pre_keys.len() + added > MAX_ONE_TIME_PRE_KEYS {
+            return false;
+        }
+
+        self.identity_key = upload.identity_key;
+        self.signed_pre_key = upload.signed_pre_key;
+
+        for key in upload.one_time_pre_keys {
+            self.one_time_pre_keys.insert(key.key_id, key.public_key);
+        }
+
+        true
+    }
+
+    /// Internal API.
+    ///
+    /// Produces a bundle of this device's keys, consuming the
+    /// one-time pre-key with the lowest id, if any are left.
+    pub(crate) fn take_bundle(&mut self, user_id: Id, device_id: Id) -> PreKeyBundle {
+        let key_id = self.one_time_pre_keys.keys().next().cloned();
+
+        let one_time_pre_key = key_id.and_then(|key_id| {
+            self.one_time_pre_keys
+                .remove(&key_id)
+                .map(|public_key| PreKey { key_id, public_key })
+        });
+
+        PreKeyBundle {
+            user_id,
+            device_id,
+            identity_key: self.identity_key.clone(),
+            signed_pre_key: self.signed_pre_key.clone(),
+            one_time_pre_key,
+        }
+    }
+
+    /// Internal API.
+    ///
+    /// Obtains how many one-time pre-keys this device has left.
+    pub(crate) fn remaining(&self) -> usize {
+        self.one_time_pre_keys.len()
+    }
+}
+
+/// Internal API.
+///
+/// Determines if the supplied key is non-empty base64.
+fn is_key(key: &str) -> bool {
+    base64::decode(key).ok().is_some_and(|key| !key.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::prekeys::*;
+
+    fn upload(key_ids: &[Id]) -> PreKeyUpload {
+        PreKeyUpload {
+            identity_key: "aWRlbnRpdHk=".to_string(),
+            signed_pre_key: SignedPreKey {
+                key_id: 1,
+                public_key: "c2lnbmVk".to_string(),
+                signature: "c2lnbmF0dXJl".to_string(),
+            },
+            one_time_pre_keys: key_ids
+                .iter()
+                .map(|key_id| PreKey {
+                    key_id: *key_id,
+                    public_key: base64::encode(format!("key {}", key_id)),
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_is_valid() {
+        assert!(upload(&[1, 2]).is_valid());
+        assert!(!upload(&[1, 1]).is_valid());
+
+        let mut invalid = upload(&[]);
+        invalid.identity_key = "not base64!".to_string();
+
+        assert!(!invalid.is_valid());
+
+        invalid.identity_key = String::new();
+
+        assert!(!invalid.is_valid());
+    }
+
+    #[test]
+    fn test_take_bundle() {
+        let mut keys = DeviceKeys::new(upload(&[3, 2]));
+
+        assert!(keys.store(upload(&[3, 5])));
+        assert_eq!(keys.remaining(), 3);
+
+        let key_ids = (0..4)
+            .map(|_| keys.take_bundle(1, 1).one_time_pre_key.map(|k| k.key_id))
+            .collect::<Vec<_>>();
+
+        assert_eq!(key_ids, vec![Some(2), Some(3), Some(5), None]);
+        assert_eq!(keys.remaining(), 0);
+
+        let most = (0..MAX_ONE_TIME_PRE_KEYS as Id).collect::<Vec<_>>();
+
+        assert!(keys.store(upload(&most)));
+        assert!(!keys.store(upload(&[MAX_ONE_TIME_PRE_KEYS as Id])));
+        assert!(!upload(&[most.as_slice(), &[MAX_ONE_TIME_PRE_KEYS as Id]].concat()).is_valid());
+        assert_eq!(keys.remaining(), MAX_ONE_TIME_PRE_KEYS);
+    }
+}
@@ -0,0 +1,347 @@
+//! Tracks which users are online, i.e. have an event stream or a
+//! WebSocket open, and on which of their devices.
+//!
+//! Streams are fed continually whilst they're open, and each time a
+//! stream is fed it renews its user's presence on its device, which
+//! lapses once it hasn't been renewed for `PRESENCE_TTL`. Streams
+//! therefore needn't be closed explicitly, e.g. when a client's
+//! connection drops without closing its WebSocket.
+//!
+//! Users may also say that they're typing in a chat, which lapses
+//! once they haven't said so again for `TYPING_TTL`.
+//!
+//! Presence is local to each node, as the streams are, so it isn't
+//! logged or replicated. Nodes instead exchange digests of it by
+//! gossip, which are merged by keeping the latest time that each
+//! device was seen, and that each user was typing, so that every node
+//! converges on those of any of them. Times in the future, e.g. from
+//! a node whose clock is ahead, are merged as `now`, so that they
+//! still lapse.
+
+use crate::chat::Id;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+
+/// How long a device remains online after its stream was last fed,
+/// in milliseconds.
+pub const PRESENCE_TTL: u64 = 30_000;
+
+/// How long a user remains typing in a chat after they last said
+/// they were, in milliseconds.
+pub const TYPING_TTL: u64 = 5_000;
+
+/// When a device of a user was last seen online, in milliseconds
+/// since the epoch. Streams that don't identify a device have no
+/// `device_id`.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Sighting {
+    pub user_id: Id,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub device_id: Option<Id>,
+
+    pub last_seen: u64,
+}
+
+/// When a user last said they were typing in a chat, in milliseconds
+/// since the epoch.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Typing {
+    pub user_id: Id,
+    pub chat_id: Id,
+    pub last_typed: u64,
+}
+
+/// A digest of the presence that a node knows of, i.e. the devices
+/// that are online and the users who are typing, which nodes gossip.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Digest {
+    pub sightings: Vec<Sighting>,
+
+    #[serde(default)]
+    pub typing: Vec<Typing>,
+}
+
+/// Which devices of each user are online, and when they were last
+/// seen, as well as who is typing in each chat.
+#[derive(Default)]
+pub struct Presence {
+    last_seen: HashMap<Id, BTreeMap<Option<Id>, u64>>,
+    last_typed: HashMap<Id, BTreeMap<Id, u64>>,
+}
+
+impl Presence {
+    /// Creates a `Presence` in which no one is online.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records that the supplied device of the supplied user was seen
+    /// online at the supplied time.
+    pub fn touch(&mut self, user_id: Id, device_id: Option<Id>, now: u64) {
+        let last_seen = self
+            .last_seen
+            .entry(user_id)
+            .or_default()
+            .entry(device_id)
+            .or_insert(now);
+
+        *last_seen = (*last_seen).max(now);
+    }
+
+    /// Records that the supplied user was typing in the chat with the
+    /// supplied id at the supplied time.
+    pub fn type_in(&mut self, user_id: Id, chat_id: Id, now: u64) {
+        let last_typed = self
+            .last_typed
+            .entry(chat_id)
+            .or_default()
+            .entry(user_id)
+            .or_insert(now);
+
+        *last_typed = (*last_typed).max(now);
+    }
+
+    /// Obtains the ids of the users who are typing in the chat with
+    /// the supplied id at the supplied time.
+    pub fn typing(&self, chat_id: Id, now: u64) -> Vec<Id> {
+        self.last_typed
+            .get(&chat_id)
+            .into_iter()
+            .flat_map(|users| users.iter())
+            .filter(|(_, last_typed)| current(**last_typed, now, TYPING_TTL))
+            .map(|(user_id, _)| *user_id)
+            .collect()
+    }
+
+    /// Obtains when each of the supplied user's devices that are
+    /// online at the supplied time were last seen.
+    pub fn devices(&self, user_id: Id, now: u64) -> Vec<Sighting> {
+        self.last_seen
+            .get(&user_id)
+            .into_iter()
+            .flat_map(|devices| devices.iter())
+            .filter(|(_, last_seen)| current(**last_seen, now, PRESENCE_TTL))
+            .map(|(device_id, last_seen)| Sighting {
+                user_id,
+                device_id: *device_id,
+                last_seen: *last_seen,
+            })
+            .collect()
+    }
+
+    /// Obtains a digest of every device that is online, and every
+    /// user who is typing, at the supplied time, discarding those
+    /// that have lapsed.
+    pub fn digest(&mut self, now: u64) -> Digest {
+        self.prune(now);
+
+        let mut digest = Digest::default();
+
+        for (user_id, devices) in self.last_seen.iter() {
+            for (device_id, last_seen) in devices.iter() {
+                digest.sightings.push(Sighting {
+                    user_id: *user_id,
+                    device_id: *device_id,
+                    last_seen: *last_seen,
+                });
+            }
+        }
+
+        for (chat_id, users) in self.last_typed.iter() {
+            for (user_id, last_typed) in users.iter() {
+                digest.typing.push(Typing {
+                    user_id: *user_id,
+                    chat_id: *chat_id,
+                    last_typed: *last_typed,
+                });
+            }
+        }
+
+        digest
+    }
+
+    /// Merges the supplied digest, e.g. from another node, keeping
+    /// the latest time that each device was seen and that each user
+    /// was typing, but no later than `now`.
+    pub fn merge(&mut self, digest: Digest, now: u64) {
+        for sighting in digest.sightings {
+            let last_seen = sighting.last_seen.min(now);
+
+            if current(last_seen, now, PRESENCE_TTL) {
+                self.touch(sighting.user_id, sighting.device_id, last_seen);
+            }
+        }
+
+        for typing in digest.typing {
+            let last_typed = typing.last_typed.min(now);
+
+            if current(last_typed, now, TYPING_TTL) {
+                self.type_in(typing.user_id, typing.chat_id, last_typed);
+            }
+        }
+
+        self.prune(now);
+    }
+
+    /// Internal API.
+    ///
+    /// Discards the devices that are no longer online, and the users
+    /// who are no longer typing, at the supplied time.
+    fn prune(&mut self, now: u64) {
+        prune(&mut self.last_seen, now, PRESENCE_TTL);
+        prune(&mut self.last_typed, now, TYPING_TTL);
+    }
+}
+
+/// Internal API.
+///
+/// Determines if something that was last seen at the supplied time,
+/// e.g. a device, hasn't lapsed at `now` given its TTL.
+fn current(last_seen: u64, now: u64, ttl: u64) -> bool {
+    now.saturating_sub(last_seen) < ttl
+}
+
+/// Internal API.
+///
+/// Discards the entries of the supplied times that have lapsed at
+/// `now` given their TTL, and the ids that have none left.
+fn prune<K: Ord>(times: &mut HashMap<Id, BTreeMap<K, u64>>, now: u64, ttl: u64) {
+    for entries in times.values_mut() {
+        entries.retain(|_, last_seen| current(*last_seen, now, ttl));
+    }
+
+    times.retain(|_, entries| !entries.is_empty());
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::presence::*;
+
+    #[test]
+    fn test_presence() {
+        let mut presence = Presence::new();
+
+        presence.touch(1, Some(1), 1000);
+        presence.touch(1, None, 2000);
+        presence.touch(1, Some(1), 500);
+        presence.touch(2, Some(3), 1000);
+
+        assert_eq!(
+            presence.devices(1, 2000),
+            vec![
+                Sighting {
+                    user_id: 1,
+                    device_id: None,
+                    last_seen: 2000,
+                },
+                Sighting {
+                    user_id: 1,
+                    device_id: Some(1),
+                    last_seen: 1000,
+                },
+            ]
+        );
+
+        // devices lapse once they haven't been seen for the TTL
+
+        assert_eq!(presence.devices(1, 1000 + PRESENCE_TTL).len(), 1);
+        assert!(presence.devices(3, 1000).is_empty());
+
+        // digests from other nodes are merged, keeping the latest
+        // sightings, and ignoring those that have already lapsed
+
+        let mut other = Presence::new();
+
+        other.touch(1, Some(1), 5000);
+        other.touch(4, None, 100);
+
+        presence.merge(other.digest(5000), 1000 + PRESENCE_TTL);
+
+        assert_eq!(
+            presence
+                .devices(1, 1000 + PRESENCE_TTL)
+                .iter()
+                .map(|sighting| (sighting.device_id, sighting.last_seen))
+                .collect::<Vec<_>>(),
+            vec![(None, 2000), (Some(1), 5000)]
+        );
+
+        assert!(presence.devices(4, 1000 + PRESENCE_TTL).is_empty());
+
+        let mut digest = presence.digest(1000 + PRESENCE_TTL);
+
+        digest
+            .sightings
+            .sort_by_key(|sighting| (sighting.user_id, sighting.device_id));
+
+        assert_eq!(
+            digest
+                .sightings
+                .iter()
+                .map(|sighting| (sighting.user_id, sighting.device_id))
+                .collect::<Vec<_>>(),
+            vec![(1, None), (1, Some(1))]
+        );
+
+        // sightings from the future are merged as now, so they lapse
+
+        let mut presence = Presence::new();
+
+        presence.merge(
+            Digest {
+                sightings: vec![Sighting {
+                    user_id: 5,
+                    device_id: None,
+                    last_seen: u64::MAX,
+                }],
+                typing: Vec::new(),
+            },
+            1000,
+        );
+
+        assert_eq!(presence.devices(5, 1000)[0].last_seen, 1000);
+        assert!(presence.devices(5, 1000 + PRESENCE_TTL).is_empty());
+    }
+
+    #[test]
+    fn test_typing() {
+        let mut presence = Presence::new();
+
+        presence.type_in(1, 10, 1000);
+        presence.type_in(2, 10, 2000);
+        presence.type_in(1, 11, 1000);
+
+        assert_eq!(presence.typing(10, 2000), vec![1, 2]);
+        assert_eq!(presence.typing(10, 1000 + TYPING_TTL), vec![2]);
+        assert!(presence.typing(12, 1000).is_empty());
+
+        // typing is gossiped with presence, and lapses in the same way
+
+        let mut other = Presence::new();
+
+        other.type_in(3, 10, 2500);
+        other.type_in(4, 10, u64::MAX);
+        other.type_in(5, 10, 100);
+
+        presence.merge(other.digest(2500), 5200);
+
+        assert_eq!(presence.typing(10, 5200), vec![1, 2, 3, 4]);
+        assert!(presence.typing(10, 5200 + TYPING_TTL).is_empty());
+
+        let digest = presence.digest(1000 + TYPING_TTL);
+
+        assert!(digest.sightings.is_empty());
+        assert_eq!(
+            digest
+                .typing
+                .iter()
+                .map(|typing| (typing.user_id, typing.last_typed))
+                .collect::<Vec<_>>(),
+            vec![(2, 2000), (3, 2500), (4, 5200)]
+        );
+    }
+}
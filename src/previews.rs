@@ -0,0 +1,113 @@
+//! Provides link previews, i.e. the title, description, and image of
+//! a page that a message links to, so that participants can see what
+//! it is without visiting it.
+//!
+//! The server doesn't fetch pages itself. Instead, the client that
+//! sends a message fetches the pages it links to and attaches their
+//! previews, and the server validates them and stores them with the
+//! message, so that every participant sees the same previews. A
+//! preview's image is the id of a blob, which is uploaded like an
+//! attachment.
+
+use serde::{Deserialize, Serialize};
+
+/// The most previews that a message can have.
+const MAX_PREVIEWS: usize = 4;
+
+/// The longest URL that can be previewed, in bytes.
+const MAX_URL_LENGTH: usize = 2048;
+
+/// The longest title of a preview, in bytes.
+const MAX_TITLE_LENGTH: usize = 256;
+
+/// The longest description of a preview, in bytes.
+const MAX_DESCRIPTION_LENGTH: usize = 1024;
+
+/// A preview of a page that a message links to.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LinkPreview {
+    pub(crate) url: String,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) title: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) description: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) image_id: Option<String>,
+}
+
+impl LinkPreview {
+    /// Internal API.
+    ///
+    /// Determines if this preview is of an HTTP(S) URL, and is within
+    /// the limits on the length of each of its fields.
+    fn is_valid(&self) -> bool {
+        let within = |field: &Option<String>, max| field.as_ref().is_none_or(|f| f.len() <= max);
+
+        (self.url.starts_with("http://") || self.url.starts_with("https://"))
+            && self.url.len() <= MAX_URL_LENGTH
+            && within(&self.title, MAX_TITLE_LENGTH)
+            && within(&self.description, MAX_DESCRIPTION_LENGTH)
+            && self.image_id.as_ref().is_none_or(|id| !id.is_empty())
+    }
+
+    /// Internal API.
+    ///
+    /// Obtains how many bytes of content this preview has.
+    pub(crate) fn size(&self) -> usize {
+        self.url.len()
+            + self.title.as_ref().map_or(0, String::len)
+            + self.description.as_ref().map_or(0, String::len)
+    }
+}
+
+/// Internal API.
+///
+/// Determines if the supplied previews are valid, and there aren't
+/// too many of them.
+pub(crate) fn validate(previews: &[LinkPreview]) -> bool {
+    previews.len() <= MAX_PREVIEWS && previews.iter().all(LinkPreview::is_valid)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::previews::*;
+
+    fn preview(url: &str) -> LinkPreview {
+        LinkPreview {
+            url: url.to_string(),
+            title: Some("Example".to_string()),
+            description: None,
+            image_id: Some("blob".to_string()),
+        }
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(validate(&[]));
+        assert!(validate(&[
+            preview("https://example.com"),
+            preview("http://a.b")
+        ]));
+
+        assert!(!validate(&[preview("ftp://example.com")]));
+        assert!(!validate(&[preview(&format!(
+            "https://{}",
+            "a".repeat(MAX_URL_LENGTH)
+        ))]));
+        assert!(!validate(&vec![preview("https://a.b"); MAX_PREVIEWS + 1]));
+
+        let mut long = preview("https://example.com");
+        long.description = Some("a".repeat(MAX_DESCRIPTION_LENGTH + 1));
+
+        assert!(!validate(&[long]));
+
+        assert_eq!(
+            serde_json::to_string(&preview("https://example.com")).unwrap(),
+            "{\"url\":\"https://example.com\",\"title\":\"Example\",\"imageId\":\"blob\"}"
+        );
+    }
+}
@@ -0,0 +1,136 @@
+//! Provides per-IP rate limiting, so that a single abusive client
+//! can't monopolize the server.
+//!
+//! Each address has a bucket of tokens, which starts full and is
+//! refilled at a steady rate up to its capacity. Each request takes a
+//! token, and a request that finds its bucket empty is rejected, along
+//! with how long the client should wait before its next token. The
+//! capacity allows short bursts, e.g. when a client first connects
+//! and fetches its chats.
+//!
+//! Buckets that have refilled are forgotten once there are many of
+//! them, as they're no different from a new one.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+/// How many addresses are tracked before those whose buckets have
+/// refilled are forgotten.
+const MAX_TRACKED_ADDRESSES: usize = 10_000;
+
+/// Limits how many requests each address may make.
+#[derive(Debug)]
+pub struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    buckets: HashMap<IpAddr, Bucket>,
+}
+
+/// Internal API.
+///
+/// The tokens that an address has left, as of when it last made a
+/// request.
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+impl RateLimiter {
+    /// Creates a limiter that allows each address the supplied
+    /// number of requests per second, in bursts of at most `burst`.
+    pub fn new(rate: u32, burst: u32) -> Self {
+        Self {
+            rate: f64::from(rate.max(1)),
+            burst: f64::from(burst.max(1)),
+            buckets: HashMap::new(),
+        }
+    }
+
+    /// Takes a token for a request from the supplied address at the
+    /// supplied time, returning how long it must wait before its next
+    /// request if there isn't one.
+    pub fn check(&mut self, ip: IpAddr, now: Instant) -> Result<(), Duration> {
+        let (rate, burst) = (self.rate, self.burst);
+
+        if self.buckets.len() >= MAX_TRACKED_ADDRESSES {
+            self.buckets
+                .retain(|_, bucket| bucket.refilled(now, rate) < burst);
+        }
+
+        let bucket = self.buckets.entry(ip).or_insert(Bucket {
+            tokens: burst,
+            updated_at: now,
+        });
+
+        bucket.tokens = bucket.refilled(now, rate).min(burst);
+        bucket.updated_at = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+
+            Ok(())
+        } else {
+            Err(Duration::from_nanos(
+                ((1.0 - bucket.tokens) / rate * 1e9).ceil() as u64,
+            ))
+        }
+    }
+}
+
+impl Bucket {
+    /// Internal API.
+    ///
+    /// Obtains how many tokens this bucket has at the supplied time,
+    /// ignoring its capacity.
+    fn refilled(&self, now: Instant, rate: f64) -> f64 {
+        let elapsed = if now > self.updated_at {
+            now - self.updated_at
+        } else {
+            Duration::from_secs(0)
+        };
+
+        let seconds = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9;
+
+        self.tokens + seconds * rate
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::rate_limit::*;
+
+    #[test]
+    fn test_check() {
+        let mut limiter = RateLimiter::new(2, 3);
+        let (a, b) = ("10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap());
+        let now = Instant::now();
+
+        // bursts are allowed, up to the capacity
+
+        for _ in 0..3 {
+            assert_eq!(limiter.check(a, now), Ok(()));
+        }
+
+        assert_eq!(limiter.check(a, now), Err(Duration::from_millis(500)));
+        assert_eq!(limiter.check(b, now), Ok(()));
+
+        // tokens are refilled at the rate
+
+        let later = now + Duration::from_millis(500);
+
+        assert_eq!(limiter.check(a, later), Ok(()));
+        assert_eq!(limiter.check(a, later), Err(Duration::from_millis(500)));
+
+        // but not beyond the capacity
+
+        let much_later = later + Duration::from_secs(60);
+
+        for _ in 0..3 {
+            assert_eq!(limiter.check(a, much_later), Ok(()));
+        }
+
+        assert!(limiter.check(a, much_later).is_err());
+    }
+}
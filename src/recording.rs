@@ -0,0 +1,288 @@
+//! Provides facilities for recording inbound `HttpRequest`s
+//! to a file, and reading them back so that they can be
+//! replayed against a fresh server.
+//!
+//! Recordings are stored as JSON lines, one request per line,
+//! each annotated with its offset from the start of recording.
+//!
+//! Credentials are redacted before requests are recorded, i.e. the
+//! values of headers that authenticate them, and the bodies of
+//! requests that exchange credentials for tokens.
+
+use crate::federation;
+use crate::http::*;
+use crate::signing;
+use serde::{Deserialize, Serialize};
+use std::io::Error as IoError;
+use std::io::ErrorKind as IoErrorKind;
+use std::io::{BufRead, Result as IoResult, Write};
+use std::time::Instant;
+
+/// The headers whose values are redacted, as they authenticate the
+/// requests they're in.
+const REDACTED_HEADERS: &[&str] = &[
+    "Authorization",
+    "Cookie",
+    "Proxy-Authorization",
+    signing::SIGNATURE_HEADER,
+    federation::SIGNATURE_HEADER,
+];
+
+/// The paths of the requests whose bodies are redacted, as they
+/// have credentials, e.g. a user's password.
+const REDACTED_BODY_PATHS: &[&str] = &["/tokens", "/v1/tokens"];
+
+/// What redacted values are replaced with.
+const REDACTED: &str = "redacted";
+
+/// An owned representation of an `HttpRequest` along with
+/// the time it was received, relative to the start of the
+/// recording. Bodies are stored base64-encoded, so those that
+/// aren't UTF-8, e.g. MessagePack, are replayed faithfully.
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RecordedRequest {
+    pub offset_ms: u64,
+    pub method: HttpMethod,
+    pub path: String,
+    pub version: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Option<String>,
+}
+
+impl RecordedRequest {
+    /// Borrow this recorded request as an `HttpRequest` so
+    /// that it can be re-issued. A body that isn't base64 is empty,
+    /// but `read_recording` rejects those.
+    pub fn as_request(&self) -> HttpRequest<'_> {
+        HttpRequest {
+            body: self
+                .body
+                .as_ref()
+                .map(|b| base64::decode(b).unwrap_or_default().into()),
+            headers: self
+                .headers
+                .iter()
+                .map(|(n, v)| (n.as_str(), v.as_str()))
+                .collect(),
+            method: self.method,
+            path: &self.path,
+            version: &self.version,
+        }
+    }
+}
+
+/// Writes each request it is given to the underlying writer,
+/// flushing after every request so that a recording survives
+/// the process being killed.
+pub struct Recorder<W: Write> {
+    started: Instant,
+    writer: W,
+}
+
+impl<W: Write> Recorder<W> {
+    /// Creates a new `Recorder` that writes to the supplied
+    /// writer. Offsets are measured from this call.
+    pub fn new(writer: W) -> Self {
+        Self {
+            started: Instant::now(),
+            writer,
+        }
+    }
+
+    /// Record the supplied request, redacting its credentials.
+    pub fn record(&mut self, request: &HttpRequest) -> IoResult<()> {
+        let path = request.path.split('?').next().unwrap_or_default();
+
+        let recorded = RecordedRequest {
+            offset_ms: self.started.elapsed().as_millis() as u64,
+            method: request.method,
+            path: request.path.to_string(),
+            version: request.version.to_string(),
+            headers: request
+                .headers
+                .iter()
+                .map(|(n, v)| {
+                    let redacted = REDACTED_HEADERS
+                        .iter()
+                        .any(|name| name.eq_ignore_ascii_case(n));
+
+                    (
+                        n.to_string(),
+                        if redacted { REDACTED } else { v }.to_string(),
+                    )
+                })
+                .collect(),
+            body: match request.body_bytes() {
+                Some(_) if REDACTED_BODY_PATHS.contains(&path) => Some(base64::encode(REDACTED)),
+
+                body => body.map(base64::encode),
+            },
+        };
+
+        serde_json::to_writer(&mut self.writer, &recorded)?;
+        self.writer.write_all(b"\n")?;
+        self.writer.flush()
+    }
+}
+
+/// Reads a recording that was previously produced by a `Recorder`,
+/// skipping any blank lines, and rejecting bodies that aren't base64.
+pub fn read_recording<R: BufRead>(reader: R) -> IoResult<Vec<RecordedRequest>> {
+    let mut requests = Vec::new();
+
+    for line in reader.lines() {
+        let line = line?;
+
+        if !line.trim().is_empty() {
+            let request: RecordedRequest = serde_json::from_str(&line)?;
+
+            if let Some(ref body) = request.body {
+                if base64::decode(body).is_err() {
+                    return Err(IoError::new(
+                        IoErrorKind::InvalidData,
+                        "recorded body is not base64",
+                    ));
+                }
+            }
+
+            requests.push(request);
+        }
+    }
+
+    Ok(requests)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::recording::*;
+
+    #[test]
+    fn test_record_and_read() {
+        let mut data = Vec::new();
+
+        {
+            let mut recorder = Recorder::new(&mut data);
+
+            recorder
+                .record(&HttpRequest {
+                    body: None,
+                    headers: vec![("Accept", "*/*")],
+                    method: HttpMethod::GET,
+                    path: "/chats?userId=1",
+                    version: "HTTP/1.1",
+                })
+                .unwrap();
+
+            recorder
+                .record(&HttpRequest {
+                    body: Some(
+                        "{ \"id\": 1, \"participantIds\": [1, 2] }"
+                            .as_bytes()
+                            .into(),
+                    ),
+                    headers: vec![("Content-Type", "application/json")],
+                    method: HttpMethod::POST,
+                    path: "/chats",
+                    version: "HTTP/1.1",
+                })
+                .unwrap();
+        }
+
+        let recording = read_recording(data.as_slice()).unwrap();
+
+        assert_eq!(recording.len(), 2);
+
+        assert_eq!(
+            recording[0].as_request(),
+            HttpRequest {
+                body: None,
+                headers: vec![("Accept", "*/*")],
+                method: HttpMethod::GET,
+                path: "/chats?userId=1",
+                version: "HTTP/1.1",
+            }
+        );
+
+        assert_eq!(
+            recording[1].as_request(),
+            HttpRequest {
+                body: Some(
+                    "{ \"id\": 1, \"participantIds\": [1, 2] }"
+                        .as_bytes()
+                        .into()
+                ),
+                headers: vec![("Content-Type", "application/json")],
+                method: HttpMethod::POST,
+                path: "/chats",
+                version: "HTTP/1.1",
+            }
+        );
+
+        assert!(recording[0].offset_ms <= recording[1].offset_ms);
+    }
+
+    #[test]
+    fn test_binary_bodies_and_redaction() {
+        let mut data = Vec::new();
+
+        {
+            let mut recorder = Recorder::new(&mut data);
+
+            recorder
+                .record(&HttpRequest {
+                    body: Some(b"\x82\xa2\xff".as_ref().into()),
+                    headers: vec![
+                        ("Content-Type", "application/msgpack"),
+                        ("authorization", "Bearer secret"),
+                        ("X-Signature", "secret"),
+                    ],
+                    method: HttpMethod::POST,
+                    path: "/v1/chats",
+                    version: "HTTP/1.1",
+                })
+                .unwrap();
+
+            recorder
+                .record(&HttpRequest {
+                    body: Some(
+                        b"{ \"username\": \"a\", \"password\": \"secret\" }"
+                            .as_ref()
+                            .into(),
+                    ),
+                    headers: Vec::new(),
+                    method: HttpMethod::POST,
+                    path: "/v1/tokens?ttl=60",
+                    version: "HTTP/1.1",
+                })
+                .unwrap();
+        }
+
+        assert!(!String::from_utf8_lossy(&data).contains("secret"));
+
+        let recording = read_recording(data.as_slice()).unwrap();
+
+        assert_eq!(
+            recording[0].as_request(),
+            HttpRequest {
+                body: Some(b"\x82\xa2\xff".as_ref().into()),
+                headers: vec![
+                    ("Content-Type", "application/msgpack"),
+                    ("authorization", "redacted"),
+                    ("X-Signature", "redacted"),
+                ],
+                method: HttpMethod::POST,
+                path: "/v1/chats",
+                version: "HTTP/1.1",
+            }
+        );
+
+        assert_eq!(recording[1].as_request().body(), Some("redacted"));
+
+        assert!(read_recording(
+            b"{\"offsetMs\":0,\"method\":\"POST\",\"path\":\"/\",\"version\":\"HTTP/1.1\",\"headers\":[],\"body\":\"not base64\"}"
+                .as_ref()
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,353 @@
+//! Provides leader to follower replication, so that a follower keeps
+//! a copy of a leader's state, e.g. as a warm standby or to serve
+//! reads.
+//!
+//! A leader retains its most recently logged entries in memory,
+//! regardless of whether its write-ahead log has since been
+//! truncated by a snapshot. A follower polls the leader for the
+//! entries after its own log index, and applies them in order, as
+//! if it were replaying its own log. If the entries it needs are no
+//! longer retained, it restores the leader's snapshot instead, and
+//! then continues from there.
+//!
+//! Followers are read-only, so that their state can't diverge from
+//! the leader's.
+//!
+//! As the leader's log and snapshot include every user's data, a
+//! leader only serves them to operators, or to requests that passed
+//! its Basic auth. A follower authenticates with Basic credentials,
+//! or by signing its requests as one of the leader's signing clients
+//! whose user is an operator, or both.
+
+use crate::chat::{ChatServer, Snapshot};
+use crate::http::HttpMethod;
+use crate::http_client::{ClientAuth, HttpClientResponse};
+use crate::signing::Signer;
+use crate::storage::LogEntry;
+use std::io::Error as IoError;
+use std::io::Result as IoResult;
+use std::time::Duration;
+
+/// The default for the most entries that a leader retains.
+pub const DEFAULT_REPLICATION_BACKLOG: usize = 10_000;
+
+/// The most entries that a follower is sent for each poll.
+pub const MAX_REPLICATED_ENTRIES: usize = 1000;
+
+/// How long each request to the leader may take to connect, write,
+/// and read.
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Internal API.
+///
+/// The entries that a leader retains for its followers, serialized
+/// as they are sent to them, in index order.
+pub(crate) struct ReplicationLog {
+    max_entries: usize,
+    entries: Vec<String>,
+}
+
+impl ReplicationLog {
+    /// Internal API.
+    ///
+    /// Creates an empty log that retains at most the supplied number
+    /// of entries.
+    pub(crate) fn new(max_entries: usize) -> Self {
+        Self {
+            max_entries,
+            entries: Vec::new(),
+        }
+    }
+
+    /// Internal API.
+    ///
+    /// Appends the supplied entry, which must be the one after the
+    /// last that was appended, discarding the oldest entries if the
+    /// log is full.
+    pub(crate) fn push(&mut self, entry: &LogEntry) -> IoResult<()> {
+        self.entries.push(serde_json::to_string(entry)?);
+
+        if self.entries.len() > self.max_entries {
+            let excess = self.entries.len() - self.max_entries;
+
+            self.entries.drain(..excess);
+        }
+
+        Ok(())
+    }
+
+    /// Internal API.
+    ///
+    /// Discards every entry, e.g. because a snapshot was restored,
+    /// so that the entries that follow aren't contiguous with them.
+    pub(crate) fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    /// Internal API.
+    ///
+    /// Obtains the entries after the one with the supplied index,
+    /// where `last_index` is the index of the last entry that was
+    /// appended. Returns `None` if some of them have been discarded,
+    /// or the supplied index is ahead of the log.
+    pub(crate) fn after(&self, index: u64, last_index: u64) -> Option<&[String]> {
+        let retained = self.entries.len() as u64;
+
+        if index > last_index || last_index - index > retained {
+            return None;
+        }
+
+        let start = (retained - (last_index - index)) as usize;
+
+        Some(&self.entries[start..(start + MAX_REPLICATED_ENTRIES).min(self.entries.len())])
+    }
+}
+
+/// What a follower fetched from its leader, which it then applies
+/// to its own server.
+pub enum Update {
+    /// The entries after the follower's log index, in index order.
+    Entries(Vec<LogEntry>),
+
+    /// The leader's snapshot, as the entries that the follower needs
+    /// are no longer retained.
+    Snapshot(Box<Snapshot<'static>>),
+}
+
+impl Update {
+    /// Applies this update to the supplied server, returning how many
+    /// entries were applied.
+    pub fn apply(self, server: &mut ChatServer) -> IoResult<usize> {
+        match self {
+            Update::Entries(entries) => {
+                let count = entries.len();
+
+                for entry in entries {
+                    server.replicate(entry)?;
+                }
+
+                Ok(count)
+            }
+
+            Update::Snapshot(snapshot) => {
+                server.resync(*snapshot)?;
+
+                Ok(0)
+            }
+        }
+    }
+}
+
+/// Fetches updates from a leader, over HTTP.
+pub struct Follower {
+    leader_url: String,
+    auth: ClientAuth,
+}
+
+impl Follower {
+    /// Creates a follower of the leader at the supplied URL, e.g.
+    /// `http://127.0.0.1:8080`.
+    pub fn new(leader_url: &str) -> Self {
+        Self {
+            leader_url: leader_url.trim_end_matches('/').to_string(),
+            auth: ClientAuth::default(),
+        }
+    }
+
+    /// Configures the follower to authenticate with the supplied
+    /// Basic credentials, e.g. `follower:secret`, for leaders whose
+    /// replication routes require Basic auth.
+    pub fn set_basic_auth(&mut self, credentials: &str) {
+        self.auth.set_basic_auth(credentials);
+    }
+
+    /// Configures the follower to sign its requests, for leaders that
+    /// configure it as a signing client whose user is an operator.
+    pub fn set_signer(&mut self, signer: Signer) {
+        self.auth.set_signer(signer);
+    }
+
+    /// Fetches the entries after the supplied log index, or the
+    /// leader's snapshot if they're no longer retained.
+    pub fn fetch(&self, after: u64) -> IoResult<Update> {
+        let response = self.get(&format!("/replication/log/{}", after))?;
+
+        match response.status {
+            200 => Ok(Update::Entries(serde_json::from_str(&response.body)?)),
+
+            410 => self.fetch_snapshot(),
+
+            status => Err(unexpected_status(status)),
+        }
+    }
+
+    /// Fetches the leader's snapshot, e.g. so that a follower starts
+    /// from the leader's state rather than its own.
+    pub fn fetch_snapshot(&self) -> IoResult<Update> {
+        let response = self.get("/replication/snapshot")?;
+
+        if !response.is_success() {
+            return Err(unexpected_status(response.status));
+        }
+
+        Ok(Update::Snapshot(Box::new(serde_json::from_str(
+            &response.body,
+        )?)))
+    }
+
+    /// Obtains the URL of the leader.
+    pub fn leader_url(&self) -> &str {
+        &self.leader_url
+    }
+
+    /// Internal API.
+    ///
+    /// Fetches the supplied path from the leader, with the headers
+    /// that authenticate the follower.
+    fn get(&self, path: &str) -> IoResult<HttpClientResponse> {
+        self.auth.request(
+            HttpMethod::GET,
+            &format!("{}{}", self.leader_url, path),
+            None,
+            FETCH_TIMEOUT,
+        )
+    }
+}
+
+/// Internal API.
+///
+/// The error for a response from the leader that isn't expected.
+fn unexpected_status(status: u16) -> IoError {
+    IoError::other(format!("unexpected status {} from leader", status))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::chat::*;
+    use crate::replication::*;
+
+    fn entry(index: u64) -> LogEntry {
+        LogEntry {
+            index,
+            now: None,
+            request: ChatRequest::StoreContactList {
+                id: index,
+                list: vec![1],
+            },
+        }
+    }
+
+    #[test]
+    fn test_after() {
+        let mut log = ReplicationLog::new(3);
+
+        assert_eq!(log.after(0, 0), Some(&[][..]));
+        assert_eq!(log.after(1, 0), None);
+
+        for index in 1..=5 {
+            log.push(&entry(index)).unwrap();
+        }
+
+        // only the last 3 entries are retained
+
+        assert_eq!(log.after(1, 5), None);
+        assert_eq!(log.after(2, 5).map(<[_]>::len), Some(3));
+        assert_eq!(log.after(4, 5).map(<[_]>::len), Some(1));
+        assert_eq!(log.after(5, 5).map(<[_]>::len), Some(0));
+        assert_eq!(log.after(6, 5), None);
+
+        assert_eq!(
+            serde_json::from_str::<LogEntry>(&log.after(4, 5).unwrap()[0])
+                .unwrap()
+                .index,
+            5
+        );
+    }
+
+    #[test]
+    fn test_apply() {
+        let mut leader = ChatServer::new();
+        leader.set_replication_backlog(2);
+
+        for (id, list) in [(1, vec![2]), (2, vec![1])].iter() {
+            leader.issue(ChatRequest::StoreContactList {
+                id: *id,
+                list: list.clone(),
+            });
+        }
+
+        leader.issue(ChatRequest::CreateChat {
+            id: Some(1),
+            participant_ids: vec![1, 2],
+            title: None,
+            created_at: None,
+            creator: None,
+        });
+
+        let entries = |server: &ChatServer, after| {
+            server.replicated_entries(after).map(|entries| {
+                entries
+                    .iter()
+                    .map(|entry| serde_json::from_str(entry).unwrap())
+                    .collect::<Vec<LogEntry>>()
+            })
+        };
+
+        let mut follower = ChatServer::new();
+        follower.set_read_only(true);
+
+        // the first entry is no longer retained, so the follower
+        // must restore a snapshot
+
+        assert!(entries(&leader, follower.log_index()).is_none());
+
+        Update::Snapshot(Box::new(
+            serde_json::from_str(&serde_json::to_string(&leader.snapshot()).unwrap()).unwrap(),
+        ))
+        .apply(&mut follower)
+        .unwrap();
+
+        assert_eq!(follower.log_index(), 3);
+        assert_eq!(follower.state_digest(), leader.state_digest());
+
+        leader.issue(ChatRequest::AddMessage {
+            id: "a".to_string(),
+            chat_id: 1,
+            source_user_id: 1,
+            destination_user_id: Some(2),
+            timestamp: 0,
+            message: "hello".to_string(),
+            attachment_ids: Vec::new(),
+            envelope: None,
+            reply_to: None,
+            kind: MessageKind::Text,
+            previews: Vec::new(),
+        });
+
+        let update = Update::Entries(entries(&leader, follower.log_index()).unwrap());
+
+        assert_eq!(update.apply(&mut follower).unwrap(), 1);
+        assert_eq!(follower.log_index(), 4);
+        assert_eq!(follower.state_digest(), leader.state_digest());
+
+        // entries that were already applied are skipped, and those
+        // that would leave a gap are rejected
+
+        assert!(follower.replicate(entry(4)).is_ok());
+        assert!(follower.replicate(entry(6)).is_err());
+        assert_eq!(follower.state_digest(), leader.state_digest());
+
+        // followers can't be mutated by clients
+
+        assert_eq!(
+            follower
+                .issue(ChatRequest::StoreContactList {
+                    id: 1,
+                    list: Vec::new(),
+                })
+                .error()
+                .map(|e| e.code),
+            Some(ErrorCode::ReadOnly)
+        );
+    }
+}
@@ -0,0 +1,89 @@
+//! Provides reports, which participants of a chat file against its
+//! messages, e.g. because they're abusive, so that an operator can
+//! review them.
+//!
+//! A report records the content and author of the message when it
+//! was reported, so that it can still be audited once the message
+//! has been edited or removed. Operators resolve reports by either
+//! removing the message, which replaces it with a tombstone as if
+//! its author had deleted it, or dismissing them. Reports are part
+//! of a `ChatServer`'s state, and are never discarded.
+
+use crate::chat::Id;
+use serde::{Deserialize, Serialize};
+
+/// A report of a message, along with how it was resolved, if it
+/// has been.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Report {
+    pub(crate) id: Id,
+    pub(crate) reporter: Id,
+    pub(crate) chat_id: Id,
+    pub(crate) message_id: String,
+    pub(crate) reason: String,
+    pub(crate) source_user_id: Id,
+    pub(crate) content: String,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) reported_at: Option<u64>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) resolution: Option<Resolution>,
+}
+
+/// How an operator resolved a report.
+#[derive(Copy, Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Resolution {
+    /// The message was removed.
+    Removed,
+
+    /// The message was left as it was.
+    Dismissed,
+}
+
+impl Report {
+    /// Internal API.
+    ///
+    /// Determines if this report is of the supplied message.
+    pub(crate) fn is_of(&self, chat_id: Id, message_id: &str) -> bool {
+        self.chat_id == chat_id && self.message_id == message_id
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::reports::*;
+
+    #[test]
+    fn test_serialize() {
+        let mut report = Report {
+            id: 1,
+            reporter: 2,
+            chat_id: 3,
+            message_id: "a".to_string(),
+            reason: "spam".to_string(),
+            source_user_id: 4,
+            content: "buy now".to_string(),
+            reported_at: None,
+            resolution: None,
+        };
+
+        assert!(report.is_of(3, "a"));
+        assert!(!report.is_of(3, "b"));
+        assert!(!report.is_of(1, "a"));
+
+        assert_eq!(
+            serde_json::to_string(&report).unwrap(),
+            "{\"id\":1,\"reporter\":2,\"chatId\":3,\"messageId\":\"a\",\"reason\":\"spam\",\"sourceUserId\":4,\"content\":\"buy now\"}"
+        );
+
+        report.resolution = Some(Resolution::Removed);
+
+        assert_eq!(
+            serde_json::from_str::<Report>(&serde_json::to_string(&report).unwrap()).unwrap(),
+            report
+        );
+    }
+}
@@ -0,0 +1,333 @@
+//! Provides a router, which finds the handler for a request from its
+//! method and path, along with the parameters in the path.
+//!
+//! Routes are added with patterns whose segments are either literal,
+//! e.g. `chats`, or named parameters, e.g. `{chat_id}`, which match
+//! any segment. A path matches a pattern if it has the same number
+//! of segments and each literal is equal, ignoring its query string.
+//! If several routes match, the one that was added first is used, so
+//! e.g. `/keys/{user_id}/fetch` must be added before
+//! `/keys/{user_id}/{device_id}`. A path that matches routes with
+//! other methods isn't routed, but those methods can be obtained, so
+//! that the request can be told which it may use.
+//!
+//! A router's routes can be mounted under a prefix in another, e.g.
+//! so that each version of an API is routed under its own prefix,
+//! such as `/v1`, and versions can diverge from each other.
+
+use crate::http::HttpMethod;
+use std::str::FromStr;
+
+/// Routes requests to handlers of type `H`.
+pub struct Router<H> {
+    routes: Vec<Route<H>>,
+}
+
+/// Internal API.
+///
+/// A handler, and the method and pattern of the requests that are
+/// routed to it.
+struct Route<H> {
+    method: HttpMethod,
+    segments: Vec<Segment>,
+    handler: H,
+}
+
+/// Internal API.
+///
+/// A segment of a route's pattern.
+#[derive(Debug, PartialEq)]
+enum Segment {
+    Literal(&'static str),
+    Param(&'static str),
+}
+
+/// The parameters in a path that was routed, by name.
+#[derive(Debug, Default, PartialEq)]
+pub struct Params<'p> {
+    values: Vec<(&'static str, &'p str)>,
+}
+
+impl<H> Default for Router<H> {
+    fn default() -> Self {
+        Self { routes: Vec::new() }
+    }
+}
+
+impl<H> Router<H> {
+    /// Creates a router without any routes.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a route for requests with the supplied method whose path
+    /// matches the supplied pattern, e.g. `/chats/{chat_id}/messages`.
+    pub fn add(&mut self, method: HttpMethod, pattern: &'static str, handler: H) -> &mut Self {
+        self.routes.push(Route {
+            method,
+            segments: parse(pattern),
+            handler,
+        });
+
+        self
+    }
+
+    /// Adds every route of the supplied router, with their patterns
+    /// prefixed by the supplied one, e.g. `/v1`.
+    pub fn mount(&mut self, prefix: &'static str, router: Router<H>) -> &mut Self {
+        for mut route in router.routes {
+            let mut segments = parse(prefix);
+
+            segments.append(&mut route.segments);
+            route.segments = segments;
+
+            self.routes.push(route);
+        }
+
+        self
+    }
+
+    /// Finds the handler for a request with the supplied method and
+    /// path, along with the path's parameters, or `None` if no route
+    /// matches it.
+    pub fn route<'p>(&self, method: HttpMethod, path: &'p str) -> Option<(&H, Params<'p>)> {
+        let path = path.split('?').next().unwrap_or_default();
+
+        self.routes
+            .iter()
+            .filter(|route| route.method == method)
+            .find_map(|route| route.matches(path).map(|params| (&route.handler, params)))
+    }
+
+    /// Obtains the methods of the routes that match the supplied
+    /// path, in the order they were added, without duplicates.
+    pub fn methods(&self, path: &str) -> Vec<HttpMethod> {
+        let path = path.split('?').next().unwrap_or_default();
+        let mut methods = Vec::new();
+
+        for route in self.routes.iter() {
+            if !methods.contains(&route.method) && route.matches(path).is_some() {
+                methods.push(route.method);
+            }
+        }
+
+        methods
+    }
+
+    /// Obtains the method and pattern of each route, in the order
+    /// they were added, e.g. to describe them.
+    pub fn patterns(&self) -> Vec<(HttpMethod, String)> {
+        self.routes
+            .iter()
+            .map(|route| (route.method, route.pattern()))
+            .collect()
+    }
+
+    /// Obtains the pattern of the route that a request with the
+    /// supplied method and path is routed to, e.g. so that requests
+    /// can be counted by route rather than by path.
+    pub fn pattern(&self, method: HttpMethod, path: &str) -> Option<String> {
+        let path = path.split('?').next().unwrap_or_default();
+
+        self.routes
+            .iter()
+            .filter(|route| route.method == method)
+            .find(|route| route.matches(path).is_some())
+            .map(Route::pattern)
+    }
+}
+
+impl<H> Route<H> {
+    /// Internal API.
+    ///
+    /// Obtains the parameters in the supplied path, if it matches
+    /// this route's pattern.
+    fn matches<'p>(&self, path: &'p str) -> Option<Params<'p>> {
+        let mut params = Params::default();
+        let mut segments = split(path);
+
+        for segment in self.segments.iter() {
+            match (segment, segments.next()) {
+                (Segment::Literal(literal), Some(value)) if *literal == value => {}
+
+                (Segment::Param(name), Some(value)) => {
+                    params.values.push((name, value));
+                }
+
+                _ => return None,
+            }
+        }
+
+        match segments.next() {
+            Some(_) => None,
+            None => Some(params),
+        }
+    }
+
+    /// Internal API.
+    ///
+    /// Obtains this route's pattern, e.g. `/chats/{chat_id}`.
+    fn pattern(&self) -> String {
+        let segments = self
+            .segments
+            .iter()
+            .map(|segment| match segment {
+                Segment::Literal(literal) => literal.to_string(),
+                Segment::Param(name) => format!("{{{}}}", name),
+            })
+            .collect::<Vec<_>>();
+
+        format!("/{}", segments.join("/"))
+    }
+}
+
+impl<'p> Params<'p> {
+    /// Obtains the value of the parameter with the supplied name, or
+    /// `None` if the route doesn't have one.
+    pub fn get(&self, name: &str) -> Option<&'p str> {
+        self.values
+            .iter()
+            .find(|(n, _)| *n == name)
+            .map(|(_, value)| *value)
+    }
+
+    /// Parses the value of the parameter with the supplied name as a
+    /// `T`, e.g. an id, returning `None` if the route doesn't have one
+    /// or it can't be parsed.
+    pub fn parse<T: FromStr>(&self, name: &str) -> Option<T> {
+        self.get(name).and_then(|value| value.parse().ok())
+    }
+}
+
+/// Internal API.
+///
+/// Parses the supplied pattern into its segments.
+fn parse(pattern: &'static str) -> Vec<Segment> {
+    split(pattern)
+        .map(|segment| {
+            if segment.starts_with('{') && segment.ends_with('}') {
+                Segment::Param(&segment[1..segment.len() - 1])
+            } else {
+                Segment::Literal(segment)
+            }
+        })
+        .collect()
+}
+
+/// Internal API.
+///
+/// Splits the supplied path or pattern into its segments, skipping
+/// the empty segment before its leading slash.
+fn split(path: &str) -> impl Iterator<Item = &str> {
+    let mut segments = path.split_terminator('/');
+
+    if path.starts_with('/') {
+        segments.next();
+    }
+
+    segments
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::http::HttpMethod;
+    use crate::router::*;
+
+    #[test]
+    fn test_route() {
+        let mut router = Router::new();
+
+        router
+            .add(HttpMethod::POST, "/chats", 1)
+            .add(HttpMethod::GET, "/chats/{chat_id}/messages", 2)
+            .add(HttpMethod::POST, "/keys/{user_id}/fetch", 3)
+            .add(HttpMethod::POST, "/keys/{user_id}/{device_id}", 4);
+
+        let route = |method, path| router.route(method, path).map(|(h, p)| (*h, p));
+
+        assert_eq!(
+            route(HttpMethod::POST, "/chats"),
+            Some((1, Params::default()))
+        );
+        assert_eq!(route(HttpMethod::POST, "/chats?a=b").map(|r| r.0), Some(1));
+        assert_eq!(route(HttpMethod::GET, "/chats"), None);
+
+        let (handler, params) = route(HttpMethod::GET, "/chats/12/messages/").unwrap();
+
+        assert_eq!(handler, 2);
+        assert_eq!(params.get("chat_id"), Some("12"));
+        assert_eq!(params.parse::<u64>("chat_id"), Some(12));
+        assert_eq!(params.get("user_id"), None);
+
+        assert_eq!(
+            route(HttpMethod::GET, "/chats/a/messages")
+                .unwrap()
+                .1
+                .parse::<u64>("chat_id"),
+            None
+        );
+        assert_eq!(route(HttpMethod::GET, "/chats/12/messages/a"), None);
+        assert_eq!(route(HttpMethod::GET, "/chats/12"), None);
+
+        // routes that were added first take precedence
+
+        assert_eq!(
+            route(HttpMethod::POST, "/keys/1/fetch").map(|r| r.0),
+            Some(3)
+        );
+
+        let (handler, params) = route(HttpMethod::POST, "/keys/1/2").unwrap();
+
+        assert_eq!(handler, 4);
+        assert_eq!(params.parse::<u64>("device_id"), Some(2));
+
+        // the methods of other routes that match are obtained instead
+
+        assert_eq!(router.methods("/chats?a=b"), vec![HttpMethod::POST]);
+        assert_eq!(router.methods("/keys/1/fetch"), vec![HttpMethod::POST]);
+        assert_eq!(router.methods("/chats/12/messages"), vec![HttpMethod::GET]);
+        assert_eq!(router.methods("/messages"), Vec::new());
+
+        assert_eq!(
+            router.patterns()[1],
+            (HttpMethod::GET, "/chats/{chat_id}/messages".to_string())
+        );
+    }
+
+    #[test]
+    fn test_mount() {
+        let mut v1 = Router::new();
+
+        v1.add(HttpMethod::GET, "/chats/{chat_id}", 1);
+
+        let mut router = Router::new();
+
+        router.mount("/v1", v1).add(HttpMethod::GET, "/chats", 2);
+
+        let (handler, params) = router.route(HttpMethod::GET, "/v1/chats/3").unwrap();
+
+        assert_eq!(*handler, 1);
+        assert_eq!(params.parse::<u64>("chat_id"), Some(3));
+
+        assert_eq!(
+            router.patterns(),
+            vec![
+                (HttpMethod::GET, "/v1/chats/{chat_id}".to_string()),
+                (HttpMethod::GET, "/chats".to_string()),
+            ]
+        );
+
+        assert!(router.route(HttpMethod::GET, "/chats/3").is_none());
+        assert!(router.route(HttpMethod::GET, "/v1/chats").is_none());
+        assert_eq!(
+            router.route(HttpMethod::GET, "/chats").map(|r| *r.0),
+            Some(2)
+        );
+
+        assert_eq!(
+            router.pattern(HttpMethod::GET, "/v1/chats/3?limit=1"),
+            Some("/v1/chats/{chat_id}".to_string())
+        );
+        assert_eq!(router.pattern(HttpMethod::POST, "/v1/chats/3"), None);
+    }
+}
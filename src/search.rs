@@ -0,0 +1,235 @@
+//! Provides the text processing and indexing that message search
+//! is built on.
+//!
+//! Text is split into words, i.e. runs of alphanumeric characters,
+//! which are compared case-insensitively. A message matches a query
+//! if every word of the query is a prefix of one of the message's
+//! words, e.g. "hel wor" matches "Hello, world!".
+//!
+//! Rather than scanning every message, searches are answered by an
+//! inverted index, which maps each word to the messages containing
+//! it. As words are ordered, the words that a term is a prefix of
+//! are adjacent, so finding them is a range query.
+
+use crate::chat::Id;
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::Bound;
+
+/// The number of characters that a snippet includes before the
+/// word that matched.
+const SNIPPET_CONTEXT: usize = 20;
+
+/// The maximum number of characters in a snippet, excluding the
+/// ellipses that mark where it was truncated.
+const SNIPPET_LENGTH: usize = 80;
+
+/// Internal API.
+///
+/// Identifies a message by its chat's id and its sequence number.
+pub(crate) type MessageKey = (Id, u64);
+
+/// Internal API.
+///
+/// An inverted index of messages' words, which is updated as
+/// messages are added, edited, and deleted.
+#[derive(Debug, Default)]
+pub(crate) struct Index {
+    postings: BTreeMap<String, BTreeSet<MessageKey>>,
+}
+
+impl Index {
+    /// Internal API.
+    ///
+    /// Adds the words of the supplied message's text to the index.
+    pub(crate) fn insert(&mut self, key: MessageKey, text: &str) {
+        for (_, word) in words(text) {
+            self.postings
+                .entry(word.to_lowercase())
+                .or_default()
+                .insert(key);
+        }
+    }
+
+    /// Internal API.
+    ///
+    /// Removes the words of the supplied message's text from the
+    /// index, which must be the text it was inserted with.
+    pub(crate) fn remove(&mut self, key: MessageKey, text: &str) {
+        for (_, word) in words(text) {
+            let word = word.to_lowercase();
+
+            if let Some(keys) = self.postings.get_mut(&word) {
+                keys.remove(&key);
+
+                if keys.is_empty() {
+                    self.postings.remove(&word);
+                }
+            }
+        }
+    }
+
+    /// Internal API.
+    ///
+    /// Finds the messages that contain a word starting with each
+    /// of the supplied terms.
+    pub(crate) fn search(&self, terms: &[String]) -> BTreeSet<MessageKey> {
+        let mut terms = terms.iter();
+
+        let mut keys = match terms.next() {
+            Some(term) => self.prefixed(term),
+            None => return BTreeSet::new(),
+        };
+
+        for term in terms {
+            if keys.is_empty() {
+                break;
+            }
+
+            keys = keys.intersection(&self.prefixed(term)).cloned().collect();
+        }
+
+        keys
+    }
+
+    /// Internal API.
+    ///
+    /// Finds the messages that contain a word starting with the
+    /// supplied term.
+    fn prefixed(&self, term: &str) -> BTreeSet<MessageKey> {
+        self.postings
+            .range::<str, _>((Bound::Included(term), Bound::Unbounded))
+            .take_while(|(word, _)| word.starts_with(term))
+            .flat_map(|(_, keys)| keys.iter().cloned())
+            .collect()
+    }
+}
+
+/// Internal API.
+///
+/// Splits the supplied text into its words, along with the byte
+/// offset of each.
+pub(crate) fn words(text: &str) -> Vec<(usize, &str)> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(|word| (word.as_ptr() as usize - text.as_ptr() as usize, word))
+        .collect()
+}
+
+/// Internal API.
+///
+/// Splits the supplied query into the lowercased words that a
+/// message must match.
+pub(crate) fn terms(query: &str) -> Vec<String> {
+    words(query)
+        .into_iter()
+        .map(|(_, word)| word.to_lowercase())
+        .collect()
+}
+
+/// Internal API.
+///
+/// Determines if the supplied text matches every one of the
+/// supplied terms, returning the offset of the word that matched
+/// the first of them if so.
+pub(crate) fn find(text: &str, terms: &[String]) -> Option<usize> {
+    let words: Vec<(usize, String)> = words(text)
+        .into_iter()
+        .map(|(offset, word)| (offset, word.to_lowercase()))
+        .collect();
+
+    let mut first = None;
+
+    for term in terms {
+        let (offset, _) = words
+            .iter()
+            .find(|(_, word)| word.starts_with(term.as_str()))?;
+
+        first.get_or_insert(*offset);
+    }
+
+    first
+}
+
+/// Internal API.
+///
+/// Extracts an excerpt of the supplied text around the word at
+/// the supplied offset, marking where it was truncated.
+pub(crate) fn snippet(text: &str, offset: usize) -> String {
+    let start = text[..offset]
+        .char_indices()
+        .rev()
+        .nth(SNIPPET_CONTEXT - 1)
+        .map_or(0, |(i, _)| i);
+
+    let end = text[start..]
+        .char_indices()
+        .nth(SNIPPET_LENGTH)
+        .map_or(text.len(), |(i, _)| start + i);
+
+    let mut snippet = String::with_capacity(end - start + 6);
+
+    if start > 0 {
+        snippet.push_str("...");
+    }
+
+    snippet.push_str(text[start..end].trim());
+
+    if end < text.len() {
+        snippet.push_str("...");
+    }
+
+    snippet
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::search::*;
+
+    #[test]
+    fn test_find() {
+        let terms = terms("HEL, wor");
+
+        assert_eq!(terms, vec!["hel".to_string(), "wor".to_string()]);
+        assert_eq!(find("Say: hello, World!", &terms), Some(5));
+        assert_eq!(find("hello there", &terms), None);
+        assert_eq!(find("shell world", &terms), None);
+        assert_eq!(find("anything", &[]), None);
+    }
+
+    #[test]
+    fn test_index() {
+        let mut index = Index::default();
+
+        index.insert((1, 1), "Hello, World!");
+        index.insert((1, 2), "hello again");
+        index.insert((2, 1), "help");
+
+        let search =
+            |index: &Index, query| index.search(&terms(query)).into_iter().collect::<Vec<_>>();
+
+        assert_eq!(search(&index, "hel"), vec![(1, 1), (1, 2), (2, 1)]);
+        assert_eq!(search(&index, "hello wor"), vec![(1, 1)]);
+        assert_eq!(search(&index, "again world"), vec![]);
+        assert_eq!(search(&index, ""), vec![]);
+
+        index.remove((1, 1), "Hello, World!");
+        index.insert((1, 1), "goodbye");
+
+        assert_eq!(search(&index, "hello"), vec![(1, 2)]);
+        assert_eq!(search(&index, "good"), vec![(1, 1)]);
+        assert!(!index.postings.contains_key("world"));
+    }
+
+    #[test]
+    fn test_snippet() {
+        assert_eq!(snippet("short text", 6), "short text");
+
+        let text = format!("{} needle {}", "á".repeat(30), "b".repeat(100));
+        let offset = text.find("needle").unwrap();
+
+        assert_eq!(
+            snippet(&text, offset),
+            format!("...{} needle {}...", "á".repeat(19), "b".repeat(53))
+        );
+    }
+}
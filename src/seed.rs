@@ -0,0 +1,177 @@
+//! Provides seed documents, which describe chats and messages
+//! that should be applied to a `ChatServer` before it starts
+//! serving requests, e.g.
+//!
+//! ```json
+//! {
+//!   "chats": [{ "id": 1, "participantIds": [51201, 22307] }],
+//!   "messages": [{
+//!     "chatId": 1,
+//!     "id": "a3113eca-bb08-4861-97bb-f5ba2535529e",
+//!     "timestamp": 1000,
+//!     "message": "Hello there!",
+//!     "sourceUserId": 51201,
+//!     "destinationUserId": 22307
+//!   }]
+//! }
+//! ```
+
+use crate::chat::*;
+use serde::Deserialize;
+use std::io::Error as IoError;
+use std::io::ErrorKind as IoErrorKind;
+use std::io::Result as IoResult;
+
+/// A document describing chats and messages to seed a
+/// `ChatServer` with.
+#[derive(Debug, PartialEq, Deserialize)]
+pub struct Seed {
+    #[serde(default)]
+    chats: Vec<Chat>,
+
+    #[serde(default)]
+    messages: Vec<SeedMessage>,
+}
+
+/// Internal API.
+///
+/// A message to add to a seeded chat.
+#[derive(Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SeedMessage {
+    chat_id: Id,
+
+    #[serde(flatten)]
+    message: ChatMessage,
+}
+
+impl Seed {
+    /// Parse the supplied seed document.
+    pub fn parse(data: &str) -> IoResult<Self> {
+        Ok(serde_json::from_str(data)?)
+    }
+
+    /// Applies the chats, and then the messages, to the supplied
+    /// server via `ChatRequest`s, failing on the first request
+    /// that isn't successful.
+    pub fn apply(self, server: &mut ChatServer) -> IoResult<()> {
+        for chat in self.chats {
+            let id = chat.id;
+
+            match server.issue(ChatRequest::CreateChat {
+                id: Some(id),
+                participant_ids: chat.participant_ids,
+                title: chat.title,
+                created_at: chat.created_at,
+                creator: chat.creator,
+            }) {
+                ChatResponse::ChatCreated { .. } => {}
+
+                other => {
+                    return Err(IoError::new(
+                        IoErrorKind::InvalidData,
+                        format!("cannot seed chat {}: {:?}", id, other),
+                    ));
+                }
+            }
+        }
+
+        for SeedMessage { chat_id, message } in self.messages {
+            let id = message.id.clone();
+
+            match server.issue(ChatRequest::AddMessage {
+                id: message.id,
+                chat_id,
+                source_user_id: message.source_user_id,
+                destination_user_id: message.destination_user_id,
+                timestamp: message.timestamp,
+                message: message.message,
+                attachment_ids: message.attachment_ids,
+                envelope: message.envelope,
+                reply_to: message.reply_to,
+                kind: message.kind,
+                previews: message.previews,
+            }) {
+                ChatResponse::MessageAdded => {}
+
+                other => {
+                    return Err(IoError::new(
+                        IoErrorKind::InvalidData,
+                        format!("cannot seed message {}: {:?}", id, other),
+                    ));
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::seed::*;
+    use std::collections::BTreeMap;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_seed() {
+        let mut server = ChatServer::new();
+
+        server.issue(ChatRequest::StoreContactList {
+            id: 1,
+            list: vec![2],
+        });
+
+        server.issue(ChatRequest::StoreContactList {
+            id: 2,
+            list: vec![1],
+        });
+
+        let seed = Seed::parse(
+            "{ \"chats\": [{ \"id\": 1, \"participantIds\": [1, 2] }], \"messages\": [{ \"chatId\": 1, \"id\": \"a\", \"timestamp\": 0, \"message\": \"test\", \"sourceUserId\": 1, \"destinationUserId\": 2 }] }",
+        )
+        .unwrap();
+
+        seed.apply(&mut server).unwrap();
+
+        assert_eq!(
+            server.issue(ChatRequest::ListChat {
+                id: 1,
+                cursor: None,
+                limit: None,
+                before: None,
+            }),
+            ChatResponse::ChatListed {
+                messages: vec![Arc::new(ChatMessage {
+                    id: "a".to_string(),
+                    seq: 1,
+                    timestamp: 0,
+                    message: "test".to_string(),
+                    attachment_ids: Vec::new(),
+                    source_user_id: 1,
+                    destination_user_id: Some(2),
+                    edited_at: None,
+                    deleted: false,
+                    reactions: BTreeMap::new(),
+                    receipts: BTreeMap::new(),
+                    received_at: None,
+                    envelope: None,
+                    reply_to: None,
+                    kind: MessageKind::Text,
+                    previews: Vec::new(),
+                    forwarded_from: None,
+                    revisions: Vec::new(),
+                })],
+                next_cursor: None,
+                truncated: false
+            }
+        );
+
+        // seeding the same chat again fails as it already exists
+
+        let seed =
+            Seed::parse("{ \"chats\": [{ \"id\": 1, \"participantIds\": [1, 2] }] }").unwrap();
+
+        assert!(seed.apply(&mut server).is_err());
+    }
+}
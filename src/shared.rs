@@ -0,0 +1,143 @@
+//! Provides `SharedChatServer`, which allows a `ChatServer` to be
+//! used by many threads at once.
+//!
+//! A `ChatServer` is `Send` and `Sync`, but mutations require
+//! exclusive access to it, so it is kept behind a `RwLock`. Queries
+//! only take the read lock, so they are answered concurrently, and
+//! only mutations wait for each other.
+//!
+//! Responses own their data, so they outlive the lock. Messages are
+//! shared with the server rather than copied, as each is kept behind
+//! an `Arc`.
+
+use crate::chat::{ChatRequest, ChatResponse, ChatServer};
+use std::sync::{PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+/// A `ChatServer` that can be shared between threads, e.g. in an
+/// `Arc`.
+#[derive(Default)]
+pub struct SharedChatServer {
+    server: RwLock<ChatServer>,
+}
+
+impl SharedChatServer {
+    /// Creates a new shared chat server from the supplied one.
+    pub fn new(server: ChatServer) -> Self {
+        Self {
+            server: RwLock::new(server),
+        }
+    }
+
+    /// Issues the supplied request, returning its response. Queries
+    /// only lock the server for reading.
+    pub fn issue(&self, request: ChatRequest) -> ChatResponse {
+        match request {
+            ChatRequest::Batch { .. } => self.write().issue(request),
+            request if request.is_mutation() => self.write().issue(request),
+            request => self.read().query(request),
+        }
+    }
+
+    /// Locks the server for reading, e.g. to take a snapshot of it.
+    /// A poisoned lock is recovered, as the server's state is valid
+    /// between requests.
+    pub fn read(&self) -> RwLockReadGuard<'_, ChatServer> {
+        self.server.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Locks the server for writing, e.g. to checkpoint it.
+    pub fn write(&self) -> RwLockWriteGuard<'_, ChatServer> {
+        self.server.write().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Obtains the server without locking it, e.g. to configure it
+    /// before it's shared.
+    pub fn get_mut(&mut self) -> &mut ChatServer {
+        self.server
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Consumes this shared server, returning the server it shared.
+    pub fn into_inner(self) -> ChatServer {
+        self.server
+            .into_inner()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::chat::*;
+    use crate::shared::*;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn test_send_and_sync() {
+        fn assert_send_and_sync<T: Send + Sync>() {}
+
+        assert_send_and_sync::<ChatServer>();
+        assert_send_and_sync::<SharedChatServer>();
+    }
+
+    #[test]
+    fn test_issue_concurrently() {
+        let shared = Arc::new(SharedChatServer::default());
+
+        for id in 1..=4 {
+            assert_eq!(
+                shared.issue(ChatRequest::StoreContactList {
+                    id,
+                    list: (1..=4).filter(|c| *c != id).collect(),
+                }),
+                ChatResponse::ContactListStored
+            );
+        }
+
+        let threads = (1..=4)
+            .map(|user_id| {
+                let shared = shared.clone();
+
+                thread::spawn(move || {
+                    let created = shared
+                        .issue(ChatRequest::CreateChat {
+                            id: None,
+                            participant_ids: vec![user_id, user_id % 4 + 1],
+                            title: None,
+                            created_at: None,
+                            creator: None,
+                        })
+                        .is_error();
+
+                    let contacts = match shared.issue(ChatRequest::ListContacts { user_id }) {
+                        ChatResponse::ContactsListed { contacts } => contacts.len(),
+                        _ => 0,
+                    };
+
+                    (created, contacts)
+                })
+            })
+            .collect::<Vec<_>>();
+
+        for thread in threads {
+            assert_eq!(thread.join().unwrap(), (false, 3));
+        }
+
+        // a mutation can't be issued as a query
+
+        let error = shared
+            .read()
+            .query(ChatRequest::StoreContactList {
+                id: 1,
+                list: Vec::new(),
+            })
+            .error();
+
+        assert_eq!(error.map(|e| e.code), Some(ErrorCode::UnsupportedRequest));
+
+        let server = Arc::try_unwrap(shared).ok().unwrap().into_inner();
+
+        assert_eq!(server.metrics().chats_created, 4);
+    }
+}
@@ -0,0 +1,454 @@
+//! Provides request signing, which authenticates server-to-server
+//! callers, e.g. other backends, without them having to obtain a
+//! token first.
+//!
+//! Each client is configured with a secret that it shares with the
+//! server, and the user that its requests are made on behalf of. A
+//! client signs each request with an HMAC-SHA256 of its method, path,
+//! timestamp, nonce, and body bytes, separated by newlines, e.g.
+//!
+//! ```text
+//! POST
+//! /v1/chats
+//! 1700000000
+//! 5f0c6a3e
+//! {"participantIds":[1,2]}
+//! ```
+//!
+//! and supplies its name, the timestamp, the nonce, and the
+//! hex-encoded signature in headers. To prevent a signed request from
+//! being replayed, its timestamp must be within a window of the
+//! server's clock, and its nonce can't be reused within that window.
+//!
+//! A `Signer` signs the requests that a client makes, e.g. those of a
+//! follower to its leader. Requests that are relayed between federated
+//! servers are signed the same way, see `federation`.
+
+use crate::auth::random_bytes;
+use crate::chat::Id;
+use crate::http::HttpMethod;
+use hmac::{Hmac, Mac};
+use serde::Deserialize;
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::io::Result as IoResult;
+
+/// The header that names the client that signed a request.
+pub const CLIENT_HEADER: &str = "X-Signature-Client";
+
+/// The header that carries the time a request was signed at, in
+/// seconds since the Unix epoch.
+pub const TIMESTAMP_HEADER: &str = "X-Signature-Timestamp";
+
+/// The header that carries a request's nonce, which must be unique
+/// for each request that a client signs.
+pub const NONCE_HEADER: &str = "X-Signature-Nonce";
+
+/// The header that carries the signature of a request.
+pub const SIGNATURE_HEADER: &str = "X-Signature";
+
+/// How far a request's timestamp may be from the server's clock, in
+/// seconds, in either direction.
+pub const TIMESTAMP_WINDOW: u64 = 300;
+
+/// The longest nonce that is accepted, in bytes, so that the nonces
+/// that are remembered can't exhaust memory.
+const MAX_NONCE_LENGTH: usize = 64;
+
+/// The clients that may sign requests, and the nonces they've used
+/// recently.
+#[derive(Debug)]
+pub struct SigningClients {
+    clients: HashMap<String, Client>,
+    nonces: HashMap<(String, String), u64>,
+}
+
+/// Internal API.
+///
+/// A client that may sign requests, and the user that they're made
+/// on behalf of.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Client {
+    secret: String,
+    user_id: Id,
+}
+
+/// A client's name and the secret it shares with a server, which
+/// sign the requests that it makes to that server.
+#[derive(Debug, Deserialize)]
+pub struct Signer {
+    client: String,
+    secret: String,
+}
+
+/// The parts of a request that are signed, along with the headers
+/// that describe its signature.
+pub struct SignedRequest<'r> {
+    pub client: &'r str,
+    pub timestamp: &'r str,
+    pub nonce: &'r str,
+    pub signature: &'r str,
+    pub method: &'r str,
+    pub path: &'r str,
+    pub body: &'r [u8],
+}
+
+impl SigningClients {
+    /// Parses the supplied JSON configuration, which maps the name of
+    /// each client to its secret and user, e.g.
+    ///
+    /// ```text
+    /// {
+    ///   "billing": { "secret": "...", "userId": 40123 }
+    /// }
+    /// ```
+    pub fn parse(json: &str) -> IoResult<Self> {
+        Ok(Self {
+            clients: serde_json::from_str(json)?,
+            nonces: HashMap::new(),
+        })
+    }
+
+    /// Verifies the supplied request at the supplied time, in seconds
+    /// since the Unix epoch, returning the id of the user it is made
+    /// on behalf of, or `None` if it isn't validly signed or has
+    /// already been seen. Its nonce is remembered if it is.
+    pub fn verify(&mut self, request: &SignedRequest, now: u64) -> Option<Id> {
+        let client = self.clients.get(request.client)?;
+        let timestamp = fresh_timestamp(request, now)?;
+
+        if !is_signed(request, &client.secret) {
+            return None;
+        }
+
+        if !remember_nonce(&mut self.nonces, request, timestamp, now) {
+            return None;
+        }
+
+        Some(client.user_id)
+    }
+}
+
+impl Signer {
+    /// Parses the supplied JSON configuration, which names the
+    /// client and its secret, e.g.
+    ///
+    /// ```text
+    /// { "client": "follower", "secret": "..." }
+    /// ```
+    pub fn parse(json: &str) -> IoResult<Self> {
+        Ok(serde_json::from_str(json)?)
+    }
+
+    /// Signs a request with the supplied method, path, and body at
+    /// the supplied time, in seconds since the Unix epoch, returning
+    /// the headers that it must include. Each request is given a
+    /// random nonce.
+    pub fn headers(
+        &self,
+        method: HttpMethod,
+        path: &str,
+        body: &[u8],
+        now: u64,
+    ) -> Vec<(&'static str, String)> {
+        let (timestamp, nonce, signature) =
+            sign_request(&self.secret, method.as_str(), path, body, now);
+
+        vec![
+            (CLIENT_HEADER, self.client.clone()),
+            (TIMESTAMP_HEADER, timestamp),
+            (NONCE_HEADER, nonce),
+            (SIGNATURE_HEADER, signature),
+        ]
+    }
+}
+
+/// Internal API.
+///
+/// Signs a request with the supplied method, path, and body at the
+/// supplied time, in seconds since the Unix epoch, with the supplied
+/// secret, returning its timestamp, its random nonce, and the
+/// signature.
+pub(crate) fn sign_request(
+    secret: &str,
+    method: &str,
+    path: &str,
+    body: &[u8],
+    now: u64,
+) -> (String, String, String) {
+    let mut bytes = [0u8; 16];
+
+    random_bytes(&mut bytes);
+
+    let (timestamp, nonce) = (now.to_string(), hex::encode(bytes));
+    let signature = sign(
+        secret.as_bytes(),
+        &message(method, path, &timestamp, &nonce, body),
+    );
+
+    (timestamp, nonce, signature)
+}
+
+/// Internal API.
+///
+/// Parses the timestamp of the supplied request, if it's within the
+/// window of the supplied time, and its nonce is acceptable.
+pub(crate) fn fresh_timestamp(request: &SignedRequest, now: u64) -> Option<u64> {
+    let timestamp = request.timestamp.parse::<u64>().ok()?;
+
+    let within = if timestamp > now {
+        timestamp - now <= TIMESTAMP_WINDOW
+    } else {
+        now - timestamp <= TIMESTAMP_WINDOW
+    };
+
+    if !within || request.nonce.is_empty() || request.nonce.len() > MAX_NONCE_LENGTH {
+        return None;
+    }
+
+    Some(timestamp)
+}
+
+/// Internal API.
+///
+/// Determines if the supplied request is signed with the supplied
+/// secret. Signatures aren't case sensitive, and are compared in
+/// constant time.
+pub(crate) fn is_signed(request: &SignedRequest, secret: &str) -> bool {
+    let signature = match hex::decode(request.signature) {
+        Ok(signature) => signature,
+        Err(_) => return false,
+    };
+
+    mac(
+        secret.as_bytes(),
+        &message(
+            request.method,
+            request.path,
+            request.timestamp,
+            request.nonce,
+            request.body,
+        ),
+    )
+    .verify_slice(&signature)
+    .is_ok()
+}
+
+/// Internal API.
+///
+/// Signs the supplied data with the supplied secret, producing a
+/// hex-encoded HMAC-SHA256.
+pub(crate) fn sign(secret: &[u8], data: &[u8]) -> String {
+    hex::encode(mac(secret, data).finalize().into_bytes())
+}
+
+/// Internal API.
+///
+/// Computes the HMAC-SHA256 of the supplied data with the supplied
+/// secret. HMAC accepts keys of any length, so this can't fail.
+fn mac(secret: &[u8], data: &[u8]) -> Hmac<Sha256> {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(secret)
+        .unwrap_or_else(|_| unreachable!("HMAC keys can be any length"));
+
+    mac.update(data);
+
+    mac
+}
+
+/// Internal API.
+///
+/// Remembers the nonce of the supplied request, whose timestamp has
+/// been parsed, returning `false` if it has already been seen from
+/// its client, i.e. the request was replayed.
+pub(crate) fn remember_nonce(
+    nonces: &mut HashMap<(String, String), u64>,
+    request: &SignedRequest,
+    timestamp: u64,
+    now: u64,
+) -> bool {
+    // nonces are only remembered for as long as their timestamps are
+    // within the window, after which the timestamp rejects the
+    // request instead
+
+    nonces.retain(|_, expires_at| *expires_at >= now);
+
+    let key = (request.client.to_string(), request.nonce.to_string());
+
+    if nonces.contains_key(&key) {
+        return false;
+    }
+
+    nonces.insert(key, timestamp + TIMESTAMP_WINDOW);
+
+    true
+}
+
+/// Internal API.
+///
+/// Obtains the message that is signed for a request, i.e. its
+/// method, path, timestamp, and nonce on their own lines, followed
+/// by its body.
+fn message(method: &str, path: &str, timestamp: &str, nonce: &str, body: &[u8]) -> Vec<u8> {
+    let mut message = format!("{}\n{}\n{}\n{}\n", method, path, timestamp, nonce).into_bytes();
+
+    message.extend_from_slice(body);
+
+    message
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::signing::*;
+
+    #[test]
+    fn test_sign() {
+        // from RFC 4231
+
+        assert_eq!(
+            sign(b"Jefe", b"what do ya want for nothing?"),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+
+        assert_eq!(
+            sign(
+                &[0xaa; 131],
+                b"Test Using Larger Than Block-Size Key - Hash Key First"
+            ),
+            "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54"
+        );
+    }
+
+    #[test]
+    fn test_verify() {
+        let mut clients =
+            SigningClients::parse("{\"billing\":{\"secret\":\"s\",\"userId\":40123}}").unwrap();
+
+        let signature = sign(b"s", b"POST\n/v1/chats\n1000\nabc\n{}");
+
+        let request = |nonce, timestamp, signature| SignedRequest {
+            client: "billing",
+            timestamp,
+            nonce,
+            signature,
+            method: "POST",
+            path: "/v1/chats",
+            body: b"{}",
+        };
+
+        assert_eq!(
+            clients.verify(&request("abc", "1000", &signature), 1100),
+            Some(40123)
+        );
+
+        // nonces can't be reused until their timestamp has expired
+
+        assert_eq!(
+            clients.verify(&request("abc", "1000", &signature), 1100),
+            None
+        );
+        assert_eq!(clients.nonces.len(), 1);
+
+        // timestamps must be within the window
+
+        let signature = sign(b"s", b"POST\n/v1/chats\n1000\ndef\n{}");
+
+        assert_eq!(
+            clients.verify(
+                &request("def", "1000", &signature),
+                1000 + TIMESTAMP_WINDOW + 1
+            ),
+            None
+        );
+        assert_eq!(
+            clients.verify(
+                &request("def", "1000", &signature),
+                1000 - TIMESTAMP_WINDOW - 1
+            ),
+            None
+        );
+
+        // rejected requests don't use up their nonce, and signatures
+        // aren't case sensitive
+
+        let uppercase = signature.to_ascii_uppercase();
+
+        assert_eq!(
+            clients.verify(&request("def", "1000", &uppercase), 1000),
+            Some(40123)
+        );
+        assert_eq!(clients.nonces.len(), 2);
+
+        // the signature must cover every part of the request
+
+        let signature = sign(b"s", b"POST\n/v1/chats\n1000\nghi\n{}");
+
+        let mut tampered = request("ghi", "1000", &signature);
+        tampered.body = b"{ }";
+
+        assert_eq!(clients.verify(&tampered, 1000), None);
+        assert_eq!(
+            clients.verify(&request("ghi", "1000", "not a signature"), 1000),
+            None
+        );
+
+        let mut unknown = request("ghi", "1000", &signature);
+        unknown.client = "other";
+
+        assert_eq!(clients.verify(&unknown, 1000), None);
+
+        assert_eq!(
+            clients.verify(&request("ghi", "1000", &signature), 1000),
+            Some(40123)
+        );
+
+        // nonces are forgotten once their timestamps expire
+
+        let signature = sign(b"s", b"POST\n/v1/chats\n1200\nabc\n{}");
+
+        assert_eq!(
+            clients.verify(
+                &request("abc", "1200", &signature),
+                1000 + TIMESTAMP_WINDOW + 1
+            ),
+            Some(40123)
+        );
+        assert_eq!(clients.nonces.len(), 1);
+
+        assert!(SigningClients::parse("{\"billing\":{\"secret\":\"s\"}}").is_err());
+    }
+
+    #[test]
+    fn test_signer() {
+        let mut clients =
+            SigningClients::parse("{\"follower\":{\"secret\":\"s\",\"userId\":7}}").unwrap();
+
+        let signer = Signer::parse("{\"client\":\"follower\",\"secret\":\"s\"}").unwrap();
+
+        let headers = signer.headers(HttpMethod::GET, "/replication/snapshot", b"", 1000);
+        let header = |name| headers.iter().find(|(n, _)| *n == name).unwrap().1.as_str();
+
+        let request = SignedRequest {
+            client: header(CLIENT_HEADER),
+            timestamp: header(TIMESTAMP_HEADER),
+            nonce: header(NONCE_HEADER),
+            signature: header(SIGNATURE_HEADER),
+            method: "GET",
+            path: "/replication/snapshot",
+            body: b"",
+        };
+
+        assert_eq!(request.timestamp, "1000");
+        assert_eq!(clients.verify(&request, 1000), Some(7));
+
+        // each request has its own nonce, so that it isn't mistaken
+        // for a replay
+
+        assert_ne!(
+            signer.headers(HttpMethod::GET, "/replication/snapshot", b"", 1000)[2].1,
+            request.nonce
+        );
+
+        assert!(Signer::parse("{\"client\":\"follower\"}").is_err());
+    }
+}
@@ -0,0 +1,472 @@
+//! Provides a `ChatStore` on top of sled, an embedded database,
+//! so that deployments get durable state without managing log
+//! and snapshot files themselves.
+//!
+//! Rather than storing a snapshot as a single document, each part
+//! of it is mapped to its own tree:
+//!
+//! * `chats`: each chat's participants and metadata, keyed by id
+//! * `messages`: each chat's messages, keyed by the chat's id and
+//!   then the message's sequence number, so that a chat's messages
+//!   are stored contiguously and in order
+//! * `chatsByUserId`, `contactLists`, `blocklists`, `contactRequests`:
+//!   the indexes and contact lists, keyed by user id
+//! * `meta`: the chat id counter and the index of the last entry
+//!   that the snapshot includes
+//!
+//! The entries appended after the snapshot are stored in the `log`
+//! tree, keyed by index. Snapshots are applied, and the log cleared,
+//! atomically.
+
+use crate::chat::Snapshot;
+use crate::storage::{ChatStore, LogEntry};
+use fs2::FileExt;
+use serde_json::{Map, Value};
+use sled::transaction::TransactionError;
+use sled::{Batch, Db, Transactional, Tree};
+use std::fs::File;
+use std::io::Error as IoError;
+use std::io::ErrorKind as IoErrorKind;
+use std::io::Result as IoResult;
+use std::path::{Path, PathBuf};
+
+/// The fields of a snapshot that are maps keyed by user id, each
+/// of which is stored in a tree of the same name.
+const USER_TREES: [&str; 4] = [
+    "chatsByUserId",
+    "contactLists",
+    "blocklists",
+    "contactRequests",
+];
+
+/// The fields of a snapshot that are scalars, which are stored
+/// in the `meta` tree.
+const META_KEYS: [&str; 2] = ["logIndex", "lastChatId"];
+
+/// A store backed by a sled database.
+pub struct SledStore {
+    path: PathBuf,
+    db: Db,
+    log: Tree,
+    meta: Tree,
+    chats: Tree,
+    messages: Tree,
+    users: [Tree; 4],
+}
+
+impl SledStore {
+    /// Opens the database at the supplied path, creating it if it
+    /// doesn't exist.
+    pub fn open<P: AsRef<Path>>(path: P) -> IoResult<Self> {
+        let db = sled::open(&path)?;
+
+        Ok(Self {
+            path: path.as_ref().to_path_buf(),
+            log: db.open_tree("log")?,
+            meta: db.open_tree("meta")?,
+            chats: db.open_tree("chats")?,
+            messages: db.open_tree("messages")?,
+            users: [
+                db.open_tree(USER_TREES[0])?,
+                db.open_tree(USER_TREES[1])?,
+                db.open_tree(USER_TREES[2])?,
+                db.open_tree(USER_TREES[3])?,
+            ],
+            db,
+        })
+    }
+}
+
+impl ChatStore for SledStore {
+    fn load(&mut self) -> IoResult<(Option<Snapshot<'static>>, Vec<LogEntry>)> {
+        let mut entries = Vec::with_capacity(self.log.len());
+
+        for item in self.log.iter() {
+            entries.push(serde_json::from_slice(&item?.1)?);
+        }
+
+        // the meta tree is only populated once a snapshot is stored
+
+        if self.meta.is_empty() {
+            return Ok((None, entries));
+        }
+
+        let mut snapshot = Map::new();
+
+        for key in META_KEYS.iter() {
+            if let Some(value) = self.meta.get(key)? {
+                snapshot.insert(key.to_string(), serde_json::from_slice(&value)?);
+            }
+        }
+
+        let mut chats = Map::new();
+
+        for item in self.chats.iter() {
+            let (key, value) = item?;
+            let mut chat: Map<String, Value> = serde_json::from_slice(&value)?;
+            let mut messages = Vec::new();
+            let mut message_ids = Vec::new();
+
+            for item in self.messages.scan_prefix(&key) {
+                let message: Value = serde_json::from_slice(&item?.1)?;
+
+                message_ids.push(message["id"].clone());
+                messages.push(message);
+            }
+
+            chat.insert("messages".to_string(), Value::Array(messages));
+            chat.insert("messageIds".to_string(), Value::Array(message_ids));
+            chats.insert(decode_id(&key)?.to_string(), Value::Object(chat));
+        }
+
+        snapshot.insert("chats".to_string(), Value::Object(chats));
+
+        for (name, tree) in USER_TREES.iter().zip(self.users.iter()) {
+            let mut users = Map::new();
+
+            for item in tree.iter() {
+                let (key, value) = item?;
+
+                users.insert(
+                    decode_id(&key)?.to_string(),
+                    serde_json::from_slice(&value)?,
+                );
+            }
+
+            snapshot.insert(name.to_string(), Value::Object(users));
+        }
+
+        Ok((
+            Some(serde_json::from_value(Value::Object(snapshot))?),
+            entries,
+        ))
+    }
+
+    fn append(&mut self, entry: &LogEntry) -> IoResult<()> {
+        self.log
+            .insert(entry.index.to_be_bytes(), serde_json::to_vec(entry)?)?;
+
+        self.db.flush()?;
+
+        Ok(())
+    }
+
+    fn snapshot(&mut self, snapshot: &Snapshot) -> IoResult<()> {
+        let mut snapshot = match serde_json::to_value(snapshot)? {
+            Value::Object(snapshot) => snapshot,
+            _ => return Err(invalid_snapshot()),
+        };
+
+        // each tree's contents are replaced, so every existing key is
+        // removed unless the snapshot overwrites it
+
+        let log = clear(&self.log)?;
+        let mut meta = clear(&self.meta)?;
+        let mut chats = clear(&self.chats)?;
+        let mut messages = clear(&self.messages)?;
+        let mut users = [
+            clear(&self.users[0])?,
+            clear(&self.users[1])?,
+            clear(&self.users[2])?,
+            clear(&self.users[3])?,
+        ];
+
+        for key in META_KEYS.iter() {
+            let value = snapshot.remove(*key).ok_or_else(invalid_snapshot)?;
+
+            meta.insert(*key, serde_json::to_vec(&value)?);
+        }
+
+        for (id, chat) in take_map(&mut snapshot, "chats")? {
+            let id = encode_id(&id)?;
+            let mut chat = match chat {
+                Value::Object(chat) => chat,
+                _ => return Err(invalid_snapshot()),
+            };
+
+            chat.remove("messageIds");
+
+            if let Some(Value::Array(chat_messages)) = chat.remove("messages") {
+                for message in chat_messages {
+                    let seq = message["seq"].as_u64().ok_or_else(invalid_snapshot)?;
+                    let mut key = id.to_vec();
+
+                    key.extend_from_slice(&seq.to_be_bytes());
+
+                    messages.insert(key, serde_json::to_vec(&message)?);
+                }
+            }
+
+            chats.insert(&id[..], serde_json::to_vec(&chat)?);
+        }
+
+        for (name, batch) in USER_TREES.iter().zip(users.iter_mut()) {
+            for (id, value) in take_map(&mut snapshot, name)? {
+                batch.insert(&encode_id(&id)?[..], serde_json::to_vec(&value)?);
+            }
+        }
+
+        let trees = (
+            &self.log,
+            &self.meta,
+            &self.chats,
+            &self.messages,
+            &self.users[0],
+            &self.users[1],
+            &self.users[2],
+            &self.users[3],
+        );
+
+        trees
+            .transaction(
+                |(
+                    log_tree,
+                    meta_tree,
+                    chats_tree,
+                    messages_tree,
+                    users0,
+                    users1,
+                    users2,
+                    users3,
+                )| {
+                    log_tree.apply_batch(&log)?;
+                    meta_tree.apply_batch(&meta)?;
+                    chats_tree.apply_batch(&chats)?;
+                    messages_tree.apply_batch(&messages)?;
+                    users0.apply_batch(&users[0])?;
+                    users1.apply_batch(&users[1])?;
+                    users2.apply_batch(&users[2])?;
+                    users3.apply_batch(&users[3])?;
+
+                    Ok(())
+                },
+            )
+            .map_err(|e: TransactionError<()>| match e {
+                TransactionError::Storage(e) => e.into(),
+                TransactionError::Abort(()) => {
+                    IoError::other("the snapshot transaction was aborted")
+                }
+            })?;
+
+        self.db.flush()?;
+
+        Ok(())
+    }
+
+    fn close(self: Box<Self>) -> IoResult<()> {
+        self.db.flush()?;
+
+        // sled's background threads release the database's lock once
+        // they've finished with it, after it's dropped, so it's waited
+        // for by acquiring the lock, and then releasing it
+
+        let file = File::open(self.path.join("db"))?;
+
+        drop(self);
+
+        FileExt::lock_exclusive(&file)?;
+        FileExt::unlock(&file)
+    }
+}
+
+/// Internal API.
+///
+/// Creates a batch that removes every key in the supplied tree.
+fn clear(tree: &Tree) -> IoResult<Batch> {
+    let mut batch = Batch::default();
+
+    for key in tree.iter().keys() {
+        batch.remove(key?);
+    }
+
+    Ok(batch)
+}
+
+/// Internal API.
+///
+/// Removes the supplied field, which must be an object, from a
+/// serialized snapshot.
+fn take_map(snapshot: &mut Map<String, Value>, name: &str) -> IoResult<Map<String, Value>> {
+    match snapshot.remove(name) {
+        Some(Value::Object(map)) => Ok(map),
+        _ => Err(invalid_snapshot()),
+    }
+}
+
+/// Internal API.
+///
+/// Encodes an id, which JSON represents as a string when it's
+/// a key, as a big-endian key so that ids are ordered numerically.
+fn encode_id(id: &str) -> IoResult<[u8; 8]> {
+    id.parse::<u64>()
+        .map(u64::to_be_bytes)
+        .map_err(|_| invalid_snapshot())
+}
+
+/// Internal API.
+///
+/// Decodes a key that was encoded by `encode_id`.
+fn decode_id(key: &[u8]) -> IoResult<u64> {
+    let mut id = [0; 8];
+
+    if key.len() != id.len() {
+        return Err(invalid_snapshot());
+    }
+
+    id.copy_from_slice(key);
+
+    Ok(u64::from_be_bytes(id))
+}
+
+fn invalid_snapshot() -> IoError {
+    IoError::new(IoErrorKind::InvalidData, "invalid snapshot")
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::chat::*;
+    use crate::sled_store::*;
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    /// Obtains an empty directory for the supplied test, which is
+    /// unique to it and this process.
+    fn temp_dir(name: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("signal-http-sled-{}-{}", name, process::id()));
+
+        let _ = fs::remove_dir_all(&path);
+
+        path
+    }
+
+    fn messages(server: &mut ChatServer) -> Vec<(u64, String)> {
+        match server.issue(ChatRequest::ListChat {
+            id: 1,
+            cursor: None,
+            limit: None,
+            before: None,
+        }) {
+            ChatResponse::ChatListed { messages, .. } => messages
+                .iter()
+                .map(|m| (m.seq, m.message.clone()))
+                .collect(),
+
+            other => panic!("unexpected response: {:?}", other),
+        }
+    }
+
+    fn add_message(server: &mut ChatServer, id: &str) {
+        assert_eq!(
+            server.issue(ChatRequest::AddMessage {
+                id: id.to_string(),
+                chat_id: 1,
+                source_user_id: 1,
+                destination_user_id: Some(2),
+                timestamp: 100,
+                message: id.to_string(),
+                attachment_ids: Vec::new(),
+                envelope: None,
+                reply_to: None,
+                kind: MessageKind::Text,
+                previews: Vec::new(),
+            }),
+            ChatResponse::MessageAdded
+        );
+    }
+
+    #[test]
+    fn test_sled_store() {
+        let path = temp_dir("test_sled_store");
+
+        {
+            let mut server = ChatServer::new();
+            server
+                .attach_store(SledStore::open(&path).unwrap())
+                .unwrap();
+
+            for (id, list) in [(1, vec![2]), (2, vec![1])].iter() {
+                server.issue(ChatRequest::StoreContactList {
+                    id: *id,
+                    list: list.clone(),
+                });
+            }
+
+            server.issue(ChatRequest::CreateChat {
+                id: None,
+                participant_ids: vec![1, 2],
+                title: Some("test".to_string()),
+                created_at: None,
+                creator: None,
+            });
+
+            add_message(&mut server, "a");
+            server.checkpoint().unwrap();
+            add_message(&mut server, "b");
+
+            // the database is only released once it's closed, which
+            // must be waited for before it's opened again
+
+            server.close_store().unwrap();
+        }
+
+        let store = SledStore::open(&path).unwrap();
+
+        assert_eq!(store.chats.len(), 1);
+        assert_eq!(store.messages.len(), 1);
+        assert_eq!(store.log.len(), 1);
+
+        let mut server = ChatServer::new();
+        server.attach_store(store).unwrap();
+
+        assert_eq!(
+            messages(&mut server),
+            vec![(1, "a".to_string()), (2, "b".to_string())]
+        );
+
+        // the message ids are rebuilt, so duplicates are still detected
+
+        assert_eq!(
+            server.issue(ChatRequest::AddMessage {
+                id: "a".to_string(),
+                chat_id: 1,
+                source_user_id: 1,
+                destination_user_id: Some(2),
+                timestamp: 100,
+                message: "a".to_string(),
+                attachment_ids: Vec::new(),
+                envelope: None,
+                reply_to: None,
+                kind: MessageKind::Text,
+                previews: Vec::new(),
+            }),
+            ChatResponse::DuplicateMessage
+        );
+
+        assert_eq!(
+            server.issue(ChatRequest::ListChats {
+                user_id: 1,
+                include_archived: false,
+                limit: None,
+                before: None,
+                since: None,
+            }),
+            ChatResponse::ChatsListed {
+                chats: vec![Chat {
+                    id: 1,
+                    participant_ids: vec![1, 2],
+                    title: Some("test".to_string()),
+                    created_at: None,
+                    creator: None,
+                    message_ttl: None,
+                    muted: None,
+                    archived: false,
+                }]
+            }
+        );
+
+        server.close_store().unwrap();
+
+        fs::remove_dir_all(&path).unwrap();
+    }
+}
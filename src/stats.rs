@@ -0,0 +1,42 @@
+//! Provides statistics about a `ChatServer`'s state, e.g. so that
+//! an operator can see how it is being used and how large it is.
+//!
+//! Unlike metrics, which count the requests that were issued since
+//! the server started, statistics are computed from its state when
+//! they're requested, so they also cover what was replayed from its
+//! store. Sizes are estimates: those of chats count the content of
+//! their messages, as retention limits do, and that of the whole
+//! server is the size of its snapshot.
+
+use crate::chat::Id;
+use serde::Serialize;
+
+/// The default for how recently a user must have sent a message to
+/// be considered active, in minutes.
+pub const DEFAULT_ACTIVE_MINUTES: u64 = 15;
+
+/// Statistics about a server's state, including each of its chats
+/// in id order.
+#[derive(Debug, Default, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Stats {
+    pub(crate) total_chats: usize,
+    pub(crate) total_messages: usize,
+    pub(crate) total_users: usize,
+    pub(crate) active_users: usize,
+    pub(crate) active_minutes: u64,
+    pub(crate) message_bytes: usize,
+    pub(crate) snapshot_bytes: usize,
+    pub(crate) chats: Vec<ChatStats>,
+}
+
+/// Statistics about a single chat. Messages that were deleted are
+/// still counted, as their tombstones are retained.
+#[derive(Debug, Default, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ChatStats {
+    pub(crate) id: Id,
+    pub(crate) participants: usize,
+    pub(crate) messages: usize,
+    pub(crate) message_bytes: usize,
+}
@@ -0,0 +1,765 @@
+//! Provides persistence for a `ChatServer`, so that its state
+//! survives restarts.
+//!
+//! Persistence is pluggable via the `ChatStore` trait. Every
+//! mutating `ChatRequest` is appended to the store before it is
+//! applied, and on startup, the store's latest snapshot and the
+//! entries appended after it are loaded to rebuild the state.
+//!
+//! Each entry is annotated with its index and the server's clock
+//! reading (if it has a clock) so that replaying it is deterministic.
+//!
+//! Two stores are provided: `MemoryStore`, which is useful for
+//! tests, and `FileStore`, which keeps a write-ahead log and a
+//! snapshot on disk, optionally encrypting them.
+
+use crate::chat::{ChatRequest, Snapshot};
+use crate::encryption::{self, Keyring, Record};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::Error as IoError;
+use std::io::ErrorKind as IoErrorKind;
+use std::io::Result as IoResult;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// A request that was appended to a store.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct LogEntry {
+    pub index: u64,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub now: Option<u64>,
+
+    pub request: ChatRequest,
+}
+
+impl Record for LogEntry {
+    const KIND: &'static str = "log";
+
+    fn index(&self) -> u64 {
+        self.index
+    }
+}
+
+impl Record for Snapshot<'_> {
+    const KIND: &'static str = "snapshot";
+
+    fn index(&self) -> u64 {
+        self.log_index()
+    }
+}
+
+/// A backend that a `ChatServer` persists its state to.
+pub trait ChatStore {
+    /// Loads the latest snapshot, if there is one, and the entries
+    /// that were appended after it, in the order they were appended.
+    fn load(&mut self) -> IoResult<(Option<Snapshot<'static>>, Vec<LogEntry>)>;
+
+    /// Appends the supplied entry, only returning once it is durable.
+    fn append(&mut self, entry: &LogEntry) -> IoResult<()>;
+
+    /// Stores the supplied snapshot, which includes every entry that
+    /// has been appended, meaning they can be discarded.
+    fn snapshot(&mut self, snapshot: &Snapshot) -> IoResult<()>;
+
+    /// Determines if storing a snapshot discards the entries that were
+    /// appended before it, which erasing a user relies on so that their
+    /// data isn't kept. By default, it does.
+    fn discards_entries(&self) -> bool {
+        true
+    }
+
+    /// Closes the store, only returning once it has released what it
+    /// holds, e.g. so that it can be opened again straight away. By
+    /// default, there's nothing to release.
+    fn close(self: Box<Self>) -> IoResult<()> {
+        Ok(())
+    }
+}
+
+/// A store that keeps everything in memory, serialized in the same
+/// way as it would be on disk.
+#[derive(Default)]
+pub struct MemoryStore {
+    snapshot: Option<String>,
+    entries: Vec<String>,
+}
+
+impl MemoryStore {
+    /// Creates a new, empty store.
+    pub fn new() -> Self {
+        Self {
+            snapshot: None,
+            entries: Vec::new(),
+        }
+    }
+}
+
+impl ChatStore for MemoryStore {
+    fn load(&mut self) -> IoResult<(Option<Snapshot<'static>>, Vec<LogEntry>)> {
+        let snapshot = match self.snapshot {
+            Some(ref data) => Some(serde_json::from_str(data)?),
+            None => None,
+        };
+
+        let mut entries = Vec::with_capacity(self.entries.len());
+
+        for data in self.entries.iter() {
+            entries.push(serde_json::from_str(data)?);
+        }
+
+        Ok((snapshot, entries))
+    }
+
+    fn append(&mut self, entry: &LogEntry) -> IoResult<()> {
+        self.entries.push(serde_json::to_string(entry)?);
+
+        Ok(())
+    }
+
+    fn snapshot(&mut self, snapshot: &Snapshot) -> IoResult<()> {
+        self.snapshot = Some(serde_json::to_string(snapshot)?);
+        self.entries.clear();
+
+        Ok(())
+    }
+}
+
+/// A store that appends entries to a write-ahead log, and writes
+/// snapshots to a separate file, truncating the log afterwards.
+///
+/// Either file is optional -- without a log, state is only as
+/// recent as the last snapshot, and without a snapshot file, the
+/// log grows without bound, so users can't be erased from it.
+pub struct FileStore {
+    log: Option<WriteAheadLog>,
+    snapshot_path: Option<PathBuf>,
+    keyring: Option<Arc<Keyring>>,
+    allow_plaintext: bool,
+}
+
+impl FileStore {
+    /// Opens a store with the supplied log and snapshot paths,
+    /// creating the log if it doesn't exist.
+    pub fn open<P: AsRef<Path>>(log_path: Option<P>, snapshot_path: Option<P>) -> IoResult<Self> {
+        Ok(Self {
+            log: match log_path {
+                Some(path) => Some(WriteAheadLog::open(path)?),
+                None => None,
+            },
+            snapshot_path: snapshot_path.map(|path| path.as_ref().to_path_buf()),
+            keyring: None,
+            allow_plaintext: false,
+        })
+    }
+
+    /// Configures the store to encrypt its log records and snapshots
+    /// with the supplied keyring. Unencrypted data is then rejected,
+    /// unless it's allowed by `set_allow_plaintext`.
+    pub fn set_keyring(&mut self, keyring: Keyring) {
+        let keyring = Arc::new(keyring);
+
+        if let Some(ref mut log) = self.log {
+            log.set_keyring(keyring.clone());
+        }
+
+        self.keyring = Some(keyring);
+    }
+
+    /// Configures whether data that was written before encryption was
+    /// enabled can still be read, so that existing data can be
+    /// migrated. Once a snapshot has been taken, it's all encrypted.
+    pub fn set_allow_plaintext(&mut self, allow_plaintext: bool) {
+        if let Some(ref mut log) = self.log {
+            log.set_allow_plaintext(allow_plaintext);
+        }
+
+        self.allow_plaintext = allow_plaintext;
+    }
+}
+
+impl ChatStore for FileStore {
+    fn load(&mut self) -> IoResult<(Option<Snapshot<'static>>, Vec<LogEntry>)> {
+        let snapshot = match self.snapshot_path {
+            Some(ref path) => read_snapshot(path, self.keyring.as_deref(), self.allow_plaintext)?,
+            None => None,
+        };
+
+        let entries = match self.log {
+            Some(ref mut log) => log.read()?,
+            None => Vec::new(),
+        };
+
+        Ok((snapshot, entries))
+    }
+
+    fn append(&mut self, entry: &LogEntry) -> IoResult<()> {
+        match self.log {
+            Some(ref mut log) => log.append(entry),
+            None => Ok(()),
+        }
+    }
+
+    fn snapshot(&mut self, snapshot: &Snapshot) -> IoResult<()> {
+        if let Some(ref path) = self.snapshot_path {
+            write_snapshot(path, snapshot, self.keyring.as_deref())?;
+
+            if let Some(ref mut log) = self.log {
+                log.truncate()?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn discards_entries(&self) -> bool {
+        self.log.is_none() || self.snapshot_path.is_some()
+    }
+}
+
+/// An append-only log of the requests that have been applied to
+/// a `ChatServer`, stored as JSON lines.
+pub struct WriteAheadLog {
+    file: File,
+    keyring: Option<Arc<Keyring>>,
+    allow_plaintext: bool,
+}
+
+impl WriteAheadLog {
+    /// Opens the log at the supplied path, creating it if it
+    /// doesn't exist.
+    pub fn open<P: AsRef<Path>>(path: P) -> IoResult<Self> {
+        Ok(Self {
+            file: OpenOptions::new()
+                .read(true)
+                .append(true)
+                .create(true)
+                .open(path)?,
+            keyring: None,
+            allow_plaintext: false,
+        })
+    }
+
+    /// Configures the log to encrypt the entries that are appended
+    /// to it, and decrypt those that are read from it.
+    pub fn set_keyring(&mut self, keyring: Arc<Keyring>) {
+        self.keyring = Some(keyring);
+    }
+
+    /// Configures whether unencrypted entries are read when the log
+    /// has a keyring.
+    pub fn set_allow_plaintext(&mut self, allow_plaintext: bool) {
+        self.allow_plaintext = allow_plaintext;
+    }
+
+    /// Reads every entry in the log. If the process died whilst an
+    /// entry was being appended, the partial entry is discarded, and
+    /// if the entries aren't in order, the log is rejected.
+    pub fn read(&mut self) -> IoResult<Vec<LogEntry>> {
+        let mut data = String::new();
+
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.read_to_string(&mut data)?;
+
+        // every complete entry is terminated by a newline, so anything
+        // after the last one was torn by a crash and is truncated
+
+        let complete = data.rfind('\n').map_or(0, |i| i + 1);
+
+        if complete < data.len() {
+            self.file.set_len(complete as u64)?;
+        }
+
+        let mut entries: Vec<LogEntry> = Vec::new();
+
+        for line in data[..complete].lines() {
+            if !line.trim().is_empty() {
+                let entry: LogEntry =
+                    encryption::decode(line, self.keyring.as_deref(), self.allow_plaintext)
+                        .map_err(|e| {
+                            IoError::new(
+                                IoErrorKind::InvalidData,
+                                format!("invalid write-ahead log entry: {}", e),
+                            )
+                        })?;
+
+                // entries are bound to their indexes when encrypted,
+                // so this also detects them being reordered
+
+                if let Some(last) = entries.last() {
+                    if entry.index <= last.index {
+                        return Err(IoError::new(
+                            IoErrorKind::InvalidData,
+                            format!(
+                                "write-ahead log entry {} follows entry {}",
+                                entry.index, last.index
+                            ),
+                        ));
+                    }
+                }
+
+                entries.push(entry);
+            }
+        }
+
+        Ok(entries)
+    }
+
+    /// Appends the supplied entry to the log, only returning once
+    /// it has been synced to disk.
+    pub fn append(&mut self, entry: &LogEntry) -> IoResult<()> {
+        let mut data = encryption::encode(entry, self.keyring.as_deref())?;
+
+        data.push(b'\n');
+
+        self.file.write_all(&data)?;
+        self.file.sync_data()
+    }
+
+    /// Removes every entry from the log, which must only be done
+    /// once they're included in a snapshot.
+    pub fn truncate(&mut self) -> IoResult<()> {
+        self.file.set_len(0)?;
+        self.file.sync_data()
+    }
+}
+
+/// Writes the supplied snapshot to the supplied path, encrypting it
+/// if a keyring is supplied. It is first written to a temporary file
+/// which then replaces the path, so a crash can't leave a partially
+/// written snapshot behind.
+pub fn write_snapshot<P: AsRef<Path>>(
+    path: P,
+    snapshot: &Snapshot,
+    keyring: Option<&Keyring>,
+) -> IoResult<()> {
+    let path = path.as_ref();
+    let mut temp_path = path.as_os_str().to_owned();
+
+    temp_path.push(".tmp");
+
+    let mut file = File::create(&temp_path)?;
+
+    file.write_all(&encryption::encode(snapshot, keyring)?)?;
+
+    file.sync_all()?;
+
+    fs::rename(&temp_path, path)
+}
+
+/// Reads the snapshot at the supplied path, if there is one,
+/// decrypting it if it was encrypted. If a keyring is supplied, an
+/// unencrypted snapshot is rejected unless `allow_plaintext` is set.
+pub fn read_snapshot<P: AsRef<Path>>(
+    path: P,
+    keyring: Option<&Keyring>,
+    allow_plaintext: bool,
+) -> IoResult<Option<Snapshot<'static>>> {
+    match fs::read_to_string(path) {
+        Ok(data) => Ok(Some(
+            encryption::decode(&data, keyring, allow_plaintext).map_err(|e| {
+                IoError::new(IoErrorKind::InvalidData, format!("invalid snapshot: {}", e))
+            })?,
+        )),
+
+        Err(ref e) if e.kind() == IoErrorKind::NotFound => Ok(None),
+
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::chat::*;
+    use crate::storage::*;
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::process;
+
+    fn temp_path(name: &str, extension: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!(
+            "signal-http-{}-{}.{}",
+            name,
+            process::id(),
+            extension
+        ));
+
+        let _ = fs::remove_file(&path);
+
+        path
+    }
+
+    fn populate(server: &mut ChatServer) {
+        for (id, list) in [(1, vec![2]), (2, vec![1])].iter() {
+            server.issue(ChatRequest::StoreContactList {
+                id: *id,
+                list: list.clone(),
+            });
+        }
+
+        server.issue(ChatRequest::CreateChat {
+            id: None,
+            participant_ids: vec![1, 2],
+            title: None,
+            created_at: None,
+            creator: None,
+        });
+
+        server.issue(ChatRequest::AddMessage {
+            id: "a".to_string(),
+            chat_id: 1,
+            source_user_id: 1,
+            destination_user_id: Some(2),
+            timestamp: 100,
+            message: "test".to_string(),
+            attachment_ids: Vec::new(),
+            envelope: None,
+            reply_to: None,
+            kind: MessageKind::Text,
+            previews: Vec::new(),
+        });
+    }
+
+    fn add_message(server: &mut ChatServer) {
+        server.issue(ChatRequest::AddMessage {
+            id: "b".to_string(),
+            chat_id: 1,
+            source_user_id: 2,
+            destination_user_id: Some(1),
+            timestamp: 200,
+            message: "test2".to_string(),
+            attachment_ids: Vec::new(),
+            envelope: None,
+            reply_to: None,
+            kind: MessageKind::Text,
+            previews: Vec::new(),
+        });
+    }
+
+    fn messages(server: &mut ChatServer) -> Vec<(u64, u64, Option<u64>, String)> {
+        match server.issue(ChatRequest::ListChat {
+            id: 1,
+            cursor: None,
+            limit: None,
+            before: None,
+        }) {
+            ChatResponse::ChatListed { messages, .. } => messages
+                .iter()
+                .map(|m| (m.seq, m.timestamp, m.received_at, m.message.clone()))
+                .collect(),
+
+            other => panic!("unexpected response: {:?}", other),
+        }
+    }
+
+    fn open_log(path: &PathBuf) -> Vec<LogEntry> {
+        WriteAheadLog::open(path).unwrap().read().unwrap()
+    }
+
+    #[test]
+    fn test_memory_store() {
+        let mut store = MemoryStore::new();
+
+        store
+            .append(&LogEntry {
+                index: 1,
+                now: None,
+                request: ChatRequest::StoreContactList {
+                    id: 1,
+                    list: vec![2],
+                },
+            })
+            .unwrap();
+
+        let (snapshot, entries) = store.load().unwrap();
+
+        assert!(snapshot.is_none());
+        assert_eq!(entries.len(), 1);
+
+        store.snapshot(&ChatServer::new().snapshot()).unwrap();
+
+        let (snapshot, entries) = store.load().unwrap();
+
+        assert!(snapshot.is_some());
+        assert!(entries.is_empty());
+    }
+
+    #[test]
+    fn test_replay() {
+        let path = temp_path("replay", "wal");
+
+        let mut server = ChatServer::new();
+        server.set_server_timestamps(ServerTimestamps::Supplement, || 500);
+        server
+            .attach_store(FileStore::open(Some(&path), None).unwrap())
+            .unwrap();
+        populate(&mut server);
+
+        // queries aren't logged
+
+        assert_eq!(open_log(&path).len(), 4);
+
+        let mut replayed = ChatServer::new();
+        replayed.set_server_timestamps(ServerTimestamps::Supplement, || 1000);
+        replayed
+            .attach_store(FileStore::open(Some(&path), None).unwrap())
+            .unwrap();
+
+        assert_eq!(
+            messages(&mut replayed),
+            vec![(1, 100, Some(500), "test".to_string())]
+        );
+
+        assert_eq!(messages(&mut replayed), messages(&mut server));
+        assert_eq!(replayed.state_digest(), server.state_digest());
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_checkpoint() {
+        let path = temp_path("checkpoint", "wal");
+        let snapshot_path = temp_path("checkpoint", "snapshot");
+
+        let mut server = ChatServer::new();
+        server
+            .attach_store(FileStore::open(Some(&path), Some(&snapshot_path)).unwrap())
+            .unwrap();
+        populate(&mut server);
+        server.checkpoint().unwrap();
+
+        assert_eq!(fs::metadata(&path).unwrap().len(), 0);
+
+        add_message(&mut server);
+
+        assert_eq!(open_log(&path).len(), 1);
+
+        let mut recovered = ChatServer::new();
+        recovered
+            .attach_store(FileStore::open(Some(&path), Some(&snapshot_path)).unwrap())
+            .unwrap();
+
+        assert_eq!(messages(&mut recovered), messages(&mut server));
+        assert_eq!(recovered.state_digest(), server.state_digest());
+
+        assert!(read_snapshot(temp_path("missing", "snapshot"), None, false)
+            .unwrap()
+            .is_none());
+
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(&snapshot_path).unwrap();
+    }
+
+    #[test]
+    fn test_erasure() {
+        let path = temp_path("erasure", "wal");
+        let snapshot_path = temp_path("erasure", "snapshot");
+
+        // without a snapshot path, the log can't be truncated, so users
+        // can't be erased from it
+
+        let mut server = ChatServer::new();
+        server
+            .attach_store(FileStore::open(Some(&path), None).unwrap())
+            .unwrap();
+        populate(&mut server);
+        add_message(&mut server);
+
+        assert!(server
+            .issue(ChatRequest::EraseUser { user_id: 1 })
+            .is_error());
+
+        assert_eq!(open_log(&path).len(), 5);
+        assert_eq!(messages(&mut server).len(), 2);
+
+        // otherwise, neither file has their messages once they're
+        // erased, though the snapshot still has the other user's
+
+        let mut server = ChatServer::new();
+        server
+            .attach_store(FileStore::open(Some(&path), Some(&snapshot_path)).unwrap())
+            .unwrap();
+
+        assert!(!server
+            .issue(ChatRequest::EraseUser { user_id: 1 })
+            .is_error());
+
+        for path in [&path, &snapshot_path].iter() {
+            assert!(!fs::read_to_string(path).unwrap().contains("\"test\""));
+        }
+
+        assert!(fs::read_to_string(&snapshot_path)
+            .unwrap()
+            .contains("\"test2\""));
+
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(&snapshot_path).unwrap();
+    }
+
+    #[test]
+    fn test_stale_entries() {
+        let path = temp_path("stale", "wal");
+        let snapshot_path = temp_path("stale", "snapshot");
+
+        let mut server = ChatServer::new();
+        server
+            .attach_store(FileStore::open(Some(&path), Some(&snapshot_path)).unwrap())
+            .unwrap();
+        populate(&mut server);
+
+        // simulate a crash between writing a snapshot and truncating
+        // the log, whose entries mustn't be applied twice
+
+        write_snapshot(&snapshot_path, &server.snapshot(), None).unwrap();
+        add_message(&mut server);
+
+        assert_eq!(open_log(&path).len(), 5);
+
+        let mut recovered = ChatServer::new();
+        recovered
+            .attach_store(FileStore::open(Some(&path), Some(&snapshot_path)).unwrap())
+            .unwrap();
+
+        assert_eq!(messages(&mut recovered), messages(&mut server));
+
+        assert_eq!(
+            recovered.issue(ChatRequest::ListChats {
+                user_id: 1,
+                include_archived: false,
+                limit: None,
+                before: None,
+                since: None,
+            }),
+            server.issue(ChatRequest::ListChats {
+                user_id: 1,
+                include_archived: false,
+                limit: None,
+                before: None,
+                since: None,
+            })
+        );
+
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(&snapshot_path).unwrap();
+    }
+
+    #[test]
+    fn test_torn_entry() {
+        let path = temp_path("torn", "wal");
+
+        let mut server = ChatServer::new();
+        server
+            .attach_store(FileStore::open(Some(&path), None).unwrap())
+            .unwrap();
+        populate(&mut server);
+
+        let length = fs::metadata(&path).unwrap().len();
+
+        OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(b"{\"index\":5,\"request\":{\"AddMe")
+            .unwrap();
+
+        assert_eq!(open_log(&path).len(), 4);
+        assert_eq!(fs::metadata(&path).unwrap().len(), length);
+
+        fs::remove_file(&path).unwrap();
+    }
+    #[test]
+    fn test_encryption() {
+        let path = temp_path("encrypted", "wal");
+        let snapshot_path = temp_path("encrypted", "snapshot");
+
+        let open = |keys: &str| {
+            let mut store = FileStore::open(Some(&path), Some(&snapshot_path)).unwrap();
+            store.set_keyring(Keyring::parse(keys).unwrap());
+            store
+        };
+
+        let old_key = "old 000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+        let new_key = "new 1f1e1d1c1b1a191817161514131211100f0e0d0c0b0a09080706050403020100";
+
+        // plaintext written before encryption was enabled is only
+        // read when migrating
+
+        let mut server = ChatServer::new();
+        server
+            .attach_store(FileStore::open(Some(&path), None).unwrap())
+            .unwrap();
+        populate(&mut server);
+
+        assert!(open(old_key).load().is_err());
+
+        let mut migrating = open(old_key);
+        migrating.set_allow_plaintext(true);
+
+        let mut server = ChatServer::new();
+        server.attach_store(migrating).unwrap();
+        server.checkpoint().unwrap();
+        add_message(&mut server);
+        server.issue(ChatRequest::AddMessage {
+            id: "c".to_string(),
+            chat_id: 1,
+            source_user_id: 1,
+            destination_user_id: Some(2),
+            timestamp: 300,
+            message: "test3".to_string(),
+            attachment_ids: Vec::new(),
+            envelope: None,
+            reply_to: None,
+            kind: MessageKind::Text,
+            previews: Vec::new(),
+        });
+
+        for path in [&path, &snapshot_path].iter() {
+            let data = fs::read_to_string(path).unwrap();
+
+            assert!(data.contains("\"keyId\":\"old\""));
+            assert!(!data.contains("test"));
+        }
+
+        // entries can't be reordered
+
+        let data = fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = data.lines().collect();
+
+        assert_eq!(lines.len(), 2);
+
+        fs::write(&path, format!("{}\n{}\n", lines[1], lines[0])).unwrap();
+
+        assert!(open(old_key).load().is_err());
+
+        fs::write(&path, &data).unwrap();
+
+        // after rotation, records written with the old key can still
+        // be read, but the new key is used from then on
+
+        let mut recovered = ChatServer::new();
+        recovered
+            .attach_store(open(&format!("{}\n{}", new_key, old_key)))
+            .unwrap();
+
+        assert_eq!(messages(&mut recovered), messages(&mut server));
+
+        recovered.checkpoint().unwrap();
+
+        assert!(fs::read_to_string(&snapshot_path)
+            .unwrap()
+            .contains("\"keyId\":\"new\""));
+
+        // without the keys, the data can't be read
+
+        assert!(FileStore::open(Some(&path), Some(&snapshot_path))
+            .unwrap()
+            .load()
+            .is_err());
+
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(&snapshot_path).unwrap();
+    }
+}
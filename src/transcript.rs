@@ -0,0 +1,178 @@
+//! Provides transcripts of chats, which are portable copies of
+//! a chat's metadata, participants, and messages, e.g. for
+//! compliance requests and user data exports.
+//!
+//! A transcript can be encoded as JSON, which includes everything
+//! about every message, or as CSV, which has a row per message and
+//! is intended for spreadsheets and the like:
+//!
+//! ```text
+//! chatId,title,participantIds,id,seq,timestamp,sourceUserId,destinationUserId,editedAt,deleted,attachmentIds,message
+//! 1,Plans,1 2,a,1,1000,1,2,,false,,"Hello, there!"
+//! ```
+
+use crate::chat::{Chat, ChatMessage};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::sync::Arc;
+
+/// The header row of a CSV transcript.
+const CSV_HEADER: &str = "chatId,title,participantIds,id,seq,timestamp,sourceUserId,\
+                          destinationUserId,editedAt,deleted,attachmentIds,message";
+
+/// The formats that a transcript can be encoded as.
+#[derive(Copy, Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TranscriptFormat {
+    Json,
+    Csv,
+}
+
+/// A chat and its messages, in sequence order. Deleted messages
+/// are included as tombstones.
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Transcript<'a> {
+    pub(crate) chat: Chat,
+    pub(crate) messages: Cow<'a, [Arc<ChatMessage>]>,
+}
+
+impl<'a> Transcript<'a> {
+    /// Encodes this transcript in the supplied format.
+    pub fn encode(&self, format: TranscriptFormat) -> String {
+        match format {
+            TranscriptFormat::Json => {
+                serde_json::to_string(self).unwrap_or_else(|_| "{}".to_string())
+            }
+
+            TranscriptFormat::Csv => self.encode_csv(),
+        }
+    }
+
+    /// Internal API.
+    ///
+    /// Encodes this transcript as CSV, with a row per message.
+    fn encode_csv(&self) -> String {
+        let title = self.chat.title.as_ref().map_or("", String::as_str);
+        let participant_ids = join(&self.chat.participant_ids);
+
+        let mut csv = String::from(CSV_HEADER);
+
+        csv.push_str("\r\n");
+
+        for message in self.messages.iter() {
+            let fields = [
+                self.chat.id.to_string(),
+                escape(title),
+                participant_ids.clone(),
+                escape(&message.id),
+                message.seq.to_string(),
+                message.timestamp.to_string(),
+                message.source_user_id.to_string(),
+                message
+                    .destination_user_id
+                    .map_or_else(String::new, |id| id.to_string()),
+                message
+                    .edited_at
+                    .map_or_else(String::new, |edited_at| edited_at.to_string()),
+                message.deleted.to_string(),
+                escape(&join(&message.attachment_ids)),
+                escape(&message.message),
+            ];
+
+            csv.push_str(&fields.join(","));
+            csv.push_str("\r\n");
+        }
+
+        csv
+    }
+}
+
+/// Internal API.
+///
+/// Joins the supplied values with spaces, so that a list can be
+/// stored in a single CSV field.
+fn join<T: ToString>(values: &[T]) -> String {
+    values
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Internal API.
+///
+/// Quotes a CSV field if it contains a delimiter, quote, or line
+/// break, doubling any quotes within it.
+fn escape(field: &str) -> String {
+    if field.contains([',', '"', '\r', '\n']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::chat::MessageKind;
+    use crate::transcript::*;
+    use std::collections::BTreeMap;
+
+    fn message(id: &str, seq: u64, text: &str) -> ChatMessage {
+        ChatMessage {
+            id: id.to_string(),
+            seq,
+            timestamp: seq * 100,
+            message: text.to_string(),
+            attachment_ids: Vec::new(),
+            source_user_id: 1,
+            destination_user_id: Some(2),
+            edited_at: None,
+            deleted: false,
+            reactions: BTreeMap::new(),
+            receipts: BTreeMap::new(),
+            received_at: None,
+            envelope: None,
+            reply_to: None,
+            kind: MessageKind::Text,
+            previews: Vec::new(),
+            forwarded_from: None,
+            revisions: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_encode() {
+        let transcript = Transcript {
+            chat: Chat {
+                id: 1,
+                participant_ids: vec![1, 2],
+                title: Some("Plans".to_string()),
+                created_at: None,
+                creator: None,
+                message_ttl: None,
+                muted: None,
+                archived: false,
+            },
+            messages: Cow::Owned(vec![
+                Arc::new(message("a", 1, "Hello, \"there\"!")),
+                Arc::new(message("b", 2, "bye")),
+            ]),
+        };
+
+        assert_eq!(
+            transcript.encode(TranscriptFormat::Csv),
+            format!(
+                "{}\r\n{}\r\n{}\r\n",
+                CSV_HEADER,
+                "1,Plans,1 2,a,1,100,1,2,,false,,\"Hello, \"\"there\"\"!\"",
+                "1,Plans,1 2,b,2,200,1,2,,false,,bye"
+            )
+        );
+
+        assert_eq!(
+            serde_json::from_str::<Transcript>(&transcript.encode(TranscriptFormat::Json)).unwrap(),
+            transcript
+        );
+    }
+}
@@ -0,0 +1,594 @@
+//! Provides outbound webhook notifications, which POST a JSON
+//! event to callback URLs when messages are added.
+//!
+//! Users register URLs that are notified of the messages sent to
+//! them, and the operator can configure endpoints that are notified
+//! of every message. Deliveries are made by a small pool of
+//! background threads using the blocking `http_client`, so they
+//! never hold up requests. Each URL is always delivered to by the
+//! same thread, so a slow endpoint only holds up the URLs that share
+//! its thread, and each thread's queue is bounded, dropping (and
+//! counting) deliveries once it is full.
+//!
+//! Failed deliveries are retried with exponential backoff. Each URL
+//! has a circuit breaker: after enough consecutive failures, its
+//! deliveries are dropped until a cooldown has elapsed, so that an
+//! endpoint that is down doesn't accumulate an unbounded backlog.
+//!
+//! Users' URLs may only refer to public addresses, unless their host
+//! is allowed by the operator, so that they can't be used to make
+//! requests to internal services. They're checked when registered,
+//! and again each time they're delivered to, as their host could
+//! since resolve elsewhere.
+
+use crate::chat::ChatEvent;
+use crate::http::HttpMethod;
+use crate::http_client;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::io::Error as IoError;
+use std::io::ErrorKind as IoErrorKind;
+use std::io::Result as IoResult;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Default for how many times a delivery is attempted.
+const DEFAULT_MAX_ATTEMPTS: u32 = 5;
+
+/// Default for the delay before the first retry of a delivery,
+/// doubling after each subsequent failure.
+const DEFAULT_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Default for how many consecutive failures open a URL's circuit.
+const DEFAULT_FAILURE_THRESHOLD: u32 = 10;
+
+/// Default for how long a URL's circuit stays open.
+const DEFAULT_COOLDOWN: Duration = Duration::from_secs(60);
+
+/// Default for how many threads deliver webhooks.
+const DEFAULT_WORKERS: usize = 4;
+
+/// Default for how many deliveries each thread's queue holds.
+const DEFAULT_QUEUE_CAPACITY: usize = 1024;
+
+/// How long each delivery may take to connect, write, and read.
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Configures how webhooks are delivered, and spawns the threads
+/// that deliver them.
+pub struct Webhooks {
+    endpoints: Vec<String>,
+    allowed_hosts: Vec<String>,
+    max_attempts: u32,
+    backoff: Duration,
+    failure_threshold: u32,
+    cooldown: Duration,
+    workers: usize,
+    queue_capacity: usize,
+}
+
+impl Default for Webhooks {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Webhooks {
+    /// Creates a new configuration with no operator endpoints.
+    pub fn new() -> Self {
+        Self {
+            endpoints: Vec::new(),
+            allowed_hosts: Vec::new(),
+            max_attempts: DEFAULT_MAX_ATTEMPTS,
+            backoff: DEFAULT_BACKOFF,
+            failure_threshold: DEFAULT_FAILURE_THRESHOLD,
+            cooldown: DEFAULT_COOLDOWN,
+            workers: DEFAULT_WORKERS,
+            queue_capacity: DEFAULT_QUEUE_CAPACITY,
+        }
+    }
+
+    /// Adds an endpoint that is notified of every message,
+    /// regardless of who it was sent to.
+    pub fn add_endpoint(&mut self, url: String) {
+        self.endpoints.push(url);
+    }
+
+    /// Allows users' URLs to refer to the supplied host even if it
+    /// resolves to a loopback, private, or link-local address.
+    pub fn allow_host(&mut self, host: String) {
+        self.allowed_hosts.push(host);
+    }
+
+    /// Configures how many times a delivery is attempted before
+    /// it is dropped.
+    pub fn set_max_attempts(&mut self, max_attempts: u32) {
+        self.max_attempts = max_attempts;
+    }
+
+    /// Configures the delay before the first retry of a delivery,
+    /// which doubles after each subsequent failure.
+    pub fn set_backoff(&mut self, backoff: Duration) {
+        self.backoff = backoff;
+    }
+
+    /// Configures how many consecutive failures open a URL's
+    /// circuit, and how long it then stays open.
+    pub fn set_circuit_breaker(&mut self, failure_threshold: u32, cooldown: Duration) {
+        self.failure_threshold = failure_threshold;
+        self.cooldown = cooldown;
+    }
+
+    /// Configures how many threads deliver webhooks, and how many
+    /// deliveries each of their queues holds before further ones
+    /// are dropped.
+    pub fn set_workers(&mut self, workers: usize, queue_capacity: usize) {
+        self.workers = workers.max(1);
+        self.queue_capacity = queue_capacity;
+    }
+
+    /// Spawns the threads that deliver webhooks, returning a queue
+    /// that deliveries are sent to. The threads exit once every
+    /// clone of the queue has been dropped.
+    pub fn spawn(self) -> IoResult<WebhookQueue> {
+        let config = Arc::new(self);
+        let mut senders = Vec::with_capacity(config.workers);
+
+        for worker in 0..config.workers {
+            let (sender, receiver) = mpsc::sync_channel(config.queue_capacity);
+            let dispatcher = Dispatcher::new(config.clone());
+
+            thread::Builder::new()
+                .name(format!("webhooks-{}", worker))
+                .spawn(move || dispatcher.run(&receiver))?;
+
+            senders.push(sender);
+        }
+
+        Ok(WebhookQueue {
+            config,
+            senders: Arc::new(senders),
+            dropped: Arc::new(AtomicU64::new(0)),
+        })
+    }
+}
+
+/// A handle to the threads that deliver webhooks.
+#[derive(Clone)]
+pub struct WebhookQueue {
+    config: Arc<Webhooks>,
+    senders: Arc<Vec<SyncSender<Delivery>>>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl WebhookQueue {
+    /// Queues the supplied event for delivery to the supplied URLs,
+    /// which users registered, and every operator endpoint.
+    pub fn notify(&self, event: &ChatEvent, urls: &[String]) {
+        let body = match serde_json::to_string(event) {
+            Ok(body) => Arc::new(body),
+            Err(_) => return,
+        };
+
+        for url in self.config.endpoints.iter() {
+            self.enqueue(Delivery {
+                url: url.clone(),
+                body: body.clone(),
+                headers: None,
+                restricted: false,
+                attempt: 1,
+            });
+        }
+
+        for url in urls {
+            self.enqueue(Delivery {
+                url: url.clone(),
+                body: body.clone(),
+                headers: None,
+                restricted: true,
+                attempt: 1,
+            });
+        }
+    }
+
+    /// Queues the supplied body for delivery to the supplied URL
+    /// only, e.g. to relay a request to another server. The supplied
+    /// function obtains the headers to send in addition to the content
+    /// type from the body each time it is attempted, so that they can
+    /// sign it at the time it's sent.
+    pub fn send<F>(&self, url: &str, body: String, headers: F)
+    where
+        F: Fn(&str) -> Vec<(String, String)> + Send + Sync + 'static,
+    {
+        self.enqueue(Delivery {
+            url: url.to_string(),
+            body: Arc::new(body),
+            headers: Some(Arc::new(headers)),
+            restricted: false,
+            attempt: 1,
+        });
+    }
+
+    /// Checks that the supplied URL, which a user is registering,
+    /// only refers to public addresses, unless its host is allowed.
+    pub fn check_url(&self, url: &str) -> IoResult<()> {
+        resolve(url, Some(&self.config.allowed_hosts)).map(|_| ())
+    }
+
+    /// Obtains how many deliveries have been dropped because their
+    /// queue was full.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Internal API.
+    ///
+    /// Queues the supplied delivery on its URL's thread, dropping it
+    /// if that thread's queue is full.
+    fn enqueue(&self, delivery: Delivery) {
+        let mut hasher = DefaultHasher::new();
+
+        delivery.url.hash(&mut hasher);
+
+        let worker = (hasher.finish() % self.senders.len() as u64) as usize;
+
+        if let Err(TrySendError::Full(delivery)) = self.senders[worker].try_send(delivery) {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+
+            eprintln!("dropped webhook to {}: queue is full", delivery.url);
+        }
+    }
+}
+
+/// Internal API.
+///
+/// An event to POST to a URL, along with what obtains any extra
+/// headers, whether the URL is a user's and so may only refer to
+/// public addresses, and which attempt it will be.
+struct Delivery {
+    url: String,
+    body: Arc<String>,
+    headers: Option<DeliveryHeaders>,
+    restricted: bool,
+    attempt: u32,
+}
+
+/// Internal API.
+///
+/// Obtains the extra headers of a delivery from its body, each time
+/// it is attempted.
+type DeliveryHeaders = Arc<dyn Fn(&str) -> Vec<(String, String)> + Send + Sync>;
+
+/// Internal API.
+///
+/// The state of a URL's circuit breaker.
+#[derive(Debug, Default, PartialEq)]
+struct Circuit {
+    failures: u32,
+    open_until: Option<Instant>,
+}
+
+/// Internal API.
+///
+/// Delivers webhooks, retrying failures and tracking each URL's
+/// circuit.
+struct Dispatcher {
+    config: Arc<Webhooks>,
+    circuits: HashMap<String, Circuit>,
+    retries: Vec<(Instant, Delivery)>,
+}
+
+impl Dispatcher {
+    fn new(config: Arc<Webhooks>) -> Self {
+        Self {
+            config,
+            circuits: HashMap::new(),
+            retries: Vec::new(),
+        }
+    }
+
+    /// Internal API.
+    ///
+    /// Delivers the deliveries that are received, and retries that
+    /// are due, until the queue is disconnected.
+    fn run(mut self, receiver: &Receiver<Delivery>) {
+        loop {
+            let next_retry = self.retries.iter().map(|(due, _)| *due).min();
+
+            let received = match next_retry {
+                Some(due) => {
+                    let now = Instant::now();
+
+                    receiver.recv_timeout(if due > now {
+                        due - now
+                    } else {
+                        Duration::from_secs(0)
+                    })
+                }
+
+                None => receiver.recv().map_err(|_| RecvTimeoutError::Disconnected),
+            };
+
+            match received {
+                Ok(delivery) => self.deliver(delivery, Instant::now()),
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => return,
+            }
+
+            let now = Instant::now();
+            let (due, pending) = self.retries.drain(..).partition(|(at, _)| *at <= now);
+
+            self.retries = pending;
+
+            for (_, delivery) in due {
+                self.deliver(delivery, now);
+            }
+        }
+    }
+
+    /// Internal API.
+    ///
+    /// Attempts the supplied delivery unless its URL's circuit is
+    /// open, scheduling a retry if it fails.
+    fn deliver(&mut self, delivery: Delivery, now: Instant) {
+        if !self.allowed(&delivery.url, now) {
+            eprintln!("dropped webhook to {}: circuit is open", delivery.url);
+
+            return;
+        }
+
+        let extra = match delivery.headers {
+            Some(ref headers) => headers(&delivery.body),
+            None => Vec::new(),
+        };
+
+        let mut headers = vec![("Content-Type", "application/json")];
+
+        headers.extend(
+            extra
+                .iter()
+                .map(|(name, value)| (name.as_str(), value.as_str())),
+        );
+
+        let allowed_hosts = match delivery.restricted {
+            true => Some(self.config.allowed_hosts.as_slice()),
+            false => None,
+        };
+
+        let result = resolve(&delivery.url, allowed_hosts)
+            .and_then(|addr| {
+                http_client::request_at(
+                    addr,
+                    HttpMethod::POST,
+                    &delivery.url,
+                    &headers,
+                    Some(&delivery.body),
+                    DELIVERY_TIMEOUT,
+                )
+            })
+            .and_then(|response| {
+                if response.is_success() {
+                    Ok(())
+                } else {
+                    Err(IoError::other(format!(
+                        "unexpected status {}",
+                        response.status
+                    )))
+                }
+            });
+
+        match result {
+            Ok(()) => self.record(&delivery.url, true, now),
+
+            Err(ref e) if e.kind() == IoErrorKind::PermissionDenied => {
+                eprintln!("dropped webhook to {}: {}", delivery.url, e);
+            }
+
+            Err(e) => {
+                self.record(&delivery.url, false, now);
+
+                if delivery.attempt < self.config.max_attempts {
+                    let backoff = self.config.backoff * 2u32.pow((delivery.attempt - 1).min(16));
+
+                    self.retries.push((
+                        now + backoff,
+                        Delivery {
+                            attempt: delivery.attempt + 1,
+                            ..delivery
+                        },
+                    ));
+                } else {
+                    eprintln!(
+                        "dropped webhook to {} after {} attempts: {}",
+                        delivery.url, delivery.attempt, e
+                    );
+                }
+            }
+        }
+    }
+
+    /// Internal API.
+    ///
+    /// Determines if a delivery to the supplied URL may be attempted,
+    /// i.e. its circuit isn't open. Once the cooldown has elapsed, one
+    /// attempt is allowed, and if it fails the circuit opens again.
+    fn allowed(&self, url: &str, now: Instant) -> bool {
+        self.circuits
+            .get(url)
+            .and_then(|circuit| circuit.open_until)
+            .is_none_or(|open_until| now >= open_until)
+    }
+
+    /// Internal API.
+    ///
+    /// Records the outcome of a delivery to the supplied URL,
+    /// opening its circuit if it has failed too many times in a row.
+    fn record(&mut self, url: &str, success: bool, now: Instant) {
+        if success {
+            self.circuits.remove(url);
+
+            return;
+        }
+
+        let circuit = self.circuits.entry(url.to_string()).or_default();
+
+        circuit.failures += 1;
+
+        if circuit.failures >= self.config.failure_threshold {
+            circuit.open_until = Some(now + self.config.cooldown);
+        }
+    }
+}
+
+/// Internal API.
+///
+/// Resolves the host of the supplied URL to the address that it's
+/// delivered to. If allowed hosts are supplied, the URL is a user's,
+/// so unless its host is one of them, every address it resolves to
+/// must be public.
+fn resolve(url: &str, allowed_hosts: Option<&[String]>) -> IoResult<SocketAddr> {
+    let addrs = http_client::resolve(url)?;
+    let addr = *addrs
+        .first()
+        .ok_or_else(|| IoError::new(IoErrorKind::NotFound, "cannot resolve host"))?;
+
+    if let Some(allowed_hosts) = allowed_hosts {
+        let host = http_client::host(url)?;
+
+        if !allowed_hosts.iter().any(|h| h.eq_ignore_ascii_case(host))
+            && !addrs.iter().all(|addr| is_public(addr.ip()))
+        {
+            return Err(IoError::new(
+                IoErrorKind::PermissionDenied,
+                "webhook URLs must not refer to loopback, private, or link-local addresses",
+            ));
+        }
+    }
+
+    Ok(addr)
+}
+
+/// Internal API.
+///
+/// Determines if the supplied address is publicly routable, rather
+/// than e.g. loopback, private, or link-local.
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+
+            !(ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_multicast()
+                || ip.is_documentation()
+                || a == 0
+                || a == 100 && (b & 0xc0) == 64)
+        }
+
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public(IpAddr::V4(ip)),
+
+            None => {
+                let first = ip.segments()[0];
+
+                !(ip.is_loopback()
+                    || ip.is_unspecified()
+                    || ip.is_multicast()
+                    || first & 0xfe00 == 0xfc00
+                    || first & 0xffc0 == 0xfe80)
+            }
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::webhooks::*;
+
+    #[test]
+    fn test_circuit_breaker() {
+        let mut config = Webhooks::new();
+        config.set_circuit_breaker(2, Duration::from_secs(60));
+
+        let mut dispatcher = Dispatcher::new(Arc::new(config));
+        let now = Instant::now();
+        let url = "http://127.0.0.1:1/hook";
+
+        dispatcher.record(url, false, now);
+
+        assert!(dispatcher.allowed(url, now));
+
+        dispatcher.record(url, false, now);
+
+        assert!(!dispatcher.allowed(url, now));
+        assert!(dispatcher.allowed("http://127.0.0.1:1/other", now));
+
+        // once the cooldown elapses, an attempt is allowed, and a
+        // success closes the circuit
+
+        let later = now + Duration::from_secs(60);
+
+        assert!(dispatcher.allowed(url, later));
+
+        dispatcher.record(url, true, later);
+
+        assert_eq!(dispatcher.circuits.get(url), None);
+    }
+
+    #[test]
+    fn test_resolve() {
+        let allowed = vec!["localhost".to_string()];
+
+        for url in [
+            "http://127.0.0.1:1/hook",
+            "http://10.0.0.1/hook",
+            "http://169.254.169.254/latest/meta-data",
+            "http://100.64.0.1/hook",
+            "http://0.0.0.0/hook",
+        ]
+        .iter()
+        {
+            assert_eq!(
+                resolve(url, Some(&allowed)).unwrap_err().kind(),
+                IoErrorKind::PermissionDenied
+            );
+
+            assert!(resolve(url, None).is_ok());
+        }
+
+        assert!(resolve("http://93.184.215.14/hook", Some(&allowed)).is_ok());
+        assert!(resolve("http://localhost:1/hook", Some(&allowed)).is_ok());
+
+        assert!(!is_public("::1".parse().unwrap()));
+        assert!(!is_public("fd00::1".parse().unwrap()));
+        assert!(!is_public("fe80::1".parse().unwrap()));
+        assert!(!is_public("::ffff:192.168.0.1".parse().unwrap()));
+        assert!(is_public("2001:4860::8888".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_queue_overflow() {
+        let (sender, receiver) = mpsc::sync_channel(1);
+
+        let queue = WebhookQueue {
+            config: Arc::new(Webhooks::new()),
+            senders: Arc::new(vec![sender]),
+            dropped: Arc::new(AtomicU64::new(0)),
+        };
+
+        for _ in 0..3 {
+            queue.send("http://127.0.0.1:1/hook", "{}".to_string(), |_| Vec::new());
+        }
+
+        assert_eq!(queue.dropped(), 2);
+        assert_eq!(receiver.try_iter().count(), 1);
+    }
+}
@@ -0,0 +1,261 @@
+//! Provides the parts of the WebSocket protocol (RFC 6455) that a
+//! server needs, i.e. accepting a client's handshake, and decoding
+//! and encoding the frames that are exchanged afterwards.
+//!
+//! Frames that clients send must be masked, and frames that servers
+//! send mustn't be. Messages must fit in a single frame, as
+//! fragmented messages aren't supported, and their payloads can't be
+//! larger than `MAX_PAYLOAD_SIZE`.
+
+use sha1::{Digest, Sha1};
+use std::convert::TryFrom;
+use std::io::Error as IoError;
+use std::io::ErrorKind as IoErrorKind;
+use std::io::Result as IoResult;
+
+/// The GUID that a client's key is combined with to accept it.
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// The largest payload that is accepted, in bytes.
+pub const MAX_PAYLOAD_SIZE: usize = 64 * 1024;
+
+/// The status of a close frame for a connection that is closing
+/// normally.
+pub const NORMAL_CLOSURE: u16 = 1000;
+
+/// The status of a close frame for a connection whose peer violated
+/// the protocol, e.g. by sending an unmasked frame.
+pub const PROTOCOL_ERROR: u16 = 1002;
+
+/// The status of a close frame for a connection whose peer sent data
+/// that can't be accepted, e.g. a binary message.
+pub const UNSUPPORTED_DATA: u16 = 1003;
+
+/// What a frame is, i.e. a message, or a control frame.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Opcode {
+    Text,
+    Binary,
+    Close,
+    Ping,
+    Pong,
+}
+
+/// A frame, i.e. a message or control frame, and its payload.
+#[derive(Debug, PartialEq)]
+pub struct Frame {
+    pub opcode: Opcode,
+    pub payload: Vec<u8>,
+}
+
+impl Opcode {
+    /// Internal API.
+    ///
+    /// Obtains the opcode with the supplied value, or `None` if it
+    /// isn't supported, e.g. a continuation.
+    fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0x1 => Some(Opcode::Text),
+            0x2 => Some(Opcode::Binary),
+            0x8 => Some(Opcode::Close),
+            0x9 => Some(Opcode::Ping),
+            0xA => Some(Opcode::Pong),
+            _ => None,
+        }
+    }
+
+    /// Internal API.
+    ///
+    /// Obtains this opcode's value.
+    fn to_u8(self) -> u8 {
+        match self {
+            Opcode::Text => 0x1,
+            Opcode::Binary => 0x2,
+            Opcode::Close => 0x8,
+            Opcode::Ping => 0x9,
+            Opcode::Pong => 0xA,
+        }
+    }
+
+    /// Internal API.
+    ///
+    /// Determines if this is a control frame, whose payload must be
+    /// small.
+    fn is_control(self) -> bool {
+        self == Opcode::Close || self == Opcode::Ping || self == Opcode::Pong
+    }
+}
+
+impl Frame {
+    /// Creates a close frame with the supplied status.
+    pub fn close(status: u16) -> Self {
+        Self {
+            opcode: Opcode::Close,
+            payload: status.to_be_bytes().to_vec(),
+        }
+    }
+
+    /// Decodes the frame at the start of the supplied data, which a
+    /// client sent, returning it along with how many bytes it took,
+    /// or `None` if the data doesn't have all of it yet.
+    pub fn decode(data: &[u8]) -> IoResult<Option<(Self, usize)>> {
+        if data.len() < 2 {
+            return Ok(None);
+        }
+
+        let (fin, reserved, opcode) = (data[0] & 0x80 != 0, data[0] & 0x70, data[0] & 0x0F);
+        let (masked, length) = (data[1] & 0x80 != 0, data[1] & 0x7F);
+
+        let opcode = match Opcode::from_u8(opcode) {
+            Some(opcode) if fin && reserved == 0 && masked => opcode,
+            _ => return Err(invalid("the frame is fragmented, unmasked, or unsupported")),
+        };
+
+        let (length, offset) = match length {
+            126 if data.len() >= 4 => (u64::from(u16::from_be_bytes([data[2], data[3]])), 4),
+
+            127 if data.len() >= 10 => {
+                let mut bytes = [0; 8];
+                bytes.copy_from_slice(&data[2..10]);
+
+                (u64::from_be_bytes(bytes), 10)
+            }
+
+            126 | 127 => return Ok(None),
+
+            length => (u64::from(length), 2),
+        };
+
+        let length = match usize::try_from(length) {
+            Ok(length) if length <= MAX_PAYLOAD_SIZE => length,
+            _ => return Err(invalid("the frame is too large")),
+        };
+
+        if opcode.is_control() && length > 125 {
+            return Err(invalid("the control frame is too large"));
+        }
+
+        if data.len() < offset + 4 + length {
+            return Ok(None);
+        }
+
+        let mask = &data[offset..offset + 4];
+
+        let payload = data[offset + 4..offset + 4 + length]
+            .iter()
+            .enumerate()
+            .map(|(i, byte)| byte ^ mask[i % 4])
+            .collect();
+
+        Ok(Some((Self { opcode, payload }, offset + 4 + length)))
+    }
+
+    /// Encodes this frame, unmasked, for a server to send.
+    pub fn encode(&self) -> Vec<u8> {
+        let length = self.payload.len();
+        let mut data = vec![0x80 | self.opcode.to_u8()];
+
+        if length < 126 {
+            data.push(length as u8);
+        } else if length <= usize::from(u16::MAX) {
+            data.push(126);
+            data.extend_from_slice(&(length as u16).to_be_bytes());
+        } else {
+            data.push(127);
+            data.extend_from_slice(&(length as u64).to_be_bytes());
+        }
+
+        data.extend_from_slice(&self.payload);
+
+        data
+    }
+}
+
+/// Obtains the value of the `Sec-WebSocket-Accept` header that
+/// accepts a handshake with the supplied `Sec-WebSocket-Key`.
+pub fn accept_key(key: &str) -> String {
+    let mut hasher = Sha1::new();
+    hasher.update(key.trim().as_bytes());
+    hasher.update(GUID.as_bytes());
+
+    base64::encode(hasher.finalize())
+}
+
+/// Internal API.
+///
+/// An error for a frame that can't be decoded.
+fn invalid(message: &str) -> IoError {
+    IoError::new(IoErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::websocket::*;
+
+    #[test]
+    fn test_accept_key() {
+        // the example from RFC 6455
+
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[test]
+    fn test_decode_and_encode() {
+        // a masked "Hello", from RFC 6455, followed by the start of
+        // another frame
+
+        let data = [
+            0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58, 0x89,
+        ];
+
+        assert_eq!(
+            Frame::decode(&data).unwrap(),
+            Some((
+                Frame {
+                    opcode: Opcode::Text,
+                    payload: b"Hello".to_vec()
+                },
+                11
+            ))
+        );
+
+        for end in 0..11 {
+            assert_eq!(Frame::decode(&data[..end]).unwrap(), None);
+        }
+
+        assert_eq!(
+            Frame {
+                opcode: Opcode::Text,
+                payload: b"Hello".to_vec()
+            }
+            .encode(),
+            vec![0x81, 0x05, 0x48, 0x65, 0x6c, 0x6c, 0x6f]
+        );
+
+        let encoded = Frame {
+            opcode: Opcode::Binary,
+            payload: vec![0; 300],
+        }
+        .encode();
+
+        assert_eq!(&encoded[..4], &[0x82, 126, 0x01, 0x2c]);
+        assert_eq!(encoded.len(), 304);
+
+        assert_eq!(
+            Frame::close(NORMAL_CLOSURE).encode(),
+            vec![0x88, 0x02, 0x03, 0xe8]
+        );
+
+        // unmasked, fragmented, oversized, and unknown frames are
+        // rejected
+
+        assert!(Frame::decode(&[0x81, 0x05]).is_err());
+        assert!(Frame::decode(&[0x01, 0x85]).is_err());
+        assert!(Frame::decode(&[0x83, 0x85]).is_err());
+        assert!(Frame::decode(&[0x89, 0xfe, 0x00, 0x7e]).is_err());
+        assert!(Frame::decode(&[0x81, 0xff, 0, 0, 0, 0, 0, 1, 0, 1]).is_err());
+    }
+}